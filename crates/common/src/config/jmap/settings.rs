@@ -55,6 +55,7 @@ pub struct JmapConfig {
     pub web_socket_heartbeat: Duration,
 
     pub oauth_key: String,
+    pub oauth_previous_key: Option<(String, u64)>,
     pub oauth_expiry_user_code: u64,
     pub oauth_expiry_auth_code: u64,
     pub oauth_expiry_token: u64,
@@ -217,6 +218,14 @@ impl JmapConfig {
                         .map(char::from)
                         .collect::<String>()
                 }),
+            oauth_previous_key: config
+                .value("oauth.previous.key")
+                .map(|key| key.to_string())
+                .and_then(|key| {
+                    config
+                        .property::<u64>("oauth.previous.expires")
+                        .map(|expires| (key, expires))
+                }),
             oauth_expiry_user_code: config
                 .property_or_default::<Duration>("oauth.expiry.user-code", "30m")
                 .unwrap_or_else(|| Duration::from_secs(30 * 60))
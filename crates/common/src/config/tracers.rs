@@ -1,6 +1,7 @@
 use std::{collections::HashMap, str::FromStr};
 
 use opentelemetry_otlp::{HttpExporterBuilder, TonicExporterBuilder, WithExportConfig};
+use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue, MetadataMap};
 use tracing::Level;
 use tracing_appender::rolling::RollingFileAppender;
 use utils::config::Config;
@@ -16,12 +17,17 @@ pub enum Tracer {
         appender: RollingFileAppender,
         ansi: bool,
     },
+    Json {
+        level: Level,
+        appender: RollingFileAppender,
+    },
     Journal {
         level: Level,
     },
     Otel {
         level: Level,
         tracer: OtelTracer,
+        sampling_ratio: f64,
     },
 }
 
@@ -71,24 +77,7 @@ impl Tracers {
                 .as_str()
             {
                 "log" => {
-                    if let Some(path) = config
-                        .value_require(("tracer", id, "path"))
-                        .map(|s| s.to_string())
-                    {
-                        let prefix = config.value(("tracer", id, "prefix")).unwrap_or("stalwart");
-                        let appender =
-                            match config.value(("tracer", id, "rotate")).unwrap_or("daily") {
-                                "daily" => tracing_appender::rolling::daily(path, prefix),
-                                "hourly" => tracing_appender::rolling::hourly(path, prefix),
-                                "minutely" => tracing_appender::rolling::minutely(path, prefix),
-                                "never" => tracing_appender::rolling::never(path, prefix),
-                                rotate => {
-                                    let appender = tracing_appender::rolling::daily(path, prefix);
-                                    let err = format!("Invalid rotate value: {rotate}");
-                                    config.new_parse_error(("tracer", id, "rotate"), err);
-                                    appender
-                                }
-                            };
+                    if let Some(appender) = parse_appender(config, id) {
                         tracers.push(Tracer::Log {
                             level,
                             appender,
@@ -98,6 +87,11 @@ impl Tracers {
                         });
                     }
                 }
+                "json" => {
+                    if let Some(appender) = parse_appender(config, id) {
+                        tracers.push(Tracer::Json { level, appender });
+                    }
+                }
                 "stdout" => {
                     tracers.push(Tracer::Stdout {
                         level,
@@ -106,19 +100,69 @@ impl Tracers {
                             .unwrap_or(true),
                     });
                 }
-                "otel" | "open-telemetry" => {
+                "otel" | "open-telemetry" | "otlp" => {
+                    let mut headers = HashMap::new();
+                    let mut err = None;
+                    for (_, value) in config.values(("tracer", id, "headers")) {
+                        if let Some((key, value)) = value.split_once(':') {
+                            headers.insert(key.trim().to_string(), value.trim().to_string());
+                        } else {
+                            err = format!("Invalid open-telemetry header {value:?}").into();
+                            break;
+                        }
+                    }
+                    if let Some(err) = err {
+                        config.new_parse_error(("tracer", id, "headers"), err);
+                    }
+                    let sampling_ratio = config
+                        .property_or_default::<f64>(("tracer", id, "sampling-ratio"), "1.0")
+                        .unwrap_or(1.0);
+                    if !(0.0..=1.0).contains(&sampling_ratio) {
+                        config.new_parse_error(
+                            ("tracer", id, "sampling-ratio"),
+                            format!("Sampling ratio {sampling_ratio} is not between 0 and 1"),
+                        );
+                    }
+                    let sampling_ratio = sampling_ratio.clamp(0.0, 1.0);
+
+                    // 'protocol' is accepted as an alias of 'transport'
+                    let transport_key = if config.contains_key(("tracer", id, "protocol")) {
+                        "protocol"
+                    } else {
+                        "transport"
+                    };
                     match config
-                        .value_require(("tracer", id, "transport"))
+                        .value_require(("tracer", id, transport_key))
                         .unwrap_or_default()
                     {
-                        "gprc" => {
+                        "grpc" | "gprc" => {
                             let mut exporter = opentelemetry_otlp::new_exporter().tonic();
                             if let Some(endpoint) = config.value(("tracer", id, "endpoint")) {
                                 exporter = exporter.with_endpoint(endpoint);
                             }
+                            if !headers.is_empty() {
+                                let mut metadata = MetadataMap::with_capacity(headers.len());
+                                for (key, value) in headers {
+                                    match (
+                                        AsciiMetadataKey::from_bytes(key.as_bytes()),
+                                        AsciiMetadataValue::try_from(value.as_str()),
+                                    ) {
+                                        (Ok(key), Ok(value)) => {
+                                            metadata.insert(key, value);
+                                        }
+                                        _ => {
+                                            let err =
+                                                format!("Invalid open-telemetry header {key:?}");
+                                            config.new_parse_error(("tracer", id, "headers"), err);
+                                        }
+                                    }
+                                }
+                                exporter = exporter.with_metadata(metadata);
+                            }
                             tracers.push(Tracer::Otel {
                                 level,
                                 tracer: OtelTracer::Gprc(exporter),
+                                sampling_ratio,
                             });
                         }
                         "http" => {
@@ -126,24 +170,6 @@ impl Tracers {
                                 .value_require(("tracer", id, "endpoint"))
                                 .map(|s| s.to_string())
                             {
-                                let mut headers = HashMap::new();
-                                let mut err = None;
-                                for (_, value) in config.values(("tracer", id, "headers")) {
-                                    if let Some((key, value)) = value.split_once(':') {
-                                        headers.insert(
-                                            key.trim().to_string(),
-                                            value.trim().to_string(),
-                                        );
-                                    } else {
-                                        err = format!("Invalid open-telemetry header {value:?}")
-                                            .into();
-                                        break;
-                                    }
-                                }
-                                if let Some(err) = err {
-                                    config.new_parse_error(("tracer", id, "headers"), err);
-                                }
-
                                 let mut exporter = opentelemetry_otlp::new_exporter()
                                     .http()
                                     .with_endpoint(endpoint);
@@ -154,13 +180,14 @@ impl Tracers {
                                 tracers.push(Tracer::Otel {
                                     level,
                                     tracer: OtelTracer::Http(exporter),
+                                    sampling_ratio,
                                 });
                             }
                         }
                         "" => {}
                         transport => {
                             let err = format!("Invalid transport: {transport}");
-                            config.new_parse_error(("tracer", id, "transport"), err);
+                            config.new_parse_error(("tracer", id, transport_key), err);
                         }
                     }
                 }
@@ -185,4 +212,35 @@ impl Tracers {
 
         Tracers { tracers }
     }
+
+    /// Overrides the level of all tracers, used by '--quiet' and '--verbose'.
+    pub fn set_level(&mut self, new_level: Level) {
+        for tracer in &mut self.tracers {
+            let (Tracer::Stdout { level, .. }
+            | Tracer::Log { level, .. }
+            | Tracer::Json { level, .. }
+            | Tracer::Journal { level }
+            | Tracer::Otel { level, .. }) = tracer;
+            *level = new_level;
+        }
+    }
+}
+
+fn parse_appender(config: &mut Config, id: &str) -> Option<RollingFileAppender> {
+    let path = config.value_require(("tracer", id, "path"))?.to_string();
+    let prefix = config.value(("tracer", id, "prefix")).unwrap_or("stalwart");
+    let appender = match config.value(("tracer", id, "rotate")).unwrap_or("daily") {
+        "daily" => tracing_appender::rolling::daily(path, prefix),
+        "hourly" => tracing_appender::rolling::hourly(path, prefix),
+        "minutely" => tracing_appender::rolling::minutely(path, prefix),
+        "never" => tracing_appender::rolling::never(path, prefix),
+        rotate => {
+            let appender = tracing_appender::rolling::daily(path, prefix);
+            let err = format!("Invalid rotate value: {rotate}");
+            config.new_parse_error(("tracer", id, "rotate"), err);
+            appender
+        }
+    };
+
+    Some(appender)
 }
@@ -263,6 +263,7 @@ impl Tracers {
         for tracer in self.tracers {
             let (Tracer::Stdout { level, .. }
             | Tracer::Log { level, .. }
+            | Tracer::Json { level, .. }
             | Tracer::Journal { level }
             | Tracer::Otel { level, .. }) = tracer;
 
@@ -292,7 +293,22 @@ impl Tracers {
                         .with_filter(filter)
                         .boxed()
                 }
-                Tracer::Otel { tracer, .. } => {
+                Tracer::Json { appender, .. } => {
+                    // Event fields are written as top-level keys, one event per line
+                    let (non_blocking, guard) = tracing_appender::non_blocking(appender);
+                    guards.push(guard);
+                    tracing_subscriber::fmt::layer()
+                        .json()
+                        .flatten_event(true)
+                        .with_writer(non_blocking)
+                        .with_filter(filter)
+                        .boxed()
+                }
+                Tracer::Otel {
+                    tracer,
+                    sampling_ratio,
+                    ..
+                } => {
                     let tracer = match tracer {
                         OtelTracer::Gprc(exporter) => opentelemetry_otlp::new_pipeline()
                             .tracing()
@@ -310,7 +326,13 @@ impl Tracers {
                                     env!("CARGO_PKG_VERSION").to_string(),
                                 ),
                             ]))
-                            .with_sampler(Sampler::AlwaysOn),
+                            .with_sampler(if sampling_ratio < 1.0 {
+                                Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                                    sampling_ratio,
+                                )))
+                            } else {
+                                Sampler::AlwaysOn
+                            }),
                     )
                     .install_batch(opentelemetry_sdk::runtime::Tokio);
 
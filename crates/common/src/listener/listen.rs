@@ -169,11 +169,17 @@ impl Server {
                                 instance = instance.id,
                                 protocol = ?instance.protocol,
                                 "Listener shutting down.");
-                            manager.shutdown().await;
                             break;
                         }
                     };
                 }
+
+                // Stop accepting connections and wait for active sessions to finish
+                drop(listener);
+                while instance.limiter.is_active() {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                manager.shutdown().await;
             });
         }
     }
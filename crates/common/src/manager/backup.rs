@@ -22,37 +22,68 @@
 */
 
 use std::{
-    collections::BTreeSet,
-    io::{BufWriter, Write},
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    future::Future,
+    io::{BufWriter, ErrorKind, Write},
     ops::Range,
-    path::{Path, PathBuf},
-    sync::mpsc::{self, SyncSender},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        mpsc::{self, SyncSender},
+        Arc,
+    },
+    task::{ready, Context, Poll},
 };
 
-use ahash::{AHashMap, AHashSet};
+use ahash::AHashSet;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::future::BoxFuture;
 use jmap_proto::types::{collection::Collection, property::Property};
 use store::{
+    blake3, rand,
+    roaring::RoaringBitmap,
     write::{
-        key::DeserializeBigEndian, AnyKey, BitmapClass, BitmapHash, BlobOp, DirectoryClass,
-        LookupClass, QueueClass, QueueEvent, TagValue, ValueClass,
+        key::DeserializeBigEndian, now, AnyKey, Bincode, BitmapClass, BitmapHash, BlobOp,
+        DirectoryClass, LookupClass, QueueClass, QueueEvent, TagValue, ValueClass,
     },
-    BitmapKey, Deserialize, IndexKey, IterateParams, LogKey, Serialize, ValueKey, SUBSPACE_BITMAPS,
-    U32_LEN, U64_LEN,
+    BitmapKey, BlobStore, Deserialize, IndexKey, IterateParams, LogKey, Serialize, Store, ValueKey,
+    SUBSPACE_BITMAPS, U32_LEN, U64_LEN,
 };
 
 use utils::{
     codec::leb128::{Leb128Reader, Leb128_},
-    failed, BlobHash, UnwrapFailure, BLOB_HASH_LEN,
+    failed_with, BlobHash, ExitCode, UnwrapFailure, BLOB_HASH_LEN,
 };
 
+use tokio::io::{AsyncRead, ReadBuf};
+
 use crate::Core;
 
-const KEY_OFFSET: usize = 1;
+use super::{metrics::BACKUP_METRICS, progress::EXPORT_PROGRESS};
+
+pub(super) const KEY_OFFSET: usize = 1;
 pub(super) const MAGIC_MARKER: u8 = 123;
-pub(super) const FILE_VERSION: u8 = 1;
+/// Version of the files written, changes to the encoding of any family must
+/// bump it and register an upgrade of the older values in `upgrade`.
+pub(super) const FILE_VERSION: u8 = 3;
+/// Length of the header of version 3 files: the magic marker, the version
+/// and the snapshot id of the backup they belong to.
+pub(super) const HEADER_LEN: usize = 2 + U64_LEN;
+/// Op byte that ends version 2 and later files, followed by the number of
+/// ops written and their blake3 checksum. Reaching the end of a file before
+/// it means the file is truncated, version 1 files end without it.
+pub(super) const TRAILER_MARKER: u8 = u8::MAX;
+/// Bytes written to a file after which its next account starts a new
+/// segment of its index.
+pub const DEFAULT_INDEX_INTERVAL: u64 = 64 * 1024 * 1024;
+/// Size of the parts files written to a blob store are uploaded in. The
+/// first part is stored under the name of the file and the next ones under
+/// `<name>.part<N>`, only a full part is followed by another one.
+pub(super) const BLOB_PART_SIZE: usize = 8 * 1024 * 1024;
 
 #[derive(Debug)]
-pub(super) enum Op {
+pub enum Op {
     Family(Family),
     AccountId(u32),
     Collection(u8),
@@ -60,8 +91,10 @@ pub(super) enum Op {
     KeyValue((Vec<u8>, Vec<u8>)),
 }
 
-#[derive(Debug, Clone, Copy)]
-pub(super) enum Family {
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub enum Family {
     Property = 0,
     TermIndex = 1,
     Acl = 2,
@@ -77,1062 +110,2500 @@ pub(super) enum Family {
     None = 255,
 }
 
-type TaskHandle = (tokio::task::JoinHandle<()>, std::thread::JoinHandle<()>);
+/// Reads a family from the data store and sends its ops to the writer, to
+/// be spawned by the caller.
+pub(super) type BackupTask = BoxFuture<'static, Result<(), BackupError>>;
+pub(super) type BackupFn = fn(&Core, SyncSender<Op>) -> BackupTask;
+
+/// Destination or source of a backup, either a local directory, a prefix
+/// within one of the configured blob stores (`s3://<store-id>/<prefix>`)
+/// or a single stream over stdout/stdin (`-`).
+#[derive(Clone)]
+pub enum BackupLocation {
+    Path(PathBuf),
+    Stdio,
+    BlobStore {
+        id: String,
+        store: BlobStore,
+        prefix: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackupFormat {
+    #[default]
+    Binary,
+    /// Newline-delimited JSON for inspection, can't be restored.
+    Json,
+}
 
-impl Core {
-    pub async fn backup(&self, dest: PathBuf) {
-        if !dest.exists() {
-            std::fs::create_dir_all(&dest).failed("Failed to create backup directory");
-        } else if !dest.is_dir() {
-            eprintln!("Backup destination {dest:?} is not a directory.");
-            std::process::exit(1);
+#[derive(Debug, Clone, Default)]
+pub struct BackupOptions {
+    pub format: BackupFormat,
+    /// Size in bytes after which a family file is continued in a new
+    /// numbered shard (`property`, `property.1`, ...).
+    pub max_file_size: Option<u64>,
+    /// Only export the queue and the blobs of the queued messages.
+    pub queue_only: bool,
+    /// Leave out the blobs that are neither linked to a document nor
+    /// referenced by a queued message.
+    pub skip_orphan_blobs: bool,
+    /// Only export the documents of these collections, across all accounts,
+    /// with their properties, term index, ACLs, indexes, bitmaps, change log
+    /// and the blobs they link to. Families without collections (directory,
+    /// config, lookup and queue) are left out, so the principals that ACLs
+    /// grant access to are not exported, nor are the documents of other
+    /// collections that exported ones reference, such as the threads and
+    /// mailboxes of emails.
+    pub collections: Option<BTreeSet<u8>>,
+    /// Bytes after which the next account of a file starts a new segment of
+    /// its index, `DEFAULT_INDEX_INTERVAL` when not set. Segments of a file
+    /// are restored concurrently.
+    pub index_interval: Option<u64>,
+}
+
+impl BackupOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_format(mut self, format: BackupFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn set_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = Some(max_file_size);
+        self
+    }
+
+    pub fn queue_only(mut self) -> Self {
+        self.queue_only = true;
+        self
+    }
+
+    pub fn skip_orphan_blobs(mut self) -> Self {
+        self.skip_orphan_blobs = true;
+        self
+    }
+
+    pub fn set_collections(mut self, collections: impl IntoIterator<Item = Collection>) -> Self {
+        self.collections = Some(collections.into_iter().map(u8::from).collect());
+        self
+    }
+
+    pub fn set_index_interval(mut self, index_interval: u64) -> Self {
+        self.index_interval = Some(index_interval);
+        self
+    }
+}
+
+/// Failure that stopped a backup before its manifest was written.
+#[derive(Debug)]
+pub struct BackupError {
+    pub cause: String,
+    code: ExitCode,
+}
+
+/// Inventory of a backup, written as `manifest.json` next to the data files.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BackupManifest {
+    pub version: u8,
+    pub created: u64,
+    /// Number of documents in each collection, by account id.
+    pub accounts: BTreeMap<u32, BTreeMap<String, u64>>,
+    pub blobs: u64,
+    pub blob_bytes: u64,
+    pub ops: BTreeMap<Family, u64>,
+    /// Files each family was split into, in order, when it exceeded the
+    /// maximum file size.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub shards: BTreeMap<String, Vec<String>>,
+    /// Blob stores other than the default one that blob contents were read
+    /// from, by the document id their contents are written under.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub blob_stores: BTreeMap<u32, String>,
+    /// Random id written in the header of every file of the backup, so that
+    /// files of different backups are not restored together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_id: Option<u64>,
+    /// Segments of the files larger than the index interval, by file name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub index: BTreeMap<String, Vec<FileSegment>>,
+}
+
+/// Range of a backup file that starts with an account id op and can be read
+/// on its own, given the family, collection and document set before it.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileSegment {
+    pub offset: u64,
+    pub len: u64,
+    /// Number of ops written before the segment.
+    pub ops_before: u64,
+    pub ops: u64,
+    /// blake3 hash of the ops of the segment, verified in place of the
+    /// trailer of the file when the segment is read on its own.
+    pub hash: String,
+    pub family: Family,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document_id: Option<u32>,
+}
+
+pub(super) struct ManifestBuilder {
+    pub manifest: BackupManifest,
+    family: Family,
+    account_id: u32,
+    collection: u8,
+    document_id: u32,
+    last_document: Option<(u32, u8, u32)>,
+    blob_store_ids: Arc<[String]>,
+}
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+pub(super) const BACKUP_FILES: [&str; 11] = [
+    "property",
+    "term_index",
+    "acl",
+    "blob",
+    "config",
+    "lookup",
+    "directory",
+    "queue",
+    "index",
+    "bitmap",
+    "log",
+];
+
+/// Leading fields of a queued message, enough to find the blob holding its
+/// contents without depending on the queue implementation.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct QueuedMessage {
+    id: u64,
+    created: u64,
+    blob_hash: BlobHash,
+}
+
+/// Returns the hash of the blob referenced by a serialized queued message.
+pub(super) fn queued_blob_hash(value: &[u8]) -> Option<BlobHash> {
+    Bincode::<QueuedMessage>::deserialize(value)
+        .ok()
+        .map(|message| message.inner.blob_hash)
+}
+
+/// Blob stores the contents of blobs are exported from, the default one first
+/// and then the others in the order of `Core::blob_store_ids`.
+struct BlobSources {
+    default: BlobStore,
+    others: Vec<BlobStore>,
+}
+
+impl BlobSources {
+    /// Returns the contents of a blob along with the document id they are
+    /// written under, `u32::MAX` when found in the default blob store.
+    async fn get_blob(&self, hash: &[u8]) -> store::Result<Option<(u32, Vec<u8>)>> {
+        if let Some(value) = self.default.get_blob(hash, 0..usize::MAX).await? {
+            return Ok(Some((u32::MAX, value)));
         }
+        for (document_id, blob_store) in self.others.iter().enumerate() {
+            if let Some(value) = blob_store.get_blob(hash, 0..usize::MAX).await? {
+                return Ok(Some((document_id as u32, value)));
+            }
+        }
+        Ok(None)
+    }
+}
 
-        let mut sync_handles = Vec::new();
+/// Returns the hashes of the blobs referenced by queued messages, in hash
+/// order and without duplicates.
+async fn queued_blob_hashes(store: &Store) -> Result<Vec<BlobHash>, BackupError> {
+    let mut hashes = Vec::new();
+
+    store
+        .iterate(
+            IterateParams::new(
+                ValueKey::from(ValueClass::Queue(QueueClass::Message(0))),
+                ValueKey::from(ValueClass::Queue(QueueClass::Message(u64::MAX))),
+            ),
+            |key, value| {
+                match queued_blob_hash(value) {
+                    Some(hash) => {
+                        hashes.push(hash);
+                    }
+                    None => eprintln!(
+                        "Warning: failed to read queued message {}. Skipping.",
+                        key.deserialize_be_u64(1)?
+                    ),
+                }
 
-        for (async_handle, sync_handle) in [
-            self.backup_properties(&dest),
-            self.backup_term_index(&dest),
-            self.backup_acl(&dest),
-            self.backup_blob(&dest),
-            self.backup_config(&dest),
-            self.backup_lookup(&dest),
-            self.backup_directory(&dest),
-            self.backup_queue(&dest),
-            self.backup_index(&dest),
-            self.backup_bitmaps(&dest),
-            self.backup_logs(&dest),
-        ] {
-            async_handle.await.failed("Task failed");
-            sync_handles.push(sync_handle);
+                Ok(true)
+            },
+        )
+        .await
+        .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+    // Blobs are written in hash order, the same as in full backups
+    hashes.sort_unstable_by(|a, b| a.as_slice().cmp(b.as_slice()));
+    hashes.dedup();
+    Ok(hashes)
+}
+
+/// Key of a bitmap in the backup, which identifies its class.
+fn bitmap_key(class: &BitmapClass) -> Vec<u8> {
+    match class {
+        BitmapClass::DocumentIds => {
+            vec![0u8]
         }
+        BitmapClass::Tag { field, value } => {
+            let mut key = Vec::with_capacity(3);
+
+            match value {
+                TagValue::Id(id) => {
+                    key.push(1u8);
+                    key.push(*field);
+                    key.extend_from_slice(&id.serialize());
+                }
+                TagValue::Text(text) => {
+                    key.push(2u8);
+                    key.push(*field);
+                    key.extend_from_slice(text);
+                }
+                TagValue::Static(id) => {
+                    key.push(3u8);
+                    key.push(*field);
+                    key.push(*id);
+                }
+            }
 
-        for handle in sync_handles {
-            handle.join().expect("Failed to join thread");
+            key
+        }
+        BitmapClass::Text { field, token } => {
+            let mut key = vec![4u8, *field];
+            key.push(token.len);
+            key.extend_from_slice(&token.hash);
+            key
         }
     }
+}
 
-    fn backup_properties(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
-        let (handle, writer) = spawn_writer(dest.join("property"));
-        (
-            tokio::spawn(async move {
-                writer
-                    .send(Op::Family(Family::Property))
-                    .failed("Failed to send family");
-
-                let mut keys = BTreeSet::new();
-
-                store
-                    .iterate(
-                        IterateParams::new(
-                            ValueKey {
-                                account_id: 0,
-                                collection: 0,
-                                document_id: 0,
-                                class: ValueClass::Property(0),
-                            },
-                            ValueKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                document_id: u32::MAX,
-                                class: ValueClass::Property(u8::MAX),
-                            },
-                        )
-                        .no_values(),
-                        |key, _| {
-                            let account_id = key.deserialize_be_u32(KEY_OFFSET)?;
-                            let collection = key.deserialize_u8(KEY_OFFSET + U32_LEN)?;
-                            let field = key.deserialize_u8(KEY_OFFSET + U32_LEN + 1)?;
-                            let document_id = key.deserialize_be_u32(KEY_OFFSET + U32_LEN + 2)?;
-
-                            keys.insert((account_id, collection, document_id, field));
+impl Core {
+    /// Exports the data store, one file per family.
+    ///
+    /// Backups are deterministic: the same store contents always produce
+    /// data files that only differ in the snapshot id of their header, as
+    /// ops are written sorted by account, collection and key within each
+    /// family, and the creation time is only recorded in the manifest. This
+    /// keeps deduplicating backup tools and diffs between backups effective.
+    pub async fn backup(
+        &self,
+        dest: impl Into<BackupLocation>,
+        options: BackupOptions,
+    ) -> BackupManifest {
+        self.try_backup(dest, options).await.unwrap_or_else(|err| {
+            failed_with(err.exit_code(), &format!("Failed to export backup: {err}"))
+        })
+    }
 
-                            Ok(true)
-                        },
+    /// Exports the data store like `backup`, returning the manifest of the
+    /// backup instead of exiting when it can't be completed. Streamed backups
+    /// return their manifest without writing it. A failed backup leaves the
+    /// files written so far without a manifest.
+    pub async fn try_backup(
+        &self,
+        dest: impl Into<BackupLocation>,
+        options: BackupOptions,
+    ) -> Result<BackupManifest, BackupError> {
+        let dest = dest.into();
+        if let BackupLocation::Path(dest) = &dest {
+            if !dest.exists() {
+                std::fs::create_dir_all(dest).map_err(|err| {
+                    BackupError::new(
+                        ExitCode::Store,
+                        format!("Failed to create backup directory: {err}"),
                     )
-                    .await
-                    .failed("Failed to iterate over data store");
+                })?;
+            } else if !dest.is_dir() {
+                return Err(BackupError::new(
+                    ExitCode::Config,
+                    format!("Backup destination {dest:?} is not a directory."),
+                ));
+            }
+        }
 
-                let mut last_account_id = u32::MAX;
-                let mut last_collection = u8::MAX;
-                let mut last_document_id = u32::MAX;
+        if options.queue_only && options.collections.is_some() {
+            return Err(BackupError::new(
+                ExitCode::Config,
+                "Queue only backups can't be filtered by collection.",
+            ));
+        }
 
-                for (account_id, collection, document_id, field) in keys {
-                    if account_id != last_account_id {
-                        writer
-                            .send(Op::AccountId(account_id))
-                            .failed("Failed to send account id");
-                        last_account_id = account_id;
+        let families: Vec<(&'static str, BackupFn)> = if options.queue_only {
+            vec![
+                (BACKUP_FILES[3], Self::backup_queue_blobs),
+                (BACKUP_FILES[7], Self::backup_queue),
+            ]
+        } else if options.collections.is_some() {
+            // Blob contents are filtered by the links kept, reading the orphans is not needed
+            vec![
+                (BACKUP_FILES[0], Self::backup_properties),
+                (BACKUP_FILES[1], Self::backup_term_index),
+                (BACKUP_FILES[2], Self::backup_acl),
+                (BACKUP_FILES[3], Self::backup_linked_blobs),
+                (BACKUP_FILES[8], Self::backup_index),
+                (BACKUP_FILES[9], Self::backup_bitmaps),
+                (BACKUP_FILES[10], Self::backup_logs),
+            ]
+        } else if options.skip_orphan_blobs {
+            Self::backup_families()
+                .into_iter()
+                .map(|(name, backup_fn)| {
+                    if name == BACKUP_FILES[3] {
+                        (name, Self::backup_linked_blobs as BackupFn)
+                    } else {
+                        (name, backup_fn)
                     }
+                })
+                .collect()
+        } else {
+            Self::backup_families().into()
+        };
+
+        let blob_store_ids = self.blob_store_ids();
+        let snapshot_id = rand::random::<u64>();
+        if let BackupLocation::Stdio = dest {
+            // Streams can't be sharded, write all families to a single file one after another
+            // and without a manifest
+            let (sync_handle, writer) =
+                spawn_writer(dest, "-", &options, snapshot_id, blob_store_ids);
+            let mut result = Ok(());
+            for (_, backup_fn) in families {
+                result = tokio::spawn(backup_fn(self, writer.clone()))
+                    .await
+                    .map_err(BackupError::task)
+                    .and_then(|result| result);
+                if result.is_err() {
+                    break;
+                }
+            }
+            drop(writer);
+            let mut manifest = BackupManifest {
+                version: FILE_VERSION,
+                created: now(),
+                snapshot_id: Some(snapshot_id),
+                ..Default::default()
+            };
+            // A failed writer makes the tasks fail to send, its error comes first
+            manifest.merge(sync_handle.join().map_err(|_| BackupError::writer())??);
+            result?;
+            return Ok(manifest);
+        }
 
-                    if collection != last_collection {
-                        writer
-                            .send(Op::Collection(collection))
-                            .failed("Failed to send collection");
-                        last_collection = collection;
-                    }
+        let mut async_handles = Vec::new();
+        let mut sync_handles = Vec::new();
 
-                    if document_id != last_document_id {
-                        writer
-                            .send(Op::DocumentId(document_id))
-                            .failed("Failed to send document id");
-                        last_document_id = document_id;
-                    }
+        EXPORT_PROGRESS.add_total(families.len() as u64);
+        for (name, backup_fn) in families {
+            let (sync_handle, writer) = spawn_writer(
+                dest.clone(),
+                name,
+                &options,
+                snapshot_id,
+                blob_store_ids.clone(),
+            );
+            async_handles.push(tokio::spawn(backup_fn(self, writer)));
+            sync_handles.push(sync_handle);
+        }
 
-                    // Obtain UID counter
-                    if collection == u8::from(Collection::Mailbox)
-                        && u8::from(Property::Value) == field
-                    {
-                        let value = store
-                            .get_counter(ValueKey {
-                                account_id,
-                                collection,
-                                document_id,
-                                class: ValueClass::Property(Property::EmailIds.into()),
-                            })
-                            .await
-                            .failed("Failed to get counter");
-                        if value != 0 {
-                            writer
-                                .send(Op::KeyValue((
-                                    vec![u8::from(Property::EmailIds)],
-                                    value.serialize(),
-                                )))
-                                .failed("Failed to send key value");
-                        }
-                    }
+        // Every task is awaited so that no writer is left running
+        let mut result = Ok(());
+        for handle in async_handles {
+            let task_result = handle
+                .await
+                .map_err(BackupError::task)
+                .and_then(|result| result);
+            if result.is_ok() {
+                result = task_result;
+            }
+        }
 
-                    // Write value
-                    let value = store
-                        .get_value::<RawBytes>(ValueKey {
-                            account_id,
-                            collection,
-                            document_id,
-                            class: ValueClass::Property(field),
-                        })
-                        .await
-                        .failed("Failed to get value")
-                        .failed("Expected value")
-                        .0;
-                    writer
-                        .send(Op::KeyValue((vec![field], value)))
-                        .failed("Failed to send key value");
-                }
-            }),
-            handle,
-        )
+        let mut manifest = BackupManifest {
+            version: FILE_VERSION,
+            created: now(),
+            snapshot_id: Some(snapshot_id),
+            ..Default::default()
+        };
+        // A failed writer makes its task fail to send, its error comes first
+        for handle in sync_handles {
+            manifest.merge(handle.join().map_err(|_| BackupError::writer())??);
+        }
+        result?;
+
+        let contents = serde_json::to_vec_pretty(&manifest).map_err(|err| {
+            BackupError::new(
+                ExitCode::Internal,
+                format!("Failed to serialize manifest: {err}"),
+            )
+        })?;
+        dest.join(MANIFEST_FILE)
+            .write(&contents)
+            .await
+            .map_err(|err| {
+                BackupError::new(
+                    ExitCode::Store,
+                    format!("Failed to write backup manifest: {err}"),
+                )
+            })?;
+
+        Ok(manifest)
     }
 
-    fn backup_term_index(&self, dest: &Path) -> TaskHandle {
+    pub(super) fn backup_families() -> [(&'static str, BackupFn); 11] {
+        [
+            (BACKUP_FILES[0], Self::backup_properties),
+            (BACKUP_FILES[1], Self::backup_term_index),
+            (BACKUP_FILES[2], Self::backup_acl),
+            (BACKUP_FILES[3], Self::backup_blob),
+            (BACKUP_FILES[4], Self::backup_config),
+            (BACKUP_FILES[5], Self::backup_lookup),
+            (BACKUP_FILES[6], Self::backup_directory),
+            (BACKUP_FILES[7], Self::backup_queue),
+            (BACKUP_FILES[8], Self::backup_index),
+            (BACKUP_FILES[9], Self::backup_bitmaps),
+            (BACKUP_FILES[10], Self::backup_logs),
+        ]
+    }
+
+    fn backup_properties(&self, writer: SyncSender<Op>) -> BackupTask {
         let store = self.storage.data.clone();
-        let (handle, writer) = spawn_writer(dest.join("term_index"));
-        (
-            tokio::spawn(async move {
-                writer
-                    .send(Op::Family(Family::TermIndex))
-                    .failed("Failed to send family");
-
-                let mut keys = BTreeSet::new();
-
-                store
-                    .iterate(
-                        IterateParams::new(
-                            ValueKey {
-                                account_id: 0,
-                                collection: 0,
-                                document_id: 0,
-                                class: ValueClass::TermIndex,
-                            },
-                            ValueKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                document_id: u32::MAX,
-                                class: ValueClass::TermIndex,
-                            },
-                        )
-                        .no_values(),
-                        |key, _| {
-                            let account_id = key.deserialize_be_u32(KEY_OFFSET)?;
-                            let collection = key.deserialize_u8(KEY_OFFSET + U32_LEN)?;
-                            let document_id = key
-                                .range(KEY_OFFSET + U32_LEN + 1..usize::MAX)?
-                                .deserialize_leb128()?;
-
-                            keys.insert((account_id, collection, document_id));
-
-                            Ok(true)
+        Box::pin(async move {
+            writer
+                .send(Op::Family(Family::Property))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            let mut keys = BTreeSet::new();
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        ValueKey {
+                            account_id: 0,
+                            collection: 0,
+                            document_id: 0,
+                            class: ValueClass::Property(0),
+                        },
+                        ValueKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            document_id: u32::MAX,
+                            class: ValueClass::Property(u8::MAX),
                         },
                     )
-                    .await
-                    .failed("Failed to iterate over data store");
-
-                let mut last_account_id = u32::MAX;
-                let mut last_collection = u8::MAX;
-
-                for (account_id, collection, document_id) in keys {
-                    if account_id != last_account_id {
-                        writer
-                            .send(Op::AccountId(account_id))
-                            .failed("Failed to send account id");
-                        last_account_id = account_id;
-                    }
+                    .no_values(),
+                    |key, _| {
+                        let account_id = key.deserialize_be_u32(KEY_OFFSET)?;
+                        let collection = key.deserialize_u8(KEY_OFFSET + U32_LEN)?;
+                        let field = key.deserialize_u8(KEY_OFFSET + U32_LEN + 1)?;
+                        let document_id = key.deserialize_be_u32(KEY_OFFSET + U32_LEN + 2)?;
+
+                        keys.insert((account_id, collection, document_id, field));
+
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+            let mut last_account_id = None;
+            let mut last_collection = None;
+            let mut last_document_id = None;
+
+            for (account_id, collection, document_id, field) in keys {
+                if Some(account_id) != last_account_id {
+                    writer
+                        .send(Op::AccountId(account_id))
+                        .backup_failed(ExitCode::Internal, "Failed to send account id")?;
+                    last_account_id = Some(account_id);
+                }
 
-                    if collection != last_collection {
-                        writer
-                            .send(Op::Collection(collection))
-                            .failed("Failed to send collection");
-                        last_collection = collection;
-                    }
+                if Some(collection) != last_collection {
+                    writer
+                        .send(Op::Collection(collection))
+                        .backup_failed(ExitCode::Internal, "Failed to send collection")?;
+                    last_collection = Some(collection);
+                }
 
+                if Some(document_id) != last_document_id {
                     writer
                         .send(Op::DocumentId(document_id))
-                        .failed("Failed to send document id");
+                        .backup_failed(ExitCode::Internal, "Failed to send document id")?;
+                    last_document_id = Some(document_id);
+                }
 
+                // Obtain UID counter
+                if collection == u8::from(Collection::Mailbox) && u8::from(Property::Value) == field
+                {
                     let value = store
-                        .get_value::<RawBytes>(ValueKey {
+                        .get_counter(ValueKey {
                             account_id,
                             collection,
                             document_id,
-                            class: ValueClass::TermIndex,
+                            class: ValueClass::Property(Property::EmailIds.into()),
                         })
                         .await
-                        .failed("Failed to get value")
-                        .failed("Expected value")
-                        .0;
-
-                    writer
-                        .send(Op::KeyValue((value.to_vec(), vec![])))
-                        .failed("Failed to send key value");
+                        .backup_failed(ExitCode::Store, "Failed to get counter")?;
+                    if value != 0 {
+                        writer
+                            .send(Op::KeyValue((
+                                vec![u8::from(Property::EmailIds)],
+                                value.serialize(),
+                            )))
+                            .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+                    }
                 }
-            }),
-            handle,
-        )
-    }
 
-    fn backup_acl(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
-        let (handle, writer) = spawn_writer(dest.join("acl"));
-        (
-            tokio::spawn(async move {
+                // Write value
+                let value = store
+                    .get_value::<RawBytes>(ValueKey {
+                        account_id,
+                        collection,
+                        document_id,
+                        class: ValueClass::Property(field),
+                    })
+                    .await
+                    .backup_failed(ExitCode::Store, "Failed to get value")?
+                    .backup_failed(ExitCode::Data, "Expected value")?
+                    .0;
                 writer
-                    .send(Op::Family(Family::Acl))
-                    .failed("Failed to send family");
+                    .send(Op::KeyValue((vec![field], value)))
+                    .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+            }
 
-                let mut last_account_id = u32::MAX;
-                let mut last_collection = u8::MAX;
-                let mut last_document_id = u32::MAX;
+            Ok::<_, BackupError>(())
+        })
+    }
 
-                store
-                    .iterate(
-                        IterateParams::new(
-                            ValueKey {
-                                account_id: 0,
-                                collection: 0,
-                                document_id: 0,
-                                class: ValueClass::Acl(0),
-                            },
-                            ValueKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                document_id: u32::MAX,
-                                class: ValueClass::Acl(u32::MAX),
-                            },
-                        ),
-                        |key, value| {
-                            let grant_account_id = key.deserialize_be_u32(KEY_OFFSET)?;
-                            let account_id = key.deserialize_be_u32(KEY_OFFSET + U32_LEN)?;
-                            let collection = key.deserialize_u8(KEY_OFFSET + (U32_LEN * 2))?;
-                            let document_id =
-                                key.deserialize_be_u32(KEY_OFFSET + (U32_LEN * 2) + 1)?;
-
-                            if account_id != last_account_id {
-                                writer
-                                    .send(Op::AccountId(account_id))
-                                    .failed("Failed to send account id");
-                                last_account_id = account_id;
-                            }
+    fn backup_term_index(&self, writer: SyncSender<Op>) -> BackupTask {
+        let store = self.storage.data.clone();
+        Box::pin(async move {
+            writer
+                .send(Op::Family(Family::TermIndex))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            let mut keys = BTreeSet::new();
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        ValueKey {
+                            account_id: 0,
+                            collection: 0,
+                            document_id: 0,
+                            class: ValueClass::TermIndex,
+                        },
+                        ValueKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            document_id: u32::MAX,
+                            class: ValueClass::TermIndex,
+                        },
+                    )
+                    .no_values(),
+                    |key, _| {
+                        let account_id = key.deserialize_be_u32(KEY_OFFSET)?;
+                        let collection = key.deserialize_u8(KEY_OFFSET + U32_LEN)?;
+                        let document_id = key
+                            .range(KEY_OFFSET + U32_LEN + 1..usize::MAX)?
+                            .deserialize_leb128()?;
+
+                        keys.insert((account_id, collection, document_id));
+
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+            let mut last_account_id = None;
+            let mut last_collection = None;
+
+            for (account_id, collection, document_id) in keys {
+                if Some(account_id) != last_account_id {
+                    writer
+                        .send(Op::AccountId(account_id))
+                        .backup_failed(ExitCode::Internal, "Failed to send account id")?;
+                    last_account_id = Some(account_id);
+                }
 
-                            if collection != last_collection {
-                                writer
-                                    .send(Op::Collection(collection))
-                                    .failed("Failed to send collection");
-                                last_collection = collection;
-                            }
+                if Some(collection) != last_collection {
+                    writer
+                        .send(Op::Collection(collection))
+                        .backup_failed(ExitCode::Internal, "Failed to send collection")?;
+                    last_collection = Some(collection);
+                }
 
-                            if document_id != last_document_id {
-                                writer
-                                    .send(Op::DocumentId(document_id))
-                                    .failed("Failed to send document id");
-                                last_document_id = document_id;
-                            }
+                writer
+                    .send(Op::DocumentId(document_id))
+                    .backup_failed(ExitCode::Internal, "Failed to send document id")?;
+
+                let value = store
+                    .get_value::<RawBytes>(ValueKey {
+                        account_id,
+                        collection,
+                        document_id,
+                        class: ValueClass::TermIndex,
+                    })
+                    .await
+                    .backup_failed(ExitCode::Store, "Failed to get value")?
+                    .backup_failed(ExitCode::Data, "Expected value")?
+                    .0;
 
-                            writer
-                                .send(Op::KeyValue((
-                                    grant_account_id.to_be_bytes().to_vec(),
-                                    value.to_vec(),
-                                )))
-                                .failed("Failed to send key value");
+                writer
+                    .send(Op::KeyValue((value.to_vec(), vec![])))
+                    .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+            }
 
-                            Ok(true)
-                        },
-                    )
-                    .await
-                    .failed("Failed to iterate over data store");
-            }),
-            handle,
-        )
+            Ok::<_, BackupError>(())
+        })
     }
 
-    fn backup_blob(&self, dest: &Path) -> TaskHandle {
+    fn backup_acl(&self, writer: SyncSender<Op>) -> BackupTask {
         let store = self.storage.data.clone();
-        let blob_store = self.storage.blob.clone();
-        let (handle, writer) = spawn_writer(dest.join("blob"));
-        (
-            tokio::spawn(async move {
-                writer
-                    .send(Op::Family(Family::Blob))
-                    .failed("Failed to send family");
-
-                let mut hashes = Vec::new();
-
-                store
-                    .iterate(
-                        IterateParams::new(
-                            ValueKey {
-                                account_id: 0,
-                                collection: 0,
-                                document_id: 0,
-                                class: ValueClass::Blob(BlobOp::Link {
-                                    hash: Default::default(),
-                                }),
-                            },
-                            ValueKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                document_id: u32::MAX,
-                                class: ValueClass::Blob(BlobOp::Link {
-                                    hash: BlobHash::new_max(),
-                                }),
-                            },
-                        ),
-                        |key, _| {
-                            let account_id = key.deserialize_be_u32(KEY_OFFSET + BLOB_HASH_LEN)?;
-                            let collection =
-                                key.deserialize_u8(KEY_OFFSET + BLOB_HASH_LEN + U32_LEN)?;
-                            let document_id =
-                                key.deserialize_be_u32(KEY_OFFSET + BLOB_HASH_LEN + U32_LEN + 1)?;
-
-                            let hash = key.range(KEY_OFFSET..KEY_OFFSET + BLOB_HASH_LEN)?.to_vec();
-
-                            if account_id != u32::MAX && document_id != u32::MAX {
-                                writer
-                                    .send(Op::AccountId(account_id))
-                                    .failed("Failed to send account id");
-                                writer
-                                    .send(Op::Collection(collection))
-                                    .failed("Failed to send collection");
-                                writer
-                                    .send(Op::DocumentId(document_id))
-                                    .failed("Failed to send document id");
-                                writer
-                                    .send(Op::KeyValue((hash, vec![])))
-                                    .failed("Failed to send key value");
-                            } else {
-                                hashes.push(hash);
-                            }
-
-                            Ok(true)
+        Box::pin(async move {
+            writer
+                .send(Op::Family(Family::Acl))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            let mut last_account_id = None;
+            let mut last_collection = None;
+            let mut last_document_id = None;
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        ValueKey {
+                            account_id: 0,
+                            collection: 0,
+                            document_id: 0,
+                            class: ValueClass::Acl(0),
                         },
-                    )
-                    .await
-                    .failed("Failed to iterate over data store");
+                        ValueKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            document_id: u32::MAX,
+                            class: ValueClass::Acl(u32::MAX),
+                        },
+                    ),
+                    |key, value| {
+                        let grant_account_id = key.deserialize_be_u32(KEY_OFFSET)?;
+                        let account_id = key.deserialize_be_u32(KEY_OFFSET + U32_LEN)?;
+                        let collection = key.deserialize_u8(KEY_OFFSET + (U32_LEN * 2))?;
+                        let document_id = key.deserialize_be_u32(KEY_OFFSET + (U32_LEN * 2) + 1)?;
+
+                        if Some(account_id) != last_account_id {
+                            writer
+                                .send(Op::AccountId(account_id))
+                                .backup_failed(ExitCode::Internal, "Failed to send account id")?;
+                            last_account_id = Some(account_id);
+                        }
 
-                if !hashes.is_empty() {
-                    writer
-                        .send(Op::AccountId(u32::MAX))
-                        .failed("Failed to send account id");
-                    writer
-                        .send(Op::DocumentId(u32::MAX))
-                        .failed("Failed to send document id");
-                    for hash in hashes {
-                        if let Some(value) = blob_store
-                            .get_blob(&hash, 0..usize::MAX)
-                            .await
-                            .failed("Failed to get blob")
-                        {
+                        if Some(collection) != last_collection {
                             writer
-                                .send(Op::KeyValue((hash, value)))
-                                .failed("Failed to send key value");
-                        } else {
-                            eprintln!(
-                            "Warning: blob hash {hash:?} does not exist in blob store. Skipping."
-                        );
+                                .send(Op::Collection(collection))
+                                .backup_failed(ExitCode::Internal, "Failed to send collection")?;
+                            last_collection = Some(collection);
                         }
-                    }
-                }
-            }),
-            handle,
-        )
-    }
 
-    fn backup_config(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
-        let (handle, writer) = spawn_writer(dest.join("config"));
-        (
-            tokio::spawn(async move {
-                writer
-                    .send(Op::Family(Family::Config))
-                    .failed("Failed to send family");
-
-                store
-                    .iterate(
-                        IterateParams::new(
-                            ValueKey {
-                                account_id: 0,
-                                collection: 0,
-                                document_id: 0,
-                                class: ValueClass::Config(vec![0]),
-                            },
-                            ValueKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                document_id: u32::MAX,
-                                class: ValueClass::Config(vec![
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                ]),
-                            },
-                        ),
-                        |key, value| {
+                        if Some(document_id) != last_document_id {
                             writer
-                                .send(Op::KeyValue((
-                                    key.range(KEY_OFFSET..usize::MAX)?.to_vec(),
-                                    value.to_vec(),
-                                )))
-                                .failed("Failed to send key value");
+                                .send(Op::DocumentId(document_id))
+                                .backup_failed(ExitCode::Internal, "Failed to send document id")?;
+                            last_document_id = Some(document_id);
+                        }
 
-                            Ok(true)
-                        },
-                    )
-                    .await
-                    .failed("Failed to iterate over data store");
-            }),
-            handle,
-        )
+                        writer
+                            .send(Op::KeyValue((
+                                grant_account_id.to_be_bytes().to_vec(),
+                                value.to_vec(),
+                            )))
+                            .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+            Ok::<_, BackupError>(())
+        })
     }
 
-    fn backup_lookup(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
-        let (handle, writer) = spawn_writer(dest.join("lookup"));
-        (
-            tokio::spawn(async move {
-                writer
-                    .send(Op::Family(Family::LookupValue))
-                    .failed("Failed to send family");
-
-                store
-                    .iterate(
-                        IterateParams::new(
-                            ValueKey {
-                                account_id: 0,
-                                collection: 0,
-                                document_id: 0,
-                                class: ValueClass::Lookup(LookupClass::Key(vec![0])),
-                            },
-                            ValueKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                document_id: u32::MAX,
-                                class: ValueClass::Lookup(LookupClass::Key(vec![
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                ])),
-                            },
-                        ),
-                        |key, value| {
-                            writer
-                                .send(Op::KeyValue((
-                                    key.range(KEY_OFFSET..usize::MAX)?.to_vec(),
-                                    value.to_vec(),
-                                )))
-                                .failed("Failed to send key value");
+    /// Ids of the configured blob stores, blob contents read from any of them
+    /// other than the default one are written under their position in this
+    /// list as document id.
+    pub(super) fn blob_store_ids(&self) -> Arc<[String]> {
+        let mut ids = self.storage.blobs.keys().cloned().collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.into()
+    }
 
-                            Ok(true)
-                        },
-                    )
-                    .await
-                    .failed("Failed to iterate over data store");
+    fn blob_sources(&self) -> BlobSources {
+        BlobSources {
+            default: self.storage.blob.clone(),
+            others: self
+                .blob_store_ids()
+                .iter()
+                .map(|id| self.storage.blobs[id].clone())
+                .collect(),
+        }
+    }
 
-                writer
-                    .send(Op::Family(Family::LookupCounter))
-                    .failed("Failed to send family");
-
-                let mut expired_counters = AHashSet::new();
-
-                store
-                    .iterate(
-                        IterateParams::new(
-                            ValueKey {
-                                account_id: 0,
-                                collection: 0,
-                                document_id: 0,
-                                class: ValueClass::Lookup(LookupClass::CounterExpiry(vec![0])),
-                            },
-                            ValueKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                document_id: u32::MAX,
-                                class: ValueClass::Lookup(LookupClass::CounterExpiry(vec![
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                ])),
-                            },
-                        )
-                        .no_values(),
-                        |key, _| {
-                            expired_counters.insert(key.range(KEY_OFFSET..usize::MAX)?.to_vec());
+    fn backup_blob(&self, writer: SyncSender<Op>) -> BackupTask {
+        self.backup_blob_contents(writer, false)
+    }
 
-                            Ok(true)
+    /// Exports the blobs like `backup_blob`, leaving out the contents of the
+    /// blobs without any link or queued message referencing them.
+    fn backup_linked_blobs(&self, writer: SyncSender<Op>) -> BackupTask {
+        self.backup_blob_contents(writer, true)
+    }
+
+    fn backup_blob_contents(&self, writer: SyncSender<Op>, skip_orphans: bool) -> BackupTask {
+        let store = self.storage.data.clone();
+        let blob_store = self.blob_sources();
+        Box::pin(async move {
+            writer
+                .send(Op::Family(Family::Blob))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            let mut hashes = Vec::new();
+            let mut orphans = Vec::new();
+            let mut last_linked = Vec::new();
+            let queued = if skip_orphans {
+                queued_blob_hashes(&store)
+                    .await?
+                    .into_iter()
+                    .map(|hash| hash.as_slice().to_vec())
+                    .collect::<AHashSet<_>>()
+            } else {
+                AHashSet::new()
+            };
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        ValueKey {
+                            account_id: 0,
+                            collection: 0,
+                            document_id: 0,
+                            class: ValueClass::Blob(BlobOp::Link {
+                                hash: Default::default(),
+                            }),
                         },
-                    )
-                    .await
-                    .failed("Failed to iterate over data store");
-
-                let mut counters = Vec::new();
-
-                store
-                    .iterate(
-                        IterateParams::new(
-                            ValueKey {
-                                account_id: 0,
-                                collection: 0,
-                                document_id: 0,
-                                class: ValueClass::Lookup(LookupClass::Counter(vec![0])),
-                            },
-                            ValueKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                document_id: u32::MAX,
-                                class: ValueClass::Lookup(LookupClass::Counter(vec![
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                    u8::MAX,
-                                ])),
-                            },
-                        )
-                        .no_values(),
-                        |key, _| {
-                            let key = key.range(KEY_OFFSET..usize::MAX)?.to_vec();
-                            if !expired_counters.contains(&key) {
-                                counters.push(key);
+                        ValueKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            document_id: u32::MAX,
+                            class: ValueClass::Blob(BlobOp::Link {
+                                hash: BlobHash::new_max(),
+                            }),
+                        },
+                    ),
+                    |key, _| {
+                        let account_id = key.deserialize_be_u32(KEY_OFFSET + BLOB_HASH_LEN)?;
+                        let collection =
+                            key.deserialize_u8(KEY_OFFSET + BLOB_HASH_LEN + U32_LEN)?;
+                        let document_id =
+                            key.deserialize_be_u32(KEY_OFFSET + BLOB_HASH_LEN + U32_LEN + 1)?;
+
+                        let hash = key.range(KEY_OFFSET..KEY_OFFSET + BLOB_HASH_LEN)?.to_vec();
+
+                        // Links are sorted before the commit of the same hash
+                        if account_id != u32::MAX && document_id != u32::MAX {
+                            if skip_orphans {
+                                last_linked.clone_from(&hash);
                             }
+                            writer
+                                .send(Op::AccountId(account_id))
+                                .backup_failed(ExitCode::Internal, "Failed to send account id")?;
+                            writer
+                                .send(Op::Collection(collection))
+                                .backup_failed(ExitCode::Internal, "Failed to send collection")?;
+                            writer
+                                .send(Op::DocumentId(document_id))
+                                .backup_failed(ExitCode::Internal, "Failed to send document id")?;
+                            writer
+                                .send(Op::KeyValue((hash, vec![])))
+                                .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+                        } else if !skip_orphans || last_linked == hash || queued.contains(&hash) {
+                            hashes.push(hash);
+                        } else {
+                            orphans.push(hash);
+                        }
 
-                            Ok(true)
-                        },
-                    )
-                    .await
-                    .failed("Failed to iterate over data store");
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
 
-                for key in counters {
-                    let value = store
-                        .get_counter(ValueKey::from(ValueClass::Lookup(LookupClass::Counter(
-                            key.clone(),
-                        ))))
+            if !hashes.is_empty() {
+                writer
+                    .send(Op::AccountId(u32::MAX))
+                    .backup_failed(ExitCode::Internal, "Failed to send account id")?;
+                writer
+                    .send(Op::DocumentId(u32::MAX))
+                    .backup_failed(ExitCode::Internal, "Failed to send document id")?;
+                let mut last_document_id = u32::MAX;
+                for hash in hashes {
+                    if let Some((document_id, value)) = blob_store
+                        .get_blob(&hash)
                         .await
-                        .failed("Failed to get counter");
-
-                    if value != 0 {
+                        .backup_failed(ExitCode::Store, "Failed to get blob")?
+                    {
+                        if document_id != last_document_id {
+                            writer
+                                .send(Op::DocumentId(document_id))
+                                .backup_failed(ExitCode::Internal, "Failed to send document id")?;
+                            last_document_id = document_id;
+                        }
                         writer
-                            .send(Op::KeyValue((key, value.serialize())))
-                            .failed("Failed to send key value");
+                            .send(Op::KeyValue((hash, value)))
+                            .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+                    } else {
+                        eprintln!(
+                            "Warning: blob hash {hash:?} does not exist in any blob store. Skipping."
+                        );
                     }
                 }
-            }),
-            handle,
-        )
+            }
+
+            // Orphans are only read to report the space saved
+            for hash in orphans {
+                let bytes = blob_store
+                    .get_blob(&hash)
+                    .await
+                    .backup_failed(ExitCode::Store, "Failed to get blob")?
+                    .map_or(0, |(_, value)| value.len());
+                BACKUP_METRICS.orphan_blob(bytes);
+            }
+
+            Ok::<_, BackupError>(())
+        })
     }
 
-    fn backup_directory(&self, dest: &Path) -> TaskHandle {
+    fn backup_config(&self, writer: SyncSender<Op>) -> BackupTask {
         let store = self.storage.data.clone();
-        let (handle, writer) = spawn_writer(dest.join("directory"));
-        (
-            tokio::spawn(async move {
-                writer
-                    .send(Op::Family(Family::Directory))
-                    .failed("Failed to send family");
-
-                let mut principal_ids = Vec::new();
-
-                store
-                    .iterate(
-                        IterateParams::new(
-                            ValueKey {
-                                account_id: 0,
-                                collection: 0,
-                                document_id: 0,
-                                class: ValueClass::Directory(DirectoryClass::NameToId(vec![0])),
-                            },
-                            ValueKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                document_id: u32::MAX,
-                                class: ValueClass::Directory(DirectoryClass::Members {
-                                    principal_id: u32::MAX,
-                                    has_member: u32::MAX,
-                                }),
-                            },
-                        ),
-                        |key, value| {
-                            let mut key = key.to_vec();
-                            key[0] -= 20;
-
-                            if key[0] == 2 {
-                                principal_ids.push(key.as_slice().range(1..usize::MAX)?.to_vec());
-                            }
-
-                            writer
-                                .send(Op::KeyValue((key, value.to_vec())))
-                                .failed("Failed to send key value");
+        Box::pin(async move {
+            writer
+                .send(Op::Family(Family::Config))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        ValueKey {
+                            account_id: 0,
+                            collection: 0,
+                            document_id: 0,
+                            class: ValueClass::Config(vec![0]),
+                        },
+                        ValueKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            document_id: u32::MAX,
+                            class: ValueClass::Config(vec![
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                            ]),
+                        },
+                    ),
+                    |key, value| {
+                        writer
+                            .send(Op::KeyValue((
+                                key.range(KEY_OFFSET..usize::MAX)?.to_vec(),
+                                value.to_vec(),
+                            )))
+                            .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+            Ok::<_, BackupError>(())
+        })
+    }
 
-                            Ok(true)
+    fn backup_lookup(&self, writer: SyncSender<Op>) -> BackupTask {
+        let store = self.storage.data.clone();
+        Box::pin(async move {
+            writer
+                .send(Op::Family(Family::LookupValue))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        ValueKey {
+                            account_id: 0,
+                            collection: 0,
+                            document_id: 0,
+                            class: ValueClass::Lookup(LookupClass::Key(vec![0])),
+                        },
+                        ValueKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            document_id: u32::MAX,
+                            class: ValueClass::Lookup(LookupClass::Key(vec![
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                            ])),
+                        },
+                    ),
+                    |key, value| {
+                        writer
+                            .send(Op::KeyValue((
+                                key.range(KEY_OFFSET..usize::MAX)?.to_vec(),
+                                value.to_vec(),
+                            )))
+                            .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+            writer
+                .send(Op::Family(Family::LookupCounter))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            let mut expired_counters = AHashSet::new();
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        ValueKey {
+                            account_id: 0,
+                            collection: 0,
+                            document_id: 0,
+                            class: ValueClass::Lookup(LookupClass::CounterExpiry(vec![0])),
+                        },
+                        ValueKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            document_id: u32::MAX,
+                            class: ValueClass::Lookup(LookupClass::CounterExpiry(vec![
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                            ])),
                         },
                     )
-                    .await
-                    .failed("Failed to iterate over data store");
+                    .no_values(),
+                    |key, _| {
+                        expired_counters.insert(key.range(KEY_OFFSET..usize::MAX)?.to_vec());
+
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+            let mut counters = Vec::new();
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        ValueKey {
+                            account_id: 0,
+                            collection: 0,
+                            document_id: 0,
+                            class: ValueClass::Lookup(LookupClass::Counter(vec![0])),
+                        },
+                        ValueKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            document_id: u32::MAX,
+                            class: ValueClass::Lookup(LookupClass::Counter(vec![
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                                u8::MAX,
+                            ])),
+                        },
+                    )
+                    .no_values(),
+                    |key, _| {
+                        let key = key.range(KEY_OFFSET..usize::MAX)?.to_vec();
+                        if !expired_counters.contains(&key) {
+                            counters.push(key);
+                        }
 
-                for principal_bytes in principal_ids {
-                    let value = store
-                        .get_counter(ValueKey::from(ValueClass::Directory(
-                            DirectoryClass::UsedQuota(
-                                principal_bytes
-                                    .as_slice()
-                                    .deserialize_leb128()
-                                    .failed("Failed to deserialize principal id"),
-                            ),
-                        )))
-                        .await
-                        .failed("Failed to get counter");
-                    if value != 0 {
-                        let mut key = Vec::with_capacity(U32_LEN + 1);
-                        key.push(4u8);
-                        key.extend_from_slice(&principal_bytes);
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+            for key in counters {
+                let value = store
+                    .get_counter(ValueKey::from(ValueClass::Lookup(LookupClass::Counter(
+                        key.clone(),
+                    ))))
+                    .await
+                    .backup_failed(ExitCode::Store, "Failed to get counter")?;
 
-                        writer
-                            .send(Op::KeyValue((key, value.serialize())))
-                            .failed("Failed to send key value");
-                    }
+                if value != 0 {
+                    writer
+                        .send(Op::KeyValue((key, value.serialize())))
+                        .backup_failed(ExitCode::Internal, "Failed to send key value")?;
                 }
-            }),
-            handle,
-        )
+            }
+
+            Ok::<_, BackupError>(())
+        })
     }
 
-    fn backup_queue(&self, dest: &Path) -> TaskHandle {
+    fn backup_directory(&self, writer: SyncSender<Op>) -> BackupTask {
         let store = self.storage.data.clone();
-        let (handle, writer) = spawn_writer(dest.join("queue"));
-        (
-            tokio::spawn(async move {
-                writer
-                    .send(Op::Family(Family::Queue))
-                    .failed("Failed to send family");
-
-                store
-                    .iterate(
-                        IterateParams::new(
-                            ValueKey {
-                                account_id: 0,
-                                collection: 0,
-                                document_id: 0,
-                                class: ValueClass::Queue(QueueClass::Message(0)),
-                            },
-                            ValueKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                document_id: u32::MAX,
-                                class: ValueClass::Queue(QueueClass::MessageEvent(QueueEvent {
-                                    due: u64::MAX,
-                                    queue_id: u64::MAX,
-                                })),
-                            },
+        Box::pin(async move {
+            writer
+                .send(Op::Family(Family::Directory))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            let mut principal_ids = Vec::new();
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        ValueKey {
+                            account_id: 0,
+                            collection: 0,
+                            document_id: 0,
+                            class: ValueClass::Directory(DirectoryClass::NameToId(vec![0])),
+                        },
+                        ValueKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            document_id: u32::MAX,
+                            class: ValueClass::Directory(DirectoryClass::Members {
+                                principal_id: u32::MAX,
+                                has_member: u32::MAX,
+                            }),
+                        },
+                    ),
+                    |key, value| {
+                        let mut key = key.to_vec();
+                        key[0] -= 20;
+
+                        if key[0] == 2 {
+                            principal_ids.push(key.as_slice().range(1..usize::MAX)?.to_vec());
+                        }
+
+                        writer
+                            .send(Op::KeyValue((key, value.to_vec())))
+                            .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+            for principal_bytes in principal_ids {
+                let value = store
+                    .get_counter(ValueKey::from(ValueClass::Directory(
+                        DirectoryClass::UsedQuota(
+                            principal_bytes
+                                .as_slice()
+                                .deserialize_leb128()
+                                .backup_failed(
+                                    ExitCode::Data,
+                                    "Failed to deserialize principal id",
+                                )?,
                         ),
-                        |key, value| {
-                            let mut key = key.to_vec();
-                            key[0] -= 50;
+                    )))
+                    .await
+                    .backup_failed(ExitCode::Store, "Failed to get counter")?;
+                if value != 0 {
+                    let mut key = Vec::with_capacity(U32_LEN + 1);
+                    key.push(4u8);
+                    key.extend_from_slice(&principal_bytes);
 
-                            writer
-                                .send(Op::KeyValue((key, value.to_vec())))
-                                .failed("Failed to send key value");
+                    writer
+                        .send(Op::KeyValue((key, value.serialize())))
+                        .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+                }
+            }
+
+            Ok::<_, BackupError>(())
+        })
+    }
 
-                            Ok(true)
+    fn backup_queue(&self, writer: SyncSender<Op>) -> BackupTask {
+        let store = self.storage.data.clone();
+        Box::pin(async move {
+            writer
+                .send(Op::Family(Family::Queue))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        ValueKey {
+                            account_id: 0,
+                            collection: 0,
+                            document_id: 0,
+                            class: ValueClass::Queue(QueueClass::Message(0)),
                         },
-                    )
-                    .await
-                    .failed("Failed to iterate over data store");
-            }),
-            handle,
-        )
+                        ValueKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            document_id: u32::MAX,
+                            class: ValueClass::Queue(QueueClass::MessageEvent(QueueEvent {
+                                due: u64::MAX,
+                                queue_id: u64::MAX,
+                            })),
+                        },
+                    ),
+                    |key, value| {
+                        let mut key = key.to_vec();
+                        key[0] -= 50;
+
+                        writer
+                            .send(Op::KeyValue((key, value.to_vec())))
+                            .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+            Ok::<_, BackupError>(())
+        })
     }
 
-    fn backup_index(&self, dest: &Path) -> TaskHandle {
+    /// Exports the contents of the blobs referenced by queued messages, without
+    /// any of the blob links owned by accounts.
+    fn backup_queue_blobs(&self, writer: SyncSender<Op>) -> BackupTask {
         let store = self.storage.data.clone();
-        let (handle, writer) = spawn_writer(dest.join("index"));
-        (
-            tokio::spawn(async move {
-                writer
-                    .send(Op::Family(Family::Index))
-                    .failed("Failed to send family");
-
-                let mut last_account_id = u32::MAX;
-                let mut last_collection = u8::MAX;
-
-                store
-                    .iterate(
-                        IterateParams::new(
-                            IndexKey {
-                                account_id: 0,
-                                collection: 0,
-                                document_id: 0,
-                                field: 0,
-                                key: vec![0],
-                            },
-                            IndexKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                document_id: u32::MAX,
-                                field: u8::MAX,
-                                key: vec![u8::MAX, u8::MAX, u8::MAX],
-                            },
-                        )
-                        .no_values(),
-                        |key, _| {
-                            let account_id = key.deserialize_be_u32(0)?;
-                            let collection = key.deserialize_u8(U32_LEN)?;
-                            let document_id = key.deserialize_be_u32(key.len() - U32_LEN)?;
-
-                            let key = key.range(U32_LEN + 1..key.len() - U32_LEN)?.to_vec();
-
-                            if account_id != last_account_id {
-                                writer
-                                    .send(Op::AccountId(account_id))
-                                    .failed("Failed to send account id");
-                                last_account_id = account_id;
-                            }
+        let blob_store = self.blob_sources();
+        Box::pin(async move {
+            writer
+                .send(Op::Family(Family::Blob))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
 
-                            if collection != last_collection {
-                                writer
-                                    .send(Op::Collection(collection))
-                                    .failed("Failed to send collection");
-                                last_collection = collection;
-                            }
+            let hashes = queued_blob_hashes(&store).await?;
 
+            if !hashes.is_empty() {
+                writer
+                    .send(Op::AccountId(u32::MAX))
+                    .backup_failed(ExitCode::Internal, "Failed to send account id")?;
+                writer
+                    .send(Op::DocumentId(u32::MAX))
+                    .backup_failed(ExitCode::Internal, "Failed to send document id")?;
+                let mut last_document_id = u32::MAX;
+                for hash in hashes {
+                    if let Some((document_id, value)) =
+                        blob_store
+                            .get_blob(hash.as_slice())
+                            .await
+                            .backup_failed(ExitCode::Store, "Failed to get blob")?
+                    {
+                        if document_id != last_document_id {
                             writer
                                 .send(Op::DocumentId(document_id))
-                                .failed("Failed to send document id");
+                                .backup_failed(ExitCode::Internal, "Failed to send document id")?;
+                            last_document_id = document_id;
+                        }
+                        writer
+                            .send(Op::KeyValue((hash.as_slice().to_vec(), value)))
+                            .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+                    } else {
+                        eprintln!(
+                            "Warning: blob hash {hash:?} does not exist in any blob store. Skipping."
+                        );
+                    }
+                }
+            }
 
-                            writer
-                                .send(Op::KeyValue((key, vec![])))
-                                .failed("Failed to send key value");
+            Ok::<_, BackupError>(())
+        })
+    }
 
-                            Ok(true)
+    fn backup_index(&self, writer: SyncSender<Op>) -> BackupTask {
+        let store = self.storage.data.clone();
+        Box::pin(async move {
+            writer
+                .send(Op::Family(Family::Index))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            let mut last_account_id = None;
+            let mut last_collection = None;
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        IndexKey {
+                            account_id: 0,
+                            collection: 0,
+                            document_id: 0,
+                            field: 0,
+                            key: vec![0],
+                        },
+                        IndexKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            document_id: u32::MAX,
+                            field: u8::MAX,
+                            key: vec![u8::MAX, u8::MAX, u8::MAX],
                         },
                     )
-                    .await
-                    .failed("Failed to iterate over data store");
-            }),
-            handle,
-        )
+                    .no_values(),
+                    |key, _| {
+                        let account_id = key.deserialize_be_u32(0)?;
+                        let collection = key.deserialize_u8(U32_LEN)?;
+                        let document_id = key.deserialize_be_u32(key.len() - U32_LEN)?;
+
+                        let key = key.range(U32_LEN + 1..key.len() - U32_LEN)?.to_vec();
+
+                        if Some(account_id) != last_account_id {
+                            writer
+                                .send(Op::AccountId(account_id))
+                                .backup_failed(ExitCode::Internal, "Failed to send account id")?;
+                            last_account_id = Some(account_id);
+                        }
+
+                        if Some(collection) != last_collection {
+                            writer
+                                .send(Op::Collection(collection))
+                                .backup_failed(ExitCode::Internal, "Failed to send collection")?;
+                            last_collection = Some(collection);
+                        }
+
+                        writer
+                            .send(Op::DocumentId(document_id))
+                            .backup_failed(ExitCode::Internal, "Failed to send document id")?;
+
+                        writer
+                            .send(Op::KeyValue((key, vec![])))
+                            .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+            Ok::<_, BackupError>(())
+        })
     }
 
-    fn backup_bitmaps(&self, dest: &Path) -> TaskHandle {
+    fn backup_bitmaps(&self, writer: SyncSender<Op>) -> BackupTask {
         let store = self.storage.data.clone();
         let has_doc_id = store.id() != "rocksdb";
+        Box::pin(async move {
+            const BM_DOCUMENT_IDS: u8 = 0;
+            const BM_TEXT: u8 = 1 << 7;
+
+            const TAG_ID: u8 = 1 << 6;
+            const TAG_TEXT: u8 = 1 << 0 | 1 << 6;
+            const TAG_STATIC: u8 = 1 << 1 | 1 << 6;
+
+            writer
+                .send(Op::Family(Family::Bitmap))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            // Classes are sorted by their key in the backup so the output is deterministic
+            let mut bitmaps: BTreeMap<(u32, u8), BTreeMap<Vec<u8>, BitmapClass>> = BTreeMap::new();
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        AnyKey {
+                            subspace: SUBSPACE_BITMAPS,
+                            key: vec![0u8],
+                        },
+                        AnyKey {
+                            subspace: SUBSPACE_BITMAPS,
+                            key: vec![u8::MAX; 10],
+                        },
+                    )
+                    .no_values(),
+                    |key, _| {
+                        let account_id = key.deserialize_be_u32(0)?;
+                        let collection = key.deserialize_u8(U32_LEN)?;
 
-        let (handle, writer) = spawn_writer(dest.join("bitmap"));
-        (
-            tokio::spawn(async move {
-                const BM_DOCUMENT_IDS: u8 = 0;
-                const BM_TEXT: u8 = 1 << 7;
+                        let entry = bitmaps.entry((account_id, collection)).or_default();
 
-                const TAG_ID: u8 = 1 << 6;
-                const TAG_TEXT: u8 = 1 << 0 | 1 << 6;
-                const TAG_STATIC: u8 = 1 << 1 | 1 << 6;
+                        let key = if has_doc_id {
+                            key.range(0..key.len() - U32_LEN)?
+                        } else {
+                            key
+                        };
+
+                        let class = match key.deserialize_u8(U32_LEN + 1)? {
+                            BM_DOCUMENT_IDS => BitmapClass::DocumentIds,
+                            TAG_ID => BitmapClass::Tag {
+                                field: key.deserialize_u8(U32_LEN + 2)?,
+                                value: TagValue::Id(
+                                    key.range(U32_LEN + 3..usize::MAX)?.deserialize_leb128()?,
+                                ),
+                            },
+                            TAG_TEXT => BitmapClass::Tag {
+                                field: key.deserialize_u8(U32_LEN + 2)?,
+                                value: TagValue::Text(key.range(U32_LEN + 3..usize::MAX)?.into()),
+                            },
+                            TAG_STATIC => BitmapClass::Tag {
+                                field: key.deserialize_u8(U32_LEN + 2)?,
+                                value: TagValue::Static(key.deserialize_u8(U32_LEN + 3)?),
+                            },
+                            text => BitmapClass::Text {
+                                field: key.deserialize_u8(U32_LEN + 2)?,
+                                token: BitmapHash {
+                                    hash: key.range(U32_LEN + 3..U32_LEN + 11)?.try_into().unwrap(),
+                                    len: text & !BM_TEXT,
+                                },
+                            },
+                        };
+                        entry.insert(bitmap_key(&class), class);
+
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
 
+            for ((account_id, collection), classes) in bitmaps {
                 writer
-                    .send(Op::Family(Family::Bitmap))
-                    .failed("Failed to send family");
+                    .send(Op::AccountId(account_id))
+                    .backup_failed(ExitCode::Internal, "Failed to send account id")?;
+                writer
+                    .send(Op::Collection(collection))
+                    .backup_failed(ExitCode::Internal, "Failed to send collection")?;
 
-                let mut bitmaps: AHashMap<(u32, u8), AHashSet<BitmapClass>> = AHashMap::new();
+                for (key, class) in classes {
+                    if let Some(bitmap) = store
+                        .get_bitmap(BitmapKey {
+                            account_id,
+                            collection,
+                            class,
+                            block_num: 0,
+                        })
+                        .await
+                        .backup_failed(ExitCode::Store, "Failed to get bitmap")?
+                    {
+                        let mut bytes = Vec::with_capacity(bitmap.serialized_size());
+                        bitmap
+                            .serialize_into(&mut bytes)
+                            .backup_failed(ExitCode::Internal, "Failed to serialize bitmap")?;
 
-                store
-                    .iterate(
-                        IterateParams::new(
-                            AnyKey {
-                                subspace: SUBSPACE_BITMAPS,
-                                key: vec![0u8],
-                            },
-                            AnyKey {
-                                subspace: SUBSPACE_BITMAPS,
-                                key: vec![u8::MAX; 10],
-                            },
-                        )
-                        .no_values(),
-                        |key, _| {
-                            let account_id = key.deserialize_be_u32(0)?;
-                            let collection = key.deserialize_u8(U32_LEN)?;
-
-                            let entry = bitmaps.entry((account_id, collection)).or_default();
-
-                            let key = if has_doc_id {
-                                key.range(0..key.len() - U32_LEN)?
-                            } else {
-                                key
-                            };
-
-                            match key.deserialize_u8(U32_LEN + 1)? {
-                                BM_DOCUMENT_IDS => {
-                                    entry.insert(BitmapClass::DocumentIds);
-                                }
-                                TAG_ID => {
-                                    entry.insert(BitmapClass::Tag {
-                                        field: key.deserialize_u8(U32_LEN + 2)?,
-                                        value: TagValue::Id(
-                                            key.range(U32_LEN + 3..usize::MAX)?
-                                                .deserialize_leb128()?,
-                                        ),
-                                    });
-                                }
-                                TAG_TEXT => {
-                                    entry.insert(BitmapClass::Tag {
-                                        field: key.deserialize_u8(U32_LEN + 2)?,
-                                        value: TagValue::Text(
-                                            key.range(U32_LEN + 3..usize::MAX)?.to_vec(),
-                                        ),
-                                    });
-                                }
-                                TAG_STATIC => {
-                                    entry.insert(BitmapClass::Tag {
-                                        field: key.deserialize_u8(U32_LEN + 2)?,
-                                        value: TagValue::Static(key.deserialize_u8(U32_LEN + 3)?),
-                                    });
-                                }
-                                text => {
-                                    entry.insert(BitmapClass::Text {
-                                        field: key.deserialize_u8(U32_LEN + 2)?,
-                                        token: BitmapHash {
-                                            hash: key
-                                                .range(U32_LEN + 3..U32_LEN + 11)?
-                                                .try_into()
-                                                .unwrap(),
-                                            len: text & !BM_TEXT,
-                                        },
-                                    });
-                                }
-                            }
+                        writer
+                            .send(Op::KeyValue((key, bytes)))
+                            .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+                    }
+                }
+            }
+
+            Ok::<_, BackupError>(())
+        })
+    }
 
-                            Ok(true)
+    fn backup_logs(&self, writer: SyncSender<Op>) -> BackupTask {
+        let store = self.storage.data.clone();
+        Box::pin(async move {
+            writer
+                .send(Op::Family(Family::Log))
+                .backup_failed(ExitCode::Internal, "Failed to send family")?;
+
+            let mut last_account_id = None;
+            let mut last_collection = None;
+
+            store
+                .iterate(
+                    IterateParams::new(
+                        LogKey {
+                            account_id: 0,
+                            collection: 0,
+                            change_id: 0,
                         },
-                    )
-                    .await
-                    .failed("Failed to iterate over data store");
+                        LogKey {
+                            account_id: u32::MAX,
+                            collection: u8::MAX,
+                            change_id: u64::MAX,
+                        },
+                    ),
+                    |key, value| {
+                        let account_id = key.deserialize_be_u32(0)?;
+                        let collection = key.deserialize_u8(U32_LEN)?;
+                        let key = key.range(U32_LEN + 1..usize::MAX)?.to_vec();
+
+                        if key.len() != U64_LEN {
+                            return Err(BackupError::new(
+                                ExitCode::Data,
+                                format!("Found invalid log entry {key:?} {value:?}"),
+                            )
+                            .into());
+                        }
 
-                for ((account_id, collection), classes) in bitmaps {
-                    writer
-                        .send(Op::AccountId(account_id))
-                        .failed("Failed to send account id");
-                    writer
-                        .send(Op::Collection(collection))
-                        .failed("Failed to send collection");
-
-                    for class in classes {
-                        if let Some(bitmap) = store
-                            .get_bitmap(BitmapKey {
-                                account_id,
-                                collection,
-                                class: class.clone(),
-                                block_num: 0,
-                            })
-                            .await
-                            .failed("Failed to get bitmap")
-                        {
-                            let key = match class {
-                                BitmapClass::DocumentIds => {
-                                    vec![0u8]
-                                }
-                                BitmapClass::Tag { field, value } => {
-                                    let mut key = Vec::with_capacity(3);
-
-                                    match value {
-                                        TagValue::Id(id) => {
-                                            key.push(1u8);
-                                            key.push(field);
-                                            key.extend_from_slice(&id.serialize());
-                                        }
-                                        TagValue::Text(text) => {
-                                            key.push(2u8);
-                                            key.push(field);
-                                            key.extend_from_slice(&text);
-                                        }
-                                        TagValue::Static(id) => {
-                                            key.push(3u8);
-                                            key.push(field);
-                                            key.push(id);
-                                        }
-                                    }
-
-                                    key
-                                }
-                                BitmapClass::Text { field, token } => {
-                                    let mut key = vec![4u8, field];
-                                    key.push(token.len);
-                                    key.extend_from_slice(&token.hash);
-                                    key
-                                }
-                            };
-
-                            let mut bytes = Vec::with_capacity(bitmap.serialized_size());
-                            bitmap
-                                .serialize_into(&mut bytes)
-                                .failed("Failed to serialize bitmap");
+                        if Some(account_id) != last_account_id {
+                            writer
+                                .send(Op::AccountId(account_id))
+                                .backup_failed(ExitCode::Internal, "Failed to send account id")?;
+                            last_account_id = Some(account_id);
+                        }
 
+                        if Some(collection) != last_collection {
                             writer
-                                .send(Op::KeyValue((key, bytes)))
-                                .failed("Failed to send key value");
+                                .send(Op::Collection(collection))
+                                .backup_failed(ExitCode::Internal, "Failed to send collection")?;
+                            last_collection = Some(collection);
                         }
+
+                        writer
+                            .send(Op::KeyValue((key, value.to_vec())))
+                            .backup_failed(ExitCode::Internal, "Failed to send key value")?;
+
+                        Ok(true)
+                    },
+                )
+                .await
+                .backup_failed(ExitCode::Store, "Failed to iterate over data store")?;
+
+            Ok::<_, BackupError>(())
+        })
+    }
+}
+
+fn spawn_writer(
+    dest: BackupLocation,
+    name: &'static str,
+    options: &BackupOptions,
+    snapshot_id: u64,
+    blob_store_ids: Arc<[String]>,
+) -> (
+    std::thread::JoinHandle<Result<BackupManifest, BackupError>>,
+    SyncSender<Op>,
+) {
+    let (tx, rx) = mpsc::sync_channel(10);
+    let rt = tokio::runtime::Handle::current();
+    let format = options.format;
+    let max_file_size = match dest {
+        BackupLocation::Stdio => None,
+        _ => options.max_file_size,
+    };
+    let mut filter = options.collections.clone().map(CollectionFilter::new);
+    // Streams have no manifest to store the index in
+    let index_interval = match (&dest, format) {
+        (BackupLocation::Stdio, _) | (_, BackupFormat::Json) => None,
+        _ => Some(options.index_interval.unwrap_or(DEFAULT_INDEX_INTERVAL)),
+    };
+
+    let handle = std::thread::spawn(move || {
+        let mut manifest = ManifestBuilder::new(blob_store_ids);
+        let mut shards = vec![shard_name(name, 0, format)];
+        let mut writer = OpWriter::new(
+            BackupFile::create(&dest.join(&shards[0]), &rt)?,
+            format,
+            snapshot_id,
+            index_interval,
+        )?;
+
+        let mut ops = Vec::with_capacity(4);
+        while let Ok(op) = rx.recv() {
+            match &mut filter {
+                Some(filter) => filter.filter(op, &mut ops),
+                None => ops.push(op),
+            }
+
+            for op in ops.drain(..) {
+                // Continue in a new shard between documents, repeating the current
+                // family, account and collection so that it can be read on its own
+                if max_file_size.is_some_and(|max| writer.bytes >= max) && writer.can_split(&op) {
+                    let name = shard_name(name, shards.len(), format);
+                    let next =
+                        OpWriter::resume(BackupFile::create(&dest.join(&name), &rt)?, &writer)?;
+                    let (file, segments) = writer.finish()?;
+                    file.close()?;
+                    if !segments.is_empty() {
+                        manifest
+                            .manifest
+                            .index
+                            .insert(shards[shards.len() - 1].clone(), segments);
                     }
+                    shards.push(name);
+                    writer = next;
                 }
-            }),
-            handle,
-        )
+
+                manifest.track(&op);
+                writer.write(op)?;
+            }
+        }
+        let (file, segments) = writer.finish()?;
+        file.close()?;
+        if !segments.is_empty() {
+            manifest
+                .manifest
+                .index
+                .insert(shards[shards.len() - 1].clone(), segments);
+        }
+        EXPORT_PROGRESS.advance(1);
+
+        if shards.len() > 1 {
+            manifest.manifest.shards.insert(name.to_string(), shards);
+        }
+        Ok(manifest.manifest)
+    });
+
+    (handle, tx)
+}
+
+/// Drops the values of the collections left out of a backup, writing the
+/// account, collection and document ids only ahead of the values kept.
+struct CollectionFilter {
+    collections: BTreeSet<u8>,
+    family: Family,
+    account_id: Option<u32>,
+    collection: Option<u8>,
+    document_id: Option<u32>,
+    written: (Option<u32>, Option<u8>, Option<u32>),
+    /// Hashes of the blobs linked from the exported documents, their
+    /// contents are written after all links.
+    linked: AHashSet<Vec<u8>>,
+}
+
+impl CollectionFilter {
+    fn new(collections: BTreeSet<u8>) -> Self {
+        CollectionFilter {
+            collections,
+            family: Family::None,
+            account_id: None,
+            collection: None,
+            document_id: None,
+            written: (None, None, None),
+            linked: AHashSet::new(),
+        }
     }
 
-    fn backup_logs(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
-        let (handle, writer) = spawn_writer(dest.join("log"));
-        (
-            tokio::spawn(async move {
-                writer
-                    .send(Op::Family(Family::Log))
-                    .failed("Failed to send family");
-
-                let mut last_account_id = u32::MAX;
-                let mut last_collection = u8::MAX;
-
-                store
-                    .iterate(
-                        IterateParams::new(
-                            LogKey {
-                                account_id: 0,
-                                collection: 0,
-                                change_id: 0,
-                            },
-                            LogKey {
-                                account_id: u32::MAX,
-                                collection: u8::MAX,
-                                change_id: u64::MAX,
-                            },
-                        ),
-                        |key, value| {
-                            let account_id = key.deserialize_be_u32(0)?;
-                            let collection = key.deserialize_u8(U32_LEN)?;
-                            let key = key.range(U32_LEN + 1..usize::MAX)?.to_vec();
+    fn filter(&mut self, op: Op, ops: &mut Vec<Op>) {
+        match op {
+            Op::Family(family) => {
+                self.family = family;
+                self.account_id = None;
+                self.collection = None;
+                self.document_id = None;
+                self.written = (None, None, None);
+                ops.push(op);
+            }
+            Op::AccountId(account_id) => self.account_id = Some(account_id),
+            Op::Collection(collection) => self.collection = Some(collection),
+            Op::DocumentId(document_id) => self.document_id = Some(document_id),
+            Op::KeyValue((key, value)) => {
+                let is_contents = self.family == Family::Blob && self.account_id == Some(u32::MAX);
+                if is_contents {
+                    if !self.linked.remove(&key) {
+                        return;
+                    }
+                } else if self
+                    .collection
+                    .map_or(true, |collection| !self.collections.contains(&collection))
+                {
+                    return;
+                } else if self.family == Family::Blob {
+                    self.linked.insert(key.clone());
+                }
 
-                            if key.len() != U64_LEN {
-                                failed(&format!("Found invalid log entry {key:?} {value:?}"));
-                            }
+                if self.account_id != self.written.0 {
+                    if let Some(account_id) = self.account_id {
+                        ops.push(Op::AccountId(account_id));
+                    }
+                }
+                // Blob contents are not part of any collection
+                if self.collection != self.written.1 && !is_contents {
+                    if let Some(collection) = self.collection {
+                        ops.push(Op::Collection(collection));
+                    }
+                }
+                if self.document_id != self.written.2 {
+                    if let Some(document_id) = self.document_id {
+                        ops.push(Op::DocumentId(document_id));
+                    }
+                }
+                self.written = (
+                    self.account_id,
+                    if is_contents {
+                        self.written.1
+                    } else {
+                        self.collection
+                    },
+                    self.document_id,
+                );
+                ops.push(Op::KeyValue((key, value)));
+            }
+        }
+    }
+}
 
-                            if account_id != last_account_id {
-                                writer
-                                    .send(Op::AccountId(account_id))
-                                    .failed("Failed to send account id");
-                                last_account_id = account_id;
-                            }
+/// Returns the collection with the given name, as shown in JSON exports.
+pub fn parse_collection(name: &str) -> Option<Collection> {
+    (0..u8::from(Collection::None))
+        .map(Collection::from)
+        .find(|collection| collection.to_string().eq_ignore_ascii_case(name))
+}
 
-                            if collection != last_collection {
-                                writer
-                                    .send(Op::Collection(collection))
-                                    .failed("Failed to send collection");
-                                last_collection = collection;
-                            }
+fn shard_name(name: &str, shard: usize, format: BackupFormat) -> String {
+    let name = if shard == 0 {
+        name.to_string()
+    } else {
+        format!("{name}.{shard}")
+    };
+    match format {
+        BackupFormat::Binary => name,
+        BackupFormat::Json => format!("{name}.jsonl"),
+    }
+}
 
-                            writer
-                                .send(Op::KeyValue((key, value.to_vec())))
-                                .failed("Failed to send key value");
+enum BackupFile {
+    File(BufWriter<std::fs::File>),
+    Stdout(BufWriter<std::io::StdoutLock<'static>>),
+    Parts(BlobParts),
+}
 
-                            Ok(true)
-                        },
-                    )
-                    .await
-                    .failed("Failed to iterate over data store");
+/// File written to a blob store, uploaded one part at a time as soon as it
+/// is full so that at most one part is held in memory.
+struct BlobParts {
+    store: BlobStore,
+    prefix: String,
+    rt: tokio::runtime::Handle,
+    part: Vec<u8>,
+    num: usize,
+}
+
+impl BackupFile {
+    fn create(location: &BackupLocation, rt: &tokio::runtime::Handle) -> Result<Self, BackupError> {
+        Ok(match location {
+            BackupLocation::Path(path) => BackupFile::File(BufWriter::new(
+                std::fs::File::create(path)
+                    .backup_failed(ExitCode::Store, "Failed to create backup file")?,
+            )),
+            BackupLocation::Stdio => BackupFile::Stdout(BufWriter::new(std::io::stdout().lock())),
+            BackupLocation::BlobStore { store, prefix, .. } => BackupFile::Parts(BlobParts {
+                store: store.clone(),
+                prefix: prefix.clone(),
+                rt: rt.clone(),
+                part: Vec::with_capacity(BLOB_PART_SIZE),
+                num: 0,
             }),
-            handle,
-        )
+        })
+    }
+
+    fn close(self) -> Result<(), BackupError> {
+        match self {
+            BackupFile::File(mut file) => file
+                .flush()
+                .backup_failed(ExitCode::Store, "Failed to flush backup file"),
+            BackupFile::Stdout(mut file) => file
+                .flush()
+                .backup_failed(ExitCode::Store, "Failed to flush stdout"),
+            BackupFile::Parts(mut parts) => {
+                // A file ending with a full part is read up to the next one, left
+                // behind by a larger file previously written under the same name
+                if parts.part.is_empty() && parts.num > 0 {
+                    let key = blob_part_key(&parts.prefix, parts.num);
+                    parts
+                        .rt
+                        .block_on(parts.store.delete_blob(key.as_bytes()))
+                        .map(|_| ())
+                        .backup_failed(ExitCode::Store, "Failed to upload backup file")
+                } else {
+                    parts
+                        .upload()
+                        .backup_failed(ExitCode::Store, "Failed to upload backup file")
+                }
+            }
+        }
     }
 }
 
-fn spawn_writer(path: PathBuf) -> (std::thread::JoinHandle<()>, SyncSender<Op>) {
-    let (tx, rx) = mpsc::sync_channel(10);
+impl BlobParts {
+    fn upload(&mut self) -> std::io::Result<()> {
+        let key = blob_part_key(&self.prefix, self.num);
+        self.rt
+            .block_on(self.store.put_blob(key.as_bytes(), &self.part))
+            .map_err(|err| std::io::Error::other(err.to_string()))?;
+        self.part.clear();
+        self.num += 1;
+        Ok(())
+    }
+}
 
-    let handle = std::thread::spawn(move || {
-        let mut file =
-            BufWriter::new(std::fs::File::create(path).failed("Failed to create backup file"));
-        file.write_all(&[MAGIC_MARKER, FILE_VERSION])
-            .failed("Failed to write version");
+impl Write for BackupFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            BackupFile::File(file) => file.write(buf),
+            BackupFile::Stdout(file) => file.write(buf),
+            BackupFile::Parts(parts) => {
+                let len = std::cmp::min(buf.len(), BLOB_PART_SIZE - parts.part.len());
+                parts.part.extend_from_slice(&buf[..len]);
+                if parts.part.len() == BLOB_PART_SIZE {
+                    parts.upload()?;
+                }
+                Ok(len)
+            }
+        }
+    }
 
-        while let Ok(op) = rx.recv() {
-            match op {
-                Op::Family(f) => {
-                    file.write_all(&[0u8, f as u8])
-                        .failed("Failed to write family");
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            BackupFile::File(file) => file.flush(),
+            BackupFile::Stdout(file) => file.flush(),
+            // Parts are only uploaded once full, or when the file is closed
+            BackupFile::Parts(_) => Ok(()),
+        }
+    }
+}
+
+/// Key of a part of a file written to a blob store.
+fn blob_part_key(prefix: &str, num: usize) -> String {
+    if num == 0 {
+        prefix.to_string()
+    } else {
+        format!("{prefix}.part{num}")
+    }
+}
+
+/// Reads a file written to a blob store, fetching one part at a time.
+pub(super) struct BlobPartReader {
+    store: BlobStore,
+    prefix: String,
+    part: Vec<u8>,
+    pos: usize,
+    next: usize,
+    pending: Option<BoxFuture<'static, store::Result<Option<Vec<u8>>>>>,
+    is_done: bool,
+}
+
+impl BlobPartReader {
+    pub fn new(store: BlobStore, prefix: String) -> Self {
+        BlobPartReader {
+            store,
+            prefix,
+            part: Vec::new(),
+            pos: 0,
+            next: 0,
+            pending: None,
+            is_done: false,
+        }
+    }
+}
+
+impl AsyncRead for BlobPartReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.pos < this.part.len() {
+                let len = std::cmp::min(this.part.len() - this.pos, buf.remaining());
+                buf.put_slice(&this.part[this.pos..this.pos + len]);
+                this.pos += len;
+                return Poll::Ready(Ok(()));
+            } else if this.is_done || (this.next > 0 && this.part.len() < BLOB_PART_SIZE) {
+                return Poll::Ready(Ok(()));
+            }
+
+            let pending = this.pending.get_or_insert_with(|| {
+                let store = this.store.clone();
+                let key = blob_part_key(&this.prefix, this.next);
+                Box::pin(async move { store.get_blob(key.as_bytes(), 0..usize::MAX).await })
+            });
+            let result = ready!(pending.as_mut().poll(cx));
+            this.pending = None;
+            match result {
+                Ok(Some(part)) => {
+                    this.part = part;
+                    this.pos = 0;
+                    this.next += 1;
                 }
-                Op::KeyValue((k, v)) => {
-                    file.write_all(&[if !v.is_empty() { 1u8 } else { 2u8 }])
-                        .failed("Failed to write key");
-                    file.write_all(&(k.len() as u32).serialize())
-                        .failed("Failed to write key value");
-                    file.write_all(&k).failed("Failed to write key");
-                    if !v.is_empty() {
-                        file.write_all(&(v.len() as u32).serialize())
-                            .failed("Failed to write key value");
-                        file.write_all(&v).failed("Failed to write key value");
-                    }
+                Ok(None) if this.next == 0 => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        ErrorKind::NotFound,
+                        "Backup file not found",
+                    )));
+                }
+                Ok(None) => this.is_done = true,
+                Err(err) => return Poll::Ready(Err(std::io::Error::other(err.to_string()))),
+            }
+        }
+    }
+}
+
+struct OpWriter<W: Write> {
+    file: W,
+    format: BackupFormat,
+    snapshot_id: u64,
+    bytes: u64,
+    hasher: blake3::Hasher,
+    index_interval: Option<u64>,
+    /// Segments of the file so far, the last one being written.
+    segments: Vec<FileSegment>,
+    segment_hasher: blake3::Hasher,
+    num_ops: u64,
+    has_values: bool,
+    buf: Vec<u8>,
+    family: Family,
+    account_id: Option<u32>,
+    collection: Option<u8>,
+    document_id: Option<u32>,
+}
+
+impl<W: Write> OpWriter<W> {
+    fn new(
+        file: W,
+        format: BackupFormat,
+        snapshot_id: u64,
+        index_interval: Option<u64>,
+    ) -> Result<Self, BackupError> {
+        let mut writer = OpWriter {
+            file,
+            format,
+            snapshot_id,
+            bytes: 0,
+            hasher: blake3::Hasher::new(),
+            index_interval,
+            segments: Vec::new(),
+            segment_hasher: blake3::Hasher::new(),
+            num_ops: 0,
+            has_values: false,
+            buf: Vec::with_capacity(1024),
+            family: Family::None,
+            account_id: None,
+            collection: None,
+            document_id: None,
+        };
+        if format == BackupFormat::Binary {
+            writer.write_bytes(&[MAGIC_MARKER, FILE_VERSION], "Failed to write version")?;
+            writer.write_bytes(&snapshot_id.serialize(), "Failed to write snapshot id")?;
+            if index_interval.is_some() {
+                writer.start_segment();
+            }
+        }
+        Ok(writer)
+    }
+
+    /// Opens a new shard that continues where `previous` left off.
+    fn resume(file: W, previous: &Self) -> Result<Self, BackupError> {
+        let mut writer = Self::new(
+            file,
+            previous.format,
+            previous.snapshot_id,
+            previous.index_interval,
+        )?;
+        if previous.family != Family::None {
+            writer.write(Op::Family(previous.family))?;
+        }
+        if let Some(account_id) = previous.account_id {
+            writer.write(Op::AccountId(account_id))?;
+        }
+        if let Some(collection) = previous.collection {
+            writer.write(Op::Collection(collection))?;
+        }
+        if let Some(document_id) = previous.document_id {
+            writer.write(Op::DocumentId(document_id))?;
+        }
+        Ok(writer)
+    }
+
+    /// Whether a shard can end before `op` without splitting a document.
+    /// Blob contents are not part of any document, whichever store they
+    /// were read from.
+    fn can_split(&self, op: &Op) -> bool {
+        self.has_values
+            && (!matches!(op, Op::KeyValue(_))
+                || !matches!(self.document_id, Some(document_id) if document_id != u32::MAX)
+                || (self.family == Family::Blob && self.account_id == Some(u32::MAX)))
+    }
+
+    fn write(&mut self, op: Op) -> Result<(), BackupError> {
+        // Segments start at the first account after the interval, the log
+        // is restored in change id order and isn't split
+        if let (Op::AccountId(_), Some(interval), Some(segment)) =
+            (&op, self.index_interval, self.segments.last())
+        {
+            if self.bytes - segment.offset >= interval && self.family != Family::Log {
+                self.start_segment();
+            }
+        }
+
+        match &op {
+            Op::Family(family) => {
+                self.family = *family;
+                self.account_id = None;
+                self.collection = None;
+                self.document_id = None;
+            }
+            Op::AccountId(account_id) => self.account_id = Some(*account_id),
+            Op::Collection(collection) => self.collection = Some(*collection),
+            Op::DocumentId(document_id) => self.document_id = Some(*document_id),
+            Op::KeyValue(_) => self.has_values = true,
+        }
+
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        match self.format {
+            BackupFormat::Binary => {
+                op.serialize_into(&mut buf);
+                self.hasher.update(&buf);
+                if !self.segments.is_empty() {
+                    self.segment_hasher.update(&buf);
+                }
+                self.num_ops += 1;
+            }
+            BackupFormat::Json => {
+                if let Op::KeyValue((key, value)) = op {
+                    serde_json::to_writer(&mut buf, &self.json_entry(&key, &value))
+                        .backup_failed(ExitCode::Store, "Failed to write operation")?;
+                    buf.push(b'\n');
                 }
-                Op::AccountId(v) => {
-                    file.write_all(&[3u8]).failed("Failed to write account id");
-                    file.write_all(&v.serialize())
-                        .failed("Failed to write account id");
+            }
+        }
+        self.write_bytes(&buf, "Failed to write operation")?;
+        self.buf = buf;
+        Ok(())
+    }
+
+    fn json_entry(&self, key: &[u8], value: &[u8]) -> serde_json::Map<String, serde_json::Value> {
+        let mut entry = serde_json::Map::new();
+        entry.insert("family".into(), format!("{:?}", self.family).into());
+        if let Some(account_id) = self.account_id.filter(|id| *id != u32::MAX) {
+            entry.insert("account_id".into(), account_id.into());
+        }
+        if let Some(collection) = self.collection.filter(|c| *c != u8::MAX) {
+            entry.insert(
+                "collection".into(),
+                Collection::from(collection).to_string().into(),
+            );
+        }
+        if let Some(document_id) = self.document_id.filter(|id| *id != u32::MAX) {
+            entry.insert("document_id".into(), document_id.into());
+        }
+        if decode_json_key_value(self.family, key, value, &mut entry).is_err() {
+            entry.insert("key".into(), STANDARD.encode(key).into());
+            entry.insert("value".into(), STANDARD.encode(value).into());
+        }
+        entry
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8], err: &str) -> Result<(), BackupError> {
+        self.file
+            .write_all(bytes)
+            .backup_failed(ExitCode::Store, err)?;
+        self.bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Ends the segment being written, if any, and starts a new one at the
+    /// current position.
+    fn start_segment(&mut self) {
+        self.end_segment();
+        self.segments.push(FileSegment {
+            offset: self.bytes,
+            len: 0,
+            ops_before: self.num_ops,
+            ops: 0,
+            hash: String::new(),
+            family: self.family,
+            collection: self.collection,
+            document_id: self.document_id,
+        });
+    }
+
+    fn end_segment(&mut self) {
+        if let Some(segment) = self.segments.last_mut() {
+            segment.len = self.bytes - segment.offset;
+            segment.ops = self.num_ops - segment.ops_before;
+            segment.hash = self.segment_hasher.finalize().to_hex().to_string();
+            self.segment_hasher.reset();
+        }
+    }
+
+    /// Writes the trailer, returning the file along with its segments when
+    /// it was split into more than one.
+    fn finish(mut self) -> Result<(W, Vec<FileSegment>), BackupError> {
+        let segments = if self.segments.len() > 1 {
+            self.end_segment();
+            std::mem::take(&mut self.segments)
+        } else {
+            Vec::new()
+        };
+        if self.format == BackupFormat::Binary {
+            // Write integrity trailer
+            let num_ops = self.num_ops.serialize();
+            let hash = self.hasher.finalize();
+            self.write_bytes(&[TRAILER_MARKER], "Failed to write trailer")?;
+            self.write_bytes(&num_ops, "Failed to write trailer")?;
+            self.write_bytes(hash.as_bytes(), "Failed to write trailer")?;
+        }
+        Ok((self.file, segments))
+    }
+}
+
+fn decode_json_key_value(
+    family: Family,
+    key: &[u8],
+    value: &[u8],
+    entry: &mut serde_json::Map<String, serde_json::Value>,
+) -> store::Result<()> {
+    let mut insert = |name: &str, value: serde_json::Value| {
+        entry.insert(name.to_string(), value);
+    };
+
+    match family {
+        Family::Property => {
+            insert("field", key.deserialize_u8(0)?.into());
+            insert("value", STANDARD.encode(value).into());
+        }
+        Family::TermIndex => {
+            insert("value", STANDARD.encode(key).into());
+        }
+        Family::Acl => {
+            insert("grant_account_id", key.deserialize_be_u32(0)?.into());
+            insert("value", STANDARD.encode(value).into());
+        }
+        Family::Blob => {
+            insert("hash", STANDARD.encode(key).into());
+            if !value.is_empty() {
+                insert("value", STANDARD.encode(value).into());
+            }
+        }
+        Family::Config => {
+            insert("key", String::from_utf8_lossy(key).into());
+            insert("value", String::from_utf8_lossy(value).into());
+        }
+        Family::LookupValue => {
+            insert("key", String::from_utf8_lossy(key).into());
+            insert("value", STANDARD.encode(value).into());
+        }
+        Family::LookupCounter => {
+            insert("key", String::from_utf8_lossy(key).into());
+            insert("value", i64::deserialize(value)?.into());
+        }
+        Family::Directory => match key.deserialize_u8(0)? {
+            0 => {
+                insert("type", "name_to_id".into());
+                insert(
+                    "name",
+                    String::from_utf8_lossy(key.range(1..key.len())?).into(),
+                );
+                insert("value", STANDARD.encode(value).into());
+            }
+            1 => {
+                insert("type", "email_to_id".into());
+                insert(
+                    "email",
+                    String::from_utf8_lossy(key.range(1..key.len())?).into(),
+                );
+                insert("value", STANDARD.encode(value).into());
+            }
+            2 => {
+                insert("type", "principal".into());
+                insert(
+                    "principal_id",
+                    key.range(1..key.len())?.deserialize_leb128::<u32>()?.into(),
+                );
+                insert("value", STANDARD.encode(value).into());
+            }
+            3 => {
+                insert("type", "domain".into());
+                insert(
+                    "domain",
+                    String::from_utf8_lossy(key.range(1..key.len())?).into(),
+                );
+            }
+            4 => {
+                insert("type", "used_quota".into());
+                insert(
+                    "principal_id",
+                    key.range(1..key.len())?.deserialize_leb128::<u32>()?.into(),
+                );
+                insert("value", i64::deserialize(value)?.into());
+            }
+            5 => {
+                insert("type", "member_of".into());
+                insert("principal_id", key.deserialize_be_u32(1)?.into());
+                insert("member_of", key.deserialize_be_u32(1 + U32_LEN)?.into());
+            }
+            6 => {
+                insert("type", "members".into());
+                insert("principal_id", key.deserialize_be_u32(1)?.into());
+                insert("has_member", key.deserialize_be_u32(1 + U32_LEN)?.into());
+            }
+            _ => return Err(store::Error::InternalError("Invalid directory key".into())),
+        },
+        Family::Queue => {
+            match key.deserialize_u8(0)? {
+                0 => {
+                    insert("type", "message".into());
+                    insert("queue_id", key.deserialize_be_u64(1)?.into());
                 }
-                Op::Collection(v) => {
-                    file.write_all(&[4u8, v])
-                        .failed("Failed to write collection");
+                1 => {
+                    insert("type", "event".into());
+                    insert("due", key.deserialize_be_u64(1)?.into());
+                    insert("queue_id", key.deserialize_be_u64(1 + U64_LEN)?.into());
                 }
-                Op::DocumentId(v) => {
-                    file.write_all(&[5u8]).failed("Failed to write document id");
-                    file.write_all(&v.serialize())
-                        .failed("Failed to write document id");
+                _ => return Err(store::Error::InternalError("Invalid queue key".into())),
+            }
+            insert("value", STANDARD.encode(value).into());
+        }
+        Family::Index => {
+            insert("field", key.deserialize_u8(0)?.into());
+            insert("key", STANDARD.encode(key.range(1..key.len())?).into());
+        }
+        Family::Bitmap => {
+            match key.deserialize_u8(0)? {
+                0 => {
+                    insert("type", "document_ids".into());
+                }
+                1 => {
+                    insert("type", "tag_id".into());
+                    insert("field", key.deserialize_u8(1)?.into());
+                    insert("tag", key.deserialize_be_u32(2)?.into());
+                }
+                2 => {
+                    insert("type", "tag_text".into());
+                    insert("field", key.deserialize_u8(1)?.into());
+                    insert(
+                        "tag",
+                        String::from_utf8_lossy(key.range(2..key.len())?).into(),
+                    );
+                }
+                3 => {
+                    insert("type", "tag_static".into());
+                    insert("field", key.deserialize_u8(1)?.into());
+                    insert("tag", key.deserialize_u8(2)?.into());
                 }
+                4 => {
+                    insert("type", "text".into());
+                    insert("field", key.deserialize_u8(1)?.into());
+                    insert("len", key.deserialize_u8(2)?.into());
+                    insert("hash", STANDARD.encode(key.range(3..11)?).into());
+                }
+                _ => return Err(store::Error::InternalError("Invalid bitmap class".into())),
             }
+            let document_ids = RoaringBitmap::deserialize_from(value)
+                .map_err(|err| store::Error::InternalError(err.to_string()))?;
+            insert(
+                "document_ids",
+                document_ids.into_iter().collect::<Vec<_>>().into(),
+            );
+        }
+        Family::Log => {
+            insert("change_id", key.deserialize_be_u64(0)?.into());
+            insert("value", STANDARD.encode(value).into());
+        }
+        Family::None => {
+            return Err(store::Error::InternalError("No family specified".into()));
         }
+    }
 
-        file.flush().failed("Failed to flush backup file");
-    });
+    Ok(())
+}
 
-    (handle, tx)
+impl BackupLocation {
+    pub fn parse(core: &Core, value: &str) -> Self {
+        if value == "-" {
+            BackupLocation::Stdio
+        } else if let Some(url) = value.strip_prefix("s3://") {
+            let (id, prefix) = url.split_once('/').unwrap_or((url, ""));
+            BackupLocation::BlobStore {
+                id: id.to_string(),
+                store: core
+                    .storage
+                    .blobs
+                    .get(id)
+                    .cloned()
+                    .failed_with(ExitCode::Config, &format!("Blob store '{id}' not found")),
+                prefix: prefix.trim_matches('/').to_string(),
+            }
+        } else {
+            BackupLocation::Path(value.into())
+        }
+    }
+
+    pub fn join(&self, name: &str) -> Self {
+        match self {
+            BackupLocation::Path(path) => BackupLocation::Path(path.join(name)),
+            BackupLocation::Stdio => BackupLocation::Stdio,
+            BackupLocation::BlobStore { id, store, prefix } => BackupLocation::BlobStore {
+                id: id.clone(),
+                store: store.clone(),
+                prefix: if prefix.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{prefix}/{name}")
+                },
+            },
+        }
+    }
+
+    pub(super) fn with_suffix(&self, suffix: &str) -> Self {
+        match self {
+            BackupLocation::Path(path) => {
+                let mut path = path.as_os_str().to_owned();
+                path.push(suffix);
+                BackupLocation::Path(path.into())
+            }
+            BackupLocation::Stdio => BackupLocation::Stdio,
+            BackupLocation::BlobStore { id, store, prefix } => BackupLocation::BlobStore {
+                id: id.clone(),
+                store: store.clone(),
+                prefix: format!("{prefix}{suffix}"),
+            },
+        }
+    }
+
+    pub(super) async fn read(&self) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            BackupLocation::Path(path) => match tokio::fs::read(path).await {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(format!("Failed to read {self}: {err}")),
+            },
+            // Progress can't be tracked when streaming
+            BackupLocation::Stdio => Ok(None),
+            BackupLocation::BlobStore { store, prefix, .. } => store
+                .get_blob(prefix.as_bytes(), 0..usize::MAX)
+                .await
+                .map_err(|err| format!("Failed to read {self}: {err}")),
+        }
+    }
+
+    /// Whether a file exists at the location, fetching at most its first
+    /// byte from a blob store.
+    pub(super) async fn exists(&self) -> Result<bool, String> {
+        match self {
+            BackupLocation::Path(path) => Ok(path.is_file()),
+            BackupLocation::Stdio => Ok(true),
+            BackupLocation::BlobStore { store, prefix, .. } => store
+                .get_blob(prefix.as_bytes(), 0..1)
+                .await
+                .map(|bytes| bytes.is_some())
+                .map_err(|err| format!("Failed to read {self}: {err}")),
+        }
+    }
+
+    /// Replaces the contents of the file at the location. Files on disk are
+    /// written next to it first and renamed over it, so that an interrupted
+    /// write never leaves a partial file behind.
+    pub(super) async fn write(&self, bytes: &[u8]) -> Result<(), String> {
+        match self {
+            BackupLocation::Path(path) => {
+                let mut tmp_path = path.as_os_str().to_owned();
+                tmp_path.push(".tmp");
+                let result = match tokio::fs::write(&tmp_path, bytes).await {
+                    Ok(()) => tokio::fs::rename(&tmp_path, path).await,
+                    Err(err) => Err(err),
+                };
+                result.map_err(|err| format!("Failed to write {self}: {err}"))
+            }
+            BackupLocation::Stdio => Ok(()),
+            BackupLocation::BlobStore { store, prefix, .. } => store
+                .put_blob(prefix.as_bytes(), bytes)
+                .await
+                .map_err(|err| format!("Failed to write {self}: {err}")),
+        }
+    }
+
+    pub(super) async fn remove(&self) -> Result<(), String> {
+        match self {
+            BackupLocation::Path(path) => match tokio::fs::remove_file(path).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    Err(format!("Failed to remove {self}: {err}"))
+                }
+                _ => Ok(()),
+            },
+            BackupLocation::Stdio => Ok(()),
+            BackupLocation::BlobStore { store, prefix, .. } => store
+                .delete_blob(prefix.as_bytes())
+                .await
+                .map(|_| ())
+                .map_err(|err| format!("Failed to remove {self}: {err}")),
+        }
+    }
+}
+
+impl BackupManifest {
+    pub async fn read(location: &BackupLocation) -> Result<Option<Self>, String> {
+        match location.join(MANIFEST_FILE).read().await? {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|err| format!("Failed to parse manifest in {location}: {err}")),
+            None => Ok(None),
+        }
+    }
+
+    pub(super) fn merge(&mut self, other: BackupManifest) {
+        for (account_id, collections) in other.accounts {
+            let account = self.accounts.entry(account_id).or_default();
+            for (collection, documents) in collections {
+                *account.entry(collection).or_default() += documents;
+            }
+        }
+        for (family, count) in other.ops {
+            *self.ops.entry(family).or_default() += count;
+        }
+        self.blobs += other.blobs;
+        self.blob_bytes += other.blob_bytes;
+        self.shards.extend(other.shards);
+        self.blob_stores.extend(other.blob_stores);
+        self.index.extend(other.index);
+    }
+}
+
+impl ManifestBuilder {
+    pub fn new(blob_store_ids: Arc<[String]>) -> Self {
+        Self {
+            blob_store_ids,
+            ..Default::default()
+        }
+    }
+
+    pub fn track(&mut self, op: &Op) {
+        match op {
+            Op::Family(family) => {
+                self.family = *family;
+                self.account_id = u32::MAX;
+                self.collection = u8::MAX;
+                self.document_id = u32::MAX;
+            }
+            Op::AccountId(account_id) => {
+                self.account_id = *account_id;
+                if *account_id != u32::MAX {
+                    BACKUP_METRICS.account(*account_id);
+                }
+            }
+            Op::Collection(collection) => self.collection = *collection,
+            Op::DocumentId(document_id) => self.document_id = *document_id,
+            Op::KeyValue((key, value)) => {
+                *self.manifest.ops.entry(self.family).or_default() += 1;
+                BACKUP_METRICS.op(self.family, key.len() + value.len());
+
+                // Blob contents are stored outside of any account, the rest are links
+                if self.family == Family::Blob
+                    && (self.account_id == u32::MAX || self.document_id == u32::MAX)
+                {
+                    self.manifest.blobs += 1;
+                    self.manifest.blob_bytes += value.len() as u64;
+                    BACKUP_METRICS.blob();
+
+                    if self.document_id != u32::MAX {
+                        if let Some(id) = self.blob_store_ids.get(self.document_id as usize) {
+                            self.manifest
+                                .blob_stores
+                                .entry(self.document_id)
+                                .or_insert_with(|| id.clone());
+                        }
+                    }
+                }
+
+                if self.account_id != u32::MAX {
+                    let account = self.manifest.accounts.entry(self.account_id).or_default();
+                    if self.collection != u8::MAX {
+                        let documents = account
+                            .entry(Collection::from(self.collection).to_string())
+                            .or_default();
+
+                        // Properties are sorted by document, count each one once
+                        let document = (self.account_id, self.collection, self.document_id);
+                        if self.family == Family::Property && self.last_document != Some(document) {
+                            *documents += 1;
+                            self.last_document = Some(document);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for ManifestBuilder {
+    fn default() -> Self {
+        Self {
+            manifest: BackupManifest::default(),
+            family: Family::None,
+            account_id: u32::MAX,
+            collection: u8::MAX,
+            document_id: u32::MAX,
+            last_document: None,
+            blob_store_ids: Arc::from([]),
+        }
+    }
+}
+
+impl From<PathBuf> for BackupLocation {
+    fn from(path: PathBuf) -> Self {
+        BackupLocation::Path(path)
+    }
+}
+
+impl Display for BackupLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupLocation::Path(path) => write!(f, "{}", path.display()),
+            BackupLocation::Stdio => write!(f, "<stdio>"),
+            BackupLocation::BlobStore { id, prefix, .. } => write!(f, "s3://{id}/{prefix}"),
+        }
+    }
+}
+
+impl Op {
+    fn serialize_into(self, buf: &mut Vec<u8>) {
+        match self {
+            Op::Family(f) => {
+                buf.extend_from_slice(&[0u8, f as u8]);
+            }
+            Op::KeyValue((k, v)) => {
+                buf.push(if !v.is_empty() { 1u8 } else { 2u8 });
+                buf.extend_from_slice(&(k.len() as u32).serialize());
+                buf.extend_from_slice(&k);
+                if !v.is_empty() {
+                    buf.extend_from_slice(&(v.len() as u32).serialize());
+                    buf.extend_from_slice(&v);
+                }
+            }
+            Op::AccountId(v) => {
+                buf.push(3u8);
+                buf.extend_from_slice(&v.serialize());
+            }
+            Op::Collection(v) => {
+                buf.extend_from_slice(&[4u8, v]);
+            }
+            Op::DocumentId(v) => {
+                buf.push(5u8);
+                buf.extend_from_slice(&v.serialize());
+            }
+        }
+    }
 }
 
 pub(super) trait DeserializeBytes {
@@ -1167,3 +2638,57 @@ impl Deserialize for RawBytes {
         Ok(Self(bytes.to_vec()))
     }
 }
+
+impl BackupError {
+    fn new(code: ExitCode, cause: impl Into<String>) -> Self {
+        Self {
+            cause: cause.into(),
+            code,
+        }
+    }
+
+    pub(super) fn task(err: tokio::task::JoinError) -> Self {
+        Self::new(ExitCode::Internal, format!("Task failed: {err}"))
+    }
+
+    fn writer() -> Self {
+        Self::new(ExitCode::Internal, "Backup writer thread failed")
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        self.code
+    }
+}
+
+impl Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.cause)
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+// Lets the ops sent from within an iteration stop it with their error
+impl From<BackupError> for store::Error {
+    fn from(err: BackupError) -> Self {
+        store::Error::InternalError(err.cause)
+    }
+}
+
+/// Counterpart of `UnwrapFailure` for backups, which may run within the
+/// server and return their errors instead of exiting.
+trait BackupFailure<T> {
+    fn backup_failed(self, code: ExitCode, message: &str) -> Result<T, BackupError>;
+}
+
+impl<T> BackupFailure<T> for Option<T> {
+    fn backup_failed(self, code: ExitCode, message: &str) -> Result<T, BackupError> {
+        self.ok_or_else(|| BackupError::new(code, message))
+    }
+}
+
+impl<T, E: Display> BackupFailure<T> for Result<T, E> {
+    fn backup_failed(self, code: ExitCode, message: &str) -> Result<T, BackupError> {
+        self.map_err(|err| BackupError::new(code, format!("{message}: {err}")))
+    }
+}
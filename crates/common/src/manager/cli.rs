@@ -0,0 +1,945 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{fmt::Write, iter::Peekable};
+
+use super::boot::QUICKSTART_LISTENERS;
+
+/// Command line option, used to parse arguments, print help and generate completions.
+pub struct CliOption {
+    pub long: &'static str,
+    pub short: Option<&'static str>,
+    pub value: CliValue,
+    pub help: &'static str,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CliValue {
+    None,
+    Required(&'static str, CliHint),
+    Optional(&'static str, CliHint),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CliHint {
+    Any,
+    Path,
+    Choice(&'static [&'static str]),
+}
+
+const BIND_PREFIX: &str = "bind-";
+const HELP_INDENT: usize = 35;
+
+pub const CLI_OPTIONS: &[CliOption] = &[
+    CliOption {
+        long: "config",
+        short: Some("c"),
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Start server with the specified configuration file",
+    },
+    CliOption {
+        long: "config-dir",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Merge all *.toml files in a directory, in lexical order",
+    },
+    CliOption {
+        long: "resource-dir",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Cache fetched resources in PATH and read them from there when offline",
+    },
+    CliOption {
+        long: "shutdown-timeout",
+        short: None,
+        value: CliValue::Required("<TIME>", CliHint::Any),
+        help: "Time to wait for active connections to finish on shutdown (default 30s)",
+    },
+    CliOption {
+        long: "check-config",
+        short: None,
+        value: CliValue::None,
+        help: "Validate the configuration file and exit without starting the server",
+    },
+    CliOption {
+        long: "quiet",
+        short: Some("q"),
+        value: CliValue::None,
+        help: "Only log warnings and errors, commands still print their summary",
+    },
+    CliOption {
+        long: "verbose",
+        short: Some("v"),
+        value: CliValue::None,
+        help: "Log debug messages, repeat (-vv) to also log trace messages",
+    },
+    CliOption {
+        long: "no-progress",
+        short: None,
+        value: CliValue::None,
+        help: "Don't print the progress of an import or export to stderr",
+    },
+    CliOption {
+        long: "output",
+        short: None,
+        value: CliValue::Required("<FORMAT>", CliHint::Choice(&["text", "json"])),
+        help: "Print the outcome of an import, export or backup verification as 'text' (default) or a 'json' object",
+    },
+    CliOption {
+        long: "lenient",
+        short: None,
+        value: CliValue::None,
+        help: "Start the server even when the configuration has errors",
+    },
+    CliOption {
+        long: "test-stores",
+        short: None,
+        value: CliValue::None,
+        help: "Check that every configured store is reachable and exit",
+    },
+    CliOption {
+        long: "print-config",
+        short: None,
+        value: CliValue::Optional("[PREFIX]", CliHint::Any),
+        help: "Print the effective configuration as TOML, optionally filtered by prefix",
+    },
+    CliOption {
+        long: "export-config",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Export the configuration keys held in the store as TOML",
+    },
+    CliOption {
+        long: "import-config",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Load configuration keys from a TOML file into the store",
+    },
+    CliOption {
+        long: "config-get",
+        short: None,
+        value: CliValue::Required("<KEY>", CliHint::Any),
+        help: "Print the value of a configuration key",
+    },
+    CliOption {
+        long: "config-set",
+        short: None,
+        value: CliValue::Required("<KEY>=<VALUE>", CliHint::Any),
+        help: "Set a configuration key",
+    },
+    CliOption {
+        long: "config-delete",
+        short: None,
+        value: CliValue::Required("<KEY>", CliHint::Any),
+        help: "Delete a configuration key",
+    },
+    CliOption {
+        long: "rotate-oauth-key",
+        short: None,
+        value: CliValue::Optional("[GRACE]", CliHint::Any),
+        help: "Replace the OAuth key, accepting tokens issued with the old key for GRACE",
+    },
+    CliOption {
+        long: "config-list",
+        short: None,
+        value: CliValue::Optional("[PREFIX]", CliHint::Any),
+        help: "List the configuration keys, optionally filtered by prefix",
+    },
+    CliOption {
+        long: "show-secrets",
+        short: None,
+        value: CliValue::None,
+        help: "Do not redact secrets when printing, listing or exporting the configuration",
+    },
+    CliOption {
+        long: "export",
+        short: Some("e"),
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Export all store data to a path, s3://<STORE>/<PREFIX> or - (stdout)",
+    },
+    CliOption {
+        long: "import",
+        short: Some("i"),
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Import store data from a path, s3://<STORE>/<PREFIX> or - (stdin)",
+    },
+    CliOption {
+        long: "export-queue",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Export only the message queue and its blobs",
+    },
+    CliOption {
+        long: "import-queue",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Import only the message queue and its blobs",
+    },
+    CliOption {
+        long: "export-maildir",
+        short: None,
+        value: CliValue::Required("<ACCOUNT> <PATH>", CliHint::Any),
+        help: "Export the mailboxes of an account to a Maildir at PATH",
+    },
+    CliOption {
+        long: "import-maildir",
+        short: None,
+        value: CliValue::Required("<ACCOUNT> <PATH>", CliHint::Any),
+        help: "Import a Maildir at PATH into the mailboxes of an account",
+    },
+    CliOption {
+        long: "diff-backup",
+        short: None,
+        value: CliValue::Required("<OLD> <NEW>", CliHint::Path),
+        help: "List the keys added, removed or changed between two backups",
+    },
+    CliOption {
+        long: "list-backup",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Print the manifest of a backup",
+    },
+    CliOption {
+        long: "verify-backup",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Check the integrity of a backup without restoring it",
+    },
+    CliOption {
+        long: "export-format",
+        short: None,
+        value: CliValue::Required("<FORMAT>", CliHint::Choice(&["binary", "json"])),
+        help: "Export format, 'binary' (default) or 'json' for inspection",
+    },
+    CliOption {
+        long: "timestamped",
+        short: None,
+        value: CliValue::None,
+        help: "Export to a new timestamped subdirectory of the export directory",
+    },
+    CliOption {
+        long: "retain",
+        short: None,
+        value: CliValue::Required("<N|DURATION>", CliHint::Any),
+        help: "Remove older timestamped backups beyond N sets or older than DURATION (e.g. 30d)",
+    },
+    CliOption {
+        long: "max-file-size",
+        short: None,
+        value: CliValue::Required("<SIZE>", CliHint::Any),
+        help: "Split exported files larger than SIZE (e.g. 2GiB) into numbered shards",
+    },
+    CliOption {
+        long: "skip-orphan-blobs",
+        short: None,
+        value: CliValue::None,
+        help: "Leave out blobs that no document or queued message references",
+    },
+    CliOption {
+        long: "export-collection",
+        short: None,
+        value: CliValue::Required("<LIST>", CliHint::Any),
+        help: "Only export the comma-separated collections of all accounts (e.g. mailbox,email)",
+    },
+    CliOption {
+        long: "index-interval",
+        short: None,
+        value: CliValue::Required("<SIZE>", CliHint::Any),
+        help: "Bytes between the account boundaries indexed in the manifest for concurrent import (e.g. 64MiB)",
+    },
+    CliOption {
+        long: "segment-concurrency",
+        short: None,
+        value: CliValue::Required("<N>", CliHint::Any),
+        help: "Number of segments of indexed files imported at the same time, 1 to import files as a whole",
+    },
+    CliOption {
+        long: "batch-size",
+        short: None,
+        value: CliValue::Required("<N>", CliHint::Any),
+        help: "Number of operations per write batch during import",
+    },
+    CliOption {
+        long: "batch-bytes",
+        short: None,
+        value: CliValue::Required("<N>", CliHint::Any),
+        help: "Maximum size in bytes of a write batch during import",
+    },
+    CliOption {
+        long: "blob-dedup-limit",
+        short: None,
+        value: CliValue::Required("<N>", CliHint::Any),
+        help: "Number of blob hashes remembered to skip repeated blob writes during import (0 disables)",
+    },
+    CliOption {
+        long: "blob-concurrency",
+        short: None,
+        value: CliValue::Required("<N>", CliHint::Any),
+        help: "Number of blobs uploaded in parallel during import (default: 8)",
+    },
+    CliOption {
+        long: "on-cancel",
+        short: None,
+        value: CliValue::Required("<MODE>", CliHint::Choice(&["flush", "discard"])),
+        help: "Write (flush, default) or drop (discard) the current batch when an import is interrupted",
+    },
+    CliOption {
+        long: "metrics-push",
+        short: None,
+        value: CliValue::Required("<URL>", CliHint::Any),
+        help: "Push backup and restore progress metrics to a Prometheus Pushgateway at URL",
+    },
+    CliOption {
+        long: "into-store",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Import into the stores of another configuration file, e.g. for recovery drills",
+    },
+    CliOption {
+        long: "dry-run",
+        short: None,
+        value: CliValue::None,
+        help: "Validate the import data without writing to the store",
+    },
+    CliOption {
+        long: "resume",
+        short: None,
+        value: CliValue::None,
+        help: "Resume an interrupted import from its last checkpoint",
+    },
+    CliOption {
+        long: "progress-dir",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Keep import progress files in PATH instead of next to the backup files",
+    },
+    CliOption {
+        long: "merge",
+        short: None,
+        value: CliValue::None,
+        help: "Import into a store that already has accounts, merging the backup with their data",
+    },
+    CliOption {
+        long: "read-buffer-size",
+        short: None,
+        value: CliValue::Required("<SIZE>", CliHint::Any),
+        help: "Size of the read buffer used during import (e.g. 4MiB, default 1MiB)",
+    },
+    CliOption {
+        long: "restore-memory-budget",
+        short: None,
+        value: CliValue::Required("<SIZE>", CliHint::Any),
+        help: "Memory used by the batches, blob uploads and read-ahead of all the files being imported (e.g. 2GiB)",
+    },
+    CliOption {
+        long: "mmap",
+        short: None,
+        value: CliValue::None,
+        help: "Memory-map backup files during import instead of reading them as a stream",
+    },
+    CliOption {
+        long: "tolerant",
+        short: None,
+        value: CliValue::None,
+        help: "Skip corrupt operations during import instead of aborting",
+    },
+    CliOption {
+        long: "skip-unknown",
+        short: None,
+        value: CliValue::None,
+        help: "Skip families and operations written by a newer version during import",
+    },
+    CliOption {
+        long: "stage-accounts",
+        short: None,
+        value: CliValue::None,
+        help: "Stage each account during import and only replace its existing data once the import succeeds, the replacement itself is not atomic",
+    },
+    CliOption {
+        long: "recompute-quota",
+        short: None,
+        value: CliValue::None,
+        help: "Recalculate used quotas from the restored data",
+    },
+    CliOption {
+        long: "verify-after-restore",
+        short: None,
+        value: CliValue::None,
+        help: "Check the consistency of the restored data",
+    },
+    CliOption {
+        long: "import-remap",
+        short: None,
+        value: CliValue::Required("<OLD:NEW>", CliHint::Any),
+        help: "Restore account id OLD as NEW (can be repeated)",
+    },
+    CliOption {
+        long: "blob-store",
+        short: None,
+        value: CliValue::Required("<OLD:NEW>", CliHint::Any),
+        help: "Restore the blobs exported from blob store OLD into NEW (can be repeated)",
+    },
+    CliOption {
+        long: "import-families",
+        short: None,
+        value: CliValue::Required("<LIST>", CliHint::Any),
+        help: "Only import the comma-separated families (e.g. property,blob,queue)",
+    },
+    CliOption {
+        long: "queue-due",
+        short: None,
+        value: CliValue::Required("<DUE>", CliHint::Any),
+        help: "Due time of imported queue events: keep (default), now or +<DURATION>",
+    },
+    CliOption {
+        long: "migrate",
+        short: None,
+        value: CliValue::None,
+        help: "Copy all data between the stores of two configurations, an interrupted migration is not resumable and starts over",
+    },
+    CliOption {
+        long: "from",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Configuration file of the server to migrate from",
+    },
+    CliOption {
+        long: "to",
+        short: None,
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Configuration file of the server to migrate to",
+    },
+    CliOption {
+        long: "gc-blobs",
+        short: None,
+        value: CliValue::None,
+        help: "Report committed blobs that are not linked to any document",
+    },
+    CliOption {
+        long: "update-resources",
+        short: None,
+        value: CliValue::None,
+        help: "Update the spam filter rules and webadmin to the newest (or pinned) versions",
+    },
+    CliOption {
+        long: "reinstall-webadmin",
+        short: None,
+        value: CliValue::None,
+        help: "Download the webadmin again and verify it against the installed hash",
+    },
+    CliOption {
+        long: "confirm",
+        short: None,
+        value: CliValue::None,
+        help: "Delete the blobs found by '--gc-blobs'",
+    },
+    CliOption {
+        long: "passwd",
+        short: None,
+        value: CliValue::Optional("[USER]", CliHint::Any),
+        help: "Change the password of USER or of the fallback administrator",
+    },
+    CliOption {
+        long: "init",
+        short: Some("I"),
+        value: CliValue::Required("<PATH>", CliHint::Path),
+        help: "Initialize a new server at a specific path",
+    },
+    CliOption {
+        long: "backend",
+        short: None,
+        value: CliValue::Required(
+            "<TYPE>",
+            CliHint::Choice(&["rocksdb", "foundationdb", "postgres", "sqlite"]),
+        ),
+        help: "Store used by '--init': rocksdb (default), foundationdb, postgres or sqlite",
+    },
+    CliOption {
+        long: "listen-addr",
+        short: None,
+        value: CliValue::Required("<IP>", CliHint::Any),
+        help: "Interface the listeners created by '--init' bind to (default [::])",
+    },
+    CliOption {
+        long: "bind-<LISTENER>",
+        short: None,
+        value: CliValue::Required("<ADDR>", CliHint::Any),
+        help: "Bind address or port of an '--init' listener (smtp, submission,\nsubmissions, imap, imaptls, sieve, https or http)",
+    },
+    CliOption {
+        long: "dkim",
+        short: None,
+        value: CliValue::Required("<ALGO>", CliHint::Choice(&["ed25519", "rsa", "all"])),
+        help: "DKIM keys generated by '--init': ed25519 (default), rsa or all",
+    },
+    CliOption {
+        long: "domain",
+        short: None,
+        value: CliValue::Required("<DOMAIN>", CliHint::Any),
+        help: "Domain of the DKIM keys generated by '--init' (default hostname)",
+    },
+    CliOption {
+        long: "compose",
+        short: None,
+        value: CliValue::None,
+        help: "Write a Docker Compose file for the server created by '--init'",
+    },
+    CliOption {
+        long: "systemd",
+        short: None,
+        value: CliValue::None,
+        help: "Write a systemd unit for the server created by '--init'",
+    },
+    CliOption {
+        long: "allow-weak-password",
+        short: None,
+        value: CliValue::None,
+        help: "Accept an administrator password that fails the strength check",
+    },
+    CliOption {
+        long: "min-password-length",
+        short: None,
+        value: CliValue::Required("<N>", CliHint::Any),
+        help: "Minimum administrator password length (default: 8)",
+    },
+    CliOption {
+        long: "force",
+        short: None,
+        value: CliValue::None,
+        help: "Overwrite an existing configuration file on '--init', or import into a store that already has accounts",
+    },
+    CliOption {
+        long: "rotate-dkim",
+        short: None,
+        value: CliValue::None,
+        help: "Replace the DKIM keys of an existing '--init' directory, their DNS records have to be published again",
+    },
+    CliOption {
+        long: "completions",
+        short: None,
+        value: CliValue::Required("<SHELL>", CliHint::Choice(&["bash", "zsh", "fish"])),
+        help: "Print a completion script for bash, zsh or fish",
+    },
+    CliOption {
+        long: "help",
+        short: Some("h"),
+        value: CliValue::None,
+        help: "Print help",
+    },
+    CliOption {
+        long: "version",
+        short: Some("V"),
+        value: CliValue::None,
+        help: "Print version and build information",
+    },
+    CliOption {
+        long: "json",
+        short: None,
+        value: CliValue::None,
+        help: "Print the version as JSON (with '--version')",
+    },
+];
+
+/// Returns the long name of an option given its long or short name.
+pub fn canonical_option(key: &str) -> &str {
+    CLI_OPTIONS
+        .iter()
+        .find(|option| option.short == Some(key))
+        .map_or(key, |option| option.long)
+}
+
+/// Returns the value an option takes as declared in [`CLI_OPTIONS`], or `None` for unknown options.
+pub fn option_value(key: &str) -> Option<CliValue> {
+    let key = if bind_listener(key).is_some() {
+        "bind-<LISTENER>"
+    } else {
+        canonical_option(key)
+    };
+    CLI_OPTIONS
+        .iter()
+        .find(|option| option.long == key)
+        .map(|option| option.value)
+}
+
+/// Reads the next option from the command line, returning its long name and value.
+///
+/// A value is only taken from the following argument when the option declares one in
+/// [`CLI_OPTIONS`], so the table is the single source of truth for the arity of each option.
+/// Returns `None` once the next argument is not an option.
+pub fn next_option(
+    args: &mut Peekable<impl Iterator<Item = String>>,
+) -> Option<Result<(String, Option<String>), String>> {
+    let arg = args.next_if(|arg| arg.starts_with('-') && arg != "-")?;
+    let (arg, short) = match arg.strip_prefix("--") {
+        Some(arg) => (arg, false),
+        None => (arg.strip_prefix('-').unwrap_or_default(), true),
+    };
+    let (key, value) = match arg.split_once('=') {
+        Some((key, value)) => (key, Some(value.trim().to_string())),
+        None => (arg, None),
+    };
+    // Repeated short flags such as '-vv' keep their key so callers can count them
+    let repeated = short && key.len() > 1 && key.bytes().all(|ch| ch == b'v');
+    let (key, arity) = if repeated {
+        (key.to_string(), option_value("v"))
+    } else {
+        let key = canonical_option(key).to_string();
+        let arity = option_value(&key);
+        (key, arity)
+    };
+
+    Some(match (arity, value) {
+        (None, _) => Err(format!("Unrecognized command '{key}', try '--help'.")),
+        (Some(CliValue::None), Some(_)) => Err(format!(
+            "Argument '{key}' does not take a value, try '--help'."
+        )),
+        (Some(CliValue::None), None) => Ok((key, None)),
+        (Some(CliValue::Required(_, _) | CliValue::Optional(_, _)), Some(value)) => {
+            Ok((key, Some(value)))
+        }
+        (Some(option), None) => {
+            let value = args.next_if(|value| !value.starts_with('-') || value == "-");
+            if value.is_none() && matches!(option, CliValue::Required(_, _)) {
+                Err(format!("Missing value for argument '{key}', try '--help'."))
+            } else {
+                Ok((key, value))
+            }
+        }
+    })
+}
+
+/// Returns the listener id of a '--bind-<LISTENER>' option.
+pub fn bind_listener(key: &str) -> Option<&str> {
+    key.strip_prefix(BIND_PREFIX).filter(|id| {
+        QUICKSTART_LISTENERS
+            .iter()
+            .any(|listener| listener.id == *id)
+    })
+}
+
+/// Formats a size in bytes using binary units, such as '41.2 GiB'.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Formats a count with thousands separators, such as '1,240,551'.
+pub fn format_count(count: u64) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3);
+    for (pos, ch) in digits.chars().enumerate() {
+        if pos > 0 && (digits.len() - pos).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(ch);
+    }
+    formatted
+}
+
+const EXIT_CODES: &str = "
+Exit codes:
+  0   Success
+  1   Unclassified failure, or differences found by '--diff-backup'
+  64  Invalid command line or configuration
+  65  Invalid or corrupted backup or input data
+  66  Store or I/O failure, which may succeed if retried
+  70  Internal error
+  78  Configuration file not found or unreadable
+  130 Import cancelled, it can be continued with '--resume'
+";
+
+pub fn help() -> String {
+    let mut help =
+        "Stalwart Mail Server\n\nUsage: stalwart-mail [OPTIONS]\n\nOptions:\n".to_string();
+
+    for option in CLI_OPTIONS {
+        let mut usage = match option.short {
+            Some(short) => format!("  -{short}, --{}", option.long),
+            None => format!("      --{}", option.long),
+        };
+        if let CliValue::Required(name, _) | CliValue::Optional(name, _) = option.value {
+            let _ = write!(usage, " {name}");
+        }
+        for (pos, line) in option.help.lines().enumerate() {
+            if pos == 0 {
+                let _ = writeln!(help, "{usage:HELP_INDENT$}{line}");
+            } else {
+                let _ = writeln!(help, "{:HELP_INDENT$}{line}", "");
+            }
+        }
+    }
+
+    help.push_str(EXIT_CODES);
+    help
+}
+
+/// Expands '--bind-<LISTENER>' into one option per listener.
+fn completion_options() -> Vec<(String, &'static CliOption)> {
+    let mut options = Vec::with_capacity(CLI_OPTIONS.len() + QUICKSTART_LISTENERS.len());
+    for option in CLI_OPTIONS {
+        if option.long.starts_with(BIND_PREFIX) {
+            options.extend(
+                QUICKSTART_LISTENERS
+                    .iter()
+                    .map(|listener| (format!("{BIND_PREFIX}{}", listener.id), option)),
+            );
+        } else {
+            options.push((option.long.to_string(), option));
+        }
+    }
+    options
+}
+
+fn short_help(option: &CliOption) -> &'static str {
+    option.help.lines().next().unwrap_or_default()
+}
+
+pub fn completions(shell: &str) -> Option<String> {
+    match shell {
+        "bash" => Some(bash_completions()),
+        "zsh" => Some(zsh_completions()),
+        "fish" => Some(fish_completions()),
+        _ => None,
+    }
+}
+
+fn bash_completions() -> String {
+    let options = completion_options();
+    let mut script = String::from(
+        "_stalwart_mail() {\n    local cur prev\n    cur=\"${COMP_WORDS[COMP_CWORD]}\"\n    prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n\n    case \"$prev\" in\n",
+    );
+
+    for (long, option) in &options {
+        match option.value {
+            CliValue::Required(_, CliHint::Path) => {
+                let _ = writeln!(
+                    script,
+                    "        --{long})\n            COMPREPLY=($(compgen -f -- \"$cur\"))\n            return\n            ;;"
+                );
+            }
+            CliValue::Required(_, CliHint::Choice(choices)) => {
+                let _ = writeln!(
+                    script,
+                    "        --{long})\n            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n            return\n            ;;",
+                    choices.join(" ")
+                );
+            }
+            CliValue::Required(_, CliHint::Any) => {
+                let _ = writeln!(
+                    script,
+                    "        --{long})\n            return\n            ;;"
+                );
+            }
+            CliValue::None | CliValue::Optional(_, _) => (),
+        }
+    }
+
+    let _ = write!(
+        script,
+        "    esac\n\n    COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n}}\n\ncomplete -F _stalwart_mail stalwart-mail\n",
+        options
+            .iter()
+            .map(|(long, _)| format!("--{long}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+
+    script
+}
+
+fn zsh_completions() -> String {
+    let mut script = String::from("#compdef stalwart-mail\n\n_arguments \\\n");
+
+    for (long, option) in completion_options() {
+        let help = short_help(option)
+            .replace('\'', "'\\''")
+            .replace('[', "\\[")
+            .replace(']', "\\]")
+            .replace(':', "\\:");
+        let action = match option.value {
+            CliValue::None => String::new(),
+            CliValue::Required(name, hint) => format!(":{}:{}", zsh_name(name), zsh_action(hint)),
+            CliValue::Optional(name, hint) => format!("::{}:{}", zsh_name(name), zsh_action(hint)),
+        };
+        let _ = writeln!(script, "  '--{long}[{help}]{action}' \\");
+    }
+    script.push_str("  && return 0\n");
+
+    script
+}
+
+fn zsh_name(name: &str) -> String {
+    name.split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .trim_matches(|ch| matches!(ch, '<' | '>' | '[' | ']'))
+        .replace(':', "\\:")
+        .to_lowercase()
+}
+
+fn zsh_action(hint: CliHint) -> String {
+    match hint {
+        CliHint::Any => " ".to_string(),
+        CliHint::Path => "_files".to_string(),
+        CliHint::Choice(choices) => format!("({})", choices.join(" ")),
+    }
+}
+
+fn fish_completions() -> String {
+    let mut script = String::from("complete -c stalwart-mail -f\n");
+
+    for (long, option) in completion_options() {
+        let help = short_help(option).replace('\'', "\\'");
+        let args = match option.value {
+            CliValue::None | CliValue::Optional(_, _) => String::new(),
+            CliValue::Required(_, CliHint::Any) => " -x".to_string(),
+            CliValue::Required(_, CliHint::Path) => " -r -F".to_string(),
+            CliValue::Required(_, CliHint::Choice(choices)) => {
+                format!(" -x -a '{}'", choices.join(" "))
+            }
+        };
+        let _ = writeln!(
+            script,
+            "complete -c stalwart-mail -l {long}{args} -d '{help}'"
+        );
+    }
+
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_options() {
+        // Option names are unique
+        let mut names = ahash::AHashSet::new();
+        for option in CLI_OPTIONS {
+            assert!(
+                names.insert(option.long),
+                "duplicate option {}",
+                option.long
+            );
+            if let Some(short) = option.short {
+                assert!(names.insert(short), "duplicate option {short}");
+            }
+        }
+
+        // Short names resolve to their long counterpart
+        assert_eq!(canonical_option("c"), "config");
+        assert_eq!(canonical_option("I"), "init");
+        assert_eq!(canonical_option("config"), "config");
+        assert_eq!(canonical_option("unknown"), "unknown");
+        assert_eq!(bind_listener("bind-smtp"), Some("smtp"));
+        assert_eq!(bind_listener("bind-pop3"), None);
+
+        // Every option is parsed with the arity declared in the table
+        let parse = |args: &[&str]| {
+            let mut args = args.iter().map(|arg| arg.to_string()).peekable();
+            let option = next_option(&mut args);
+            (option, args.collect::<Vec<_>>())
+        };
+        for option in CLI_OPTIONS {
+            let long = option.long.replace("<LISTENER>", "smtp");
+            let flag = format!("--{long}");
+            let (parsed, rest) = parse(&[&flag, "value", "next"]);
+            match option.value {
+                CliValue::None => {
+                    assert_eq!(parsed, Some(Ok((long.clone(), None))), "{long}");
+                    assert_eq!(rest, ["value", "next"], "{long}");
+                    assert!(
+                        matches!(parse(&[&format!("{flag}=value")]).0, Some(Err(_))),
+                        "{long}"
+                    );
+                }
+                CliValue::Required(_, _) | CliValue::Optional(_, _) => {
+                    let expected = Some(Ok((long.clone(), Some("value".to_string()))));
+                    assert_eq!(parsed, expected, "{long}");
+                    assert_eq!(rest, ["next"], "{long}");
+                    assert_eq!(parse(&[&format!("{flag}=value")]).0, expected, "{long}");
+                    assert_eq!(
+                        matches!(parse(&[&flag, "--quiet"]).0, Some(Err(_))),
+                        matches!(option.value, CliValue::Required(_, _)),
+                        "{long}"
+                    );
+                }
+            }
+            if let Some(short) = option.short {
+                let (parsed, _) = parse(&[&format!("-{short}"), "value"]);
+                assert_eq!(parsed.unwrap().unwrap().0, option.long);
+            }
+        }
+        assert_eq!(parse(&["-vv"]).0, Some(Ok(("vv".to_string(), None))));
+        assert_eq!(
+            parse(&["-vvv", "value"]).0,
+            Some(Ok(("vvv".to_string(), None)))
+        );
+        assert!(matches!(parse(&["--vv"]).0, Some(Err(_))));
+        assert!(matches!(parse(&["-vv=value"]).0, Some(Err(_))));
+        assert!(matches!(parse(&["--unknown"]).0, Some(Err(_))));
+        assert!(matches!(parse(&["--bind-pop3", "110"]).0, Some(Err(_))));
+        assert_eq!(parse(&["value"]), (None, vec!["value".to_string()]));
+
+        // Sizes
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(44_238_163_149), "41.2 GiB");
+        assert_eq!(format_count(0), "0");
+        assert_eq!(format_count(999), "999");
+        assert_eq!(format_count(1_240_551), "1,240,551");
+
+        // Help lists every option
+        let help = help();
+        for option in CLI_OPTIONS {
+            assert!(help.contains(&format!("--{}", option.long)));
+        }
+        assert!(help.contains("66  Store or I/O failure"));
+
+        // Completions list every option
+        for shell in ["bash", "zsh", "fish"] {
+            let script = completions(shell).unwrap();
+            let words = script
+                .split(|ch: char| ch.is_whitespace() || matches!(ch, '"' | '\'' | '[' | ')'))
+                .collect::<Vec<_>>();
+            for (long, _) in completion_options() {
+                let found = if shell == "fish" {
+                    words.windows(2).any(|w| w[0] == "-l" && w[1] == long)
+                } else {
+                    words.contains(&format!("--{long}").as_str())
+                };
+                assert!(found, "{shell} is missing {long}");
+            }
+        }
+        assert!(completions("tcsh").is_none());
+    }
+}
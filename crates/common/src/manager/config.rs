@@ -41,8 +41,12 @@ use utils::{
 pub struct ConfigManager {
     pub cfg_local: ArcSwap<BTreeMap<String, String>>,
     pub cfg_local_path: PathBuf,
+    /// Directory whose `*.toml` files are merged after the local configuration file.
+    pub cfg_local_dir: Option<PathBuf>,
     pub cfg_local_patterns: Arc<Patterns>,
     pub cfg_store: Store,
+    /// Directory where fetched resources are cached and read from when offline.
+    pub resource_dir: Option<PathBuf>,
 }
 
 #[derive(Default)]
@@ -63,6 +67,7 @@ enum MatchType {
     All,
 }
 
+#[derive(Debug)]
 pub(crate) struct ExternalConfig {
     pub id: String,
     pub version: String,
@@ -124,7 +129,7 @@ impl ConfigManager {
         Ok(results)
     }
 
-    async fn db_list(
+    pub(crate) async fn db_list(
         &self,
         prefix: &str,
         strip_prefix: bool,
@@ -183,12 +188,11 @@ impl ConfigManager {
             }
         }
 
-        if !batch.is_empty() {
-            self.cfg_store.write(batch.build()).await?;
-        }
-
+        // Local changes are applied first so they can be reverted if the store write fails
+        let previous_local = self.cfg_local.load_full();
+        let mut local_updated = false;
         if !local_batch.is_empty() {
-            let mut local = self.cfg_local.load().as_ref().clone();
+            let mut local = previous_local.as_ref().clone();
             let mut has_changes = false;
 
             for key in local_batch {
@@ -206,7 +210,29 @@ impl ConfigManager {
                 }
             }
             if has_changes {
-                self.update_local(local).await?;
+                if let Err(err) = self.update_local(local).await {
+                    self.cfg_local.store(previous_local);
+                    return Err(err);
+                }
+                local_updated = true;
+            }
+        }
+
+        // Store keys are written in a single transaction
+        if !batch.is_empty() {
+            if let Err(err) = self.cfg_store.write(batch.build()).await {
+                if local_updated {
+                    if let Err(rollback_err) =
+                        self.update_local(previous_local.as_ref().clone()).await
+                    {
+                        return Err(store::Error::InternalError(format!(
+                            "Failed to write configuration: {err} (rollback of local changes failed: {rollback_err})"
+                        )));
+                    }
+                }
+                return Err(store::Error::InternalError(format!(
+                    "Failed to write configuration, no changes were applied: {err}"
+                )));
             }
         }
 
@@ -359,7 +385,7 @@ impl ConfigManager {
             keys: Vec::new(),
         };
         for (key, value) in config.keys {
-            if key.starts_with("version.") {
+            if key.starts_with("version.") && !key.ends_with(".pin") {
                 external.id = key.clone();
                 external.version = value.clone();
                 external.keys.push(ConfigKey::from((key, value)));
@@ -382,10 +408,17 @@ impl ConfigManager {
             }
         }
 
-        if !external.version.is_empty() {
-            Ok(external)
-        } else {
-            Err("External configuration file does not contain a version key".to_string())
+        if external.version.is_empty() {
+            return Err("External configuration file does not contain a version key".to_string());
+        }
+
+        // Never move away from a pinned version
+        match self.resource_pin(resource_id).await? {
+            Some(pin) if pin != external.version => Err(format!(
+                "Resource {resource_id} is pinned to version {pin} but version {} was fetched",
+                external.version
+            )),
+            _ => Ok(external),
         }
     }
 }
@@ -491,8 +524,10 @@ impl Clone for ConfigManager {
         Self {
             cfg_local: ArcSwap::from_pointee(self.cfg_local.load().as_ref().clone()),
             cfg_local_path: self.cfg_local_path.clone(),
+            cfg_local_dir: self.cfg_local_dir.clone(),
             cfg_local_patterns: self.cfg_local_patterns.clone(),
             cfg_store: self.cfg_store.clone(),
+            resource_dir: self.resource_dir.clone(),
         }
     }
 }
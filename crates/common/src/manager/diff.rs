@@ -0,0 +1,271 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{cmp::Ordering, collections::BTreeMap, fmt::Display};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::StreamExt;
+use store::blake3;
+
+use super::{
+    backup::{BackupLocation, Family, Op},
+    restore::{backup_files, read_manifest, OpReader, RestoreError, RestoreOptions},
+};
+
+/// Position of a key in a backup, independent of the file it was read from.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DiffKey {
+    pub family: Family,
+    pub account_id: u32,
+    pub collection: u8,
+    pub document_id: u32,
+    pub key: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// Keys that differ between two backups, sorted by family, account,
+/// collection, document and key.
+#[derive(Debug, Default)]
+pub struct BackupDiff {
+    pub changes: Vec<(DiffKind, DiffKey)>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiffSummary {
+    pub added: u64,
+    pub removed: u64,
+    pub changed: u64,
+}
+
+type CanonicalOps = BTreeMap<DiffKey, blake3::Hash>;
+
+/// Compares two backups key by key. Values are compared by their hash, so
+/// only the keys and value hashes of both backups are kept in memory.
+pub async fn diff_backups(
+    old: &BackupLocation,
+    new: &BackupLocation,
+) -> Result<BackupDiff, RestoreError> {
+    Ok(BackupDiff::new(
+        read_canonical_ops(old).await?,
+        read_canonical_ops(new).await?,
+    ))
+}
+
+async fn read_canonical_ops(src: &BackupLocation) -> Result<CanonicalOps, RestoreError> {
+    let manifest = read_manifest(src).await?;
+    let options = RestoreOptions::default();
+    let mut ops = CanonicalOps::new();
+
+    for file in backup_files(src, manifest.as_ref()).await? {
+        let mut reader = OpReader::open(&file, &options).await?;
+        let mut family = Family::None;
+        let mut account_id = 0;
+        let mut collection = 0;
+        let mut document_id = 0;
+
+        while let Some(op) = reader.next().await {
+            match op? {
+                Op::Family(f) => family = f,
+                Op::AccountId(a) => account_id = a,
+                Op::Collection(c) => collection = c,
+                Op::DocumentId(d) => document_id = d,
+                Op::KeyValue((key, value)) => {
+                    ops.insert(
+                        DiffKey {
+                            family,
+                            account_id,
+                            collection,
+                            document_id,
+                            key,
+                        },
+                        blake3::hash(&value),
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(ops)
+}
+
+impl BackupDiff {
+    fn new(old: CanonicalOps, new: CanonicalOps) -> Self {
+        let mut changes = Vec::new();
+        let mut old = old.into_iter().peekable();
+        let mut new = new.into_iter().peekable();
+
+        loop {
+            let order = match (old.peek(), new.peek()) {
+                (Some((old_key, _)), Some((new_key, _))) => old_key.cmp(new_key),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break,
+            };
+            match order {
+                Ordering::Less => changes.push((DiffKind::Removed, old.next().unwrap().0)),
+                Ordering::Greater => changes.push((DiffKind::Added, new.next().unwrap().0)),
+                Ordering::Equal => {
+                    let (key, old_value) = old.next().unwrap();
+                    let (_, new_value) = new.next().unwrap();
+                    if old_value != new_value {
+                        changes.push((DiffKind::Changed, key));
+                    }
+                }
+            }
+        }
+
+        BackupDiff { changes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Number of changes by family and account.
+    pub fn summary(&self) -> BTreeMap<(Family, u32), DiffSummary> {
+        let mut summary: BTreeMap<(Family, u32), DiffSummary> = BTreeMap::new();
+        for (kind, key) in &self.changes {
+            let entry = summary.entry((key.family, key.account_id)).or_default();
+            match kind {
+                DiffKind::Added => entry.added += 1,
+                DiffKind::Removed => entry.removed += 1,
+                DiffKind::Changed => entry.changed += 1,
+            }
+        }
+        summary
+    }
+}
+
+impl Display for DiffKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DiffKind::Added => "+",
+            DiffKind::Removed => "-",
+            DiffKind::Changed => "~",
+        })
+    }
+}
+
+impl Display for DiffKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Ops outside of any account or document, such as blob contents, use the maximum id
+        let id = |id: u32, max: u32| {
+            if id != max {
+                id.to_string()
+            } else {
+                "-".to_string()
+            }
+        };
+        write!(
+            f,
+            "{:?} account={} collection={} document={} key={}",
+            self.family,
+            id(self.account_id, u32::MAX),
+            id(self.collection as u32, u8::MAX as u32),
+            id(self.document_id, u32::MAX),
+            STANDARD.encode(&self.key)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use store::blake3;
+
+    use crate::manager::backup::Family;
+
+    use super::{BackupDiff, CanonicalOps, DiffKey, DiffKind, DiffSummary};
+
+    fn key(family: Family, account_id: u32, key: &[u8]) -> DiffKey {
+        DiffKey {
+            family,
+            account_id,
+            collection: 0,
+            document_id: 0,
+            key: key.to_vec(),
+        }
+    }
+
+    #[test]
+    fn diff_canonical_ops() {
+        let old = CanonicalOps::from_iter([
+            (key(Family::Property, 1, b"a"), blake3::hash(b"1")),
+            (key(Family::Property, 1, b"b"), blake3::hash(b"2")),
+            (key(Family::Property, 2, b"a"), blake3::hash(b"3")),
+            (key(Family::Log, 1, b"a"), blake3::hash(b"4")),
+        ]);
+        let new = CanonicalOps::from_iter([
+            (key(Family::Property, 1, b"a"), blake3::hash(b"1")),
+            (key(Family::Property, 1, b"b"), blake3::hash(b"changed")),
+            (key(Family::Property, 2, b"b"), blake3::hash(b"3")),
+            (key(Family::Log, 1, b"a"), blake3::hash(b"4")),
+            (key(Family::Log, 1, b"b"), blake3::hash(b"5")),
+        ]);
+
+        assert!(BackupDiff::new(old.clone(), old.clone()).is_empty());
+
+        let diff = BackupDiff::new(old, new);
+        assert_eq!(
+            diff.changes,
+            vec![
+                (DiffKind::Changed, key(Family::Property, 1, b"b")),
+                (DiffKind::Removed, key(Family::Property, 2, b"a")),
+                (DiffKind::Added, key(Family::Property, 2, b"b")),
+                (DiffKind::Added, key(Family::Log, 1, b"b")),
+            ]
+        );
+        assert_eq!(
+            diff.summary().into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    (Family::Property, 1),
+                    DiffSummary {
+                        changed: 1,
+                        ..Default::default()
+                    }
+                ),
+                (
+                    (Family::Property, 2),
+                    DiffSummary {
+                        added: 1,
+                        removed: 1,
+                        ..Default::default()
+                    }
+                ),
+                (
+                    (Family::Log, 1),
+                    DiffSummary {
+                        added: 1,
+                        ..Default::default()
+                    }
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,324 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use ahash::AHashMap;
+use directory::backend::internal::manage::ManageDirectory;
+use jmap_proto::{
+    object::Object,
+    types::{collection::Collection, keyword::Keyword, property::Property, value::Value},
+};
+use store::{
+    write::{key::DeserializeBigEndian, BlobOp, ValueClass},
+    BitmapKey, IndexKey, IterateParams, ValueKey, U32_LEN, U64_LEN,
+};
+use utils::{BlobHash, BLOB_HASH_LEN};
+
+use crate::Core;
+
+use super::backup::{DeserializeBytes, KEY_OFFSET};
+
+pub const INBOX_NAME: &str = "INBOX";
+
+#[derive(Debug, Default)]
+pub struct MaildirStats {
+    /// Number of messages written to each folder, by folder name.
+    pub folders: Vec<(String, u64)>,
+    pub missing_blobs: u64,
+    /// Messages that could not be imported.
+    pub failed: u64,
+}
+
+impl Core {
+    /// Writes the mailboxes of `account` to `path` as a Maildir++ tree: the inbox
+    /// is the root folder and every other mailbox is a `.Parent.Child` folder.
+    pub async fn export_maildir(&self, account: &str, path: &Path) -> Result<MaildirStats, String> {
+        let account_id = self.maildir_account_id(account).await?;
+        let store = &self.storage.data;
+
+        // Obtain mailboxes
+        let mut mailboxes = AHashMap::new();
+        for mailbox_id in store
+            .get_bitmap(BitmapKey::document_ids(account_id, Collection::Mailbox))
+            .await
+            .map_err(|err| format!("Failed to obtain mailbox ids: {err}"))?
+            .unwrap_or_default()
+        {
+            if let Some(mailbox) = store
+                .get_value::<Object<Value>>(ValueKey::<ValueClass>::property(
+                    account_id,
+                    Collection::Mailbox,
+                    mailbox_id,
+                    Property::Value,
+                ))
+                .await
+                .map_err(|err| format!("Failed to obtain mailbox {mailbox_id}: {err}"))?
+            {
+                mailboxes.insert(mailbox_id, mailbox);
+            }
+        }
+        let mut folders = mailboxes
+            .keys()
+            .map(|mailbox_id| (*mailbox_id, folder_name(*mailbox_id, &mailboxes)))
+            .collect::<Vec<_>>();
+        folders.sort_by(|a, b| a.1.cmp(&b.1));
+
+        // Obtain message blobs and received dates
+        let blobs = self.email_blobs(account_id).await?;
+        let received_at = self.email_received_at(account_id).await?;
+
+        let mut stats = MaildirStats::default();
+        for (mailbox_id, folder) in folders {
+            let folder_path = folder_path(path, &folder);
+            for dir in ["cur", "new", "tmp"] {
+                std::fs::create_dir_all(folder_path.join(dir))
+                    .map_err(|err| format!("Failed to create {}: {err}", folder_path.display()))?;
+            }
+
+            let mut count = 0;
+            for document_id in store
+                .get_bitmap(BitmapKey::tag(
+                    account_id,
+                    Collection::Email,
+                    Property::MailboxIds,
+                    mailbox_id,
+                ))
+                .await
+                .map_err(|err| format!("Failed to obtain messages of {folder:?}: {err}"))?
+                .unwrap_or_default()
+            {
+                let contents = match blobs.get(&document_id) {
+                    Some(hash) => self
+                        .storage
+                        .blob
+                        .get_blob(hash.as_ref(), 0..usize::MAX)
+                        .await
+                        .map_err(|err| format!("Failed to read blob {hash:?}: {err}"))?,
+                    None => None,
+                };
+                let Some(contents) = contents else {
+                    eprintln!("⚠️ Skipping message {document_id} in {folder:?}: blob not found.");
+                    stats.missing_blobs += 1;
+                    continue;
+                };
+                let keywords = store
+                    .get_value::<Vec<Keyword>>(ValueKey::<ValueClass>::property(
+                        account_id,
+                        Collection::Email,
+                        document_id,
+                        Property::Keywords,
+                    ))
+                    .await
+                    .map_err(|err| format!("Failed to obtain keywords of {document_id}: {err}"))?
+                    .unwrap_or_default();
+                let received_at = received_at.get(&document_id).copied().unwrap_or_default();
+
+                let file_path = folder_path.join("cur").join(format!(
+                    "{received_at}.{account_id}_{document_id}.stalwart,S={}:2,{}",
+                    contents.len(),
+                    maildir_flags(&keywords)
+                ));
+                std::fs::write(&file_path, &contents)
+                    .and_then(|_| std::fs::File::options().write(true).open(&file_path))
+                    .and_then(|file| {
+                        file.set_modified(UNIX_EPOCH + Duration::from_secs(received_at))
+                    })
+                    .map_err(|err| format!("Failed to write {}: {err}", file_path.display()))?;
+                count += 1;
+            }
+
+            stats.folders.push((folder, count));
+        }
+
+        Ok(stats)
+    }
+
+    pub async fn maildir_account_id(&self, account: &str) -> Result<u32, String> {
+        match self
+            .storage
+            .data
+            .get_account_id(account)
+            .await
+            .map_err(|err| format!("Failed to obtain account id: {err:?}"))?
+        {
+            Some(account_id) => Ok(account_id),
+            None => account
+                .parse()
+                .map_err(|_| format!("Account {account:?} not found.")),
+        }
+    }
+
+    async fn email_blobs(&self, account_id: u32) -> Result<AHashMap<u32, BlobHash>, String> {
+        let mut blobs = AHashMap::new();
+        let collection = u8::from(Collection::Email);
+
+        self.storage
+            .data
+            .iterate(
+                IterateParams::new(
+                    ValueKey {
+                        account_id: 0,
+                        collection: 0,
+                        document_id: 0,
+                        class: ValueClass::Blob(BlobOp::Link {
+                            hash: Default::default(),
+                        }),
+                    },
+                    ValueKey {
+                        account_id: u32::MAX,
+                        collection: u8::MAX,
+                        document_id: u32::MAX,
+                        class: ValueClass::Blob(BlobOp::Link {
+                            hash: BlobHash::new_max(),
+                        }),
+                    },
+                )
+                .no_values(),
+                |key, _| {
+                    if key.deserialize_be_u32(KEY_OFFSET + BLOB_HASH_LEN)? == account_id
+                        && key.deserialize_u8(KEY_OFFSET + BLOB_HASH_LEN + U32_LEN)? == collection
+                    {
+                        let document_id =
+                            key.deserialize_be_u32(KEY_OFFSET + BLOB_HASH_LEN + U32_LEN + 1)?;
+                        if let Ok(hash) = BlobHash::try_from_hash_slice(
+                            key.range(KEY_OFFSET..KEY_OFFSET + BLOB_HASH_LEN)?,
+                        ) {
+                            blobs.insert(document_id, hash);
+                        }
+                    }
+
+                    Ok(true)
+                },
+            )
+            .await
+            .map_err(|err| format!("Failed to obtain message blobs: {err}"))?;
+
+        Ok(blobs)
+    }
+
+    async fn email_received_at(&self, account_id: u32) -> Result<AHashMap<u32, u64>, String> {
+        let mut received_at = AHashMap::new();
+
+        self.storage
+            .data
+            .iterate(
+                IterateParams::new(
+                    IndexKey {
+                        account_id,
+                        collection: Collection::Email.into(),
+                        document_id: 0,
+                        field: Property::ReceivedAt.into(),
+                        key: 0u64.to_be_bytes().to_vec(),
+                    },
+                    IndexKey {
+                        account_id,
+                        collection: Collection::Email.into(),
+                        document_id: u32::MAX,
+                        field: Property::ReceivedAt.into(),
+                        key: u64::MAX.to_be_bytes().to_vec(),
+                    },
+                )
+                .no_values(),
+                |key, _| {
+                    received_at.insert(
+                        key.deserialize_be_u32(key.len() - U32_LEN)?,
+                        key.deserialize_be_u64(key.len() - U32_LEN - U64_LEN)?,
+                    );
+
+                    Ok(true)
+                },
+            )
+            .await
+            .map_err(|err| format!("Failed to obtain received dates: {err}"))?;
+
+        Ok(received_at)
+    }
+}
+
+pub fn print_maildir_report(stats: &MaildirStats) {
+    for (folder, count) in &stats.folders {
+        eprintln!("{folder}: {count} messages");
+    }
+    if stats.missing_blobs > 0 {
+        eprintln!(
+            "⚠️ {} messages were skipped because their blob is missing.",
+            stats.missing_blobs
+        );
+    }
+    if stats.failed > 0 {
+        eprintln!("⚠️ {} messages could not be imported.", stats.failed);
+    }
+}
+
+/// Builds the Maildir++ folder name of a mailbox from its ancestors, with
+/// the hierarchy separator in mailbox names replaced by an underscore.
+fn folder_name(mailbox_id: u32, mailboxes: &AHashMap<u32, Object<Value>>) -> String {
+    let mut names = Vec::new();
+    let mut next_id = Some(mailbox_id);
+
+    while let Some(mailbox) = next_id
+        .and_then(|mailbox_id| mailboxes.get(&mailbox_id))
+        .filter(|_| names.len() < mailboxes.len())
+    {
+        if matches!(mailbox.get(&Property::Role), Value::Text(role) if role == "inbox") {
+            names.push(INBOX_NAME.to_string());
+        } else if let Value::Text(name) = mailbox.get(&Property::Name) {
+            names.push(name.replace(['.', '/'], "_"));
+        }
+        next_id = match mailbox.get(&Property::ParentId) {
+            Value::Id(parent_id) if parent_id.id() > 0 => Some((parent_id.id() - 1) as u32),
+            _ => None,
+        };
+    }
+
+    names.reverse();
+    names.join(".")
+}
+
+fn folder_path(path: &Path, folder: &str) -> PathBuf {
+    if folder == INBOX_NAME {
+        path.to_path_buf()
+    } else {
+        path.join(format!(".{folder}"))
+    }
+}
+
+/// Maps keywords to Maildir info flags, which must be listed in ASCII order.
+fn maildir_flags(keywords: &[Keyword]) -> String {
+    [
+        (Keyword::Draft, 'D'),
+        (Keyword::Flagged, 'F'),
+        (Keyword::Forwarded, 'P'),
+        (Keyword::Answered, 'R'),
+        (Keyword::Seen, 'S'),
+        (Keyword::Deleted, 'T'),
+    ]
+    .into_iter()
+    .filter(|(keyword, _)| keywords.contains(keyword))
+    .map(|(_, flag)| flag)
+    .collect()
+}
@@ -0,0 +1,262 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::task::JoinHandle;
+
+use crate::USER_AGENT;
+
+use super::backup::Family;
+
+const FAMILIES: [(Family, &str); 12] = [
+    (Family::Property, "property"),
+    (Family::TermIndex, "term_index"),
+    (Family::Acl, "acl"),
+    (Family::Blob, "blob"),
+    (Family::Config, "config"),
+    (Family::LookupValue, "lookup_value"),
+    (Family::LookupCounter, "lookup_counter"),
+    (Family::Directory, "directory"),
+    (Family::Queue, "queue"),
+    (Family::Index, "index"),
+    (Family::Bitmap, "bitmap"),
+    (Family::Log, "log"),
+];
+const PUSH_INTERVAL: Duration = Duration::from_secs(15);
+const NO_ACCOUNT: u64 = u32::MAX as u64;
+
+pub static BACKUP_METRICS: JobMetrics = JobMetrics::new("backup");
+pub static RESTORE_METRICS: JobMetrics = JobMetrics::new("restore");
+
+/// Progress of a backup or restore job, rendered in the Prometheus text format
+/// and pushed to a Pushgateway while the job runs.
+pub struct JobMetrics {
+    job: &'static str,
+    ops: [AtomicU64; FAMILIES.len()],
+    bytes: AtomicU64,
+    blobs: AtomicU64,
+    batches: AtomicU64,
+    orphan_blobs: AtomicU64,
+    orphan_bytes: AtomicU64,
+    account_id: AtomicU64,
+}
+
+pub struct MetricsPusher {
+    metrics: &'static JobMetrics,
+    url: String,
+    task: JoinHandle<()>,
+}
+
+impl JobMetrics {
+    const fn new(job: &'static str) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+
+        JobMetrics {
+            job,
+            ops: [ZERO; FAMILIES.len()],
+            bytes: ZERO,
+            blobs: ZERO,
+            batches: ZERO,
+            orphan_blobs: ZERO,
+            orphan_bytes: ZERO,
+            account_id: AtomicU64::new(NO_ACCOUNT),
+        }
+    }
+
+    pub fn op(&self, family: Family, bytes: usize) {
+        if let Some(ops) = self.ops.get(family as usize) {
+            ops.fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn blob(&self) {
+        self.blobs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn batch(&self) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of batches written so far.
+    pub fn batches(&self) -> u64 {
+        self.batches.load(Ordering::Relaxed)
+    }
+
+    /// Counts a blob left out of the backup as no document links to it.
+    pub fn orphan_blob(&self, bytes: usize) {
+        self.orphan_blobs.fetch_add(1, Ordering::Relaxed);
+        self.orphan_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Number of orphaned blobs skipped so far and their size in bytes.
+    pub fn orphans(&self) -> (u64, u64) {
+        (
+            self.orphan_blobs.load(Ordering::Relaxed),
+            self.orphan_bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn account(&self, account_id: u32) {
+        self.account_id.store(account_id as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let job = self.job;
+        let mut out = String::with_capacity(1024);
+
+        let _ = writeln!(out, "# TYPE stalwart_{job}_ops_total counter");
+        for ((_, label), ops) in FAMILIES.iter().zip(&self.ops) {
+            let _ = writeln!(
+                out,
+                "stalwart_{job}_ops_total{{family=\"{label}\"}} {}",
+                ops.load(Ordering::Relaxed)
+            );
+        }
+        for (name, value) in [
+            ("bytes_total", &self.bytes),
+            ("blobs_written_total", &self.blobs),
+            ("batches_flushed_total", &self.batches),
+            ("orphan_blobs_skipped_total", &self.orphan_blobs),
+            ("orphan_bytes_skipped_total", &self.orphan_bytes),
+        ] {
+            let _ = writeln!(out, "# TYPE stalwart_{job}_{name} counter");
+            let _ = writeln!(
+                out,
+                "stalwart_{job}_{name} {}",
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        // Accounts are only reported once the job reaches one
+        let account_id = self.account_id.load(Ordering::Relaxed);
+        if account_id != NO_ACCOUNT {
+            let _ = writeln!(out, "# TYPE stalwart_{job}_current_account gauge");
+            let _ = writeln!(out, "stalwart_{job}_current_account {account_id}");
+        }
+
+        out
+    }
+
+    /// Pushes the metrics to the Pushgateway at `url` periodically, until
+    /// the returned pusher is finished.
+    pub fn push_to(&'static self, url: &str) -> MetricsPusher {
+        let url = format!(
+            "{}/metrics/job/stalwart_{}",
+            url.trim_end_matches('/'),
+            self.job
+        );
+        let task = tokio::spawn({
+            let url = url.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(PUSH_INTERVAL).await;
+                    if let Err(err) = self.push(&url).await {
+                        tracing::warn!(
+                            context = "metrics",
+                            event = "error",
+                            url = url,
+                            reason = err,
+                            "Failed to push metrics"
+                        );
+                    }
+                }
+            }
+        });
+
+        MetricsPusher {
+            metrics: self,
+            url,
+            task,
+        }
+    }
+
+    async fn push(&self, url: &str) -> Result<(), String> {
+        reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .user_agent(USER_AGENT)
+            .build()
+            .unwrap_or_default()
+            .put(url)
+            .body(self.render())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl MetricsPusher {
+    /// Stops the periodic pushes and pushes the final values.
+    pub async fn finish(self) {
+        self.task.abort();
+        if let Err(err) = self.metrics.push(&self.url).await {
+            eprintln!("⚠️ Failed to push metrics to {}: {err}", self.url);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manager::backup::Family;
+
+    use super::JobMetrics;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = JobMetrics::new("restore");
+        assert!(!metrics.render().contains("current_account"));
+
+        metrics.op(Family::Bitmap, 10);
+        metrics.op(Family::Bitmap, 5);
+        metrics.op(Family::Blob, 100);
+        metrics.op(Family::None, 1);
+        metrics.blob();
+        metrics.batch();
+        metrics.orphan_blob(64);
+        metrics.account(42);
+
+        let rendered = metrics.render();
+        for line in [
+            "stalwart_restore_ops_total{family=\"bitmap\"} 2",
+            "stalwart_restore_ops_total{family=\"blob\"} 1",
+            "stalwart_restore_ops_total{family=\"term_index\"} 0",
+            "stalwart_restore_bytes_total 116",
+            "stalwart_restore_blobs_written_total 1",
+            "stalwart_restore_batches_flushed_total 1",
+            "stalwart_restore_orphan_blobs_skipped_total 1",
+            "stalwart_restore_orphan_bytes_skipped_total 64",
+            "# TYPE stalwart_restore_current_account gauge",
+            "stalwart_restore_current_account 42",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "{line}\n{rendered}");
+        }
+    }
+}
@@ -0,0 +1,285 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use crate::Core;
+
+use super::{
+    backup::{BackupError, BackupFn, BackupManifest, Family, ManifestBuilder, Op, FILE_VERSION},
+    restore::{
+        op_channel, restore_ops, BlobDedup, MemoryBudget, OpContext, OpSource, ReadPosition,
+        RestoreError, RestoreOptions, RestoreShared, RestoreStats,
+    },
+};
+
+impl Core {
+    /// Copies the contents of this server's stores into the stores of `dest`,
+    /// streaming the backup straight into the restore without staging it on disk.
+    ///
+    /// Migrations have no checkpoints: the source is a live store whose
+    /// contents can change between two runs, so there is no position to
+    /// resume from. An interrupted migration is started over into an empty
+    /// destination instead, which is why a destination that already has
+    /// accounts is refused unless merging.
+    pub async fn migrate(
+        &self,
+        dest: &Core,
+        options: RestoreOptions,
+    ) -> Result<RestoreStats, RestoreError> {
+        if options.resume {
+            return Err(RestoreError::new(
+                "migration",
+                0,
+                Family::None,
+                "Migrations can't be resumed, empty the destination and migrate again",
+            ));
+        }
+        dest.check_target_store("migration", &options).await?;
+
+        // Spawn a backup and a restore task for each family
+        let mut tasks = Vec::new();
+        let (memory, read_ahead) = MemoryBudget::split(options.memory_budget);
+        let shared = RestoreShared {
+            dedup: BlobDedup::new(options.blob_dedup_limit),
+            memory,
+            read_ahead,
+            ..Default::default()
+        };
+        let blob_store_ids = self.blob_store_ids();
+        let blob_stores = dest.restore_blob_stores(
+            blob_store_ids
+                .iter()
+                .enumerate()
+                .map(|(document_id, id)| (document_id as u32, id.clone())),
+            &options,
+        );
+        for (name, backup_fn) in Self::backup_families() {
+            let (bridge, source) = self.spawn_source(
+                name,
+                backup_fn,
+                options.batch_size,
+                shared.read_ahead.read_ahead(options.batch_bytes).await,
+                blob_store_ids.clone(),
+            );
+            let store = dest.storage.data.clone();
+            let blob_stores = blob_stores.clone();
+            let options = options.clone();
+            let shared = shared.clone();
+            tasks.push((
+                name,
+                bridge,
+                tokio::spawn(async move {
+                    restore_ops(
+                        store,
+                        blob_stores,
+                        source,
+                        &options,
+                        &BTreeMap::new(),
+                        &shared,
+                    )
+                    .await
+                }),
+            ));
+        }
+
+        let mut manifest = BackupManifest {
+            version: FILE_VERSION,
+            created: store::write::now(),
+            ..Default::default()
+        };
+        let mut stats = RestoreStats::default();
+        for (name, bridge, task) in tasks {
+            stats.merge(
+                task.await
+                    .map_err(|err| RestoreError::new(name, 0, Family::None, err))??,
+            );
+            manifest.merge(
+                bridge
+                    .await
+                    .map_err(|err| RestoreError::new(name, 0, Family::None, err))?
+                    .map_err(|err| RestoreError::new(name, 0, Family::None, err))?,
+            );
+        }
+
+        dest.check_blob_links("migration", &mut stats, &options)
+            .await?;
+        dest.check_acl_grants("migration", &mut stats, &options)
+            .await?;
+        dest.check_directory_refs("migration", &mut stats, &options)
+            .await?;
+
+        if options.recompute_quota && !options.dry_run {
+            dest.write_recomputed_quotas(&stats)
+                .await
+                .map_err(|err| RestoreError::new("migration", 0, Family::Directory, err))?;
+        }
+
+        if options.verify && !options.dry_run {
+            dest.verify_restore(&mut stats)
+                .await
+                .map_err(|err| RestoreError::new("migration", 0, Family::None, err))?;
+        }
+
+        // Make sure all documents made it to the destination
+        if !options.dry_run && options.restores_family(Family::Property) {
+            let expected =
+                document_counts(&manifest, |account_id| options.remap_account_id(account_id));
+            let found = dest
+                .inventory()
+                .await
+                .map_err(|err| RestoreError::new("migration", 0, Family::Property, err))?;
+            let found = document_counts(&found, |account_id| account_id);
+
+            for key in expected.keys().chain(found.keys()).collect::<BTreeSet<_>>() {
+                let (account_id, collection) = key;
+                let expected = expected.get(key).copied().unwrap_or_default();
+                let found = found.get(key).copied().unwrap_or_default();
+                if expected != found {
+                    stats.errors.push(format!(
+                        "Account {account_id} has {found} {collection} documents \
+                         in the destination, expected {expected}"
+                    ));
+                }
+            }
+        }
+        stats.manifest = Some(manifest);
+
+        Ok(stats)
+    }
+
+    pub(super) fn spawn_source(
+        &self,
+        name: &'static str,
+        backup_fn: BackupFn,
+        capacity: usize,
+        read_ahead: MemoryBudget,
+        blob_store_ids: Arc<[String]>,
+    ) -> (
+        tokio::task::JoinHandle<Result<BackupManifest, BackupError>>,
+        OpSource,
+    ) {
+        let (writer, rx) = std::sync::mpsc::sync_channel(capacity);
+        let backup = backup_fn(self, writer);
+        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        let task = tokio::spawn(async move {
+            let _ = result_tx.send(backup.await);
+        });
+        let (tx, ops) = op_channel(capacity, read_ahead);
+
+        // Forward the ops from the backup writer to the restore
+        let bridge = tokio::task::spawn_blocking(move || {
+            let mut manifest = ManifestBuilder::new(blob_store_ids);
+            let mut family = Family::None;
+            let mut context = OpContext::default();
+            let mut num_ops = 0;
+            let mut is_closed = false;
+
+            while let Ok(op) = rx.recv() {
+                manifest.track(&op);
+
+                // Keep draining the backup if the restore stopped early
+                if !is_closed {
+                    if let Op::Family(f) = &op {
+                        family = *f;
+                    }
+                    context.track(&op);
+                    num_ops += 1;
+                    let position = ReadPosition {
+                        family,
+                        offset: num_ops,
+                        op_offset: num_ops - 1,
+                        num_ops,
+                        context,
+                        resynced: false,
+                    };
+                    is_closed = tx.blocking_send(Ok((op, position))).is_err();
+                }
+            }
+
+            // The writer is dropped once the backup is done, a failed backup
+            // fails the restore after the ops it sent. Aborted backups stopped
+            // along with the restore.
+            if let Ok(Err(err)) = result_rx.blocking_recv() {
+                return Err(err);
+            }
+            Ok(manifest.manifest)
+        });
+
+        (
+            bridge,
+            OpSource {
+                name: name.to_string(),
+                version: FILE_VERSION,
+                progress: None,
+                resume_from: None,
+                position: ReadPosition {
+                    family: Family::None,
+                    offset: 0,
+                    op_offset: 0,
+                    num_ops: 0,
+                    context: OpContext::default(),
+                    resynced: false,
+                },
+                ops,
+                task,
+            },
+        )
+    }
+
+    /// Inventory of the documents currently in the data store.
+    async fn inventory(&self) -> Result<BackupManifest, BackupError> {
+        // Documents are counted from their properties
+        let (name, backup_fn) = Self::backup_families()[0];
+        let (bridge, mut source) = self.spawn_source(
+            name,
+            backup_fn,
+            1024,
+            MemoryBudget::default(),
+            self.blob_store_ids(),
+        );
+        while source.ops.recv().await.is_some() {}
+        source.task.await.map_err(BackupError::task)?;
+        bridge.await.map_err(BackupError::task)?
+    }
+}
+
+fn document_counts(
+    manifest: &BackupManifest,
+    remap: impl Fn(u32) -> u32,
+) -> BTreeMap<(u32, String), u64> {
+    let mut counts = BTreeMap::new();
+    for (account_id, collections) in &manifest.accounts {
+        for (collection, documents) in collections {
+            if *documents > 0 {
+                *counts
+                    .entry((remap(*account_id), collection.clone()))
+                    .or_default() += documents;
+            }
+        }
+    }
+    counts
+}
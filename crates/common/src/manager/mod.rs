@@ -21,7 +21,15 @@
  * for more details.
 */
 
-use std::time::Duration;
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use sha2::{Digest, Sha256};
 
 use crate::USER_AGENT;
 
@@ -29,36 +37,263 @@ use self::config::ConfigManager;
 
 pub mod backup;
 pub mod boot;
+pub mod cli;
 pub mod config;
+pub mod diff;
+pub mod maildir;
+pub mod metrics;
+pub mod migrate;
+pub mod password;
+pub mod progress;
 pub mod reload;
 pub mod restore;
+pub mod retention;
+pub mod schedule;
+pub mod upgrade;
 pub mod webadmin;
 
 const DEFAULT_SPAMFILTER_URL: &str = "https://get.stalw.art/resources/config/spamfilter.toml";
 const DEFAULT_WEBADMIN_URL: &str =
     "https://github.com/stalwartlabs/webadmin/releases/latest/download/webadmin.zip";
+const DEFAULT_WEBADMIN_PINNED_URL: &str =
+    "https://github.com/stalwartlabs/webadmin/releases/download/v{version}/webadmin.zip";
+const DEFAULT_RESOURCE_DIR: &str = "resources";
 pub const WEBADMIN_KEY: &[u8] = "STALWART_WEBADMIN".as_bytes();
 
 impl ConfigManager {
     pub async fn fetch_resource(&self, resource_id: &str) -> Result<Vec<u8>, String> {
-        if let Some(url) = self
+        let pin = self.resource_pin(resource_id).await?;
+        let url = if let Some(url) = self
             .get(&format!("config.resource.{resource_id}"))
             .await
             .map_err(|err| {
                 format!("Failed to fetch configuration key 'resource.{resource_id}': {err}",)
+            })? {
+            url
+        } else {
+            match (resource_id, &pin) {
+                ("spam-filter", _) => DEFAULT_SPAMFILTER_URL.to_string(),
+                ("webadmin", None) => DEFAULT_WEBADMIN_URL.to_string(),
+                ("webadmin", Some(_)) => DEFAULT_WEBADMIN_PINNED_URL.to_string(),
+                _ => return Err(format!("Unknown resource: {resource_id}")),
+            }
+        };
+
+        // Pinned resources are fetched from a versioned URL when one is configured
+        let url = match &pin {
+            Some(version) => url.replace("{version}", version),
+            None => url,
+        };
+        let integrity = self.resource_integrity(resource_id).await?;
+        if url.starts_with("file://") {
+            let bytes = fetch_resource(&url).await?;
+            let signature = integrity.fetch_signature(&url).await?;
+            integrity.verify(resource_id, &bytes, signature.as_deref())?;
+            return Ok(bytes);
+        }
+
+        // Resources are cached under the name of the file they were downloaded from
+        let cache_path = self.resource_dir().map(|dir| {
+            dir.join(
+                url.rsplit('/')
+                    .next()
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or(resource_id),
+            )
+        });
+
+        match fetch_resource(&url).await {
+            Ok(bytes) => {
+                let signature = integrity.fetch_signature(&url).await?;
+                integrity.verify(resource_id, &bytes, signature.as_deref())?;
+                tracing::info!(
+                    context = "config",
+                    event = "fetch",
+                    resource_id = resource_id,
+                    source = "network",
+                    url = url,
+                    "Fetched resource from network"
+                );
+                if let Some(cache_path) = &cache_path {
+                    let mut result = write_cache(cache_path, &bytes).await;
+                    if let (Ok(_), Some(signature)) = (&result, &signature) {
+                        result = write_cache(&signature_path(cache_path), signature).await;
+                    }
+                    if let Err(err) = result {
+                        tracing::warn!(
+                            context = "config",
+                            event = "error",
+                            resource_id = resource_id,
+                            path = %cache_path.display(),
+                            reason = %err,
+                            "Failed to cache resource"
+                        );
+                    }
+                }
+                Ok(bytes)
+            }
+            Err(err) => match &cache_path {
+                Some(cache_path) => match tokio::fs::read(cache_path).await {
+                    Ok(bytes) => {
+                        let signature = if integrity.public_key.is_some() {
+                            tokio::fs::read(signature_path(cache_path)).await.ok()
+                        } else {
+                            None
+                        };
+                        integrity.verify(resource_id, &bytes, signature.as_deref())?;
+                        tracing::warn!(
+                            context = "config",
+                            event = "fetch",
+                            resource_id = resource_id,
+                            source = "cache",
+                            path = %cache_path.display(),
+                            reason = %err,
+                            "Fetched resource from cache"
+                        );
+                        Ok(bytes)
+                    }
+                    Err(_) => Err(format!(
+                        "{err} (no cached copy found at {})",
+                        cache_path.display()
+                    )),
+                },
+                None => Err(err),
+            },
+        }
+    }
+
+    /// Returns the version a resource is pinned to with 'version.<id>.pin'.
+    pub async fn resource_pin(&self, resource_id: &str) -> Result<Option<String>, String> {
+        self.get(&format!("version.{resource_id}.pin"))
+            .await
+            .map(|pin| pin.filter(|pin| !pin.is_empty()))
+            .map_err(|err| {
+                format!("Failed to fetch configuration key 'version.{resource_id}.pin': {err}")
+            })
+    }
+
+    async fn resource_integrity(&self, resource_id: &str) -> Result<ResourceIntegrity, String> {
+        let public_key = self
+            .get("config.resource-key")
+            .await
+            .map_err(|err| format!("Failed to fetch configuration key 'resource-key': {err}"))?
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                STANDARD
+                    .decode(key.trim())
+                    .map_err(|err| format!("Invalid resource signing key: {err}"))
+            })
+            .transpose()?;
+        let checksum = self
+            .get(&format!("config.resource-checksum.{resource_id}"))
+            .await
+            .map_err(|err| {
+                format!(
+                    "Failed to fetch configuration key 'resource-checksum.{resource_id}': {err}"
+                )
             })?
-        {
-            fetch_resource(&url).await
+            .filter(|checksum| !checksum.is_empty());
+
+        Ok(ResourceIntegrity {
+            public_key,
+            checksum,
+        })
+    }
+
+    /// Returns the directory where fetched resources are cached, which is the
+    /// 'resources' directory next to the local configuration file by default.
+    pub fn resource_dir(&self) -> Option<PathBuf> {
+        self.resource_dir.clone().or_else(|| {
+            self.cfg_local_path
+                .parent()
+                .map(|parent| parent.join(DEFAULT_RESOURCE_DIR))
+        })
+    }
+}
+
+/// Pinned checksum and signing key that fetched resources must match.
+struct ResourceIntegrity {
+    public_key: Option<Vec<u8>>,
+    checksum: Option<String>,
+}
+
+impl ResourceIntegrity {
+    /// Fetches the detached signature published next to a resource.
+    async fn fetch_signature(&self, url: &str) -> Result<Option<Vec<u8>>, String> {
+        if self.public_key.is_some() {
+            fetch_resource(&format!("{url}.sig")).await.map(Some)
         } else {
-            match resource_id {
-                "spam-filter" => fetch_resource(DEFAULT_SPAMFILTER_URL).await,
-                "webadmin" => fetch_resource(DEFAULT_WEBADMIN_URL).await,
-                _ => Err(format!("Unknown resource: {resource_id}")),
+            Ok(None)
+        }
+    }
+
+    fn verify(
+        &self,
+        resource_id: &str,
+        bytes: &[u8],
+        signature: Option<&[u8]>,
+    ) -> Result<(), String> {
+        if let Some(checksum) = &self.checksum {
+            let digest = sha256_hex(bytes);
+            if !digest.eq_ignore_ascii_case(checksum.trim()) {
+                return Err(format!(
+                    "Checksum mismatch for resource {resource_id}: expected {checksum}, found {digest}"
+                ));
             }
         }
+
+        if let Some(public_key) = &self.public_key {
+            let signature = signature
+                .and_then(|signature| std::str::from_utf8(signature).ok())
+                .and_then(|signature| STANDARD.decode(signature.trim()).ok())
+                .ok_or_else(|| {
+                    format!("Missing or invalid signature for resource {resource_id}")
+                })?;
+            UnparsedPublicKey::new(&ED25519, public_key)
+                .verify(bytes, &signature)
+                .map_err(|_| format!("Signature verification failed for resource {resource_id}"))?;
+        }
+
+        if self.checksum.is_none() && self.public_key.is_none() {
+            tracing::warn!(
+                context = "config",
+                event = "fetch",
+                resource_id = resource_id,
+                "Resource was not verified, set 'config.resource-key' or 'config.resource-checksum.{}' to verify it",
+                resource_id
+            );
+        }
+
+        Ok(())
     }
 }
 
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .fold(String::with_capacity(64), |mut digest, byte| {
+            let _ = write!(digest, "{byte:02x}");
+            digest
+        })
+}
+
+fn signature_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".sig");
+    PathBuf::from(path)
+}
+
+async fn write_cache(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    // Write to a temporary file first so that a partial download never replaces a good copy
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, bytes).await?;
+    tokio::fs::rename(&tmp_path, path).await
+}
+
 async fn fetch_resource(url: &str) -> Result<Vec<u8>, String> {
     if let Some(path) = url.strip_prefix("file://") {
         tokio::fs::read(path)
@@ -80,3 +315,333 @@ async fn fetch_resource(url: &str) -> Result<Vec<u8>, String> {
             .map(|bytes| bytes.to_vec())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use arc_swap::ArcSwap;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+    use sha2::{Digest, Sha256};
+
+    use utils::config::Config;
+
+    use store::{backend::fs::FsStore, BlobStore};
+
+    use super::{
+        config::{ConfigManager, Patterns},
+        sha256_hex, signature_path,
+        webadmin::WEBADMIN_VERSION_KEY,
+        WEBADMIN_KEY,
+    };
+
+    #[tokio::test]
+    async fn fetch_resource_cache() {
+        let dir = std::env::temp_dir().join("stalwart_resource_cache_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let source = dir.join("source.toml");
+        let cache_dir = dir.join("cache");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&source, "version.spam-filter = \"1.0\"\n").unwrap();
+
+        let manager = |url: String| ConfigManager {
+            cfg_local: ArcSwap::from_pointee(BTreeMap::from([
+                ("config.resource.spam-filter".to_string(), url),
+                ("config.resource-key".to_string(), String::new()),
+                (
+                    "config.resource-checksum.spam-filter".to_string(),
+                    String::new(),
+                ),
+                ("version.spam-filter.pin".to_string(), String::new()),
+            ])),
+            resource_dir: Some(cache_dir.clone()),
+            ..Default::default()
+        };
+
+        // Nothing is cached yet
+        let offline = manager("http://127.0.0.1:1/spamfilter.toml".to_string());
+        assert!(offline
+            .fetch_resource("spam-filter")
+            .await
+            .unwrap_err()
+            .contains("no cached copy"));
+
+        // Pre-downloaded bundles are used when the network is unavailable
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::copy(&source, cache_dir.join("spamfilter.toml")).unwrap();
+        assert_eq!(
+            offline.fetch_resource("spam-filter").await.unwrap(),
+            std::fs::read(&source).unwrap()
+        );
+
+        // Local files are read directly and never cached
+        let local = manager(format!("file://{}", source.display()));
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+        assert_eq!(
+            local.fetch_resource("spam-filter").await.unwrap(),
+            std::fs::read(&source).unwrap()
+        );
+        assert!(!cache_dir.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetch_resource_verify() {
+        let dir = std::env::temp_dir().join("stalwart_resource_verify_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let source = dir.join("spamfilter.toml");
+        let contents = b"version.spam-filter = \"1.0\"\n";
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&source, contents).unwrap();
+
+        // Sign the resource
+        let rng = SystemRandom::new();
+        let key_pair =
+            Ed25519KeyPair::from_pkcs8(Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref())
+                .unwrap();
+        let public_key = STANDARD.encode(key_pair.public_key().as_ref());
+        let signature = STANDARD.encode(key_pair.sign(contents).as_ref());
+        std::fs::write(signature_path(&source), &signature).unwrap();
+
+        let manager = |keys: &[(&str, String)]| ConfigManager {
+            cfg_local: ArcSwap::from_pointee(
+                [
+                    ("config.resource-key", String::new()),
+                    ("config.resource-checksum.spam-filter", String::new()),
+                    ("version.spam-filter.pin", String::new()),
+                    (
+                        "config.resource.spam-filter",
+                        format!("file://{}", source.display()),
+                    ),
+                ]
+                .iter()
+                .chain(keys)
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect::<BTreeMap<_, _>>(),
+            ),
+            ..Default::default()
+        };
+
+        // Valid signature and checksum
+        let checksum = Sha256::digest(contents)
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<String>();
+        assert_eq!(
+            manager(&[
+                ("config.resource-key", public_key.clone()),
+                ("config.resource-checksum.spam-filter", checksum),
+            ])
+            .fetch_resource("spam-filter")
+            .await
+            .unwrap(),
+            contents
+        );
+
+        // Checksum mismatch
+        assert!(
+            manager(&[("config.resource-checksum.spam-filter", "00".repeat(32))])
+                .fetch_resource("spam-filter")
+                .await
+                .unwrap_err()
+                .contains("Checksum mismatch")
+        );
+
+        // Tampered resource
+        std::fs::write(&source, b"version.spam-filter = \"6.6\"\n").unwrap();
+        assert!(manager(&[("config.resource-key", public_key.clone())])
+            .fetch_resource("spam-filter")
+            .await
+            .unwrap_err()
+            .contains("Signature verification failed"));
+
+        // Missing signature
+        std::fs::remove_file(signature_path(&source)).unwrap();
+        assert!(manager(&[("config.resource-key", public_key)])
+            .fetch_resource("spam-filter")
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetch_resource_pin() {
+        let dir = std::env::temp_dir().join("stalwart_resource_pin_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for version in ["1.0", "2.0"] {
+            std::fs::write(
+                dir.join(format!("spamfilter-{version}.toml")),
+                format!("version.spam-filter = \"{version}\"\n"),
+            )
+            .unwrap();
+        }
+
+        let manager = |url: String, pin: &str| ConfigManager {
+            cfg_local: ArcSwap::from_pointee(BTreeMap::from([
+                ("config.resource.spam-filter".to_string(), url),
+                ("config.resource-key".to_string(), String::new()),
+                (
+                    "config.resource-checksum.spam-filter".to_string(),
+                    String::new(),
+                ),
+                ("version.spam-filter.pin".to_string(), pin.to_string()),
+            ])),
+            ..Default::default()
+        };
+
+        // The pinned version is substituted in the URL
+        let versioned = format!("file://{}/spamfilter-{{version}}.toml", dir.display());
+        assert_eq!(
+            manager(versioned.clone(), "1.0")
+                .fetch_config_resource("spam-filter")
+                .await
+                .unwrap()
+                .version,
+            "1.0"
+        );
+
+        // Unpinned resources accept any version
+        let latest = format!("file://{}/spamfilter-2.0.toml", dir.display());
+        assert_eq!(
+            manager(latest.clone(), "")
+                .fetch_config_resource("spam-filter")
+                .await
+                .unwrap()
+                .version,
+            "2.0"
+        );
+
+        // Pinned resources never move to a different version
+        assert!(manager(latest, "1.0")
+            .fetch_config_resource("spam-filter")
+            .await
+            .unwrap_err()
+            .contains("pinned to version 1.0"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn set_rollback() {
+        let dir = std::env::temp_dir().join("stalwart_config_rollback_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg_local_path = dir.join("config.toml");
+        let contents = "store.db.path = \"/var/db\"\n";
+        std::fs::write(&cfg_local_path, contents).unwrap();
+
+        let manager = |cfg_local_path| ConfigManager {
+            cfg_local: ArcSwap::from_pointee(BTreeMap::from([(
+                "store.db.path".to_string(),
+                "/var/db".to_string(),
+            )])),
+            cfg_local_path,
+            cfg_local_patterns: Patterns::parse(&mut Config::default()).into(),
+            ..Default::default()
+        };
+        let keys = [
+            ("store.db.path", "/tmp/db"),
+            ("queue.outbound.next-hop", "mx"),
+        ];
+
+        // The store write fails after the local file was updated
+        let manager_ = manager(cfg_local_path.clone());
+        assert!(manager_
+            .set(keys)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("no changes were applied"));
+        assert_eq!(
+            manager_.cfg_local.load().get("store.db.path").unwrap(),
+            "/var/db"
+        );
+        assert_eq!(std::fs::read_to_string(&cfg_local_path).unwrap(), contents);
+
+        // The local file can't be written
+        let manager_ = manager(dir.join("missing").join("config.toml"));
+        assert!(manager_.set(keys).await.is_err());
+        assert_eq!(
+            manager_.cfg_local.load().get("store.db.path").unwrap(),
+            "/var/db"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn install_webadmin() {
+        let dir = std::env::temp_dir().join("stalwart_webadmin_install_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let bundle = dir.join("webadmin.zip");
+        std::fs::write(&bundle, b"bundle v1").unwrap();
+
+        let blob_store = BlobStore::from(
+            FsStore::open(
+                &mut Config::new(format!("store.fs.path = {:?}\n", dir.join("blobs"))).unwrap(),
+                "store.fs",
+            )
+            .await
+            .unwrap(),
+        );
+        let manager = ConfigManager {
+            cfg_local: ArcSwap::from_pointee(BTreeMap::from([
+                (
+                    "config.resource.webadmin".to_string(),
+                    format!("file://{}", bundle.display()),
+                ),
+                ("config.resource-key".to_string(), String::new()),
+                (
+                    "config.resource-checksum.webadmin".to_string(),
+                    String::new(),
+                ),
+                ("version.webadmin.pin".to_string(), String::new()),
+                (WEBADMIN_VERSION_KEY.to_string(), String::new()),
+            ])),
+            cfg_local_path: dir.join("config.toml"),
+            cfg_local_patterns: Patterns::parse(
+                &mut Config::new("config.local-keys.0 = \"*\"\n").unwrap(),
+            )
+            .into(),
+            ..Default::default()
+        };
+        let stored = || async {
+            blob_store
+                .get_blob(WEBADMIN_KEY, 0..usize::MAX)
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        // The hash is recorded on first install
+        let hash = manager.install_webadmin(&blob_store, false).await.unwrap();
+        assert_eq!(hash, sha256_hex(b"bundle v1"));
+        assert_eq!(manager.webadmin_hash().await.unwrap(), Some(hash.clone()));
+        assert_eq!(stored().await, b"bundle v1");
+
+        // Different bytes are rejected unless upgrading
+        std::fs::write(&bundle, b"bundle v2").unwrap();
+        assert!(manager
+            .install_webadmin(&blob_store, false)
+            .await
+            .unwrap_err()
+            .contains("hash mismatch"));
+        assert_eq!(stored().await, b"bundle v1");
+        assert_eq!(manager.webadmin_hash().await.unwrap(), Some(hash));
+
+        let hash = manager.install_webadmin(&blob_store, true).await.unwrap();
+        assert_eq!(hash, sha256_hex(b"bundle v2"));
+        assert_eq!(manager.webadmin_hash().await.unwrap(), Some(hash));
+        assert_eq!(stored().await, b"bundle v2");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::collections::HashSet;
+
+pub const DEFAULT_MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Estimated bits of entropy below which a password is rejected. Random
+/// alphanumeric passwords reach it at 9 characters, lowercase-only
+/// passphrases at 11.
+const MIN_PASSWORD_ENTROPY: f64 = 50.0;
+
+/// Strength requirements for administrator passwords chosen by the operator
+/// in '--init' and '--passwd'.
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub allow_weak: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: DEFAULT_MIN_PASSWORD_LENGTH,
+            allow_weak: false,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn check(&self, password: &str) -> Result<(), String> {
+        if self.allow_weak {
+            return Ok(());
+        }
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(format!(
+                "Password is too short, use at least {} characters.",
+                self.min_length
+            ));
+        }
+        if estimate_entropy(password) < MIN_PASSWORD_ENTROPY {
+            return Err(
+                "Password is too weak, use a longer password mixing upper and lower case \
+                letters, digits and symbols."
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Estimates entropy as if each character was drawn at random from the
+/// character classes present in the password. Repeated characters count
+/// twice at most, so that 'aaaaaaaaaaaa' or 'abababababab' are not mistaken
+/// for long passwords.
+fn estimate_entropy(password: &str) -> f64 {
+    let mut pool = 0;
+    let mut classes = [false; 4];
+    let mut distinct = HashSet::new();
+    let mut length = 0;
+    for ch in password.chars() {
+        let class = if ch.is_ascii_lowercase() {
+            0
+        } else if ch.is_ascii_uppercase() {
+            1
+        } else if ch.is_ascii_digit() {
+            2
+        } else {
+            3
+        };
+        if !classes[class] {
+            classes[class] = true;
+            pool += [26, 26, 10, 33][class];
+        }
+        distinct.insert(ch);
+        length += 1;
+    }
+    let length = length.min(distinct.len() * 2);
+
+    length as f64 * f64::from(pool).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PasswordPolicy;
+
+    #[test]
+    fn password_policy() {
+        let policy = PasswordPolicy::default();
+        for weak in [
+            "",
+            "secret",
+            "password",
+            "12345678901234",
+            "aaaaaaaaaaaaaaaaaaaa",
+            "abababababababababab",
+            "Summer24",
+        ] {
+            assert!(policy.check(weak).is_err(), "{weak:?} accepted");
+        }
+        for strong in [
+            "x7Kq2mWz9R",
+            "correct horse battery staple",
+            "correcthorsebatterystaple",
+            "Tr0ub4dor&3x",
+        ] {
+            assert!(policy.check(strong).is_ok(), "{strong:?} rejected");
+        }
+
+        let policy = PasswordPolicy {
+            min_length: 16,
+            allow_weak: false,
+        };
+        assert!(policy.check("x7Kq2mWz9R").is_err());
+        assert!(policy.check("x7Kq2mWz9R-f3Lp8").is_ok());
+
+        let policy = PasswordPolicy {
+            min_length: 16,
+            allow_weak: true,
+        };
+        assert!(policy.check("secret").is_ok());
+    }
+}
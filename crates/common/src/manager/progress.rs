@@ -0,0 +1,334 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    collections::BTreeMap,
+    io::{IsTerminal, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::task::JoinHandle;
+
+const TTY_INTERVAL: Duration = Duration::from_secs(1);
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Exports are tracked by family files written, as the size of the store
+/// is not known in advance.
+pub static EXPORT_PROGRESS: Progress = Progress::new("Exporting");
+/// Imports are tracked by bytes read from the backup files. Backups read
+/// from stdin or a blob store have no known size and report no progress.
+pub static IMPORT_PROGRESS: Progress = Progress::new("Importing");
+
+/// Completion of a CLI import or export, printed to stderr while it runs.
+pub struct Progress {
+    label: &'static str,
+    done: AtomicU64,
+    total: AtomicU64,
+    files_done: AtomicU64,
+    files_total: AtomicU64,
+    /// Account each worker is currently at, by the file it reads.
+    accounts: Mutex<BTreeMap<String, u32>>,
+}
+
+pub struct ProgressReporter {
+    progress: &'static Progress,
+    task: JoinHandle<()>,
+    started: Instant,
+    is_tty: bool,
+}
+
+pub struct ProgressSupervisor {
+    progress: &'static Progress,
+    context: &'static str,
+    task: Option<JoinHandle<()>>,
+    started: Instant,
+    done: u64,
+    files_done: u64,
+}
+
+impl Progress {
+    const fn new(label: &'static str) -> Self {
+        Progress {
+            label,
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            files_done: AtomicU64::new(0),
+            files_total: AtomicU64::new(0),
+            accounts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn add_total(&self, units: u64) {
+        self.total.fetch_add(units, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, units: u64) {
+        self.done.fetch_add(units, Ordering::Relaxed);
+    }
+
+    pub fn add_files(&self, files: u64) {
+        self.files_total.fetch_add(files, Ordering::Relaxed);
+    }
+
+    /// Records the account the worker reading `file` has reached.
+    pub fn set_account(&self, file: &str, account_id: u32) {
+        if let Ok(mut accounts) = self.accounts.lock() {
+            accounts.insert(file.to_string(), account_id);
+        }
+    }
+
+    pub fn file_done(&self, file: &str) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        self.worker_done(file);
+    }
+
+    /// Stops reporting the account of a worker, such as one reading a
+    /// segment of a file restored concurrently.
+    pub fn worker_done(&self, worker: &str) {
+        if let Ok(mut accounts) = self.accounts.lock() {
+            accounts.remove(worker);
+        }
+    }
+
+    /// Prints the progress to stderr until the returned reporter is finished,
+    /// as a single updating line on a terminal or as one line every
+    /// `LOG_INTERVAL` otherwise.
+    pub fn report(&'static self) -> ProgressReporter {
+        let is_tty = std::io::stderr().is_terminal();
+        let started = Instant::now();
+        let task = tokio::spawn(async move {
+            let mut last_percent = None;
+            loop {
+                tokio::time::sleep(if is_tty { TTY_INTERVAL } else { LOG_INTERVAL }).await;
+                let Some((percent, line)) = self.render(started.elapsed()) else {
+                    continue;
+                };
+                if is_tty {
+                    eprint!("\r\x1b[K{line}");
+                } else if last_percent != Some(percent) {
+                    eprintln!("{line}");
+                }
+                let _ = std::io::stderr().flush();
+                last_percent = Some(percent);
+            }
+        });
+
+        ProgressReporter {
+            progress: self,
+            task,
+            started,
+            is_tty,
+        }
+    }
+
+    /// Logs the aggregate progress along with the account each worker is at
+    /// every `interval` until the returned supervisor is finished, which logs
+    /// a summary of the work done meanwhile. A zero interval only logs the
+    /// summary.
+    pub fn supervise(
+        &'static self,
+        context: &'static str,
+        interval: Duration,
+    ) -> ProgressSupervisor {
+        let started = Instant::now();
+        let task = (!interval.is_zero()).then(|| {
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    let Some((percent, line)) = self.render(started.elapsed()) else {
+                        continue;
+                    };
+                    tracing::info!(
+                        context = context,
+                        event = "progress",
+                        percent = percent,
+                        files_done = self.files_done.load(Ordering::Relaxed),
+                        files_total = self.files_total.load(Ordering::Relaxed),
+                        accounts = self.render_accounts(),
+                        "{line}"
+                    );
+                }
+            })
+        });
+
+        ProgressSupervisor {
+            progress: self,
+            context,
+            task,
+            started,
+            done: self.done.load(Ordering::Relaxed),
+            files_done: self.files_done.load(Ordering::Relaxed),
+        }
+    }
+
+    fn render(&self, elapsed: Duration) -> Option<(u64, String)> {
+        render_line(
+            self.label,
+            self.done.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+            (
+                self.files_done.load(Ordering::Relaxed),
+                self.files_total.load(Ordering::Relaxed),
+            ),
+            elapsed,
+        )
+    }
+
+    fn render_accounts(&self) -> String {
+        self.accounts
+            .lock()
+            .map(|accounts| {
+                accounts
+                    .iter()
+                    .map(|(file, account_id)| format!("{file}: {account_id}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl ProgressReporter {
+    /// Stops reporting, ending the progress line on a terminal.
+    pub fn finish(self) {
+        self.task.abort();
+        if self.is_tty {
+            if let Some((_, line)) = self.progress.render(self.started.elapsed()) {
+                eprintln!("\r\x1b[K{line}");
+            }
+        }
+    }
+}
+
+impl ProgressSupervisor {
+    /// Stops logging the progress and logs a summary of the files and units
+    /// completed since the supervisor started.
+    pub fn finish(self) {
+        tracing::info!(
+            context = self.context,
+            event = "finish",
+            files = self
+                .progress
+                .files_done
+                .load(Ordering::Relaxed)
+                .saturating_sub(self.files_done),
+            units = self
+                .progress
+                .done
+                .load(Ordering::Relaxed)
+                .saturating_sub(self.done),
+            elapsed = ?self.started.elapsed(),
+            "{} finished.",
+            self.progress.label
+        );
+    }
+}
+
+impl Drop for ProgressSupervisor {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+fn render_line(
+    label: &str,
+    done: u64,
+    total: u64,
+    (files_done, files_total): (u64, u64),
+    elapsed: Duration,
+) -> Option<(u64, String)> {
+    if total == 0 {
+        return None;
+    }
+    let done = done.min(total);
+    let percent = done * 100 / total;
+    let mut line = format!("{label}: {percent}%");
+    if files_total > 0 {
+        line.push_str(&format!(
+            " ({}/{files_total} files)",
+            files_done.min(files_total)
+        ));
+    }
+    if done > 0 && done < total {
+        let remaining = elapsed.mul_f64((total - done) as f64 / done as f64);
+        line.push_str(" — ");
+        line.push_str(&format_remaining(remaining));
+    }
+    Some((percent, line))
+}
+
+fn format_remaining(remaining: Duration) -> String {
+    let minutes = remaining.as_secs().div_ceil(60);
+    match minutes {
+        0 | 1 => "<1 min remaining".to_string(),
+        2..=59 => format!("~{minutes} min remaining"),
+        _ => format!("~{}h {:02}min remaining", minutes / 60, minutes % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::render_line;
+
+    #[test]
+    fn render_progress() {
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+
+        assert_eq!(render_line("Importing", 0, 0, (0, 0), minutes(1)), None);
+        assert_eq!(
+            render_line("Importing", 0, 100, (0, 0), minutes(1)),
+            Some((0, "Importing: 0%".to_string()))
+        );
+        assert_eq!(
+            render_line("Importing", 47, 100, (0, 0), minutes(11)),
+            Some((47, "Importing: 47% — ~13 min remaining".to_string()))
+        );
+        assert_eq!(
+            render_line("Exporting", 1, 12, (0, 0), minutes(10)),
+            Some((8, "Exporting: 8% — ~1h 50min remaining".to_string()))
+        );
+        assert_eq!(
+            render_line("Importing", 99, 100, (0, 0), minutes(10)),
+            Some((99, "Importing: 99% — <1 min remaining".to_string()))
+        );
+        assert_eq!(
+            render_line("Importing", 120, 100, (0, 0), minutes(10)),
+            Some((100, "Importing: 100%".to_string()))
+        );
+        assert_eq!(
+            render_line("Importing", 73, 100, (146, 200), minutes(22)),
+            Some((
+                73,
+                "Importing: 73% (146/200 files) — ~9 min remaining".to_string()
+            ))
+        );
+    }
+}
@@ -21,11 +21,15 @@
  * for more details.
 */
 
+use std::collections::{BTreeMap, BTreeSet};
+
 use ahash::AHashSet;
 use arc_swap::ArcSwap;
 use parking_lot::RwLock;
 use store::Stores;
 use utils::config::{ipmask::IpAddrOrMask, utils::ParseValue, Config};
+#[cfg(not(target_env = "msvc"))]
+use utils::UnwrapFailure;
 
 use crate::{
     config::{
@@ -33,11 +37,14 @@ use crate::{
         tracers::Tracers,
     },
     listener::blocked::BLOCKED_IP_KEY,
-    Core,
+    Core, SharedCore,
 };
 
 use super::config::{ConfigManager, Patterns};
 
+/// Settings that are only applied when the server starts.
+const RESTART_PREFIXES: &[&str] = &["server.listener.", "server.socket.", "server.run-as."];
+
 pub struct ReloadResult {
     pub config: Config,
     pub new_core: Option<Core>,
@@ -97,7 +104,79 @@ impl Core {
     }
 
     pub async fn reload(&self) -> store::Result<ReloadResult> {
-        let mut config = self.storage.config.build_config("").await?;
+        self.reload_with(self.storage.config.cfg_local.load().as_ref().clone())
+            .await
+    }
+
+    /// Reads the local configuration file again before reloading, used when
+    /// the file was edited rather than the settings stored in the database.
+    pub async fn reload_from_disk(&self) -> store::Result<ReloadResult> {
+        let path = &self.storage.config.cfg_local_path;
+        let dir = self.storage.config.cfg_local_dir.as_ref();
+        let mut config = Config::default();
+
+        // Without a configuration file, local changes are written to the directory
+        if !dir.is_some_and(|dir| path.starts_with(dir)) {
+            match std::fs::read_to_string(path) {
+                Ok(value) => {
+                    if let Err(err) = config.parse_with_includes(&value, path) {
+                        config.new_parse_error("*", err);
+                        return Ok(config.into());
+                    }
+                }
+                Err(err) => {
+                    config.new_build_error(
+                        "*",
+                        format!("Could not read configuration file {path:?}: {err}"),
+                    );
+                    return Ok(config.into());
+                }
+            }
+        }
+        if let Some(dir) = dir {
+            if let Err(err) = config.parse_dir(dir) {
+                config.new_parse_error("*", err);
+                return Ok(config.into());
+            }
+        }
+
+        self.reload_with(config.keys).await
+    }
+
+    async fn reload_with(
+        &self,
+        cfg_local: BTreeMap<String, String>,
+    ) -> store::Result<ReloadResult> {
+        let mut config = Config {
+            keys: cfg_local.clone(),
+            ..Default::default()
+        };
+        config.resolve_macros().await;
+        self.storage.config.extend_config(&mut config, "").await?;
+        config.apply_env_overrides();
+
+        // Listeners are bound at startup and can't be swapped
+        let current = self.storage.config.build_config("server").await?;
+        for key in config
+            .keys
+            .keys()
+            .chain(current.keys.keys())
+            .filter(|key| {
+                RESTART_PREFIXES
+                    .iter()
+                    .any(|prefix| key.starts_with(prefix))
+            })
+            .collect::<BTreeSet<_>>()
+        {
+            if config.keys.get(key) != current.keys.get(key) {
+                tracing::warn!(
+                    context = "config",
+                    event = "reload",
+                    key = key,
+                    "Setting changed but requires a restart to take effect."
+                );
+            }
+        }
 
         // Parse tracers
         Tracers::parse(&mut config);
@@ -118,14 +197,16 @@ impl Core {
 
         // Build manager
         let manager = ConfigManager {
-            cfg_local: ArcSwap::from_pointee(self.storage.config.cfg_local.load().as_ref().clone()),
+            cfg_local: ArcSwap::from_pointee(cfg_local),
             cfg_local_path: self.storage.config.cfg_local_path.clone(),
+            cfg_local_dir: self.storage.config.cfg_local_dir.clone(),
             cfg_local_patterns: Patterns::parse(&mut config).into(),
             cfg_store: config
                 .value("storage.data")
                 .and_then(|id| stores.stores.get(id))
                 .cloned()
                 .unwrap_or_default(),
+            resource_dir: self.storage.config.resource_dir.clone(),
         };
 
         // Parse settings and build shared core
@@ -162,6 +243,51 @@ impl Core {
     }
 }
 
+/// Reloads the configuration file whenever the process receives a SIGHUP.
+#[cfg(not(target_env = "msvc"))]
+pub fn spawn_hangup_handler(core: SharedCore) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut h_hup = signal(SignalKind::hangup()).failed("start signal handler");
+    tokio::spawn(async move {
+        while h_hup.recv().await.is_some() {
+            tracing::info!(
+                context = "config",
+                event = "reload",
+                "Received SIGHUP, reloading configuration."
+            );
+
+            match core.load().reload_from_disk().await {
+                Ok(result) => {
+                    result.config.log_errors(false);
+                    if let Some(new_core) = result.new_core {
+                        core.store(new_core.into());
+                        tracing::info!(
+                            context = "config",
+                            event = "reload",
+                            "Configuration reloaded."
+                        );
+                    } else {
+                        tracing::warn!(
+                            context = "config",
+                            event = "reload",
+                            "Configuration not reloaded due to errors."
+                        );
+                    }
+                }
+                Err(err) => {
+                    tracing::error!(
+                        context = "config",
+                        event = "reload",
+                        reason = %err,
+                        "Failed to reload configuration."
+                    );
+                }
+            }
+        }
+    });
+}
+
 impl From<Config> for ReloadResult {
     fn from(config: Config) -> Self {
         Self {
@@ -22,19 +22,34 @@
 */
 
 use std::{
-    io::ErrorKind,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::Display,
+    io::{ErrorKind, SeekFrom},
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
 };
 
 use crate::Core;
-use jmap_proto::types::{collection::Collection, property::Property};
+use ahash::{AHashMap, AHashSet};
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use futures::{future::BoxFuture, stream::FuturesUnordered, Stream, StreamExt};
+use jmap_proto::{
+    object::Object,
+    types::{collection::Collection, property::Property, value::Value},
+};
+use memmap2::Mmap;
+use parking_lot::Mutex;
 use store::{
+    blake3,
     roaring::RoaringBitmap,
     write::{
-        key::DeserializeBigEndian, BatchBuilder, BitmapClass, BitmapHash, BlobOp, DirectoryClass,
-        LookupClass, Operation, TagValue, ValueClass,
+        key::DeserializeBigEndian, now, Batch, BatchBuilder, BitmapClass, BitmapHash, BlobOp,
+        DirectoryClass, LookupClass, Operation, TagValue, ValueClass,
     },
-    BlobStore, Store, U32_LEN,
+    BitmapKey, BlobStore, IterateParams, Store, ValueKey, U32_LEN,
 };
 use store::{
     write::{QueueClass, QueueEvent},
@@ -42,375 +57,4623 @@ use store::{
 };
 use tokio::{
     fs::File,
-    io::{AsyncReadExt, BufReader},
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader, ReadBuf,
+    },
+    sync::{
+        mpsc::{self, error::SendError},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+use utils::{
+    codec::leb128::{Leb128Reader, Leb128Vec},
+    config::utils::ParseValue,
+    failed_with, BlobHash, ExitCode, BLOB_HASH_LEN,
 };
-use utils::{failed, BlobHash, UnwrapFailure};
 
-use super::backup::{DeserializeBytes, Family, Op, FILE_VERSION, MAGIC_MARKER};
+use super::{
+    backup::{
+        queued_blob_hash, BackupLocation, BackupManifest, BlobPartReader, DeserializeBytes, Family,
+        FileSegment, Op, BACKUP_FILES, FILE_VERSION, HEADER_LEN, MAGIC_MARKER, MANIFEST_FILE,
+        TRAILER_MARKER,
+    },
+    metrics::RESTORE_METRICS,
+    progress::IMPORT_PROGRESS,
+    upgrade::{pending_upgrades, upgrade_key_value},
+};
+
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+pub const DEFAULT_BATCH_BYTES: usize = 32 * 1024 * 1024;
+pub const DEFAULT_BLOB_DEDUP_LIMIT: usize = 1_000_000;
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_BLOB_CONCURRENCY: usize = 8;
+pub const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_SEGMENT_CONCURRENCY: usize = 4;
+
+/// Account ids missing from the remap table that are reported one by one,
+/// any further ones are only counted in a single warning.
+const MAX_UNMAPPED_WARNINGS: usize = 1000;
+
+/// Bytes read past a family marker while checking that the op after it
+/// decodes, when resynchronizing after a read error.
+const MAX_RESYNC_LOOKAHEAD: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct RestoreOptions {
+    pub batch_size: usize,
+    /// Size in bytes of the keys and values after which a batch is written,
+    /// regardless of its number of operations. Lowered for stores limiting
+    /// the size of their transactions.
+    pub batch_bytes: usize,
+    pub dry_run: bool,
+    pub resume: bool,
+    /// Directory the progress files are kept in instead of next to the
+    /// files being restored, for backups on a read-only location.
+    pub progress_dir: Option<PathBuf>,
+    pub tolerant: bool,
+    pub recompute_quota: bool,
+    pub account_remap: AHashMap<u32, u32>,
+    /// Account ids missing from `account_remap` that a warning was logged
+    /// for, shared by all the files of a restore.
+    pub unmapped_accounts: Arc<Mutex<AHashSet<u32>>>,
+    /// Blob stores to restore the blobs exported from another blob store
+    /// into, by id. Blobs of the default blob store always go to the default
+    /// one.
+    pub blob_store_remap: AHashMap<String, String>,
+    /// Check the consistency of the restored data once the import completes.
+    pub verify: bool,
+    /// Families to restore, all of them when `None`.
+    pub families: Option<BTreeSet<Family>>,
+    /// Due time of the restored queue events.
+    pub queue_due: QueueDue,
+    /// Only restore the queue and the blobs of the queued messages.
+    pub queue_only: bool,
+    /// Maximum number of written blob hashes remembered to skip writing the
+    /// same blob twice, 0 disables deduplication.
+    pub blob_dedup_limit: usize,
+    /// Memory-map uncompressed backup files stored on disk and decode their
+    /// ops from the mapped bytes instead of reading them as a stream.
+    pub mmap: bool,
+    /// Capacity of the buffer used to read backup files as a stream.
+    pub read_buffer_size: usize,
+    /// Maximum number of blobs uploaded to the blob store at the same time
+    /// while the backup file is decoded.
+    pub blob_concurrency: usize,
+    /// Stops the restore before its next operation once cancelled, keeping
+    /// the progress files so that it can be continued with `resume`.
+    pub cancel: CancellationToken,
+    /// What happens to the batch being built when the restore is cancelled.
+    pub on_cancel: OnCancel,
+    /// Skip the families and op types unknown to this build, written by a
+    /// newer version, instead of failing. Ops of an unknown family are
+    /// skipped up to the next family, an unknown op type resynchronizes to
+    /// the next segment of the index or, past the last one, to the next
+    /// family whose first op decodes. Ops found by scanning for a family are
+    /// only reported, never written.
+    pub skip_unknown: bool,
+    /// Restore the data of each account into a staging account first, and
+    /// only replace the existing data of the accounts with it once the whole
+    /// backup was restored. A restore failing before that removes the staged
+    /// data and leaves the existing accounts untouched. Replacing the accounts
+    /// is not atomic, see `Core::swap_staged_accounts`.
+    pub stage_accounts: bool,
+    /// How often the aggregate progress of the files being restored is
+    /// logged, zero only logs a summary once the restore completes.
+    pub progress_interval: Duration,
+    /// Bytes the batches being built, the blobs being uploaded and the ops
+    /// read ahead of them may take up across all the files restored at once,
+    /// half of it going to the ops read ahead. A file reaching it writes its
+    /// batch and waits for the other files to release theirs, and files only
+    /// start once up to `batch_bytes` of read-ahead is free for them. Without
+    /// it, each file reads up to `batch_bytes` ahead.
+    pub memory_budget: Option<usize>,
+    /// Maximum number of segments of indexed files restored at the same
+    /// time, across all files. Indexed files are read as a whole when 1.
+    pub segment_concurrency: usize,
+    /// Restore into a store that already has accounts, merging the backup
+    /// with their data. Otherwise such a store is refused.
+    pub merge: bool,
+    /// Fail the swap of the staged accounts before copying this family.
+    #[cfg(feature = "test_mode")]
+    pub fail_swap_at: Option<Family>,
+}
+
+/// How a cancelled restore leaves the operations read since its last
+/// checkpoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnCancel {
+    /// Write the batch and take a checkpoint after it.
+    #[default]
+    Flush,
+    /// Drop the batch, resuming reads its operations again from the
+    /// previous checkpoint.
+    Discard,
+}
+
+/// How the due time of restored queue events is rewritten. Only the event
+/// is rescheduled, the retry times stored with each message still apply
+/// once it is picked up for delivery.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueueDue {
+    /// Keep the due time found in the backup.
+    #[default]
+    Keep,
+    /// Make all events due at the time of the restore.
+    Now,
+    /// Delay the due time found in the backup by a number of seconds.
+    Offset(u64),
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreStats {
+    pub ops: BTreeMap<Family, u64>,
+    pub errors: Vec<String>,
+    pub skipped: BTreeMap<Family, BTreeMap<String, u64>>,
+    /// Operations left out by the family filter.
+    pub filtered: BTreeMap<Family, u64>,
+    /// Used quota per principal as stored in the backup and as recomputed from
+    /// the restored messages and scripts, only populated with `recompute_quota`.
+    pub quota_stored: BTreeMap<u32, i64>,
+    pub quota_restored: BTreeMap<u32, i64>,
+    /// Manifest found next to the backup, if any.
+    pub manifest: Option<BackupManifest>,
+    /// Restored documents, only populated with `verify`.
+    pub documents: BTreeMap<(u32, u8), RoaringBitmap>,
+    /// Links to a blob the file restoring them did not commit, along with the
+    /// account, collection and document id of each link. Backups sort the
+    /// links of a blob before its commit, so only the links read last by a
+    /// file or segment are left to check once the whole backup was restored.
+    pub pending_links: Vec<(BlobHash, Vec<(u32, u8, u32)>)>,
+    /// Blobs a file or segment committed before reading any link, which the
+    /// pending links of the file or segment before it may point to.
+    pub leading_commits: AHashSet<BlobHash>,
+    /// Hashes of the restored blob links, only populated with `verify`.
+    pub linked_blobs: AHashSet<BlobHash>,
+    /// Blobs committed by the restore, only populated with `queue_only`.
+    pub committed_blobs: AHashSet<BlobHash>,
+    /// Blobs referenced by the restored queued messages, along with their
+    /// queue ids, only populated with `queue_only`.
+    pub queued_blobs: AHashMap<BlobHash, Vec<u64>>,
+    /// Blobs not written again because an identical one was already restored.
+    pub deduplicated_blobs: u64,
+    /// Restored ACL grants by grantee, along with the account, collection and
+    /// document id of each grant, only populated with `account_remap`.
+    pub acl_grants: AHashMap<u32, Vec<(u32, u8, u32)>>,
+    /// Restored principals, and the principal ids referenced by each restored
+    /// membership and name or email mapping.
+    pub principals: AHashSet<u32>,
+    pub directory_refs: Vec<(DirectoryClass, Vec<u32>)>,
+    /// Bytes, blobs and documents restored per family.
+    pub families: BTreeMap<Family, FamilyStats>,
+    /// Whether the restore stopped early through `RestoreOptions::cancel`.
+    pub cancelled: bool,
+    /// Accounts whose data was replaced with the staged data, only populated
+    /// with `stage_accounts`.
+    pub swapped_accounts: BTreeSet<u32>,
+}
+
+/// Totals of the operations applied for a family, counted along with `ops`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct FamilyStats {
+    /// Size of the keys and values applied.
+    pub bytes: u64,
+    /// Blobs written to the blob store, repeated blobs are only counted once.
+    pub blobs: u64,
+    /// Documents receiving at least one operation.
+    pub documents: u64,
+}
+
+/// Error raised while restoring a backup file, along with the position
+/// in the file where it was found.
+#[derive(Debug)]
+pub struct RestoreError {
+    pub file: String,
+    pub offset: u64,
+    pub family: Family,
+    pub cause: String,
+    /// Whether the store or the filesystem failed rather than the backup
+    /// being invalid, in which case retrying may succeed.
+    pub store: bool,
+    /// Whether the backup uses a family or op type unknown to this build.
+    pub unknown: bool,
+    /// Account, collection and document of the op that failed.
+    pub context: OpContext,
+}
+
+/// Account, collection and document that the ops read since the start of
+/// the current family apply to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpContext {
+    pub account_id: Option<u32>,
+    pub collection: Option<u8>,
+    pub document_id: Option<u32>,
+}
+
+/// Context established by the ops preceding a key value. Each family starts
+/// without context, the `has_*` flags record which context ops were read
+/// since, as `u32::MAX` is a valid account id for principals.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Cursor {
+    account_id: u32,
+    /// Account id the batch is written under, a staging account id while
+    /// the account is staged.
+    batch_account_id: u32,
+    collection: u8,
+    document_id: u32,
+    family: Family,
+    has_account_id: bool,
+    has_collection: bool,
+    has_document_id: bool,
+}
+
+/// Position of the last committed batch, stored next to the backup file so that
+/// an interrupted import can be resumed with `--resume`.
+///
+/// Batches are only checkpointed after they have been written, so ops are never
+/// applied twice except for the batch in flight when the import was interrupted.
+/// Most families are idempotent (`set` operations and bitmaps), but counters
+/// (`Property::EmailIds`, `LookupCounter` and `UsedQuota`) are restored with
+/// atomic additions and would be double-counted if a committed batch were
+/// replayed. These are protected by resuming strictly after the last committed
+/// offset.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Checkpoint {
+    offset: u64,
+    num_ops: u64,
+    account_id: u32,
+    collection: u8,
+    document_id: u32,
+    family: u8,
+}
+
+enum RestoreOp {
+    Set {
+        class: ValueClass,
+        value: Vec<u8>,
+    },
+    Add {
+        class: ValueClass,
+        value: i64,
+    },
+    Index {
+        field: u8,
+        key: Vec<u8>,
+    },
+    Bitmap {
+        class: BitmapClass,
+        document_ids: RoaringBitmap,
+    },
+    Log {
+        change_id: u64,
+        value: Vec<u8>,
+    },
+    Blob {
+        hash: BlobHash,
+        value: Vec<u8>,
+    },
+}
+
+/// Blob stores the restored blob contents are written to. Contents exported
+/// from a blob store other than the default one are found under the document
+/// id listed for that store in `BackupManifest::blob_stores`.
+#[derive(Clone)]
+pub(super) struct BlobStores {
+    default: BlobStore,
+    others: AHashMap<u32, (String, Option<BlobStore>)>,
+}
+
+impl BlobStores {
+    fn get(&self, document_id: u32) -> Result<&BlobStore, String> {
+        match self.others.get(&document_id) {
+            Some((_, Some(blob_store))) => Ok(blob_store),
+            Some((id, None)) => Err(format!(
+                "Blob store {id:?} not found: configure it or use \
+                 '--blob-store' to restore its blobs into another one"
+            )),
+            None => Ok(&self.default),
+        }
+    }
+}
+
+/// Families only holding data of an account, all of which `purge_account`
+/// removes, restored into staging accounts with `stage_accounts`.
+const STAGED_FAMILIES: [Family; 5] = [
+    Family::Property,
+    Family::TermIndex,
+    Family::Index,
+    Family::Bitmap,
+    Family::Log,
+];
+
+/// Staging account ids the staged families of each restored account are
+/// written under with `stage_accounts`. Ids are assigned downwards from
+/// `u32::MAX - 1` in account id order, so that a resumed restore stages into
+/// the same accounts.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Staging {
+    accounts: Arc<AHashMap<u32, u32>>,
+}
+
+impl Staging {
+    fn new(account_ids: BTreeSet<u32>) -> Result<Self, String> {
+        let accounts = account_ids
+            .iter()
+            .zip((0..u32::MAX).rev())
+            .map(|(account_id, staging_id)| (*account_id, staging_id))
+            .collect::<AHashMap<_, _>>();
+        if let Some(account_id) = accounts.values().find(|id| account_ids.contains(id)) {
+            return Err(format!(
+                "Account {account_id} uses an id reserved for staging accounts"
+            ));
+        }
+
+        Ok(Self {
+            accounts: Arc::new(accounts),
+        })
+    }
+
+    fn account_id(&self, family: Family, account_id: u32) -> u32 {
+        if STAGED_FAMILIES.contains(&family) {
+            self.accounts
+                .get(&account_id)
+                .copied()
+                .unwrap_or(account_id)
+        } else {
+            account_id
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    /// Forwards the ops of the staging accounts read back from the store under
+    /// the id of the account they were staged for, dropping those of any other
+    /// account. Each account is purged before its first op is forwarded, the
+    /// task returns the accounts purged so far.
+    fn unstage(
+        &self,
+        store: Store,
+        source: OpSource,
+        capacity: usize,
+        mut swapped: BTreeSet<u32>,
+    ) -> (OpSource, JoinHandle<BTreeSet<u32>>) {
+        let staged = self
+            .accounts
+            .iter()
+            .map(|(account_id, staging_id)| (*staging_id, *account_id))
+            .collect::<AHashMap<_, _>>();
+        let (tx, ops) = op_channel(capacity, MemoryBudget::default());
+        let OpSource {
+            name,
+            version,
+            position,
+            ops: mut staged_ops,
+            task,
+            ..
+        } = source;
+
+        let src = name.clone();
+        let forward = tokio::spawn(async move {
+            let mut is_staged = false;
+            while let Some(result) = staged_ops.recv().await {
+                let result = match result {
+                    Ok((Op::Family(family), position)) => {
+                        is_staged = false;
+                        Ok((Op::Family(family), position))
+                    }
+                    Ok((Op::AccountId(staging_id), position)) => {
+                        let Some(&account_id) = staged.get(&staging_id) else {
+                            is_staged = false;
+                            continue;
+                        };
+                        is_staged = true;
+                        if swapped.insert(account_id) {
+                            if let Err(err) = purge_staged_families(&store, account_id).await {
+                                let _ = tx
+                                    .send(Err(position
+                                        .error(
+                                            &src,
+                                            format!("Failed to purge account {account_id}: {err}"),
+                                        )
+                                        .in_store()))
+                                    .await;
+                                break;
+                            }
+                        }
+                        Ok((Op::AccountId(account_id), position))
+                    }
+                    Ok(_) if !is_staged => continue,
+                    result => result,
+                };
+                if tx.send(result).await.is_err() {
+                    break;
+                }
+            }
+            swapped
+        });
+
+        (
+            OpSource {
+                name,
+                version,
+                progress: None,
+                resume_from: None,
+                position,
+                ops,
+                task,
+            },
+            forward,
+        )
+    }
+}
+
+/// Removes the staged families of an account, along with the mailbox uid
+/// counters that `purge_account` leaves behind.
+async fn purge_staged_families(store: &Store, account_id: u32) -> store::Result<()> {
+    store.purge_account(account_id).await?;
+    store
+        .delete_range(
+            ValueKey {
+                account_id,
+                collection: u8::from(Collection::Mailbox),
+                document_id: 0,
+                class: ValueClass::Property(u8::from(Property::EmailIds)),
+            },
+            ValueKey {
+                account_id,
+                collection: u8::from(Collection::Mailbox),
+                document_id: u32::MAX,
+                class: ValueClass::Property(u8::from(Property::EmailIds)),
+            },
+        )
+        .await
+}
+
+/// State shared by the files restored at once.
+#[derive(Clone, Default)]
+pub(super) struct RestoreShared {
+    pub dedup: BlobDedup,
+    pub staging: Staging,
+    pub memory: MemoryBudget,
+    pub read_ahead: MemoryBudget,
+}
+
+/// Memory available to the batches and blob uploads of all the files of a
+/// restore, counted in KiB. Each file holds what it reserved until its next
+/// batch is written, a file that can't reserve more writes its batch before
+/// waiting so that files never wait on each other while holding memory.
+///
+/// The ops read ahead of the batches take up a separate part of the budget,
+/// out of which each file sets aside its own read-ahead before it starts. A
+/// reader only waits for the ops of its own file to be received, so a file
+/// waiting for batch memory never keeps another file from reading.
+#[derive(Clone, Default)]
+pub(super) struct MemoryBudget {
+    semaphore: Option<Arc<Semaphore>>,
+    units: u32,
+    /// Part of a larger budget set aside for this one, released once it's dropped.
+    _parent: Option<Arc<OwnedSemaphorePermit>>,
+}
+
+impl MemoryBudget {
+    pub fn new(bytes: Option<usize>) -> Self {
+        match bytes {
+            Some(bytes) => {
+                let units = bytes
+                    .div_ceil(1024)
+                    .clamp(1, Semaphore::MAX_PERMITS.min(u32::MAX as usize))
+                    as u32;
+                Self {
+                    semaphore: Some(Arc::new(Semaphore::new(units as usize))),
+                    units,
+                    _parent: None,
+                }
+            }
+            None => Self::default(),
+        }
+    }
+
+    /// Splits a memory budget between the batches being built and the ops
+    /// read ahead of them.
+    pub fn split(bytes: Option<usize>) -> (Self, Self) {
+        (
+            Self::new(bytes.map(|bytes| bytes - bytes / 2)),
+            Self::new(bytes.map(|bytes| bytes / 2)),
+        )
+    }
+
+    /// Read-ahead of a single file, up to `bytes`. Bounded budgets wait until
+    /// that much of them is free and keep it set aside until the file is done.
+    pub async fn read_ahead(&self, bytes: usize) -> Self {
+        let Some(semaphore) = &self.semaphore else {
+            return Self::new(Some(bytes));
+        };
+        let units = self.units(bytes).max(1);
+        Self {
+            semaphore: Some(Arc::new(Semaphore::new(units as usize))),
+            units,
+            _parent: semaphore
+                .clone()
+                .acquire_many_owned(units)
+                .await
+                .ok()
+                .map(Arc::new),
+        }
+    }
+
+    /// Units taken by an op, ops larger than the whole budget take all of it.
+    fn units(&self, bytes: usize) -> u32 {
+        bytes.div_ceil(1024).min(self.units as usize) as u32
+    }
+
+    /// Adds the memory used by an op to `reserved`, failing if it isn't
+    /// available right away.
+    fn try_reserve(&self, reserved: &mut Option<OwnedSemaphorePermit>, bytes: usize) -> bool {
+        let Some(semaphore) = &self.semaphore else {
+            return true;
+        };
+        match semaphore.clone().try_acquire_many_owned(self.units(bytes)) {
+            Ok(permit) => {
+                merge_permit(reserved, permit);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Adds the memory used by an op to `reserved` once the other files
+    /// released enough of it.
+    async fn reserve(&self, reserved: &mut Option<OwnedSemaphorePermit>, bytes: usize) {
+        if let Some(semaphore) = &self.semaphore {
+            if let Ok(permit) = semaphore
+                .clone()
+                .acquire_many_owned(self.units(bytes))
+                .await
+            {
+                merge_permit(reserved, permit);
+            }
+        }
+    }
+}
+
+fn merge_permit(reserved: &mut Option<OwnedSemaphorePermit>, permit: OwnedSemaphorePermit) {
+    match reserved {
+        Some(reserved) => reserved.merge(permit),
+        None => *reserved = Some(permit),
+    }
+}
+
+/// Hashes of the blobs written so far, shared by the tasks of a restore.
+/// Once `limit` hashes are tracked any other blob is always written.
+#[derive(Clone, Default)]
+pub(super) struct BlobDedup {
+    hashes: Arc<Mutex<AHashSet<BlobHash>>>,
+    limit: usize,
+}
+
+impl BlobDedup {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            hashes: Default::default(),
+            limit,
+        }
+    }
+
+    fn contains(&self, hash: &BlobHash) -> bool {
+        self.limit > 0 && self.hashes.lock().contains(hash)
+    }
+
+    fn insert(&self, hash: BlobHash) {
+        let mut hashes = self.hashes.lock();
+        if hashes.len() < self.limit {
+            hashes.insert(hash);
+        }
+    }
+}
+
+impl Core {
+    pub async fn restore(
+        &self,
+        src: impl Into<BackupLocation>,
+        options: RestoreOptions,
+    ) -> RestoreStats {
+        let src = src.into();
+        let span = tracing::info_span!("restore", source = %src);
+        self.try_restore(src, options)
+            .instrument(span)
+            .await
+            .unwrap_or_else(|err| {
+                failed_with(err.exit_code(), &format!("Failed to restore backup: {err}"))
+            })
+    }
+
+    pub async fn try_restore(
+        &self,
+        src: impl Into<BackupLocation>,
+        options: RestoreOptions,
+    ) -> Result<RestoreStats, RestoreError> {
+        let src = src.into();
+        let manifest = read_manifest(&src).await?;
+        let files = backup_files(&src, manifest.as_ref()).await?;
+        if let Some(dir) = options.progress_dir.as_ref().filter(|_| !options.dry_run) {
+            tokio::fs::create_dir_all(dir).await.map_err(|err| {
+                RestoreError::new(
+                    &src,
+                    0,
+                    Family::None,
+                    format!(
+                        "Failed to create progress directory {}: {err}",
+                        dir.display()
+                    ),
+                )
+                .in_store()
+            })?;
+        }
+        check_snapshot_ids(manifest.as_ref(), &files, &options).await?;
+        self.check_target_store(&src, &options).await?;
+        for file in &files {
+            if let BackupLocation::Path(path) = file {
+                IMPORT_PROGRESS.add_total(path.metadata().map_or(0, |metadata| metadata.len()));
+            }
+        }
+        IMPORT_PROGRESS.add_files(files.len() as u64);
+        let supervisor = IMPORT_PROGRESS.supervise("restore", options.progress_interval);
+
+        if options.restores_family(Family::Property) && !options.restores_family(Family::Blob) {
+            tracing::warn!(
+                context = "restore",
+                event = "filter",
+                "Restoring properties without blobs, any referenced blobs \
+                 must already exist in the blob store."
+            );
+        }
+
+        let expected_ops = manifest
+            .as_ref()
+            .map(|manifest| manifest.ops.clone())
+            .unwrap_or_default();
+
+        let (memory, read_ahead) = MemoryBudget::split(options.memory_budget);
+        let shared = RestoreShared {
+            dedup: BlobDedup::new(options.blob_dedup_limit),
+            staging: if options.stage_accounts && !options.dry_run {
+                self.stage_accounts(&src, manifest.as_ref(), &options)
+                    .await?
+            } else {
+                Staging::default()
+            },
+            memory,
+            read_ahead,
+        };
+
+        let mut progress_files = files
+            .iter()
+            .filter_map(|file| options.progress_file(file, None))
+            .collect::<Vec<_>>();
+
+        // Spawn a task for each file, except for the shards of the log family
+        // which are restored one after another to keep change ids in order
+        let (mut log_files, files): (Vec<_>, Vec<_>) = files
+            .into_iter()
+            .partition(|file| log_shard(file).is_some());
+        log_files.sort_unstable_by_key(log_shard);
+        let blob_stores = self.restore_blob_stores(
+            manifest
+                .iter()
+                .flat_map(|manifest| manifest.blob_stores.clone()),
+            &options,
+        );
+        let mut tasks = Vec::with_capacity(files.len() + 1);
+        if let Some(first) = log_files.first().cloned() {
+            let log_files = log_files
+                .into_iter()
+                .map(|file| {
+                    let index = file_index(manifest.as_ref(), &file);
+                    (file, index)
+                })
+                .collect::<Vec<_>>();
+            let store = self.storage.data.clone();
+            let blob_stores = blob_stores.clone();
+            let options = options.clone();
+            let expected_ops = expected_ops.clone();
+            let shared = shared.clone();
+            tasks.push((
+                first,
+                tokio::spawn(async move {
+                    let mut stats = RestoreStats::default();
+                    for (file, index) in log_files {
+                        stats.merge(
+                            restore_file(
+                                store.clone(),
+                                blob_stores.clone(),
+                                &file,
+                                &index,
+                                &options,
+                                &expected_ops,
+                                &shared,
+                            )
+                            .await?,
+                        );
+                        if stats.cancelled {
+                            break;
+                        }
+                    }
+                    Ok(stats)
+                }),
+            ));
+        }
+        // Indexed files are split at account boundaries, their segments
+        // share a limit so a few large files don't starve the others
+        let segments_limit = Arc::new(Semaphore::new(options.segment_concurrency.max(1)));
+        let version = manifest
+            .as_ref()
+            .map_or(FILE_VERSION, |manifest| manifest.version);
+        for file in files {
+            let store = self.storage.data.clone();
+            let blob_stores = blob_stores.clone();
+            let options = options.clone();
+            let expected_ops = expected_ops.clone();
+            let shared = shared.clone();
+            let index = file_index(manifest.as_ref(), &file);
+            let segments = (!index.is_empty()
+                && options.segment_concurrency > 1
+                && matches!(file, BackupLocation::Path(_)))
+            .then(|| index.clone());
+            if let Some(segments) = &segments {
+                progress_files.extend(
+                    (0..segments.len()).filter_map(|num| options.progress_file(&file, Some(num))),
+                );
+            }
+            let segments_limit = segments_limit.clone();
+            tasks.push((
+                file.clone(),
+                tokio::spawn(async move {
+                    match segments {
+                        Some(segments) => {
+                            restore_indexed_file(
+                                store,
+                                blob_stores,
+                                &file,
+                                segments,
+                                version,
+                                &options,
+                                &expected_ops,
+                                &shared,
+                                segments_limit,
+                            )
+                            .await
+                        }
+                        None => {
+                            restore_file(
+                                store,
+                                blob_stores,
+                                &file,
+                                &index,
+                                &options,
+                                &expected_ops,
+                                &shared,
+                            )
+                            .await
+                        }
+                    }
+                }),
+            ));
+        }
+
+        let mut stats = RestoreStats {
+            manifest,
+            ..Default::default()
+        };
+        let mut tasks = tasks.into_iter();
+        while let Some((file, task)) = tasks.next() {
+            match task
+                .await
+                .map_err(|err| RestoreError::new(&file, 0, Family::None, err))
+                .and_then(|result| result)
+            {
+                Ok(file_stats) => stats.merge(file_stats),
+                Err(err) => {
+                    // Stop the other files so nothing is written once the
+                    // restore failed, and before removing what they staged
+                    for (_, task) in tasks {
+                        task.abort();
+                        let _ = task.await;
+                    }
+                    if !shared.staging.is_empty() {
+                        self.discard_staging(&shared.staging, &progress_files).await;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        supervisor.finish();
+
+        // Links to blobs of files not restored yet are expected, the checks
+        // run once the resumed restore completes
+        if stats.cancelled {
+            tracing::warn!(
+                context = "restore",
+                event = "cancel",
+                "Restore cancelled, progress files are kept to resume from."
+            );
+            return Ok(stats);
+        }
+
+        // Progress files are kept until the swap completes, resuming a failed
+        // swap reads nothing more and swaps again
+        if !shared.staging.is_empty() {
+            stats.swapped_accounts = self
+                .swap_staged_accounts(&src, &shared.staging, &options)
+                .await?;
+        }
+        if !options.dry_run {
+            for progress in progress_files {
+                progress
+                    .remove()
+                    .await
+                    .map_err(|err| RestoreError::new(&progress, 0, Family::None, err))?;
+            }
+        }
+
+        self.check_blob_links(&src, &mut stats, &options).await?;
+        self.check_acl_grants(&src, &mut stats, &options).await?;
+        self.check_directory_refs(&src, &mut stats, &options)
+            .await?;
+        self.check_queued_blobs(&src, &mut stats, &options).await?;
+
+        // Replace the stored quotas with the recomputed totals
+        if options.recompute_quota && !options.dry_run {
+            self.write_recomputed_quotas(&stats)
+                .await
+                .map_err(|err| RestoreError::new(&src, 0, Family::Directory, err).in_store())?;
+        }
+
+        if options.verify && !options.dry_run {
+            self.verify_restore(&mut stats)
+                .await
+                .map_err(|err| RestoreError::new(&src, 0, Family::None, err))?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Refuses to restore into a store that already has accounts unless the
+    /// restore is meant to merge with them. Resumed, staged and queue only
+    /// restores expect existing data and are not checked.
+    pub(super) async fn check_target_store(
+        &self,
+        src: impl Display,
+        options: &RestoreOptions,
+    ) -> Result<(), RestoreError> {
+        if options.merge
+            || options.dry_run
+            || options.resume
+            || options.stage_accounts
+            || options.queue_only
+        {
+            return Ok(());
+        }
+
+        let mut accounts = 0u64;
+        self.storage
+            .data
+            .iterate(
+                IterateParams::new(
+                    ValueKey::from(ValueClass::Directory(DirectoryClass::Principal(0))),
+                    ValueKey::from(ValueClass::Directory(DirectoryClass::Principal(u32::MAX))),
+                )
+                .no_values(),
+                |_, _| {
+                    accounts += 1;
+                    Ok(true)
+                },
+            )
+            .await
+            .map_err(|err| {
+                RestoreError::new(
+                    &src,
+                    0,
+                    Family::Directory,
+                    format!("Failed to read principals: {err}"),
+                )
+                .in_store()
+            })?;
+
+        if accounts > 0 {
+            Err(RestoreError::new(
+                src,
+                0,
+                Family::Directory,
+                format!(
+                    "The store already contains {accounts} accounts, restoring into it \
+                     would merge the backup with their data. Use '--merge' to restore \
+                     anyway or import into an empty store."
+                ),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Assigns a staging account to each account of the backup, removing any
+    /// data left in them by a previous restore unless it is resumed.
+    async fn stage_accounts(
+        &self,
+        src: &BackupLocation,
+        manifest: Option<&BackupManifest>,
+        options: &RestoreOptions,
+    ) -> Result<Staging, RestoreError> {
+        let manifest = manifest.ok_or_else(|| {
+            RestoreError::new(
+                src,
+                0,
+                Family::None,
+                "Staging accounts requires a backup with a manifest",
+            )
+        })?;
+        if options.queue_only
+            || STAGED_FAMILIES
+                .iter()
+                .any(|family| !options.restores_family(*family))
+        {
+            return Err(RestoreError::new(
+                src,
+                0,
+                Family::None,
+                "Staging accounts requires restoring the property, \
+                 term_index, index, bitmap and log families",
+            ));
+        }
+
+        let staging = Staging::new(
+            manifest
+                .accounts
+                .keys()
+                .map(|a| options.account_remap.get(a).copied().unwrap_or(*a))
+                .filter(|account_id| *account_id != u32::MAX)
+                .collect(),
+        )
+        .map_err(|err| RestoreError::new(src, 0, Family::None, err))?;
+
+        if !options.resume {
+            for staging_id in staging.accounts.values() {
+                purge_staged_families(&self.storage.data, *staging_id)
+                    .await
+                    .map_err(|err| {
+                        RestoreError::new(
+                            src,
+                            0,
+                            Family::None,
+                            format!("Failed to purge staging account {staging_id}: {err}"),
+                        )
+                        .in_store()
+                    })?;
+            }
+        }
+
+        Ok(staging)
+    }
+
+    /// Replaces the data of each account that has staged data with it, then
+    /// removes the staging accounts. The staged data is read back family by
+    /// family, which scans these families for all accounts once.
+    ///
+    /// The swap is not atomic: each account is purged when the first family
+    /// reaches it and its staged families are then copied one after the
+    /// other. Until the last family was copied, a failure leaves the accounts
+    /// swapped so far with only part of their data. The staged data and the
+    /// progress files are kept in that case, so that resuming the restore
+    /// reads nothing more and runs the swap again from the start.
+    async fn swap_staged_accounts(
+        &self,
+        src: &BackupLocation,
+        staging: &Staging,
+        options: &RestoreOptions,
+    ) -> Result<BTreeSet<u32>, RestoreError> {
+        let store = &self.storage.data;
+        let swap_options = RestoreOptions {
+            batch_size: options.batch_size,
+            batch_bytes: options.batch_bytes,
+            ..Default::default()
+        };
+        let blob_stores = self.restore_blob_stores([], &swap_options);
+        let blob_store_ids = self.blob_store_ids();
+        let shared = RestoreShared::default();
+        let mut swapped = BTreeSet::new();
+
+        for (name, backup_fn) in Self::backup_families() {
+            let Some(family) = Family::parse(name).filter(|f| STAGED_FAMILIES.contains(f)) else {
+                continue;
+            };
+            #[cfg(feature = "test_mode")]
+            if options.fail_swap_at == Some(family) {
+                return Err(RestoreError::new(
+                    src,
+                    0,
+                    family,
+                    "Failed to move staged accounts into place, resume the \
+                     restore to retry: interrupted",
+                ));
+            }
+
+            let (bridge, source) = self.spawn_source(
+                name,
+                backup_fn,
+                swap_options.batch_size,
+                MemoryBudget::new(Some(swap_options.batch_bytes)),
+                blob_store_ids.clone(),
+            );
+            let (source, forward) =
+                staging.unstage(store.clone(), source, swap_options.batch_size, swapped);
+            let result = restore_ops(
+                store.clone(),
+                blob_stores.clone(),
+                source,
+                &swap_options,
+                &BTreeMap::new(),
+                &shared,
+            )
+            .await;
+            swapped = forward
+                .await
+                .map_err(|err| RestoreError::new(src, 0, Family::None, err))?;
+            bridge
+                .await
+                .map_err(|err| RestoreError::new(src, 0, Family::None, err))?
+                .map_err(|err| RestoreError::new(src, 0, family, err))?;
+            result.map_err(|err| {
+                RestoreError::new(
+                    src,
+                    0,
+                    family,
+                    format!(
+                        "Failed to move staged accounts into place, resume the \
+                         restore to retry: {}",
+                        err.cause
+                    ),
+                )
+            })?;
+        }
+
+        // Every account was swapped, a staging account left behind is purged
+        // by the next staged restore rather than swapped again on resume
+        for staging_id in staging.accounts.values() {
+            if let Err(err) = purge_staged_families(store, *staging_id).await {
+                tracing::warn!(
+                    context = "restore",
+                    event = "error",
+                    account_id = staging_id,
+                    reason = %err,
+                    "Failed to purge staging account."
+                );
+            }
+        }
+
+        tracing::info!(
+            context = "restore",
+            event = "swap",
+            accounts = swapped.len(),
+            "Replaced the data of the restored accounts with the staged data."
+        );
+
+        Ok(swapped)
+    }
+
+    /// Removes the data staged by a failed restore along with its progress
+    /// files, leaving the existing accounts as they were.
+    async fn discard_staging(&self, staging: &Staging, progress_files: &[BackupLocation]) {
+        for staging_id in staging.accounts.values() {
+            if let Err(err) = purge_staged_families(&self.storage.data, *staging_id).await {
+                tracing::warn!(
+                    context = "restore",
+                    event = "error",
+                    account_id = staging_id,
+                    reason = %err,
+                    "Failed to purge staging account."
+                );
+            }
+        }
+        for progress in progress_files {
+            let _ = progress.remove().await;
+        }
+    }
+
+    /// Checks the restored data against the store, adding any inconsistencies
+    /// found to the errors of `stats`.
+    pub(super) async fn verify_restore(&self, stats: &mut RestoreStats) -> Result<(), String> {
+        let store = &self.storage.data;
+
+        for ((account_id, collection), document_ids) in &stats.documents {
+            let stored = store
+                .get_bitmap(BitmapKey::document_ids(*account_id, *collection))
+                .await
+                .map_err(|err| format!("Failed to read document ids: {err}"))?
+                .unwrap_or_default();
+            let missing = document_ids - &stored;
+            if !missing.is_empty() {
+                stats.errors.push(format!(
+                    "Account {account_id}: {} {} documents missing from the document ids bitmap",
+                    missing.len(),
+                    Collection::from(*collection)
+                ));
+            }
+        }
+
+        for (account_id, restored) in &stats.quota_restored {
+            let stored = store
+                .get_counter(DirectoryClass::UsedQuota(*account_id))
+                .await
+                .map_err(|err| format!("Failed to read used quota: {err}"))?;
+            if stored != *restored {
+                stats.errors.push(format!(
+                    "Account {account_id}: used quota is {stored} bytes, \
+                     restored messages and scripts add up to {restored} bytes"
+                ));
+            }
+        }
+
+        let mut dangling = 0;
+        for hash in &stats.linked_blobs {
+            if !store
+                .blob_exists(hash)
+                .await
+                .map_err(|err| format!("Failed to read blob: {err}"))?
+            {
+                dangling += 1;
+            }
+        }
+        if dangling > 0 {
+            stats
+                .errors
+                .push(format!("{dangling} blob links point to uncommitted blobs"));
+        }
+
+        Ok(())
+    }
+
+    /// Checks the links left pending by each file once the whole backup was
+    /// restored, the other links were checked while restoring. Links whose
+    /// blob was neither committed by the restore nor already present in the
+    /// store are handled by `check_links`.
+    pub(super) async fn check_blob_links(
+        &self,
+        src: impl Display,
+        stats: &mut RestoreStats,
+        options: &RestoreOptions,
+    ) -> Result<(), RestoreError> {
+        let store = &self.storage.data;
+        let leading_commits = std::mem::take(&mut stats.leading_commits);
+
+        let mut batch = BatchBuilder::new();
+        for pending in std::mem::take(&mut stats.pending_links) {
+            if leading_commits.contains(&pending.0) {
+                continue;
+            }
+            check_links(store, pending, &mut batch, stats, options, |err| {
+                RestoreError::new(&src, 0, Family::Blob, err)
+            })
+            .await?;
+            if batch.ops.len() >= options.batch_size {
+                write_batch(store, std::mem::take(&mut batch).build())
+                    .await
+                    .map_err(|err| RestoreError::new(&src, 0, Family::Blob, err).in_store())?;
+            }
+        }
+        if !batch.is_empty() {
+            write_batch(store, batch.build())
+                .await
+                .map_err(|err| RestoreError::new(&src, 0, Family::Blob, err).in_store())?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops the restored ACL grants of remapped accounts whose grantee was
+    /// neither remapped, restored nor found in the store, as the grant would
+    /// otherwise go to whichever principal is assigned that id later on.
+    pub(super) async fn check_acl_grants(
+        &self,
+        src: impl Display,
+        stats: &mut RestoreStats,
+        options: &RestoreOptions,
+    ) -> Result<(), RestoreError> {
+        let store = &self.storage.data;
+        let remapped = options
+            .account_remap
+            .values()
+            .copied()
+            .collect::<AHashSet<_>>();
+
+        let mut batch = BatchBuilder::new();
+        for (grantee, grants) in std::mem::take(&mut stats.acl_grants) {
+            if remapped.contains(&grantee)
+                || stats.principals.contains(&grantee)
+                || store
+                    .get_value::<()>(ValueKey::from(ValueClass::Directory(
+                        DirectoryClass::Principal(grantee),
+                    )))
+                    .await
+                    .map_err(|err| {
+                        RestoreError::new(
+                            &src,
+                            0,
+                            Family::Acl,
+                            format!("Failed to read principal: {err}"),
+                        )
+                        .in_store()
+                    })?
+                    .is_some()
+            {
+                continue;
+            }
+
+            for (account_id, collection, document_id) in grants {
+                stats.skip(RestoreError::new(
+                    &src,
+                    0,
+                    Family::Acl,
+                    format!(
+                        "Dangling ACL grant: account {account_id}, collection {}, \
+                         document {document_id} is shared with principal {grantee} \
+                         which is neither remapped nor found in the directory",
+                        Collection::from(collection)
+                    ),
+                ));
+                if !options.dry_run {
+                    batch
+                        .with_account_id(account_id)
+                        .with_collection(collection)
+                        .update_document(document_id)
+                        .clear(ValueClass::Acl(grantee));
+                    if batch.ops.len() >= options.batch_size {
+                        write_batch(store, std::mem::take(&mut batch).build())
+                            .await
+                            .map_err(|err| {
+                                RestoreError::new(&src, 0, Family::Acl, err).in_store()
+                            })?;
+                    }
+                }
+            }
+        }
+        if !batch.is_empty() {
+            write_batch(store, batch.build())
+                .await
+                .map_err(|err| RestoreError::new(&src, 0, Family::Acl, err).in_store())?;
+        }
+
+        Ok(())
+    }
+
+    /// Looks for restored memberships and name or email mappings referencing
+    /// principals that were neither restored nor found in the store, which a
+    /// partial restore can leave behind. Dangling references are removed in
+    /// tolerant mode, reported as errors in dry-run mode and fail the restore
+    /// otherwise.
+    pub(super) async fn check_directory_refs(
+        &self,
+        src: impl Display,
+        stats: &mut RestoreStats,
+        options: &RestoreOptions,
+    ) -> Result<(), RestoreError> {
+        let store = &self.storage.data;
+
+        let mut exists = AHashMap::new();
+        let mut batch = BatchBuilder::new();
+        for (class, principal_ids) in std::mem::take(&mut stats.directory_refs) {
+            for principal_id in principal_ids {
+                if stats.principals.contains(&principal_id) {
+                    continue;
+                }
+                let found = match exists.get(&principal_id) {
+                    Some(found) => *found,
+                    None => {
+                        let found = store
+                            .get_value::<()>(ValueKey::from(ValueClass::Directory(
+                                DirectoryClass::Principal(principal_id),
+                            )))
+                            .await
+                            .map_err(|err| {
+                                RestoreError::new(
+                                    &src,
+                                    0,
+                                    Family::Directory,
+                                    format!("Failed to read principal: {err}"),
+                                )
+                                .in_store()
+                            })?
+                            .is_some();
+                        exists.insert(principal_id, found);
+                        found
+                    }
+                };
+                if found {
+                    continue;
+                }
+
+                let err = format!(
+                    "Dangling directory reference: {} references principal \
+                     {principal_id} which is missing from the backup",
+                    describe_directory_ref(&class)
+                );
+                if options.dry_run {
+                    stats.errors.push(err);
+                } else if options.tolerant {
+                    stats.skip(RestoreError::new(&src, 0, Family::Directory, err));
+                    batch.clear(ValueClass::Directory(class));
+                    if batch.ops.len() >= options.batch_size {
+                        write_batch(store, std::mem::take(&mut batch).build())
+                            .await
+                            .map_err(|err| {
+                                RestoreError::new(&src, 0, Family::Directory, err).in_store()
+                            })?;
+                    }
+                } else {
+                    return Err(RestoreError::new(&src, 0, Family::Directory, err));
+                }
+                break;
+            }
+        }
+        if !batch.is_empty() {
+            write_batch(store, batch.build())
+                .await
+                .map_err(|err| RestoreError::new(&src, 0, Family::Directory, err).in_store())?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolves the blob stores that blob contents were exported from, by the
+    /// document id they are found under, to the configured blob stores they
+    /// are restored into.
+    pub(super) fn restore_blob_stores(
+        &self,
+        exported: impl IntoIterator<Item = (u32, String)>,
+        options: &RestoreOptions,
+    ) -> BlobStores {
+        BlobStores {
+            default: self.storage.blob.clone(),
+            others: exported
+                .into_iter()
+                .map(|(document_id, id)| {
+                    let id = options.blob_store_remap.get(&id).cloned().unwrap_or(id);
+                    let blob_store = self.storage.blobs.get(&id).cloned();
+                    (document_id, (id, blob_store))
+                })
+                .collect(),
+        }
+    }
+
+    /// Looks for restored queued messages whose blob was neither committed by
+    /// the restore nor already present in the store, which would otherwise
+    /// fail on their next delivery attempt.
+    pub(super) async fn check_queued_blobs(
+        &self,
+        src: impl Display,
+        stats: &mut RestoreStats,
+        options: &RestoreOptions,
+    ) -> Result<(), RestoreError> {
+        let store = &self.storage.data;
+
+        for (hash, queue_ids) in std::mem::take(&mut stats.queued_blobs) {
+            if stats.committed_blobs.contains(&hash)
+                || store.blob_exists(&hash).await.map_err(|err| {
+                    RestoreError::new(&src, 0, Family::Blob, format!("Failed to read blob: {err}"))
+                        .in_store()
+                })?
+            {
+                continue;
+            }
+
+            for queue_id in queue_ids {
+                let err = format!(
+                    "Missing queued message blob: message {queue_id} references blob \
+                     {hash:?} which is missing from the backup"
+                );
+                if options.dry_run {
+                    stats.errors.push(err);
+                } else if options.tolerant {
+                    stats.skip(RestoreError::new(&src, 0, Family::Queue, err));
+                } else {
+                    return Err(RestoreError::new(&src, 0, Family::Queue, err));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(super) async fn write_recomputed_quotas(&self, stats: &RestoreStats) -> Result<(), String> {
+        let mut batch = BatchBuilder::new();
+        for account_id in stats.quota_stored.keys().chain(stats.quota_restored.keys()) {
+            batch.clear(DirectoryClass::UsedQuota(*account_id));
+        }
+        for (account_id, used_quota) in &stats.quota_restored {
+            batch.add(DirectoryClass::UsedQuota(*account_id), *used_quota);
+        }
+        if !batch.is_empty() {
+            write_batch(&self.storage.data, batch.build())
+                .await
+                .map_err(|err| format!("Failed to write recomputed quotas: {err}"))?;
+        }
+        Ok(())
+    }
+}
+
+fn describe_directory_ref(class: &DirectoryClass) -> String {
+    match class {
+        DirectoryClass::MemberOf {
+            principal_id,
+            member_of,
+        } => format!("membership of principal {principal_id} in {member_of}"),
+        DirectoryClass::Members {
+            principal_id,
+            has_member,
+        } => format!("member {has_member} of principal {principal_id}"),
+        DirectoryClass::NameToId(name) => {
+            format!("name {:?}", String::from_utf8_lossy(name))
+        }
+        DirectoryClass::EmailToId(email) => {
+            format!("email {:?}", String::from_utf8_lossy(email))
+        }
+        class => format!("{class:?}"),
+    }
+}
+
+/// Lists the files of a backup, skipping progress files and the manifest.
+/// Returns the shard number of a file of the log family, `log`, `log.1`, ...
+fn log_shard(file: &BackupLocation) -> Option<usize> {
+    match file_name(file)?.strip_prefix(BACKUP_FILES[10])? {
+        "" => Some(0),
+        shard => shard.strip_prefix('.')?.parse().ok(),
+    }
+}
+
+/// Name of a backup file within its directory or prefix.
+fn file_name(file: &BackupLocation) -> Option<String> {
+    match file {
+        BackupLocation::Path(path) => Some(path.file_name()?.to_str()?.to_string()),
+        BackupLocation::BlobStore { prefix, .. } => {
+            Some(prefix.rsplit('/').next().unwrap_or_default().to_string())
+        }
+        BackupLocation::Stdio => None,
+    }
+}
+
+/// Segments of a file listed in the index of the manifest, if any.
+fn file_index(manifest: Option<&BackupManifest>, file: &BackupLocation) -> Vec<FileSegment> {
+    manifest
+        .zip(file_name(file))
+        .and_then(|(manifest, name)| manifest.index.get(&name).cloned())
+        .unwrap_or_default()
+}
+
+/// Reads the manifest of a backup directory or prefix, if any.
+pub(super) async fn read_manifest(
+    src: &BackupLocation,
+) -> Result<Option<BackupManifest>, RestoreError> {
+    match src {
+        BackupLocation::Path(path) if path.is_dir() => BackupManifest::read(src).await,
+        BackupLocation::BlobStore { .. } => BackupManifest::read(src).await,
+        BackupLocation::Path(_) | BackupLocation::Stdio => Ok(None),
+    }
+    .map_err(|err| RestoreError::new(src, 0, Family::None, err).in_store())
+}
+
+/// Lists the files of a backup. Shards of a family are restored in parallel,
+/// which is safe as every shard repeats the family, account and collection it
+/// continues from and log entries carry their own change ids.
+/// Fails when the files to restore were written by different backups, which
+/// would leave the links of the documents restored from one backup pointing
+/// to blobs restored from another. Files written before version 3 have no
+/// snapshot id and are not checked, nor are streams as they can't be read
+/// twice.
+async fn check_snapshot_ids(
+    manifest: Option<&BackupManifest>,
+    files: &[BackupLocation],
+    options: &RestoreOptions,
+) -> Result<(), RestoreError> {
+    let mut expected = manifest
+        .and_then(|manifest| manifest.snapshot_id)
+        .map(|snapshot_id| (snapshot_id, MANIFEST_FILE.to_string()));
+
+    for file in files {
+        if matches!(file, BackupLocation::Stdio) {
+            continue;
+        }
+        // Files that can't be opened are reported when they are restored
+        let Some(snapshot_id) = OpReader::open(file, options)
+            .await
+            .ok()
+            .and_then(|reader| reader.snapshot_id())
+        else {
+            continue;
+        };
+
+        match &expected {
+            Some((expected_id, _)) if *expected_id == snapshot_id => (),
+            Some((expected_id, source)) => {
+                return Err(RestoreError::new(
+                    file,
+                    0,
+                    Family::None,
+                    format!(
+                        "File belongs to backup snapshot {snapshot_id:016x} but {source} \
+                         belongs to snapshot {expected_id:016x}, data and blob files of \
+                         different backups can't be restored together"
+                    ),
+                ));
+            }
+            None => expected = Some((snapshot_id, file.to_string())),
+        }
+    }
+
+    Ok(())
+}
+
+pub(super) async fn backup_files(
+    src: &BackupLocation,
+    manifest: Option<&BackupManifest>,
+) -> Result<Vec<BackupLocation>, RestoreError> {
+    let shards = manifest.map(|manifest| &manifest.shards);
+    Ok(match src {
+        BackupLocation::Path(path) if path.is_dir() => {
+            let mut files = Vec::new();
+            for entry in std::fs::read_dir(path)
+                .map_err(|err| RestoreError::new(src, 0, Family::None, err).in_store())?
+            {
+                let path = entry
+                    .map_err(|err| RestoreError::new(src, 0, Family::None, err).in_store())?
+                    .path();
+                if path.is_file()
+                    && !matches!(
+                        path.extension().and_then(|ext| ext.to_str()),
+                        Some("progress" | "tmp")
+                    )
+                    && path.file_name().and_then(|name| name.to_str()) != Some(MANIFEST_FILE)
+                {
+                    files.push(BackupLocation::Path(path));
+                }
+            }
+            files.sort_unstable_by_key(|file| file.to_string());
+
+            // A missing shard would silently drop part of a family
+            for name in shards
+                .into_iter()
+                .flat_map(|shards| shards.values().flatten())
+            {
+                if !path.join(name).is_file() {
+                    return Err(RestoreError::new(
+                        src,
+                        0,
+                        Family::None,
+                        format!("Backup file {name:?} listed in the manifest is missing"),
+                    ));
+                }
+            }
+            files
+        }
+        BackupLocation::Path(_) | BackupLocation::Stdio => vec![src.clone()],
+        BackupLocation::BlobStore { .. } => {
+            // Families that were not exported have no file, as in a directory,
+            // but a missing shard would silently drop part of a family
+            let mut files = Vec::new();
+            for name in BACKUP_FILES {
+                let (names, required) = match shards.and_then(|shards| shards.get(name)) {
+                    Some(shards) => (shards.clone(), true),
+                    None => (vec![name.to_string()], false),
+                };
+                for name in names {
+                    let file = src.join(&name);
+                    if file
+                        .exists()
+                        .await
+                        .map_err(|err| RestoreError::new(src, 0, Family::None, err).in_store())?
+                    {
+                        files.push(file);
+                    } else if required {
+                        return Err(RestoreError::new(
+                            src,
+                            0,
+                            Family::None,
+                            format!("Backup file {name:?} listed in the manifest is missing"),
+                        ));
+                    }
+                }
+            }
+            files
+        }
+    })
+}
+
+/// Result of verifying a backup file.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct VerifyReport {
+    pub file: String,
+    pub ops: BTreeMap<Family, u64>,
+    pub errors: Vec<String>,
+}
+
+/// Reads every op of every file in a backup, decoding its contents and checking
+/// blob hashes and integrity trailers, without writing anything.
+pub async fn verify_backup(src: &BackupLocation) -> Result<Vec<VerifyReport>, RestoreError> {
+    let manifest = read_manifest(src).await?;
+    let mut reports = Vec::new();
+    for file in backup_files(src, manifest.as_ref()).await? {
+        let index = file_index(manifest.as_ref(), &file);
+        reports.push(verify_file(&file, &index).await);
+    }
+    Ok(reports)
+}
+
+async fn verify_file(src: &BackupLocation, index: &[FileSegment]) -> VerifyReport {
+    let mut report = VerifyReport {
+        file: src.to_string(),
+        ..Default::default()
+    };
+    let options = RestoreOptions::default();
+    let mut reader = match OpReader::open(src, &options).await {
+        Ok(reader) => reader.with_index(index),
+        Err(err) => {
+            report.errors.push(err.to_string());
+            return report;
+        }
+    };
+    let mut cursor = Cursor::default();
+
+    while let Some(result) = reader.next().await {
+        let op = match result {
+            Ok(op) => op,
+            Err(err) => {
+                report.errors.push(err.to_string());
+                match reader.resync().await {
+                    Some(op) => op,
+                    None => break,
+                }
+            }
+        };
+
+        match op {
+            Op::Family(f) => cursor.set_family(f),
+            Op::AccountId(a) => cursor.set_account_id(a, &Staging::default()),
+            Op::Collection(c) => cursor.set_collection(c),
+            Op::DocumentId(d) => cursor.set_document_id(d),
+            Op::KeyValue((key, value)) => {
+                *report.ops.entry(cursor.family).or_default() += 1;
+                if let Err(err) = cursor
+                    .check_context()
+                    .and_then(|_| decode_key_value(&cursor, reader.version(), key, value, &options))
+                {
+                    report.errors.push(reader.op_error(err).to_string());
+                }
+            }
+        }
+    }
+
+    report
+}
+
+async fn restore_file(
+    store: Store,
+    blob_stores: BlobStores,
+    src: &BackupLocation,
+    index: &[FileSegment],
+    options: &RestoreOptions,
+    expected_ops: &BTreeMap<Family, u64>,
+    shared: &RestoreShared,
+) -> Result<RestoreStats, RestoreError> {
+    if options.cancel.is_cancelled() {
+        return Ok(RestoreStats {
+            cancelled: true,
+            ..Default::default()
+        });
+    }
+
+    let reader = OpReader::open(src, options).await?.with_index(index);
+    let version = reader.version();
+    for upgrade in pending_upgrades(version) {
+        tracing::info!(
+            context = "restore",
+            event = "upgrade",
+            file = %src,
+            version = version,
+            upgrade = upgrade.description,
+            "Upgrading values written by an older version."
+        );
+    }
+
+    let result = restore_reader(
+        store,
+        blob_stores,
+        src,
+        reader,
+        None,
+        options,
+        expected_ops,
+        shared,
+    )
+    .await;
+    IMPORT_PROGRESS.file_done(&src.to_string());
+    result
+}
+
+/// Restores the segments of an indexed file concurrently, up to the permits
+/// of `segments_limit` across all files. Files that can't be read from an
+/// offset, such as compressed ones, are restored as a whole.
+#[allow(clippy::too_many_arguments)]
+async fn restore_indexed_file(
+    store: Store,
+    blob_stores: BlobStores,
+    src: &BackupLocation,
+    segments: Vec<FileSegment>,
+    version: u8,
+    options: &RestoreOptions,
+    expected_ops: &BTreeMap<Family, u64>,
+    shared: &RestoreShared,
+    segments_limit: Arc<Semaphore>,
+) -> Result<RestoreStats, RestoreError> {
+    if options.cancel.is_cancelled() {
+        return Ok(RestoreStats {
+            cancelled: true,
+            ..Default::default()
+        });
+    }
+    if !OpReader::can_open_segments(src, version).await {
+        return restore_file(
+            store,
+            blob_stores,
+            src,
+            &segments,
+            options,
+            expected_ops,
+            shared,
+        )
+        .await;
+    }
+
+    let file_size = match src {
+        BackupLocation::Path(path) => path.metadata().map_or(0, |metadata| metadata.len()),
+        _ => 0,
+    };
+    let segments_size = segments.iter().map(|segment| segment.len).sum::<u64>();
+    let mut tasks = Vec::with_capacity(segments.len());
+    for (num, segment) in segments.into_iter().enumerate() {
+        let store = store.clone();
+        let blob_stores = blob_stores.clone();
+        let src = src.clone();
+        let options = options.clone();
+        let expected_ops = expected_ops.clone();
+        let shared = shared.clone();
+        let segments_limit = segments_limit.clone();
+        tasks.push(tokio::spawn(async move {
+            let _permit = segments_limit
+                .acquire()
+                .await
+                .map_err(|err| RestoreError::new(&src, segment.offset, segment.family, err))?;
+            if options.cancel.is_cancelled() {
+                return Ok(RestoreStats {
+                    cancelled: true,
+                    ..Default::default()
+                });
+            }
+            let reader = OpReader::open_segment(&src, &options, &segment, version).await?;
+            restore_reader(
+                store,
+                blob_stores,
+                &src,
+                reader,
+                Some((num, &segment)),
+                &options,
+                &expected_ops,
+                &shared,
+            )
+            .await
+        }));
+    }
+
+    let mut stats = RestoreStats::default();
+    let mut tasks = tasks.into_iter();
+    while let Some(task) = tasks.next() {
+        match task
+            .await
+            .map_err(|err| RestoreError::new(src, 0, Family::None, err))
+            .and_then(|result| result)
+        {
+            Ok(segment_stats) => stats.merge(segment_stats),
+            Err(err) => {
+                for task in tasks {
+                    task.abort();
+                    let _ = task.await;
+                }
+                return Err(err);
+            }
+        }
+    }
+
+    // Count the header and trailer once the file is done
+    IMPORT_PROGRESS.advance(file_size.saturating_sub(segments_size));
+    IMPORT_PROGRESS.file_done(&src.to_string());
+    Ok(stats)
+}
+
+/// Restores the ops of a file, or of one of its segments, resuming from its
+/// progress file.
+#[allow(clippy::too_many_arguments)]
+async fn restore_reader(
+    store: Store,
+    blob_stores: BlobStores,
+    src: &BackupLocation,
+    mut reader: OpReader,
+    segment: Option<(usize, &FileSegment)>,
+    options: &RestoreOptions,
+    expected_ops: &BTreeMap<Family, u64>,
+    shared: &RestoreShared,
+) -> Result<RestoreStats, RestoreError> {
+    // Segments continue from the context of the ops before them
+    let (progress, worker, mut resume_from, start, end) = match segment {
+        Some((num, segment)) => (
+            options.progress_file(src, Some(num)),
+            format!("{src}#{num}"),
+            Some(Cursor::at_segment(segment)),
+            segment.offset,
+            segment.offset + segment.len,
+        ),
+        None => (
+            options.progress_file(src, None),
+            src.to_string(),
+            None,
+            0,
+            match src {
+                BackupLocation::Path(path) => path.metadata().map_or(0, |metadata| metadata.len()),
+                _ => 0,
+            },
+        ),
+    };
+
+    // Resume from the last checkpoint, if any
+    if let Some(progress) = &progress {
+        if let Some(checkpoint) = Checkpoint::load(progress)
+            .await
+            .map_err(|err| reader.error(err))?
+        {
+            if !options.resume {
+                return Err(reader.error(format!(
+                    "Found progress file {progress} from an interrupted import, \
+                     use '--resume' to continue or delete it to start over."
+                )));
+            }
+
+            reader
+                .skip_to(checkpoint.offset, checkpoint.num_ops)
+                .await?;
+            resume_from = Some(checkpoint.cursor());
+        }
+    }
+
+    // Read ahead up to a batch worth of ops while the previous batch is being
+    // written, large values count by their size rather than as a single op
+    let (tx, ops) = op_channel(
+        options.batch_size,
+        shared.read_ahead.read_ahead(options.batch_bytes).await,
+    );
+    let position = reader.read_position();
+    let version = reader.version();
+    let tolerant = options.tolerant;
+    let skip_unknown = options.skip_unknown;
+    let compressed = reader.is_compressed();
+    let task_worker = worker.clone();
+    let task = tokio::spawn(async move {
+        let mut read_offset = start;
+        while let Some(result) = reader.next().await {
+            if let Ok(Op::AccountId(account_id)) = &result {
+                if *account_id != u32::MAX {
+                    IMPORT_PROGRESS.set_account(&task_worker, *account_id);
+                }
+            }
+
+            // Compressed files only count once read, their offsets can't be
+            // compared to the file size
+            if !compressed {
+                let offset = reader.read_position().offset;
+                IMPORT_PROGRESS.advance(offset.saturating_sub(read_offset));
+                read_offset = offset;
+            }
+
+            let result = result.map(|op| (op, reader.read_position()));
+            let is_err = result.is_err();
+            let is_skipped =
+                matches!(&result, Err(err) if tolerant || (skip_unknown && err.unknown));
+            if tx.send(result).await.is_err() || (is_err && !is_skipped) {
+                break;
+            }
+
+            if is_err && !reader.is_skipping_family() {
+                match reader.resync().await {
+                    Some(op) => {
+                        if tx.send(Ok((op, reader.read_position()))).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+        // Count the header and trailer of files once they are done
+        IMPORT_PROGRESS.advance(end.saturating_sub(read_offset));
+    });
+
+    let result = restore_ops(
+        store,
+        blob_stores,
+        OpSource {
+            name: src.to_string(),
+            version,
+            progress,
+            resume_from,
+            position,
+            ops,
+            task,
+        },
+        options,
+        expected_ops,
+        shared,
+    )
+    .await;
+    IMPORT_PROGRESS.worker_done(&worker);
+    result
+}
+
+pub(super) async fn restore_ops(
+    store: Store,
+    blob_stores: BlobStores,
+    source: OpSource,
+    options: &RestoreOptions,
+    expected_ops: &BTreeMap<Family, u64>,
+    shared: &RestoreShared,
+) -> Result<RestoreStats, RestoreError> {
+    let RestoreShared {
+        dedup,
+        staging,
+        memory,
+        ..
+    } = shared;
+    let OpSource {
+        name: src,
+        version,
+        progress,
+        resume_from,
+        mut position,
+        mut ops,
+        task,
+    } = source;
+    let mut cursor = Cursor::default();
+    let mut batch = BatchBuilder::new();
+    let mut bitmaps = MergedBitmaps::new();
+    let mut batch_bytes = 0;
+    let mut reserved = None;
+    let max_batch_bytes = max_batch_bytes(&store, options);
+    let mut stats = RestoreStats::default();
+    let mut last_change = None;
+    let mut last_document = None;
+    let mut link_check = LinkCheck::default();
+    let mut uploads = BlobUploads::new();
+
+    if let Some(resume_from) = resume_from {
+        cursor = resume_from;
+        cursor.batch_account_id = staging.account_id(cursor.family, cursor.account_id);
+        batch
+            .with_account_id(cursor.batch_account_id)
+            .with_collection(cursor.collection)
+            .update_document(cursor.document_id);
+    }
+
+    while let Some(result) = ops.recv().await {
+        // Everything before the received operation is either written along
+        // with a checkpoint or dropped, so the store is left as a resume expects
+        if options.cancel.is_cancelled() {
+            task.abort();
+            stats.cancelled = true;
+            match options.on_cancel {
+                OnCancel::Flush
+                    if !batch.is_empty() || !bitmaps.is_empty() || !uploads.is_empty() =>
+                {
+                    commit_uploads(&mut uploads, &mut batch, &src, &position).await?;
+                    flush_batch(&store, &mut batch, &mut bitmaps, &cursor)
+                        .await
+                        .map_err(|err| position.error(&src, err).in_store())?;
+                    if let Some(progress) = &progress {
+                        let (offset, num_ops) = position.after();
+                        Checkpoint::new(&cursor, offset, num_ops)
+                            .save(progress)
+                            .await
+                            .map_err(|err| position.error(&src, err))?;
+                    }
+                }
+                OnCancel::Flush => (),
+                OnCancel::Discard => {
+                    // Blobs without a commit are purged by the blob cleanup
+                    for upload in uploads.iter() {
+                        upload.abort();
+                    }
+                }
+            }
+            return Ok(stats);
+        }
+
+        let op = match result {
+            Ok((op, read_position)) => {
+                position = read_position;
+                op
+            }
+            Err(err) if options.tolerant || (options.skip_unknown && err.unknown) => {
+                stats.skip(err);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        match op {
+            Op::Family(f) => {
+                cursor.set_family(f);
+                if let Some(expected) = expected_ops.get(&f) {
+                    batch
+                        .ops
+                        .reserve((*expected as usize).min(options.batch_size));
+                }
+            }
+            Op::AccountId(a) => {
+                cursor.set_account_id(options.account_remap.get(&a).copied().unwrap_or(a), staging);
+                batch.with_account_id(cursor.batch_account_id);
+                if cursor.account_id != u32::MAX {
+                    RESTORE_METRICS.account(cursor.account_id);
+                }
+            }
+            Op::Collection(c) => {
+                cursor.set_collection(c);
+                batch.with_collection(cursor.collection);
+            }
+            Op::DocumentId(d) => {
+                cursor.set_document_id(d);
+                batch.update_document(cursor.document_id);
+            }
+            Op::KeyValue((key, value)) => {
+                let family = cursor.family;
+                // Ops found by scanning past a read error could be garbage that
+                // happens to decode, they are reported and never written
+                if position.resynced {
+                    stats.skip(
+                        position
+                            .op_error(&src, "Not restored after resynchronizing past a read error"),
+                    );
+                    continue;
+                }
+                // Queue imports leave the blob links of accounts untouched
+                if !options.restores_family(family)
+                    || (options.queue_only
+                        && family == Family::Blob
+                        && cursor.account_id != u32::MAX
+                        && cursor.document_id != u32::MAX)
+                {
+                    *stats.filtered.entry(family).or_default() += 1;
+                    continue;
+                }
+                *stats.ops.entry(family).or_default() += 1;
+                RESTORE_METRICS.op(family, key.len() + value.len());
+                let family_stats = stats.families.entry(family).or_default();
+                family_stats.bytes += (key.len() + value.len()) as u64;
+                let document = (
+                    family,
+                    cursor.account_id,
+                    cursor.collection,
+                    cursor.document_id,
+                );
+                if cursor.has_document_id
+                    && cursor.document_id != u32::MAX
+                    && last_document != Some(document)
+                {
+                    family_stats.documents += 1;
+                    last_document = Some(document);
+                }
+
+                let key_len = key.len();
+                let op_bytes = key_len + value.len();
+                let op = match cursor
+                    .check_context()
+                    .and_then(|_| decode_key_value(&cursor, version, key, value, options))
+                    .and_then(|op| check_log_order(&mut last_change, &cursor, op))
+                {
+                    Ok(op) => op,
+                    Err(err) if options.dry_run => {
+                        stats.errors.push(if position.context.is_empty() {
+                            format!(
+                                "{src}: {family:?} op #{} at offset {}: {err}",
+                                stats.ops[&family], position.op_offset
+                            )
+                        } else {
+                            format!(
+                                "{src}: {family:?} op #{} at offset {} ({}): {err}",
+                                stats.ops[&family], position.op_offset, position.context
+                            )
+                        });
+                        continue;
+                    }
+                    Err(err) if options.tolerant => {
+                        stats.skip(position.op_error(&src, err));
+                        continue;
+                    }
+                    Err(err) => return Err(position.op_error(&src, err)),
+                };
+
+                if options.recompute_quota || options.verify {
+                    match &op {
+                        RestoreOp::Add {
+                            class: ValueClass::Directory(DirectoryClass::UsedQuota(account_id)),
+                            value,
+                        } if options.recompute_quota => {
+                            *stats.quota_stored.entry(*account_id).or_default() += value;
+                            continue;
+                        }
+                        op => {
+                            if let Some(size) = restored_size(&cursor, op) {
+                                *stats.quota_restored.entry(cursor.account_id).or_default() += size;
+                            }
+                        }
+                    }
+                }
+
+                match &op {
+                    RestoreOp::Set {
+                        class: ValueClass::Blob(BlobOp::Link { hash }),
+                        ..
+                    } => {
+                        if let Some(pending) = link_check.next(hash) {
+                            settle_links(
+                                &store,
+                                dedup,
+                                pending,
+                                &mut batch,
+                                &mut stats,
+                                &cursor,
+                                options,
+                                |err| position.op_error(&src, err),
+                            )
+                            .await?;
+                        }
+                        link_check.links.push((
+                            cursor.account_id,
+                            cursor.collection,
+                            cursor.document_id,
+                        ));
+                        if options.verify {
+                            stats.linked_blobs.insert(hash.clone());
+                        }
+                    }
+                    RestoreOp::Blob { hash, .. } => {
+                        if link_check.hash.is_none() {
+                            stats.leading_commits.insert(hash.clone());
+                        }
+                        if let Some(pending) = link_check.next(hash) {
+                            settle_links(
+                                &store,
+                                dedup,
+                                pending,
+                                &mut batch,
+                                &mut stats,
+                                &cursor,
+                                options,
+                                |err| position.op_error(&src, err),
+                            )
+                            .await?;
+                        }
+                        link_check.committed = true;
+                        if options.queue_only {
+                            stats.committed_blobs.insert(hash.clone());
+                        }
+                    }
+                    RestoreOp::Set {
+                        class: ValueClass::Acl(grantee),
+                        ..
+                    } if !options.account_remap.is_empty() => {
+                        stats.acl_grants.entry(*grantee).or_default().push((
+                            cursor.account_id,
+                            cursor.collection,
+                            cursor.document_id,
+                        ));
+                    }
+                    RestoreOp::Set {
+                        class: ValueClass::Directory(class),
+                        value,
+                    } => {
+                        let principal_ids = match class {
+                            DirectoryClass::Principal(principal_id) => {
+                                stats.principals.insert(*principal_id);
+                                vec![]
+                            }
+                            DirectoryClass::MemberOf {
+                                principal_id,
+                                member_of: other_id,
+                            }
+                            | DirectoryClass::Members {
+                                principal_id,
+                                has_member: other_id,
+                            } => vec![*principal_id, *other_id],
+                            DirectoryClass::NameToId(_) | DirectoryClass::EmailToId(_) => value
+                                .as_slice()
+                                .read_leb128::<u32>()
+                                .map(|(principal_id, _)| vec![principal_id])
+                                .unwrap_or_default(),
+                            DirectoryClass::Domain(_) | DirectoryClass::UsedQuota(_) => vec![],
+                        };
+                        if !principal_ids.is_empty() {
+                            stats.directory_refs.push((class.clone(), principal_ids));
+                        }
+                    }
+                    RestoreOp::Set {
+                        class: ValueClass::Queue(QueueClass::Message(queue_id)),
+                        value,
+                    } if options.queue_only => {
+                        if let Some(hash) = queued_blob_hash(value) {
+                            stats.queued_blobs.entry(hash).or_default().push(*queue_id);
+                        }
+                    }
+                    _ if options.verify && family == Family::Property => {
+                        stats
+                            .documents
+                            .entry((cursor.account_id, cursor.collection))
+                            .or_default()
+                            .insert(cursor.document_id);
+                    }
+                    _ => (),
+                }
+
+                if options.dry_run {
+                    continue;
+                }
+
+                if !memory.try_reserve(&mut reserved, op_bytes) {
+                    // Release the memory held by this file before waiting for
+                    // the other files to release theirs
+                    if !batch.is_empty() || !bitmaps.is_empty() || !uploads.is_empty() {
+                        batch_bytes = 0;
+                        commit_uploads(&mut uploads, &mut batch, &src, &position).await?;
+                        flush_batch(&store, &mut batch, &mut bitmaps, &cursor)
+                            .await
+                            .map_err(|err| position.op_error(&src, err).in_store())?;
+
+                        // The op is not applied yet, resume from its start
+                        if let Some(progress) = &progress {
+                            let (offset, num_ops) = position.before();
+                            Checkpoint::new(&cursor, offset, num_ops)
+                                .save(progress)
+                                .await
+                                .map_err(|err| position.op_error(&src, err).in_store())?;
+                        }
+                    }
+                    reserved = None;
+                    memory.reserve(&mut reserved, op_bytes).await;
+                }
+
+                match op {
+                    RestoreOp::Set { class, value } => {
+                        batch_bytes += key_len + value.len();
+                        batch.set(class, value);
+                    }
+                    RestoreOp::Add { class, value } => {
+                        batch_bytes += key_len + std::mem::size_of_val(&value);
+                        batch.add(class, value);
+                    }
+                    RestoreOp::Index { field, key } => {
+                        batch_bytes += key.len();
+                        batch.ops.push(Operation::Index {
+                            field,
+                            key,
+                            set: true,
+                        });
+                    }
+                    RestoreOp::Bitmap {
+                        class,
+                        document_ids,
+                    } if store.supports_bitmap_merge() => {
+                        batch_bytes += key_len + document_ids.serialized_size();
+                        bitmaps.push((
+                            BitmapKey {
+                                account_id: cursor.batch_account_id,
+                                collection: cursor.collection,
+                                class,
+                                block_num: 0,
+                            },
+                            document_ids,
+                        ));
+                    }
+                    RestoreOp::Bitmap {
+                        class,
+                        document_ids,
+                    } => {
+                        for document_id in document_ids {
+                            batch.ops.push(Operation::DocumentId { document_id });
+                            batch.ops.push(Operation::Bitmap {
+                                class: class.clone(),
+                                set: true,
+                            });
+
+                            batch_bytes += key_len;
+                            if batch.ops.len() >= options.batch_size
+                                || batch_bytes >= max_batch_bytes
+                            {
+                                batch_bytes = 0;
+                                commit_uploads(&mut uploads, &mut batch, &src, &position).await?;
+                                flush_batch(&store, &mut batch, &mut bitmaps, &cursor)
+                                    .await
+                                    .map_err(|err| position.op_error(&src, err).in_store())?;
+                                reserved = None;
+
+                                // Bitmaps are idempotent, resume from the start of this op
+                                if let Some(progress) = &progress {
+                                    let (offset, num_ops) = position.before();
+                                    Checkpoint::new(&cursor, offset, num_ops)
+                                        .save(progress)
+                                        .await
+                                        .map_err(|err| position.op_error(&src, err).in_store())?;
+                                }
+                            }
+                        }
+                    }
+                    RestoreOp::Log { change_id, value } => {
+                        batch_bytes += key_len + value.len();
+                        batch.ops.push(Operation::Log {
+                            change_id,
+                            collection: cursor.collection,
+                            set: value,
+                        });
+                    }
+                    RestoreOp::Blob { hash, value } => {
+                        let blob_store = match blob_stores.get(cursor.document_id) {
+                            Ok(blob_store) => blob_store.clone(),
+                            Err(err) if options.tolerant => {
+                                stats.skip(position.op_error(&src, err));
+                                continue;
+                            }
+                            Err(err) => return Err(position.op_error(&src, err)),
+                        };
+
+                        // Shared blobs are written and committed once
+                        if dedup.contains(&hash) {
+                            stats.deduplicated_blobs += 1;
+                            continue;
+                        }
+
+                        // Uploads overlap with decoding, a blob is only committed once stored
+                        if uploads.len() >= options.blob_concurrency.max(1) {
+                            if let Some(result) = uploads.next().await {
+                                batch_bytes += commit_upload(&mut batch, result, &src, &position)?;
+                            }
+                        }
+                        // Blobs are remembered before they land so that copies are not
+                        // uploaded twice, a failed upload fails the whole import
+                        dedup.insert(hash.clone());
+                        stats.families.entry(family).or_default().blobs += 1;
+
+                        // Blobs in flight count until their commit is written
+                        batch_bytes += value.len();
+                        uploads.push(tokio::spawn(async move {
+                            blob_store
+                                .put_blob(hash.as_ref(), &value)
+                                .await
+                                .map(|_| hash)
+                                .map_err(|err| (position, format!("Failed to write blob: {err}")))
+                        }));
+                    }
+                }
+            }
+        }
+
+        if batch.ops.len() + bitmaps.len() >= options.batch_size || batch_bytes >= max_batch_bytes {
+            batch_bytes = 0;
+            // Checkpoints must not move past blobs that are not committed yet
+            commit_uploads(&mut uploads, &mut batch, &src, &position).await?;
+            flush_batch(&store, &mut batch, &mut bitmaps, &cursor)
+                .await
+                .map_err(|err| position.error(&src, err).in_store())?;
+            reserved = None;
+
+            if let Some(progress) = &progress {
+                let (offset, num_ops) = position.after();
+                Checkpoint::new(&cursor, offset, num_ops)
+                    .save(progress)
+                    .await
+                    .map_err(|err| position.error(&src, err))?;
+            }
+        }
+    }
+
+    // The commit of the last linked blob can follow in the next file or segment
+    stats.pending_links.extend(link_check.finish());
+
+    task.await.map_err(|err| position.error(&src, err))?;
+    commit_uploads(&mut uploads, &mut batch, &src, &position).await?;
+
+    if !batch.is_empty() || !bitmaps.is_empty() {
+        flush_batch(&store, &mut batch, &mut bitmaps, &cursor)
+            .await
+            .map_err(|err| position.error(&src, err).in_store())?;
+    }
+
+    // Finished files are skipped when resuming, progress files are removed
+    // once the whole restore completes
+    if let Some(progress) = progress {
+        let (offset, num_ops) = position.after();
+        Checkpoint::new(&cursor, offset, num_ops)
+            .save(&progress)
+            .await
+            .map_err(|err| position.error(&src, err))?;
+    }
+
+    Ok(stats)
+}
+
+/// Links read for the last blob hash of a file. Backups sort the links of a
+/// blob right before its commit, so the links of a hash are settled as soon
+/// as the next hash is read and only those of one blob are kept in memory.
+#[derive(Default)]
+struct LinkCheck {
+    hash: Option<BlobHash>,
+    links: Vec<(u32, u8, u32)>,
+    committed: bool,
+}
+
+impl LinkCheck {
+    /// Moves on to `hash`, returning the links of the previous hash if its
+    /// blob was not committed.
+    fn next(&mut self, hash: &BlobHash) -> Option<(BlobHash, Vec<(u32, u8, u32)>)> {
+        if self.hash.as_ref() == Some(hash) {
+            return None;
+        }
+        let links = std::mem::take(&mut self.links);
+        let committed = std::mem::take(&mut self.committed);
+        self.hash
+            .replace(hash.clone())
+            .filter(|_| !committed && !links.is_empty())
+            .map(|hash| (hash, links))
+    }
+
+    fn finish(self) -> Option<(BlobHash, Vec<(u32, u8, u32)>)> {
+        let LinkCheck {
+            hash,
+            links,
+            committed,
+        } = self;
+        hash.filter(|_| !committed && !links.is_empty())
+            .map(|hash| (hash, links))
+    }
+}
+
+/// Checks the links to a blob the file did not commit while restoring it,
+/// so that a strict restore fails before writing the rest of the backup.
+/// Blobs uploaded by another file are not checked again.
+#[allow(clippy::too_many_arguments)]
+async fn settle_links(
+    store: &Store,
+    dedup: &BlobDedup,
+    pending: (BlobHash, Vec<(u32, u8, u32)>),
+    batch: &mut BatchBuilder,
+    stats: &mut RestoreStats,
+    cursor: &Cursor,
+    options: &RestoreOptions,
+    error: impl Fn(String) -> RestoreError,
+) -> Result<(), RestoreError> {
+    if dedup.contains(&pending.0) {
+        return Ok(());
+    }
+    if check_links(store, pending, batch, stats, options, error).await? {
+        // Clearing the links moved the batch to their documents
+        batch
+            .with_account_id(cursor.batch_account_id)
+            .with_collection(cursor.collection)
+            .update_document(cursor.document_id);
+    }
+    Ok(())
+}
+
+/// Looks up the blob of links the restore did not commit. Links to a blob
+/// missing from the store are reported as errors in dry-run mode, cleared in
+/// tolerant mode and fail the restore otherwise. Returns whether the links
+/// were cleared in `batch`.
+async fn check_links(
+    store: &Store,
+    (hash, links): (BlobHash, Vec<(u32, u8, u32)>),
+    batch: &mut BatchBuilder,
+    stats: &mut RestoreStats,
+    options: &RestoreOptions,
+    error: impl Fn(String) -> RestoreError,
+) -> Result<bool, RestoreError> {
+    if store
+        .blob_exists(&hash)
+        .await
+        .map_err(|err| error(format!("Failed to read blob: {err}")).in_store())?
+    {
+        return Ok(false);
+    }
+
+    for (account_id, collection, document_id) in links {
+        let err = format!(
+            "Dangling blob link: account {account_id}, collection {}, \
+             document {document_id} links to blob {hash:?} which is \
+             missing from the backup",
+            Collection::from(collection)
+        );
+        if options.dry_run {
+            stats.errors.push(err);
+        } else if options.tolerant {
+            stats.skip(error(err));
+            batch
+                .with_account_id(account_id)
+                .with_collection(collection)
+                .update_document(document_id)
+                .clear(BlobOp::Link { hash: hash.clone() });
+        } else {
+            return Err(error(err));
+        }
+    }
+
+    Ok(!options.dry_run)
+}
+
+/// Log entries have to be applied in increasing change id order within each
+/// account and collection, as change tracking reads them back in that order.
+/// Backups list them sorted, anything else means the file was altered.
+fn check_log_order(
+    last_change: &mut Option<(u32, u8, u64)>,
+    cursor: &Cursor,
+    op: RestoreOp,
+) -> Result<RestoreOp, String> {
+    if let RestoreOp::Log { change_id, .. } = &op {
+        let change = (cursor.account_id, cursor.collection, *change_id);
+        if let Some(last) = last_change.filter(|last| last.0 == change.0 && last.1 == change.1) {
+            if last.2 >= change.2 {
+                return Err(format!(
+                    "Log entry {change_id} out of order, found after change {}",
+                    last.2
+                ));
+            }
+        }
+        *last_change = Some(change);
+    }
+    Ok(op)
+}
+
+fn decode_key_value(
+    cursor: &Cursor,
+    version: u8,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    options: &RestoreOptions,
+) -> Result<RestoreOp, String> {
+    let (key, value) = upgrade_key_value(version, cursor.family, cursor.collection, key, value)?;
+    Ok(match cursor.family {
+        Family::Property => {
+            let field = key
+                .as_slice()
+                .deserialize_u8(0)
+                .expect_op("Failed to deserialize field")?;
+            if cursor.collection == u8::from(Collection::Mailbox)
+                && u8::from(Property::EmailIds) == field
+            {
+                RestoreOp::Add {
+                    class: ValueClass::Property(field),
+                    value: i64::deserialize(&value)
+                        .expect_op("Failed to deserialize mailbox uidnext")?,
+                }
+            } else {
+                RestoreOp::Set {
+                    class: ValueClass::Property(field),
+                    value,
+                }
+            }
+        }
+        Family::TermIndex => RestoreOp::Set {
+            class: ValueClass::TermIndex,
+            value: key,
+        },
+        Family::Acl => RestoreOp::Set {
+            class: ValueClass::Acl(
+                options.remap_account_id(
+                    key.as_slice()
+                        .deserialize_be_u32(0)
+                        .expect_op("Failed to deserialize acl")?,
+                ),
+            ),
+            value,
+        },
+        Family::Blob => {
+            let hash = BlobHash::try_from_hash_slice(&key).expect_op("Invalid blob hash")?;
+
+            if cursor.account_id != u32::MAX && cursor.document_id != u32::MAX {
+                RestoreOp::Set {
+                    class: ValueClass::Blob(BlobOp::Link { hash }),
+                    value: vec![],
+                }
+            } else if BlobHash::from(value.as_slice()) != hash {
+                return Err(format!(
+                    "Blob hash mismatch: content does not match hash {hash:?}"
+                ));
+            } else {
+                RestoreOp::Blob { hash, value }
+            }
+        }
+        Family::Config => RestoreOp::Set {
+            class: ValueClass::Config(key),
+            value,
+        },
+        Family::LookupValue => RestoreOp::Set {
+            class: ValueClass::Lookup(LookupClass::Key(key)),
+            value,
+        },
+        Family::LookupCounter => RestoreOp::Add {
+            class: ValueClass::Lookup(LookupClass::Counter(key)),
+            value: i64::deserialize(&value).expect_op("Failed to deserialize counter")?,
+        },
+        Family::Directory => {
+            let key = key.as_slice();
+            let mut value = value;
+            let class = match key.first().expect_op("Failed to read directory key type")? {
+                0 => {
+                    value = options.remap_leb128_id(value, 0)?;
+                    DirectoryClass::NameToId(
+                        key.get(1..)
+                            .expect_op("Failed to read directory string")?
+                            .to_vec(),
+                    )
+                }
+                1 => {
+                    value = options.remap_leb128_id(value, 0)?;
+                    DirectoryClass::EmailToId(
+                        key.get(1..)
+                            .expect_op("Failed to read directory string")?
+                            .to_vec(),
+                    )
+                }
+                2 => {
+                    value = options.remap_leb128_id(value, 1)?;
+                    DirectoryClass::Principal(
+                        options.remap_account_id(
+                            key.get(1..)
+                                .expect_op("Failed to read range for principal id")?
+                                .deserialize_leb128()
+                                .expect_op("Failed to deserialize principal id")?,
+                        ),
+                    )
+                }
+                3 => DirectoryClass::Domain(
+                    key.get(1..)
+                        .expect_op("Failed to read directory string")?
+                        .to_vec(),
+                ),
+                4 => {
+                    return Ok(RestoreOp::Add {
+                        class: ValueClass::Directory(DirectoryClass::UsedQuota(
+                            options.remap_account_id(
+                                key.get(1..)
+                                    .expect_op("Failed to read principal id")?
+                                    .deserialize_leb128()
+                                    .expect_op("Failed to read principal id")?,
+                            ),
+                        )),
+                        value: i64::deserialize(&value).expect_op("Failed to deserialize quota")?,
+                    });
+                }
+                5 => DirectoryClass::MemberOf {
+                    principal_id: options.remap_account_id(
+                        key.deserialize_be_u32(1)
+                            .expect_op("Failed to read principal id")?,
+                    ),
+                    member_of: options.remap_account_id(
+                        key.deserialize_be_u32(1 + U32_LEN)
+                            .expect_op("Failed to read principal id")?,
+                    ),
+                },
+                6 => DirectoryClass::Members {
+                    principal_id: options.remap_account_id(
+                        key.deserialize_be_u32(1)
+                            .expect_op("Failed to read principal id")?,
+                    ),
+                    has_member: options.remap_account_id(
+                        key.deserialize_be_u32(1 + U32_LEN)
+                            .expect_op("Failed to read principal id")?,
+                    ),
+                },
+
+                _ => return Err("Invalid directory key".to_string()),
+            };
+            RestoreOp::Set {
+                class: ValueClass::Directory(class),
+                value,
+            }
+        }
+        Family::Queue => {
+            let key = key.as_slice();
+
+            match key.first().expect_op("Failed to read queue key type")? {
+                0 => RestoreOp::Set {
+                    class: ValueClass::Queue(QueueClass::Message(
+                        key.deserialize_be_u64(1)
+                            .expect_op("Failed to deserialize queue message id")?,
+                    )),
+                    value,
+                },
+                1 => RestoreOp::Set {
+                    class: ValueClass::Queue(QueueClass::MessageEvent(QueueEvent {
+                        due: options.queue_due.apply(
+                            key.deserialize_be_u64(1)
+                                .expect_op("Failed to deserialize queue message id")?,
+                        ),
+                        queue_id: key
+                            .deserialize_be_u64(1 + U64_LEN)
+                            .expect_op("Failed to deserialize queue message id")?,
+                    })),
+                    value,
+                },
+                _ => return Err("Invalid queue key".to_string()),
+            }
+        }
+        Family::Index => RestoreOp::Index {
+            field: key
+                .first()
+                .copied()
+                .expect_op("Failed to read index field")?,
+            key: key.get(1..).expect_op("Failed to read index key")?.to_vec(),
+        },
+        Family::Bitmap => {
+            let mut document_ids = RoaringBitmap::deserialize_from(&value[..])
+                .expect_op("Failed to deserialize bitmap")?;
+
+            // Principal ids are document ids of the global principal collection
+            if !options.account_remap.is_empty()
+                && cursor.account_id == u32::MAX
+                && cursor.collection == u8::from(Collection::Principal)
+            {
+                document_ids = document_ids
+                    .into_iter()
+                    .map(|id| options.remap_account_id(id))
+                    .collect();
+            }
+            let key = key.as_slice();
+            let class = match key.first().expect_op("Failed to read bitmap class")? {
+                0 => BitmapClass::DocumentIds,
+                1 => BitmapClass::Tag {
+                    field: key.get(1).copied().expect_op("Failed to read field")?,
+                    value: TagValue::Id(
+                        key.deserialize_be_u32(2)
+                            .expect_op("Failed to read tag id")?,
+                    ),
+                },
+                2 => BitmapClass::Tag {
+                    field: key.get(1).copied().expect_op("Failed to read field")?,
+                    value: TagValue::Text(
+                        key.get(2..).expect_op("Failed to read tag text")?.into(),
+                    ),
+                },
+                3 => BitmapClass::Tag {
+                    field: key.get(1).copied().expect_op("Failed to read field")?,
+                    value: TagValue::Static(
+                        key.get(2)
+                            .copied()
+                            .expect_op("Failed to read tag static id")?,
+                    ),
+                },
+                4 => BitmapClass::Text {
+                    field: key.get(1).copied().expect_op("Failed to read field")?,
+                    token: BitmapHash {
+                        len: key
+                            .get(2)
+                            .copied()
+                            .expect_op("Failed to read tag static id")?,
+                        hash: key
+                            .get(3..11)
+                            .expect_op("Failed to read tag static id")?
+                            .try_into()
+                            .expect_op("Failed to read tag static id")?,
+                    },
+                },
+                _ => return Err("Invalid bitmap class".to_string()),
+            };
+
+            RestoreOp::Bitmap {
+                class,
+                document_ids,
+            }
+        }
+        Family::Log => RestoreOp::Log {
+            change_id: key
+                .as_slice()
+                .deserialize_be_u64(0)
+                .expect_op("Failed to deserialize change id")?,
+            value,
+        },
+        Family::None => return Err("No family specified in file".to_string()),
+    })
+}
+
+/// Size counted against the account quota for a restored op, which is the raw
+/// message size for emails and the script size for Sieve scripts.
+fn restored_size(cursor: &Cursor, op: &RestoreOp) -> Option<i64> {
+    match op {
+        RestoreOp::Index { field, key }
+            if cursor.collection == u8::from(Collection::Email)
+                && *field == u8::from(Property::Size) =>
+        {
+            key.as_slice().deserialize_be_u32(0).ok().map(i64::from)
+        }
+        RestoreOp::Set {
+            class: ValueClass::Property(field),
+            value,
+        } if cursor.collection == u8::from(Collection::SieveScript)
+            && *field == u8::from(Property::Value) =>
+        {
+            match Object::<Value>::deserialize(value)
+                .ok()?
+                .get(&Property::BlobId)
+            {
+                Value::BlobId(blob_id) => blob_id.section.as_ref().map(|s| s.size as i64),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Blob uploads in flight, each resolving to the hash to commit once the
+/// blob is stored or to the position of the op that failed.
+type BlobUploads = FuturesUnordered<JoinHandle<Result<BlobHash, (ReadPosition, String)>>>;
+
+/// Adds the commit of an uploaded blob to the batch, returning its size.
+fn commit_upload(
+    batch: &mut BatchBuilder,
+    result: Result<Result<BlobHash, (ReadPosition, String)>, tokio::task::JoinError>,
+    src: &str,
+    position: &ReadPosition,
+) -> Result<usize, RestoreError> {
+    let hash = result
+        .map_err(|err| position.error(src, err))?
+        .map_err(|(position, err)| position.op_error(src, err).in_store())?;
+    RESTORE_METRICS.blob();
+    batch.set(ValueClass::Blob(BlobOp::Commit { hash }), vec![]);
+    Ok(BLOB_HASH_LEN)
+}
+
+/// Waits for all blob uploads in flight and adds their commits to the batch.
+async fn commit_uploads(
+    uploads: &mut BlobUploads,
+    batch: &mut BatchBuilder,
+    src: &str,
+    position: &ReadPosition,
+) -> Result<(), RestoreError> {
+    while let Some(result) = uploads.next().await {
+        commit_upload(batch, result, src, position)?;
+    }
+    Ok(())
+}
+
+/// Whole bitmaps read since the last flush, merged into stores that support it
+/// once the batch they were read along with is written.
+type MergedBitmaps = Vec<(BitmapKey<BitmapClass>, RoaringBitmap)>;
+
+async fn flush_batch(
+    store: &Store,
+    batch: &mut BatchBuilder,
+    bitmaps: &mut MergedBitmaps,
+    cursor: &Cursor,
+) -> Result<(), String> {
+    if !batch.is_empty() {
+        write_batch(store, std::mem::take(batch).build()).await?;
+    }
+    // Merging a bitmap again is harmless, a resume can replay them
+    for (key, document_ids) in std::mem::take(bitmaps) {
+        retry_write(|| store.merge_bitmap(key.clone(), &document_ids))
+            .await
+            .map_err(|(err, _)| format!("Failed to write bitmap: {err}"))?;
+        RESTORE_METRICS.batch();
+    }
+    batch
+        .with_account_id(cursor.batch_account_id)
+        .with_collection(cursor.collection)
+        .update_document(cursor.document_id);
+    Ok(())
+}
+
+/// Size of the keys and values after which a batch is written, kept to half
+/// the transaction size limit of the store as the keys written for an op,
+/// such as its indexes, take more room than the op itself.
+fn max_batch_bytes(store: &Store, options: &RestoreOptions) -> usize {
+    match store.max_write_bytes() {
+        Some(limit) if limit / 2 < options.batch_bytes => {
+            tracing::debug!(
+                context = "restore",
+                event = "limit",
+                limit = limit,
+                "Store limits the size of its transactions, writing batches of at most {} bytes.",
+                limit / 2
+            );
+            limit / 2
+        }
+        _ => options.batch_bytes,
+    }
+}
+
+/// Writes a batch, retrying with exponential backoff when the store fails with
+/// a transient error and splitting it in half when it is rejected for being too
+/// large. Errors that leave the outcome of a commit unknown are not retried, as
+/// counters would be added twice.
+async fn write_batch(store: &Store, batch: Batch) -> Result<(), String> {
+    let mut pending = vec![batch.ops];
+
+    while let Some(ops) = pending.pop() {
+        match retry_write(|| store.write(Batch { ops: ops.clone() })).await {
+            Ok(_) => RESTORE_METRICS.batch(),
+            Err((err, WriteError::TooLarge)) if ops.len() > 1 => {
+                tracing::debug!(
+                    context = "restore",
+                    event = "split",
+                    ops = ops.len(),
+                    "Batch rejected, splitting it in half: {err}"
+                );
+                let (first, second) = split_ops(ops);
+                pending.push(second);
+                pending.push(first);
+            }
+            Err((err, _)) => return Err(format!("Failed to write batch: {err}")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs a write, retrying with exponential backoff while the store fails with
+/// a transient error, and returns the last error along with its kind otherwise.
+async fn retry_write<T, F, Fut>(mut write: F) -> Result<T, (store::Error, WriteError)>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = store::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match write().await {
+            Ok(result) => return Ok(result),
+            Err(err) => match WriteError::from(&err) {
+                WriteError::Transient if attempt < WRITE_ATTEMPTS => {
+                    let delay = WRITE_BACKOFF * 2u32.pow(attempt);
+                    attempt += 1;
+                    tracing::warn!(
+                        context = "restore",
+                        event = "retry",
+                        attempt = attempt,
+                        "Failed to write batch, retrying in {delay:?}: {err}"
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                kind => return Err((err, kind)),
+            },
+        }
+    }
+}
+
+const WRITE_ATTEMPTS: u32 = 5;
+const WRITE_BACKOFF: Duration = Duration::from_millis(250);
+
+#[derive(Debug, PartialEq, Eq)]
+enum WriteError {
+    Transient,
+    TooLarge,
+    Fatal,
+}
+
+impl From<&store::Error> for WriteError {
+    fn from(err: &store::Error) -> Self {
+        let store::Error::InternalError(message) = err else {
+            return WriteError::Fatal;
+        };
+        let message = message.to_lowercase();
+
+        if [
+            "exceeds byte limit",
+            "too large",
+            "too many sql variables",
+            "max_allowed_packet",
+        ]
+        .iter()
+        .any(|pattern| message.contains(pattern))
+        {
+            WriteError::TooLarge
+        } else if message.contains("unknown result") {
+            WriteError::Fatal
+        } else if [
+            "too old",
+            "conflict",
+            "database is locked",
+            "deadlock",
+            "could not serialize",
+        ]
+        .iter()
+        .any(|pattern| message.contains(pattern))
+        {
+            WriteError::Transient
+        } else {
+            WriteError::Fatal
+        }
+    }
+}
+
+/// Splits a list of operations in half, repeating the account, collection and
+/// document the second half continues from.
+fn split_ops(mut ops: Vec<Operation>) -> (Vec<Operation>, Vec<Operation>) {
+    let tail = ops.split_off(ops.len() / 2);
+    let mut account_id = None;
+    let mut collection = None;
+    let mut document_id = None;
+    for op in &ops {
+        match op {
+            Operation::AccountId { .. } => account_id = Some(op.clone()),
+            Operation::Collection { .. } => collection = Some(op.clone()),
+            Operation::DocumentId { .. } => document_id = Some(op.clone()),
+            _ => (),
+        }
+    }
+
+    let mut second = Vec::with_capacity(tail.len() + 3);
+    second.extend(account_id);
+    second.extend(collection);
+    second.extend(document_id);
+    second.extend(tail);
+    (ops, second)
+}
+
+impl Checkpoint {
+    fn new(cursor: &Cursor, offset: u64, num_ops: u64) -> Self {
+        Self {
+            offset,
+            num_ops,
+            account_id: cursor.account_id,
+            collection: cursor.collection,
+            document_id: cursor.document_id,
+            family: cursor.family as u8,
+        }
+    }
+
+    async fn load(location: &BackupLocation) -> Result<Option<Self>, String> {
+        match location.read().await? {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|err| format!("Failed to parse progress file {location}: {err}")),
+            None => Ok(None),
+        }
+    }
+
+    async fn save(&self, location: &BackupLocation) -> Result<(), String> {
+        location
+            .write(
+                &serde_json::to_vec(self)
+                    .map_err(|err| format!("Failed to serialize progress: {err}"))?,
+            )
+            .await
+    }
+
+    /// Checkpoints are only taken at valid positions, so the context they
+    /// restore counts as read.
+    fn cursor(&self) -> Cursor {
+        Cursor {
+            account_id: self.account_id,
+            batch_account_id: self.account_id,
+            collection: self.collection,
+            document_id: self.document_id,
+            family: Family::try_from(self.family).unwrap_or(Family::None),
+            has_account_id: true,
+            has_collection: true,
+            has_document_id: true,
+        }
+    }
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Self {
+            account_id: u32::MAX,
+            batch_account_id: u32::MAX,
+            collection: u8::MAX,
+            document_id: u32::MAX,
+            family: Family::None,
+            has_account_id: false,
+            has_collection: false,
+            has_document_id: false,
+        }
+    }
+}
+
+impl Cursor {
+    /// Context at the start of a segment, the account id op it starts with
+    /// is read as part of it.
+    fn at_segment(segment: &FileSegment) -> Self {
+        let mut cursor = Cursor::default();
+        cursor.set_family(segment.family);
+        if let Some(collection) = segment.collection {
+            cursor.set_collection(collection);
+        }
+        if let Some(document_id) = segment.document_id {
+            cursor.set_document_id(document_id);
+        }
+        cursor
+    }
+
+    fn set_family(&mut self, family: Family) {
+        *self = Cursor {
+            family,
+            ..Default::default()
+        };
+    }
+
+    fn set_account_id(&mut self, account_id: u32, staging: &Staging) {
+        self.account_id = account_id;
+        self.batch_account_id = staging.account_id(self.family, account_id);
+        self.has_account_id = true;
+    }
+
+    fn set_collection(&mut self, collection: u8) {
+        self.collection = collection;
+        self.has_collection = true;
+    }
+
+    fn set_document_id(&mut self, document_id: u32) {
+        self.document_id = document_id;
+        self.has_document_id = true;
+    }
+
+    /// Checks that the context required by the family of a key value was set
+    /// since the family started.
+    fn check_context(&self) -> Result<(), String> {
+        let (account_id, collection, document_id) = match self.family {
+            Family::Property | Family::TermIndex | Family::Acl | Family::Index => {
+                (true, true, true)
+            }
+            Family::Bitmap | Family::Log => (true, true, false),
+            Family::Blob => {
+                // Links belong to a document, contents to account u32::MAX and either
+                // document u32::MAX or the one selecting the blob store they came from
+                if self.has_account_id
+                    && self.has_document_id
+                    && self.account_id != u32::MAX
+                    && self.document_id == u32::MAX
+                {
+                    return Err(format!(
+                        "Blob with account id {} and document id {} is neither \
+                         a link nor a blob",
+                        self.account_id, self.document_id
+                    ));
+                }
+                (true, self.account_id != u32::MAX, true)
+            }
+            Family::Config
+            | Family::LookupValue
+            | Family::LookupCounter
+            | Family::Directory
+            | Family::Queue => return Ok(()),
+            Family::None => return Err("No family specified in file".to_string()),
+        };
+
+        let missing = if account_id && !self.has_account_id {
+            "account id"
+        } else if collection && !self.has_collection {
+            "collection"
+        } else if document_id && !self.has_document_id {
+            "document id"
+        } else {
+            return Ok(());
+        };
+        Err(format!(
+            "{:?} value found before its {missing}",
+            self.family
+        ))
+    }
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: DEFAULT_BATCH_SIZE,
+            batch_bytes: DEFAULT_BATCH_BYTES,
+            dry_run: false,
+            resume: false,
+            progress_dir: None,
+            tolerant: false,
+            recompute_quota: false,
+            account_remap: AHashMap::new(),
+            unmapped_accounts: Default::default(),
+            blob_store_remap: AHashMap::new(),
+            verify: false,
+            families: None,
+            queue_due: QueueDue::Keep,
+            queue_only: false,
+            blob_dedup_limit: DEFAULT_BLOB_DEDUP_LIMIT,
+            mmap: false,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            blob_concurrency: DEFAULT_BLOB_CONCURRENCY,
+            cancel: CancellationToken::new(),
+            on_cancel: OnCancel::Flush,
+            skip_unknown: false,
+            stage_accounts: false,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            memory_budget: None,
+            segment_concurrency: DEFAULT_SEGMENT_CONCURRENCY,
+            merge: false,
+            #[cfg(feature = "test_mode")]
+            fail_swap_at: None,
+        }
+    }
+}
+
+impl OnCancel {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "flush" => Some(OnCancel::Flush),
+            "discard" => Some(OnCancel::Discard),
+            _ => None,
+        }
+    }
+}
+
+impl QueueDue {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "keep" => Some(QueueDue::Keep),
+            "now" => Some(QueueDue::Now),
+            _ => Duration::parse_value(value.strip_prefix('+')?)
+                .ok()
+                .map(|offset| QueueDue::Offset(offset.as_secs())),
+        }
+    }
+
+    fn apply(&self, due: u64) -> u64 {
+        match self {
+            QueueDue::Keep => due,
+            QueueDue::Now => now(),
+            QueueDue::Offset(offset) => due.saturating_add(*offset),
+        }
+    }
+}
+
+impl RestoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn set_batch_bytes(mut self, batch_bytes: usize) -> Self {
+        self.batch_bytes = batch_bytes;
+        self
+    }
+
+    pub fn set_blob_concurrency(mut self, blob_concurrency: usize) -> Self {
+        self.blob_concurrency = blob_concurrency;
+        self
+    }
+
+    pub fn set_read_buffer_size(mut self, read_buffer_size: usize) -> Self {
+        self.read_buffer_size = read_buffer_size;
+        self
+    }
+
+    pub fn set_blob_dedup_limit(mut self, blob_dedup_limit: usize) -> Self {
+        self.blob_dedup_limit = blob_dedup_limit;
+        self
+    }
+
+    pub fn set_families(mut self, families: impl IntoIterator<Item = Family>) -> Self {
+        self.families = Some(families.into_iter().collect());
+        self
+    }
+
+    pub fn set_memory_budget(mut self, memory_budget: usize) -> Self {
+        self.memory_budget = Some(memory_budget);
+        self
+    }
+
+    pub fn set_progress_interval(mut self, progress_interval: Duration) -> Self {
+        self.progress_interval = progress_interval;
+        self
+    }
+
+    pub fn set_segment_concurrency(mut self, segment_concurrency: usize) -> Self {
+        self.segment_concurrency = segment_concurrency;
+        self
+    }
+
+    pub fn set_queue_due(mut self, queue_due: QueueDue) -> Self {
+        self.queue_due = queue_due;
+        self
+    }
+
+    pub fn set_cancel(mut self, cancel: CancellationToken, on_cancel: OnCancel) -> Self {
+        self.cancel = cancel;
+        self.on_cancel = on_cancel;
+        self
+    }
+
+    pub fn remap_account(mut self, old: u32, new: u32) -> Self {
+        self.account_remap.insert(old, new);
+        self
+    }
+
+    pub fn remap_blob_store(mut self, old: impl Into<String>, new: impl Into<String>) -> Self {
+        self.blob_store_remap.insert(old.into(), new.into());
+        self
+    }
+
+    pub fn dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+
+    pub fn resume(mut self) -> Self {
+        self.resume = true;
+        self
+    }
+
+    pub fn set_progress_dir(mut self, progress_dir: impl Into<PathBuf>) -> Self {
+        self.progress_dir = Some(progress_dir.into());
+        self
+    }
+
+    /// Progress file of a backup file, or of one of its segments, which dry
+    /// runs have none of as they write nothing to resume.
+    fn progress_file(
+        &self,
+        file: &BackupLocation,
+        segment: Option<usize>,
+    ) -> Option<BackupLocation> {
+        let suffix = match segment {
+            Some(num) => format!(".{num}.progress"),
+            None => ".progress".to_string(),
+        };
+        match (&self.progress_dir, file_name(file)) {
+            _ if self.dry_run => None,
+            (Some(dir), Some(name)) => {
+                Some(BackupLocation::Path(dir.join(format!("{name}{suffix}"))))
+            }
+            _ => Some(file.with_suffix(&suffix)),
+        }
+    }
+
+    pub fn merge(mut self) -> Self {
+        self.merge = true;
+        self
+    }
+
+    pub fn tolerant(mut self) -> Self {
+        self.tolerant = true;
+        self
+    }
+
+    pub fn mmap(mut self) -> Self {
+        self.mmap = true;
+        self
+    }
+
+    pub fn skip_unknown(mut self) -> Self {
+        self.skip_unknown = true;
+        self
+    }
+
+    pub fn stage_accounts(mut self) -> Self {
+        self.stage_accounts = true;
+        self
+    }
+
+    #[cfg(feature = "test_mode")]
+    pub fn fail_swap_at(mut self, family: Family) -> Self {
+        self.fail_swap_at = Some(family);
+        self
+    }
+
+    pub fn recompute_quota(mut self) -> Self {
+        self.recompute_quota = true;
+        self
+    }
+
+    pub fn verify(mut self) -> Self {
+        self.verify = true;
+        self
+    }
+
+    pub fn queue_only(mut self) -> Self {
+        self.queue_only = true;
+        self
+    }
+
+    pub(super) fn restores_family(&self, family: Family) -> bool {
+        if self.queue_only {
+            return matches!(family, Family::Queue | Family::Blob);
+        }
+        self.families
+            .as_ref()
+            .map_or(true, |families| families.contains(&family))
+    }
+
+    /// Translates an account id embedded in a key or value. Ids missing from a
+    /// non-empty remap table are kept as they are, and a warning is logged
+    /// the first time each one is found.
+    pub(super) fn remap_account_id(&self, account_id: u32) -> u32 {
+        if self.account_remap.is_empty() || account_id == u32::MAX {
+            account_id
+        } else if let Some(new_account_id) = self.account_remap.get(&account_id) {
+            *new_account_id
+        } else {
+            let mut unmapped = self.unmapped_accounts.lock();
+            if unmapped.len() < MAX_UNMAPPED_WARNINGS && unmapped.insert(account_id) {
+                tracing::warn!(
+                    context = "restore",
+                    event = "remap",
+                    account_id = account_id,
+                    "Account id referenced in backup not found in remap table, keeping original id."
+                );
+                if unmapped.len() == MAX_UNMAPPED_WARNINGS {
+                    tracing::warn!(
+                        context = "restore",
+                        event = "remap",
+                        "Further account ids not found in the remap table are not logged."
+                    );
+                }
+            }
+            account_id
+        }
+    }
+
+    /// Translates a LEB128 encoded account id found at `offset` in a value.
+    fn remap_leb128_id(&self, value: Vec<u8>, offset: usize) -> Result<Vec<u8>, String> {
+        if self.account_remap.is_empty() {
+            return Ok(value);
+        }
+
+        let (account_id, len) = value
+            .get(offset..)
+            .and_then(|bytes| bytes.read_leb128::<u32>())
+            .expect_op("Failed to read principal id")?;
+        let mut remapped = Vec::with_capacity(value.len() + 1);
+        remapped.extend_from_slice(&value[..offset]);
+        remapped.push_leb128(self.remap_account_id(account_id));
+        remapped.extend_from_slice(&value[offset + len..]);
+        Ok(remapped)
+    }
+}
+
+impl RestoreStats {
+    pub fn merge(&mut self, other: RestoreStats) {
+        for (family, count) in other.ops {
+            *self.ops.entry(family).or_default() += count;
+        }
+        self.errors.extend(other.errors);
+        for (key, document_ids) in other.documents {
+            *self.documents.entry(key).or_default() |= document_ids;
+        }
+        self.pending_links.extend(other.pending_links);
+        self.leading_commits.extend(other.leading_commits);
+        self.linked_blobs.extend(other.linked_blobs);
+        self.committed_blobs.extend(other.committed_blobs);
+        for (grantee, grants) in other.acl_grants {
+            self.acl_grants.entry(grantee).or_default().extend(grants);
+        }
+        self.principals.extend(other.principals);
+        self.directory_refs.extend(other.directory_refs);
+        self.deduplicated_blobs += other.deduplicated_blobs;
+        self.cancelled |= other.cancelled;
+        self.swapped_accounts.extend(other.swapped_accounts);
+        for (family, totals) in other.families {
+            let family_stats = self.families.entry(family).or_default();
+            family_stats.bytes += totals.bytes;
+            family_stats.blobs += totals.blobs;
+            family_stats.documents += totals.documents;
+        }
+        for (hash, queue_ids) in other.queued_blobs {
+            self.queued_blobs.entry(hash).or_default().extend(queue_ids);
+        }
+        for (family, count) in other.filtered {
+            *self.filtered.entry(family).or_default() += count;
+        }
+        for (account_id, used_quota) in other.quota_stored {
+            *self.quota_stored.entry(account_id).or_default() += used_quota;
+        }
+        for (account_id, used_quota) in other.quota_restored {
+            *self.quota_restored.entry(account_id).or_default() += used_quota;
+        }
+        for (family, reasons) in other.skipped {
+            let skipped = self.skipped.entry(family).or_default();
+            for (reason, count) in reasons {
+                *skipped.entry(reason).or_default() += count;
+            }
+        }
+    }
+
+    fn skip(&mut self, err: RestoreError) {
+        tracing::warn!(
+            context = "restore",
+            event = "skip",
+            file = err.file,
+            offset = err.offset,
+            family = ?err.family,
+            reason = err.cause,
+            "{}",
+            if err.unknown {
+                "Skipping unknown operation."
+            } else {
+                "Skipping corrupt operation."
+            }
+        );
+
+        // Group by the error message, leaving out the details of the underlying cause
+        let reason = err
+            .cause
+            .split_once(": ")
+            .map_or(err.cause.as_str(), |(reason, _)| reason)
+            .to_string();
+        *self
+            .skipped
+            .entry(err.family)
+            .or_default()
+            .entry(reason)
+            .or_default() += 1;
+    }
+}
+
+trait ExpectOp<T> {
+    fn expect_op(self, message: &str) -> Result<T, String>;
+}
+
+impl<T> ExpectOp<T> for Option<T> {
+    fn expect_op(self, message: &str) -> Result<T, String> {
+        self.ok_or_else(|| message.to_string())
+    }
+}
+
+impl<T, E: Display> ExpectOp<T> for Result<T, E> {
+    fn expect_op(self, message: &str) -> Result<T, String> {
+        self.map_err(|err| format!("{message}: {err}"))
+    }
+}
+
+/// Stream of the operations stored in a backup file.
+///
+/// Read errors are returned as `Some(Err(..))` and do not end the stream,
+/// callers may skip past them with [`OpReader::resync`].
+pub struct OpReader {
+    decoder: Option<OpDecoder>,
+    pending: Option<PendingOp>,
+    is_done: bool,
+}
+
+/// Position of an op within a backup file.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadPosition {
+    pub family: Family,
+    pub offset: u64,
+    pub op_offset: u64,
+    pub num_ops: u64,
+    pub context: OpContext,
+    /// Whether the op was found by scanning for a family after a read error
+    /// rather than at an offset listed in the index of the file.
+    pub resynced: bool,
+}
+
+pub(super) type OpResult = Result<(Op, ReadPosition), RestoreError>;
+
+/// Ops to restore, along with the task producing them.
+pub(super) struct OpSource {
+    pub name: String,
+    /// File version the ops were written with, older values are upgraded.
+    pub version: u8,
+    pub progress: Option<BackupLocation>,
+    pub resume_from: Option<Cursor>,
+    pub position: ReadPosition,
+    pub ops: OpReceiver,
+    pub task: JoinHandle<()>,
+}
+
+/// Ops queued for `restore_ops`, along with the memory they take up.
+type QueuedOp = (OpResult, Option<OwnedSemaphorePermit>);
+
+/// Channel bounded both by the number of ops queued and by the size of their
+/// keys and values, taken from `memory` until each op is received.
+pub(super) fn op_channel(capacity: usize, memory: MemoryBudget) -> (OpSender, OpReceiver) {
+    let (tx, rx) = mpsc::channel(capacity);
+    (OpSender { tx, memory }, OpReceiver { rx })
+}
+
+pub(super) struct OpSender {
+    tx: mpsc::Sender<QueuedOp>,
+    memory: MemoryBudget,
+}
+
+pub(super) struct OpReceiver {
+    rx: mpsc::Receiver<QueuedOp>,
+}
+
+impl OpSender {
+    /// Queues an op once the memory it takes up is available.
+    pub async fn send(&self, result: OpResult) -> Result<(), SendError<OpResult>> {
+        let mut reserved = None;
+        if let Ok((Op::KeyValue((key, value)), _)) = &result {
+            self.memory
+                .reserve(&mut reserved, key.len() + value.len())
+                .await;
+        }
+        self.tx
+            .send((result, reserved))
+            .await
+            .map_err(|SendError((result, _))| SendError(result))
+    }
+
+    pub fn blocking_send(&self, result: OpResult) -> Result<(), SendError<OpResult>> {
+        futures::executor::block_on(self.send(result))
+    }
+}
+
+impl OpReceiver {
+    /// Receives the next op, releasing the memory it took up while queued.
+    pub async fn recv(&mut self) -> Option<OpResult> {
+        self.rx.recv().await.map(|(result, _)| result)
+    }
+}
+
+type PendingOp = BoxFuture<'static, (OpDecoder, Result<Option<Op>, RestoreError>)>;
+
+struct OpDecoder {
+    file: OpInput,
+    src: BackupLocation,
+    compression: Option<Compression>,
+    version: u8,
+    /// Snapshot id of the backup the file belongs to, from version 3.
+    snapshot_id: Option<u64>,
+    family: Family,
+    context: OpContext,
+    /// Unknown family whose ops are being skipped, with `skip_unknown`.
+    unknown_family: Option<u8>,
+    skip_unknown: bool,
+    hasher: blake3::Hasher,
+    num_ops: u64,
+    offset: u64,
+    op_offset: u64,
+    /// End of the segment being read on its own, whose hash is verified in
+    /// place of the trailer of the file.
+    segment_end: Option<SegmentEnd>,
+    /// Segments of the index starting after the current offset, which read
+    /// errors resynchronize to.
+    sync_points: VecDeque<FileSegment>,
+    /// Context ops of the segment resynchronized to, returned before the
+    /// next op is read.
+    queued: VecDeque<Op>,
+    /// Whether reading continues from a family found by scanning past a read
+    /// error, whose ops can't be told apart from garbage that decodes.
+    resynced: bool,
+}
+
+/// Bytes ops are decoded from, either a stream or a memory-mapped file
+/// whose keys and values are hashed in place and copied once into the op.
+enum OpInput {
+    Stream(Box<dyn AsyncRead + Unpin + Send>),
+    Mapped(std::io::Cursor<Mmap>),
+}
+
+struct SegmentEnd {
+    offset: u64,
+    num_ops: u64,
+    hash: String,
+}
+
+impl OpReader {
+    pub async fn open(
+        src: &BackupLocation,
+        options: &RestoreOptions,
+    ) -> Result<Self, RestoreError> {
+        OpDecoder::new(src, options).await.map(|decoder| Self {
+            decoder: Some(decoder),
+            pending: None,
+            is_done: false,
+        })
+    }
+
+    /// Opens a segment of an indexed file, positioned at its start.
+    pub async fn open_segment(
+        src: &BackupLocation,
+        options: &RestoreOptions,
+        segment: &FileSegment,
+        version: u8,
+    ) -> Result<Self, RestoreError> {
+        OpDecoder::new_segment(src, options, segment, version)
+            .await
+            .map(|decoder| Self {
+                decoder: Some(decoder),
+                pending: None,
+                is_done: false,
+            })
+    }
+
+    /// Whether the segments of a file can be read on their own, which needs
+    /// a file on disk written with the version of the manifest and not
+    /// compressed since.
+    pub async fn can_open_segments(src: &BackupLocation, version: u8) -> bool {
+        let BackupLocation::Path(path) = src else {
+            return false;
+        };
+        let mut header = [0u8; 2];
+        match File::open(path).await {
+            Ok(mut file) => {
+                file.read_exact(&mut header).await.is_ok()
+                    && header == [MAGIC_MARKER, version]
+                    && version >= 3
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn decoder(&self) -> &OpDecoder {
+        self.decoder
+            .as_ref()
+            .expect("OpReader accessed while an operation is being read")
+    }
+
+    /// File version the ops are read from.
+    pub fn version(&self) -> u8 {
+        self.decoder().version
+    }
+
+    /// Snapshot id of the backup the file belongs to, files written before
+    /// version 3 have none.
+    pub fn snapshot_id(&self) -> Option<u64> {
+        self.decoder().snapshot_id
+    }
+
+    fn decoder_mut(&mut self) -> &mut OpDecoder {
+        self.decoder
+            .as_mut()
+            .expect("OpReader accessed while an operation is being read")
+    }
+
+    /// Whether the file is decompressed while it is read, in which case
+    /// offsets refer to the decompressed contents.
+    pub fn is_compressed(&self) -> bool {
+        self.decoder().compression.is_some()
+    }
+
+    /// Position of the last op read.
+    pub fn read_position(&self) -> ReadPosition {
+        let decoder = self.decoder();
+        ReadPosition {
+            family: decoder.family,
+            offset: decoder.offset,
+            op_offset: decoder.op_offset,
+            num_ops: decoder.num_ops,
+            context: decoder.context,
+            resynced: decoder.resynced,
+        }
+    }
+
+    pub async fn skip_to(&mut self, offset: u64, num_ops: u64) -> Result<(), RestoreError> {
+        self.decoder_mut().skip_to(offset, num_ops).await
+    }
+
+    /// Sets the segments of the file listed in the manifest index, the only
+    /// offsets read errors resynchronize to where ops are known to start.
+    pub fn with_index(mut self, segments: &[FileSegment]) -> Self {
+        let decoder = self.decoder_mut();
+        decoder.sync_points = segments
+            .iter()
+            .filter(|segment| segment.offset > decoder.offset)
+            .cloned()
+            .collect();
+        self
+    }
+
+    /// Whether the ops of an unknown family are being skipped, in which case
+    /// reading continues after an error without resynchronizing.
+    pub fn is_skipping_family(&self) -> bool {
+        self.decoder().unknown_family.is_some()
+    }
+
+    /// Skips past a read error to the next segment of the index or, past the
+    /// last one, to the next family marker followed by an op that decodes.
+    /// Ops read after scanning for a family are flagged as `resynced` in
+    /// their position. Returns `None` once the end of the file is reached.
+    pub async fn resync(&mut self) -> Option<Op> {
+        let op = self.decoder_mut().resync().await;
+        self.is_done = op.is_none();
+        op
+    }
+
+    /// Error at the current read position.
+    pub fn error(&self, cause: impl Display) -> RestoreError {
+        self.decoder().error(cause)
+    }
+
+    /// Error at the start of the last op read.
+    pub fn op_error(&self, cause: impl Display) -> RestoreError {
+        self.decoder().op_error(cause)
+    }
+}
+
+impl Stream for OpReader {
+    type Item = Result<Op, RestoreError>;
 
-impl Core {
-    pub async fn restore(&self, src: PathBuf) {
-        // Backup the core
-        if src.is_dir() {
-            // Iterate directory and spawn a task for each file
-            let mut tasks = Vec::new();
-            for entry in std::fs::read_dir(&src).failed("Failed to read directory") {
-                let entry = entry.failed("Failed to read entry");
-                let path = entry.path();
-                if path.is_file() {
-                    let storage = self.storage.clone();
-                    let blob_store = self.storage.blob.clone();
-                    tasks.push(tokio::spawn(async move {
-                        restore_file(storage.data, blob_store, &path).await;
-                    }));
-                }
-            }
-
-            for task in tasks {
-                task.await.failed("Failed to wait for task");
-            }
-        } else {
-            restore_file(self.storage.data.clone(), self.storage.blob.clone(), &src).await;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.is_done {
+            return Poll::Ready(None);
         }
+
+        // The decoder is moved into the pending read and handed back once it completes
+        let pending = match &mut this.pending {
+            Some(pending) => pending,
+            pending => {
+                let mut decoder = this
+                    .decoder
+                    .take()
+                    .expect("OpReader polled after a read was dropped");
+                pending.insert(Box::pin(async move {
+                    let result = decoder.read_op().await;
+                    (decoder, result)
+                }))
+            }
+        };
+        let (decoder, result) = ready!(pending.as_mut().poll(cx));
+        this.pending = None;
+        this.decoder = Some(decoder);
+        this.is_done = matches!(result, Ok(None));
+
+        Poll::Ready(result.transpose())
     }
 }
 
-async fn restore_file(store: Store, blob_store: BlobStore, path: &Path) {
-    let mut reader = OpReader::new(path).await;
-    let mut account_id = u32::MAX;
-    let mut document_id = u32::MAX;
-    let mut collection = u8::MAX;
-    let mut family = Family::None;
+impl OpDecoder {
+    async fn new(src: &BackupLocation, options: &RestoreOptions) -> Result<Self, RestoreError> {
+        let error = |cause: String| RestoreError::new(src, 0, Family::None, cause);
+        // Compressed files are decompressed as a stream even when they can be mapped
+        let mapped = match src {
+            BackupLocation::Path(path) if options.mmap => {
+                map_file(path).filter(|map| Compression::detect(map).is_none())
+            }
+            _ => None,
+        };
+        let (mut file, compression) = match mapped {
+            Some(map) => (OpInput::Mapped(std::io::Cursor::new(map)), None),
+            None => Self::open_stream(src, options).await?,
+        };
 
-    let mut batch = BatchBuilder::new();
+        if file
+            .read_u8()
+            .await
+            .map_err(|err| error(format!("Failed to read magic marker: {err}")))?
+            != MAGIC_MARKER
+        {
+            return Err(error("Invalid magic marker".to_string()));
+        }
 
-    while let Some(op) = reader.next().await {
-        match op {
-            Op::Family(f) => family = f,
-            Op::AccountId(a) => {
-                account_id = a;
-                batch.with_account_id(account_id);
+        let version = file
+            .read_u8()
+            .await
+            .map_err(|err| error(format!("Failed to read version: {err}")))?;
+        if version > FILE_VERSION {
+            return Err(error(format!(
+                "Backup was created by a newer version (file v{version}, \
+                 this build supports v{FILE_VERSION}); please upgrade"
+            )));
+        } else if version == 0 {
+            return Err(error(format!("Invalid file version {version}")));
+        }
+
+        let (snapshot_id, offset) = if version >= 3 {
+            let snapshot_id = file
+                .read_u64()
+                .await
+                .map_err(|err| error(format!("Failed to read snapshot id: {err}")))?;
+            (Some(snapshot_id), HEADER_LEN as u64)
+        } else {
+            (None, 2)
+        };
+
+        Ok(Self {
+            file,
+            src: src.clone(),
+            compression,
+            version,
+            snapshot_id,
+            family: Family::None,
+            context: OpContext::default(),
+            unknown_family: None,
+            skip_unknown: options.skip_unknown,
+            hasher: blake3::Hasher::new(),
+            num_ops: 0,
+            offset,
+            op_offset: offset,
+            segment_end: None,
+            sync_points: VecDeque::new(),
+            queued: VecDeque::new(),
+            resynced: false,
+        })
+    }
+
+    async fn open_stream(
+        src: &BackupLocation,
+        options: &RestoreOptions,
+    ) -> Result<(OpInput, Option<Compression>), RestoreError> {
+        let error = |cause: String| RestoreError::new(src, 0, Family::None, cause);
+        let mut file: Box<dyn AsyncBufRead + Unpin + Send> = match src {
+            BackupLocation::Path(path) => {
+                let file = File::open(path)
+                    .await
+                    .map_err(|err| error(format!("Failed to open file: {err}")).in_store())?;
+                Box::new(BufReader::with_capacity(options.read_buffer_size, file))
             }
-            Op::Collection(c) => {
-                collection = c;
-                batch.with_collection(collection);
+            BackupLocation::Stdio => Box::new(BufReader::with_capacity(
+                options.read_buffer_size,
+                tokio::io::stdin(),
+            )),
+            BackupLocation::BlobStore { store, prefix, .. } => Box::new(BufReader::with_capacity(
+                options.read_buffer_size,
+                BlobPartReader::new(store.clone(), prefix.clone()),
+            )),
+        };
+
+        // Backups piped through a compressor are decompressed as they are read,
+        // anything else is left for the magic marker check
+        let compression = Compression::detect(file.fill_buf().await.map_err(|err| {
+            if err.kind() == ErrorKind::NotFound {
+                error("Backup file not found".to_string()).in_store()
+            } else {
+                error(format!("Failed to read magic marker: {err}"))
             }
-            Op::DocumentId(d) => {
-                document_id = d;
-                batch.update_document(document_id);
-            }
-            Op::KeyValue((key, value)) => match family {
-                Family::Property => {
-                    let field = key
-                        .as_slice()
-                        .deserialize_u8(0)
-                        .expect("Failed to deserialize field");
-                    if collection == u8::from(Collection::Mailbox)
-                        && u8::from(Property::EmailIds) == field
-                    {
-                        batch.add(
-                            ValueClass::Property(field),
-                            i64::deserialize(&value)
-                                .expect("Failed to deserialize mailbox uidnext"),
-                        );
-                    } else {
-                        batch.set(ValueClass::Property(field), value);
-                    }
-                }
-                Family::TermIndex => {
-                    batch.set(ValueClass::TermIndex, key);
-                }
-                Family::Acl => {
-                    batch.set(
-                        ValueClass::Acl(
-                            key.as_slice()
-                                .deserialize_be_u32(0)
-                                .expect("Failed to deserialize acl"),
-                        ),
-                        value,
-                    );
-                }
-                Family::Blob => {
-                    let hash = BlobHash::try_from_hash_slice(&key).expect("Invalid blob hash");
+        })?);
+        let file: Box<dyn AsyncRead + Unpin + Send> = match compression {
+            Some(Compression::Gzip) => {
+                let mut decoder = GzipDecoder::new(file);
+                decoder.multiple_members(true);
+                Box::new(decoder)
+            }
+            Some(Compression::Zstd) => {
+                let mut decoder = ZstdDecoder::new(file);
+                decoder.multiple_members(true);
+                Box::new(decoder)
+            }
+            None => Box::new(file),
+        };
 
-                    if account_id != u32::MAX && document_id != u32::MAX {
-                        batch.set(ValueClass::Blob(BlobOp::Link { hash }), vec![]);
-                    } else {
-                        blob_store
-                            .put_blob(&key, &value)
-                            .await
-                            .expect("Failed to write blob");
-                        batch.set(ValueClass::Blob(BlobOp::Commit { hash }), vec![]);
-                    }
-                }
-                Family::Config => {
-                    batch.set(ValueClass::Config(key), value);
-                }
-                Family::LookupValue => {
-                    batch.set(ValueClass::Lookup(LookupClass::Key(key)), value);
+        Ok((OpInput::Stream(file), compression))
+    }
+
+    async fn new_segment(
+        src: &BackupLocation,
+        options: &RestoreOptions,
+        segment: &FileSegment,
+        version: u8,
+    ) -> Result<Self, RestoreError> {
+        let error = |cause: String| RestoreError::new(src, segment.offset, segment.family, cause);
+        let BackupLocation::Path(path) = src else {
+            return Err(error(
+                "Only files on disk can be read by segment".to_string(),
+            ));
+        };
+        let file = match options.mmap.then(|| map_file(path)).flatten() {
+            Some(map) => {
+                let mut cursor = std::io::Cursor::new(map);
+                cursor.set_position(segment.offset);
+                OpInput::Mapped(cursor)
+            }
+            None => {
+                let mut file = File::open(path)
+                    .await
+                    .map_err(|err| error(format!("Failed to open file: {err}")).in_store())?;
+                file.seek(SeekFrom::Start(segment.offset))
+                    .await
+                    .map_err(|err| error(format!("Failed to seek to segment: {err}")))?;
+                OpInput::Stream(Box::new(BufReader::with_capacity(
+                    options.read_buffer_size,
+                    file,
+                )))
+            }
+        };
+
+        Ok(Self {
+            file,
+            src: src.clone(),
+            compression: None,
+            version,
+            snapshot_id: None,
+            family: segment.family,
+            context: OpContext {
+                account_id: None,
+                collection: segment.collection,
+                document_id: segment.document_id,
+            },
+            unknown_family: None,
+            skip_unknown: options.skip_unknown,
+            hasher: blake3::Hasher::new(),
+            num_ops: segment.ops_before,
+            offset: segment.offset,
+            op_offset: segment.offset,
+            segment_end: Some(SegmentEnd {
+                offset: segment.offset + segment.len,
+                num_ops: segment.ops_before + segment.ops,
+                hash: segment.hash.clone(),
+            }),
+            sync_points: VecDeque::new(),
+            queued: VecDeque::new(),
+            resynced: false,
+        })
+    }
+
+    async fn read_op(&mut self) -> Result<Option<Op>, RestoreError> {
+        loop {
+            self.op_offset = self.offset;
+
+            if let Some(op) = self.queued.pop_front() {
+                return Ok(Some(op));
+            }
+
+            if let Some(end) = &self.segment_end {
+                if self.offset >= end.offset {
+                    return self.verify_segment().map(|_| None);
                 }
-                Family::LookupCounter => {
-                    batch.add(
-                        ValueClass::Lookup(LookupClass::Counter(key)),
-                        i64::deserialize(&value).expect("Failed to deserialize counter"),
-                    );
+            }
+
+            let op = match self.version {
+                1 => self.read_op_v1().await,
+                _ => self.read_op_v2().await,
+            };
+
+            // Ops of unknown families are decoded as usual but only their key
+            // values are reported, until the next family starts
+            match (self.unknown_family, op) {
+                (Some(family), Ok(Some(Op::KeyValue(_)))) => {
+                    return Err(self
+                        .op_error(format!("Unknown family type {family}"))
+                        .unknown());
                 }
-                Family::Directory => {
-                    let key = key.as_slice();
-                    let class = match key.first().expect("Failed to read directory key type") {
-                        0 => DirectoryClass::NameToId(
-                            key.get(1..)
-                                .expect("Failed to read directory string")
-                                .to_vec(),
-                        ),
-                        1 => DirectoryClass::EmailToId(
-                            key.get(1..)
-                                .expect("Failed to read directory string")
-                                .to_vec(),
-                        ),
-                        2 => DirectoryClass::Principal(
-                            key.get(1..)
-                                .expect("Failed to read range for principal id")
-                                .deserialize_leb128()
-                                .expect("Failed to deserialize principal id"),
-                        ),
-                        3 => DirectoryClass::Domain(
-                            key.get(1..)
-                                .expect("Failed to read directory string")
-                                .to_vec(),
-                        ),
-                        4 => {
-                            batch.add(
-                                ValueClass::Directory(DirectoryClass::UsedQuota(
-                                    key.get(1..)
-                                        .expect("Failed to read principal id")
-                                        .deserialize_leb128()
-                                        .expect("Failed to read principal id"),
-                                )),
-                                i64::deserialize(&value).expect("Failed to deserialize quota"),
-                            );
+                (Some(_), Ok(Some(Op::AccountId(_) | Op::Collection(_) | Op::DocumentId(_)))) => (),
+                (_, op) => return op,
+            }
+        }
+    }
 
-                            continue;
-                        }
-                        5 => DirectoryClass::MemberOf {
-                            principal_id: key
-                                .deserialize_be_u32(1)
-                                .expect("Failed to read principal id"),
-                            member_of: key
-                                .deserialize_be_u32(1 + U32_LEN)
-                                .expect("Failed to read principal id"),
-                        },
-                        6 => DirectoryClass::Members {
-                            principal_id: key
-                                .deserialize_be_u32(1)
-                                .expect("Failed to read principal id"),
-                            has_member: key
-                                .deserialize_be_u32(1 + U32_LEN)
-                                .expect("Failed to read principal id"),
-                        },
-
-                        _ => failed("Invalid directory key"),
-                    };
-                    batch.set(ValueClass::Directory(class), value);
-                }
-                Family::Queue => {
-                    let key = key.as_slice();
-
-                    match key.first().expect("Failed to read queue key type") {
-                        0 => {
-                            batch.set(
-                                ValueClass::Queue(QueueClass::Message(
-                                    key.deserialize_be_u64(1)
-                                        .expect("Failed to deserialize queue message id"),
-                                )),
-                                value,
-                            );
-                        }
-                        1 => {
-                            batch.set(
-                                ValueClass::Queue(QueueClass::MessageEvent(QueueEvent {
-                                    due: key
-                                        .deserialize_be_u64(1)
-                                        .expect("Failed to deserialize queue message id"),
-                                    queue_id: key
-                                        .deserialize_be_u64(1 + U64_LEN)
-                                        .expect("Failed to deserialize queue message id"),
-                                })),
-                                value,
-                            );
-                        }
-                        _ => failed("Invalid queue key"),
+    /// Version 1 files end without a trailer.
+    async fn read_op_v1(&mut self) -> Result<Option<Op>, RestoreError> {
+        match self.file.read_u8().await {
+            Ok(byte) => self.decode_op(byte).await.map(Some),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(self.error(format!("Failed to read file: {err}"))),
+        }
+    }
+
+    /// Version 2 files end with an integrity trailer.
+    async fn read_op_v2(&mut self) -> Result<Option<Op>, RestoreError> {
+        match self.file.read_u8().await {
+            Ok(TRAILER_MARKER) => {
+                self.verify_trailer().await?;
+                Ok(None)
+            }
+            Ok(byte) => self.decode_op(byte).await.map(Some),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                Err(self.error("File is truncated: missing integrity trailer"))
+            }
+            Err(err) => Err(self.error(format!("Failed to read file: {err}"))),
+        }
+    }
+
+    async fn decode_op(&mut self, byte: u8) -> Result<Op, RestoreError> {
+        self.hasher.update(&[byte]);
+        self.num_ops += 1;
+        self.offset += 1;
+
+        let op = match byte {
+            0 => {
+                let family = self.expect_u8().await?;
+                self.unknown_family = None;
+                match Family::try_from(family) {
+                    Ok(family) => {
+                        self.family = family;
+                        Op::Family(family)
+                    }
+                    Err(err) if self.skip_unknown => {
+                        self.family = Family::None;
+                        self.context = OpContext::default();
+                        self.unknown_family = Some(family);
+                        return Err(self.op_error(err).unknown());
+                    }
+                    Err(err) => {
+                        return Err(self
+                            .op_error(format!("Failed to read family: {err}"))
+                            .unknown());
                     }
                 }
-                Family::Index => batch.ops.push(Operation::Index {
-                    field: key.first().copied().expect("Failed to read index field"),
-                    key: key.get(1..).expect("Failed to read index key").to_vec(),
-                    set: true,
-                }),
-                Family::Bitmap => {
-                    let document_ids = RoaringBitmap::deserialize_from(&value[..])
-                        .expect("Failed to deserialize bitmap");
-                    let key = key.as_slice();
-                    let class = match key.first().expect("Failed to read bitmap class") {
-                        0 => BitmapClass::DocumentIds,
-                        1 => BitmapClass::Tag {
-                            field: key.get(1).copied().expect("Failed to read field"),
-                            value: TagValue::Id(
-                                key.deserialize_be_u32(2).expect("Failed to read tag id"),
-                            ),
-                        },
-                        2 => BitmapClass::Tag {
-                            field: key.get(1).copied().expect("Failed to read field"),
-                            value: TagValue::Text(
-                                key.get(2..).expect("Failed to read tag text").to_vec(),
-                            ),
-                        },
-                        3 => BitmapClass::Tag {
-                            field: key.get(1).copied().expect("Failed to read field"),
-                            value: TagValue::Static(
-                                key.get(2).copied().expect("Failed to read tag static id"),
-                            ),
-                        },
-                        4 => BitmapClass::Text {
-                            field: key.get(1).copied().expect("Failed to read field"),
-                            token: BitmapHash {
-                                len: key.get(2).copied().expect("Failed to read tag static id"),
-                                hash: key
-                                    .get(3..11)
-                                    .expect("Failed to read tag static id")
-                                    .try_into()
-                                    .unwrap(),
-                            },
-                        },
-                        _ => failed("Invalid bitmap class"),
-                    };
-
-                    for document_id in document_ids {
-                        batch.ops.push(Operation::DocumentId { document_id });
-                        batch.ops.push(Operation::Bitmap {
-                            class: class.clone(),
-                            set: true,
-                        });
+            }
+            1 => Op::KeyValue((
+                self.expect_sized_bytes().await?,
+                self.expect_sized_bytes().await?,
+            )),
+            2 => Op::KeyValue((self.expect_sized_bytes().await?, vec![])),
+            3 => Op::AccountId(self.expect_u32_be().await?),
+            4 => Op::Collection(self.expect_u8().await?),
+            5 => Op::DocumentId(self.expect_u32_be().await?),
+            unknown => {
+                return Err(self
+                    .op_error(format!("Unknown op type {unknown}"))
+                    .unknown());
+            }
+        };
+        self.context.track(&op);
+        Ok(op)
+    }
 
-                        if batch.ops.len() >= 1000 {
-                            store
-                                .write(batch.build())
-                                .await
-                                .failed("Failed to write batch");
-                            batch = BatchBuilder::new();
-                            batch
-                                .with_account_id(account_id)
-                                .with_collection(collection);
+    async fn verify_trailer(&mut self) -> Result<(), RestoreError> {
+        let num_ops =
+            self.file.read_u64().await.map_err(|err| {
+                self.error(format!("Failed to read trailer operation count: {err}"))
+            })?;
+        let mut hash = [0u8; 32];
+        self.file
+            .read_exact(&mut hash)
+            .await
+            .map_err(|err| self.error(format!("Failed to read trailer checksum: {err}")))?;
+
+        if num_ops != self.num_ops {
+            Err(self.error(format!(
+                "File is incomplete: expected {num_ops} operations, found {}",
+                self.num_ops
+            )))
+        } else if self.hasher.finalize().as_bytes() != &hash {
+            Err(self.error("File failed checksum verification"))
+        } else if self.file.read_u8().await.is_ok() {
+            Err(self.error("File contains unexpected data after the trailer"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn verify_segment(&self) -> Result<(), RestoreError> {
+        let Some(end) = &self.segment_end else {
+            return Ok(());
+        };
+        if self.offset != end.offset || self.num_ops != end.num_ops {
+            Err(self.error(format!(
+                "Segment is incomplete: expected {} operations up to offset {}, \
+                 found {} up to offset {}",
+                end.num_ops, end.offset, self.num_ops, self.offset
+            )))
+        } else if self.hasher.finalize().to_hex().as_str() != end.hash {
+            Err(self.error("Segment failed checksum verification"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn expect_u8(&mut self) -> Result<u8, RestoreError> {
+        let value = self
+            .file
+            .read_u8()
+            .await
+            .map_err(|err| self.op_error(format!("Failed to read u8: {err}")))?;
+        self.hasher.update(&[value]);
+        self.offset += 1;
+        Ok(value)
+    }
+
+    async fn expect_u32_be(&mut self) -> Result<u32, RestoreError> {
+        let value = self
+            .file
+            .read_u32()
+            .await
+            .map_err(|err| self.op_error(format!("Failed to read u32: {err}")))?;
+        self.hasher.update(&value.to_be_bytes());
+        self.offset += U32_LEN as u64;
+        Ok(value)
+    }
+
+    async fn expect_sized_bytes(&mut self) -> Result<Vec<u8>, RestoreError> {
+        let len = self.expect_u32_be().await? as usize;
+        let bytes = match self.file.take_mapped(len) {
+            Some(Ok(bytes)) => {
+                self.hasher.update(bytes);
+                bytes.to_vec()
+            }
+            Some(Err(err)) => {
+                return Err(self.op_error(format!("Failed to read bytes: {err}")));
+            }
+            None => {
+                let mut bytes = vec![0; len];
+                self.file
+                    .read_exact(&mut bytes)
+                    .await
+                    .map_err(|err| self.op_error(format!("Failed to read bytes: {err}")))?;
+                self.hasher.update(&bytes);
+                bytes
+            }
+        };
+        self.offset += len as u64;
+        Ok(bytes)
+    }
+
+    async fn skip_to(&mut self, offset: u64, num_ops: u64) -> Result<(), RestoreError> {
+        // Hash the skipped ops so the trailer can still be verified
+        let len = offset.saturating_sub(self.offset) as usize;
+        match self.file.take_mapped(len) {
+            Some(Ok(bytes)) => {
+                self.hasher.update(bytes);
+                self.offset += len as u64;
+            }
+            Some(Err(err)) => {
+                return Err(self.error(format!("Failed to skip to offset {offset}: {err}")));
+            }
+            None => (),
+        }
+        let mut buf = vec![0u8; 64 * 1024];
+        while self.offset < offset {
+            let len = std::cmp::min(buf.len() as u64, offset - self.offset) as usize;
+            self.file
+                .read_exact(&mut buf[..len])
+                .await
+                .map_err(|err| self.error(format!("Failed to skip to offset {offset}: {err}")))?;
+            self.hasher.update(&buf[..len]);
+            self.offset += len as u64;
+        }
+        self.num_ops = num_ops;
+        Ok(())
+    }
+
+    async fn resync(&mut self) -> Option<Op> {
+        // Segments of the index are known to start with an account id op
+        while let Some(segment) = self.sync_points.pop_front() {
+            if segment.offset < self.offset {
+                continue;
+            }
+            let mut buf = vec![0u8; 64 * 1024];
+            while self.offset < segment.offset {
+                let len = std::cmp::min(buf.len() as u64, segment.offset - self.offset) as usize;
+                self.file.read_exact(&mut buf[..len]).await.ok()?;
+                self.hasher.update(&buf[..len]);
+                self.offset += len as u64;
+            }
+            self.op_offset = self.offset;
+            self.num_ops = segment.ops_before;
+            self.family = segment.family;
+            self.context = OpContext {
+                account_id: None,
+                collection: segment.collection,
+                document_id: segment.document_id,
+            };
+            self.unknown_family = None;
+            self.resynced = false;
+            self.queued.extend(
+                segment
+                    .collection
+                    .map(Op::Collection)
+                    .into_iter()
+                    .chain(segment.document_id.map(Op::DocumentId)),
+            );
+            return Some(Op::Family(segment.family));
+        }
+
+        // Past the last segment, look for a family marker followed by an op
+        // that decodes, and read the bytes after the marker again from there
+        let start = self.offset;
+        let mut window = Vec::new();
+        let mut pos = 0;
+        let found = 'scan: loop {
+            while window.len() < pos + 2 {
+                if !self.resync_read(&mut window, pos + 2 - window.len()).await {
+                    break 'scan None;
+                }
+            }
+            if window[pos] == 0 {
+                if let Ok(family) = Family::try_from(window[pos + 1]) {
+                    loop {
+                        let available = window.len() - pos - 2;
+                        match decoded_op_len(&window[pos + 2..]) {
+                            Some(len) if len <= available => break 'scan Some(family),
+                            Some(len) if len <= MAX_RESYNC_LOOKAHEAD => {
+                                if !self.resync_read(&mut window, len - available).await {
+                                    break;
+                                }
+                            }
+                            _ => break,
                         }
                     }
                 }
-                Family::Log => {
-                    batch.ops.push(Operation::Log {
-                        change_id: key
-                            .as_slice()
-                            .deserialize_be_u64(0)
-                            .expect("Failed to deserialize change id"),
-                        collection,
-                        set: value,
-                    });
-                }
-                Family::None => failed("No family specified in file"),
-            },
+            }
+            pos += 1;
+        };
+
+        let consumed = if found.is_some() {
+            pos + 2
+        } else {
+            window.len()
+        };
+        self.hasher.update(&window[..consumed]);
+        self.offset = start + consumed as u64;
+        let family = found?;
+        self.op_offset = self.offset - 2;
+        self.num_ops += 1;
+        self.family = family;
+        self.context = OpContext::default();
+        self.unknown_family = None;
+        self.resynced = true;
+
+        // Mapped files are read again from the offset, streams from the window
+        match &mut self.file {
+            OpInput::Mapped(cursor) => cursor.set_position(self.offset),
+            OpInput::Stream(file) => {
+                let file = std::mem::replace(file, Box::new(tokio::io::empty()));
+                self.file = OpInput::Stream(Box::new(
+                    std::io::Cursor::new(window.split_off(consumed)).chain(file),
+                ));
+            }
         }
+        Some(Op::Family(family))
+    }
 
-        if batch.ops.len() >= 1000 {
-            store
-                .write(batch.build())
-                .await
-                .failed("Failed to write batch");
-            batch = BatchBuilder::new();
-            batch
-                .with_account_id(account_id)
-                .with_collection(collection)
-                .update_document(document_id);
+    /// Appends up to `len` bytes to the window scanned when resynchronizing,
+    /// without hashing them. Returns `false` at the end of the file or segment.
+    async fn resync_read(&mut self, window: &mut Vec<u8>, len: usize) -> bool {
+        // Segments end where the next one starts
+        let read_offset = self.offset + window.len() as u64;
+        let len = match &self.segment_end {
+            Some(end) => std::cmp::min(len as u64, end.offset.saturating_sub(read_offset)) as usize,
+            None => len,
+        };
+        if len == 0 {
+            return false;
         }
+        let from = window.len();
+        window.resize(from + len, 0);
+        let read = self.file.read(&mut window[from..]).await.unwrap_or(0);
+        window.truncate(from + read);
+        read > 0
     }
 
-    if !batch.is_empty() {
-        store
-            .write(batch.build())
-            .await
-            .failed("Failed to write batch");
+    fn error(&self, cause: impl Display) -> RestoreError {
+        RestoreError::new(&self.src, self.offset, self.family, cause).with_context(self.context)
+    }
+
+    fn op_error(&self, cause: impl Display) -> RestoreError {
+        RestoreError::new(&self.src, self.op_offset, self.family, cause).with_context(self.context)
     }
 }
 
-struct OpReader {
-    file: BufReader<File>,
+/// Length of the op encoded at the start of `bytes`, which may be longer than
+/// the bytes available when they end within the op, or `None` if it doesn't
+/// start with a valid op. Family markers are rejected as well, families found
+/// when resynchronizing have to be followed by the ops within them.
+fn decoded_op_len(bytes: &[u8]) -> Option<usize> {
+    let sized_len = |at: usize| {
+        bytes
+            .get(at..at + U32_LEN)
+            .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+    };
+    match bytes.first().copied() {
+        None => Some(1),
+        Some(op @ (1 | 2)) => {
+            let Some(key_len) = sized_len(1) else {
+                return Some(1 + U32_LEN);
+            };
+            if key_len == 0 {
+                return None;
+            }
+            let len = 1 + U32_LEN + key_len;
+            if op == 2 {
+                Some(len)
+            } else {
+                Some(sized_len(len).map_or(len + U32_LEN, |value_len| len + U32_LEN + value_len))
+            }
+        }
+        Some(3 | 5) => Some(1 + U32_LEN),
+        Some(4) => Some(2),
+        Some(_) => None,
+    }
 }
 
-impl OpReader {
-    async fn new(path: &Path) -> Self {
-        let mut file = BufReader::new(File::open(&path).await.failed("Failed to open file"));
+/// Compression of a backup file, detected from the magic bytes it starts with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
 
-        if file
-            .read_u8()
-            .await
-            .failed(&format!("Failed to read magic marker from {path:?}"))
-            != MAGIC_MARKER
-        {
-            failed(&format!("Invalid magic marker in {path:?}"));
+impl OpInput {
+    /// Returns the next `len` bytes of a mapped file without copying them,
+    /// or `None` when reading a stream.
+    fn take_mapped(&mut self, len: usize) -> Option<std::io::Result<&[u8]>> {
+        let OpInput::Mapped(cursor) = self else {
+            return None;
+        };
+        let start = cursor.position() as usize;
+        let map_len = cursor.get_ref().len();
+        Some(match start.checked_add(len).filter(|end| *end <= map_len) {
+            Some(end) => {
+                cursor.set_position(end as u64);
+                Ok(&cursor.get_ref()[start..end])
+            }
+            None => {
+                cursor.set_position(map_len as u64);
+                Err(ErrorKind::UnexpectedEof.into())
+            }
+        })
+    }
+}
+
+impl AsyncRead for OpInput {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            OpInput::Stream(reader) => Pin::new(reader).poll_read(cx, buf),
+            OpInput::Mapped(cursor) => Pin::new(cursor).poll_read(cx, buf),
         }
+    }
+}
 
-        if file
-            .read_u8()
-            .await
-            .failed(&format!("Failed to read version from {path:?}"))
-            != FILE_VERSION
-        {
-            failed(&format!("Invalid file version in {path:?}"));
+/// Maps a backup file into memory, returning `None` for files that can't be
+/// mapped such as pipes, which are then read as a stream.
+fn map_file(path: &std::path::Path) -> Option<Mmap> {
+    // Safety: backup files are not expected to be modified while being imported,
+    // a concurrent truncation would otherwise fault on access.
+    match std::fs::File::open(path).and_then(|file| unsafe { Mmap::map(&file) }) {
+        Ok(map) => {
+            #[cfg(unix)]
+            let _ = map.advise(memmap2::Advice::Sequential);
+            Some(map)
+        }
+        Err(err) => {
+            tracing::debug!(
+                context = "restore",
+                event = "mmap",
+                reason = %err,
+                "Failed to map backup file, reading it as a stream."
+            );
+            None
+        }
+    }
+}
+
+impl Compression {
+    fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
         }
+    }
+}
 
-        Self { file }
+impl ReadPosition {
+    /// Position after the op.
+    fn after(&self) -> (u64, u64) {
+        (self.offset, self.num_ops)
     }
 
-    async fn next(&mut self) -> Option<Op> {
-        match self.file.read_u8().await {
-            Ok(byte) => match byte {
-                0 => Op::Family(
-                    Family::try_from(self.expect_u8().await).failed("Failed to read family"),
-                ),
-                1 => Op::KeyValue((
-                    self.expect_sized_bytes().await,
-                    self.expect_sized_bytes().await,
-                )),
-                2 => Op::KeyValue((self.expect_sized_bytes().await, vec![])),
-                3 => Op::AccountId(self.expect_u32_be().await),
-                4 => Op::Collection(self.expect_u8().await),
-                5 => Op::DocumentId(self.expect_u32_be().await),
-                unknown => {
-                    failed(&format!("Unknown op type {unknown}"));
-                }
+    /// Position before the op.
+    fn before(&self) -> (u64, u64) {
+        (self.op_offset, self.num_ops.saturating_sub(1))
+    }
+
+    /// Error at the end of the op.
+    fn error(&self, src: impl Display, cause: impl Display) -> RestoreError {
+        RestoreError::new(src, self.offset, self.family, cause).with_context(self.context)
+    }
+
+    /// Error at the start of the op.
+    fn op_error(&self, src: impl Display, cause: impl Display) -> RestoreError {
+        RestoreError::new(src, self.op_offset, self.family, cause).with_context(self.context)
+    }
+}
+
+impl OpContext {
+    /// Updates the context with the next op of a file.
+    pub(super) fn track(&mut self, op: &Op) {
+        match op {
+            Op::Family(_) => *self = OpContext::default(),
+            Op::AccountId(account_id) => self.account_id = Some(*account_id),
+            Op::Collection(collection) => self.collection = Some(*collection),
+            Op::DocumentId(document_id) => self.document_id = Some(*document_id),
+            Op::KeyValue(_) => (),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &OpContext::default()
+    }
+}
+
+impl Display for OpContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut separator = "";
+        if let Some(account_id) = self.account_id {
+            write!(f, "account {account_id}")?;
+            separator = ", ";
+        }
+        if let Some(collection) = self.collection {
+            match Collection::from(collection) {
+                Collection::None => write!(f, "{separator}collection {collection}")?,
+                name => write!(f, "{separator}collection {name}")?,
             }
-            .into(),
-            Err(err) if err.kind() == ErrorKind::UnexpectedEof => None,
-            Err(err) => failed(&format!("Failed to read file: {err:?}")),
+            separator = ", ";
         }
+        if let Some(document_id) = self.document_id {
+            write!(f, "{separator}document {document_id}")?;
+        }
+        Ok(())
     }
+}
 
-    async fn expect_u8(&mut self) -> u8 {
-        self.file.read_u8().await.failed("Failed to read u8")
+impl RestoreError {
+    pub(super) fn new(src: impl Display, offset: u64, family: Family, cause: impl Display) -> Self {
+        Self {
+            file: src.to_string(),
+            offset,
+            family,
+            cause: cause.to_string(),
+            store: false,
+            unknown: false,
+            context: OpContext::default(),
+        }
     }
 
-    async fn expect_u32_be(&mut self) -> u32 {
-        self.file.read_u32().await.failed("Failed to read u32")
+    pub(super) fn with_context(mut self, context: OpContext) -> Self {
+        self.context = context;
+        self
     }
 
-    async fn expect_sized_bytes(&mut self) -> Vec<u8> {
-        let len = self.expect_u32_be().await as usize;
-        let mut bytes = vec![0; len];
-        self.file
-            .read_exact(&mut bytes)
-            .await
-            .failed("Failed to read bytes");
-        bytes
+    pub(super) fn in_store(mut self) -> Self {
+        self.store = true;
+        self
+    }
+
+    pub(super) fn unknown(mut self) -> Self {
+        self.unknown = true;
+        self
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        if self.store {
+            ExitCode::Store
+        } else {
+            ExitCode::Data
+        }
+    }
+}
+
+impl Display for RestoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.context.is_empty() {
+            write!(
+                f,
+                "{} at offset {} ({:?}): {}",
+                self.file, self.offset, self.family, self.cause
+            )
+        } else {
+            write!(
+                f,
+                "{} at offset {} ({:?}, {}): {}",
+                self.file, self.offset, self.family, self.context, self.cause
+            )
+        }
     }
 }
 
+impl std::error::Error for RestoreError {}
+
 impl TryFrom<u8> for Family {
     type Error = String;
 
@@ -432,3 +4695,236 @@ impl TryFrom<u8> for Family {
         }
     }
 }
+
+impl Family {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "property" => Some(Self::Property),
+            "term_index" => Some(Self::TermIndex),
+            "acl" => Some(Self::Acl),
+            "blob" => Some(Self::Blob),
+            "config" => Some(Self::Config),
+            "lookup_value" => Some(Self::LookupValue),
+            "lookup_counter" => Some(Self::LookupCounter),
+            "directory" => Some(Self::Directory),
+            "queue" => Some(Self::Queue),
+            "index" => Some(Self::Index),
+            "bitmap" => Some(Self::Bitmap),
+            "log" => Some(Self::Log),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use store::write::{Operation, ValueClass, ValueOp};
+    use utils::codec::leb128::Leb128Vec;
+
+    use crate::manager::backup::{Family, Op};
+
+    use super::{
+        op_channel, split_ops, MemoryBudget, OpContext, OpResult, ReadPosition, RestoreError,
+        RestoreOptions, WriteError,
+    };
+
+    #[test]
+    fn write_errors() {
+        for (message, expected) in [
+            (
+                "Transaction is too old to perform reads or be committed",
+                WriteError::Transient,
+            ),
+            (
+                "Transaction not committed due to conflict with another transaction",
+                WriteError::Transient,
+            ),
+            ("database is locked", WriteError::Transient),
+            ("Transaction exceeds byte limit", WriteError::TooLarge),
+            (
+                "Transaction may or may not have committed (commit unknown result)",
+                WriteError::Fatal,
+            ),
+            ("Disk full", WriteError::Fatal),
+        ] {
+            assert_eq!(
+                WriteError::from(&store::Error::InternalError(message.to_string())),
+                expected,
+                "{message}"
+            );
+        }
+        assert_eq!(
+            WriteError::from(&store::Error::AssertValueFailed),
+            WriteError::Fatal
+        );
+    }
+
+    #[test]
+    fn split_batch() {
+        let value = |id: u8| Operation::Value {
+            class: ValueClass::Config(vec![id]),
+            op: ValueOp::Clear,
+        };
+        let ops = vec![
+            Operation::AccountId { account_id: 1 },
+            Operation::Collection { collection: 2 },
+            Operation::DocumentId { document_id: 3 },
+            value(0),
+            Operation::DocumentId { document_id: 4 },
+            value(1),
+            value(2),
+            value(3),
+        ];
+        let (first, second) = split_ops(ops.clone());
+        assert_eq!(first, ops[..4]);
+        assert_eq!(
+            second,
+            [
+                Operation::AccountId { account_id: 1 },
+                Operation::Collection { collection: 2 },
+                Operation::DocumentId { document_id: 3 },
+                Operation::DocumentId { document_id: 4 },
+                value(1),
+                value(2),
+                value(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn error_context() {
+        let mut context = OpContext::default();
+        let err = RestoreError::new("property", 10, Family::Property, "Failed to read u32");
+        assert_eq!(
+            err.to_string(),
+            "property at offset 10 (Property): Failed to read u32"
+        );
+
+        for op in [
+            Op::Family(Family::Property),
+            Op::AccountId(3),
+            Op::Collection(1),
+            Op::DocumentId(40),
+        ] {
+            context.track(&op);
+        }
+        assert_eq!(
+            err.with_context(context).to_string(),
+            "property at offset 10 (Property, account 3, collection mailbox, document 40): \
+             Failed to read u32"
+        );
+
+        // Context does not carry over to the next family
+        context.track(&Op::Family(Family::Log));
+        assert!(context.is_empty());
+        context.track(&Op::Collection(200));
+        assert_eq!(context.to_string(), "collection 200");
+    }
+
+    #[test]
+    fn account_remap() {
+        let options = RestoreOptions::new()
+            .remap_account(1, 11)
+            .remap_account(300, 2);
+        assert_eq!(options.remap_account_id(1), 11);
+        assert_eq!(options.remap_account_id(300), 2);
+        assert_eq!(options.remap_account_id(u32::MAX), u32::MAX);
+        for _ in 0..3 {
+            assert_eq!(options.remap_account_id(5), 5);
+        }
+        assert_eq!(
+            options.unmapped_accounts.lock().iter().collect::<Vec<_>>(),
+            [&5]
+        );
+
+        // Principal ids are LEB128 encoded in directory values
+        let mut value = vec![7];
+        value.push_leb128(300u32);
+        value.push(9);
+        let mut expected = vec![7];
+        expected.push_leb128(2u32);
+        expected.push(9);
+        assert_eq!(options.remap_leb128_id(value.clone(), 1).unwrap(), expected);
+        assert_eq!(
+            RestoreOptions::new()
+                .remap_leb128_id(value.clone(), 1)
+                .unwrap(),
+            value
+        );
+        assert!(options.remap_leb128_id(vec![7], 1).is_err());
+    }
+
+    fn position() -> ReadPosition {
+        ReadPosition {
+            family: Family::Property,
+            offset: 0,
+            op_offset: 0,
+            num_ops: 0,
+            context: OpContext::default(),
+            resynced: false,
+        }
+    }
+
+    fn value_op(len: usize) -> OpResult {
+        Ok((Op::KeyValue((vec![], vec![0; len])), position()))
+    }
+
+    #[tokio::test]
+    async fn read_ahead() {
+        let (tx, mut rx) = op_channel(100, MemoryBudget::new(Some(4096)));
+
+        // Values wait for the ones queued before them, markers take up nothing
+        assert!(tx.send(value_op(3072)).await.is_ok());
+        assert!(tx.send(value_op(1024)).await.is_ok());
+        assert!(tx.send(Ok((Op::AccountId(1), position()))).await.is_ok());
+        assert!(tx.send(value_op(1)).now_or_never().is_none());
+        assert!(rx.recv().await.is_some());
+        assert!(tx.send(value_op(1)).now_or_never().is_some());
+
+        // Values larger than the limit wait for the queue to drain
+        for _ in 0..3 {
+            assert!(rx.recv().await.is_some());
+        }
+        assert!(tx.send(value_op(1024 * 1024)).now_or_never().is_some());
+        assert!(tx.send(value_op(1)).now_or_never().is_none());
+        assert!(rx.recv().await.is_some());
+        assert!(tx.send(value_op(1)).now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn memory_budget() {
+        let (memory, read_ahead) = MemoryBudget::split(Some(4096));
+
+        // Each file reads ahead within the part of the budget set aside for it
+        let (tx, _rx) = op_channel(100, read_ahead.read_ahead(1024).await);
+        let (other_tx, mut other_rx) = op_channel(100, read_ahead.read_ahead(1024).await);
+        assert!(tx.send(value_op(1024)).await.is_ok());
+        assert!(tx.send(value_op(1)).now_or_never().is_none());
+
+        // A file waiting for batch memory with its ops still queued doesn't
+        // keep the file holding that memory from reading
+        let mut reserved = None;
+        assert!(memory.try_reserve(&mut reserved, 2048));
+        assert!(!memory.try_reserve(&mut None, 1));
+        assert!(other_tx.send(value_op(1024)).now_or_never().is_some());
+        assert!(other_rx.recv().await.is_some());
+        assert!(other_tx.send(value_op(1024)).now_or_never().is_some());
+        drop(reserved);
+        assert!(memory.try_reserve(&mut None, 2048));
+
+        // Other files start once a file is done with its read-ahead
+        let mut third = Box::pin(read_ahead.read_ahead(1024));
+        assert!(third.as_mut().now_or_never().is_none());
+        drop((tx, _rx));
+        assert!(third.now_or_never().is_some());
+
+        // Without a budget each file reads ahead up to its own limit
+        let read_ahead = MemoryBudget::default();
+        let (tx, _rx) = op_channel(100, read_ahead.read_ahead(1024).await);
+        let (other_tx, _other_rx) = op_channel(100, read_ahead.read_ahead(1024).await);
+        assert!(tx.send(value_op(1024)).await.is_ok());
+        assert!(tx.send(value_op(1)).now_or_never().is_none());
+        assert!(other_tx.send(value_op(1024)).now_or_never().is_some());
+    }
+}
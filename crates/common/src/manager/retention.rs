@@ -0,0 +1,187 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDateTime};
+use utils::config::utils::ParseValue;
+
+use super::{
+    backup::{BackupLocation, BackupManifest},
+    restore::verify_backup,
+};
+
+const BACKUP_SET_FORMAT: &str = "%Y-%m-%dT%H%M%SZ";
+
+/// Timestamped backup sets to keep after a successful export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    /// Keep the newest sets.
+    Count(usize),
+    /// Keep the sets created within this many seconds.
+    Age(u64),
+}
+
+/// A backup set that was left in place, with the reason it was not pruned.
+#[derive(Debug)]
+pub struct SkippedSet {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub removed: Vec<PathBuf>,
+    pub skipped: Vec<SkippedSet>,
+}
+
+impl Retention {
+    /// Parses a number of sets (`7`) or a maximum age (`30d`).
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().parse::<usize>() {
+            Ok(0) => None,
+            Ok(count) => Some(Retention::Count(count)),
+            Err(_) => Duration::parse_value(value)
+                .ok()
+                .map(|age| Retention::Age(age.as_secs())),
+        }
+    }
+}
+
+impl ParseValue for Retention {
+    fn parse_value(value: &str) -> utils::config::Result<Self> {
+        Retention::parse(value).ok_or_else(|| {
+            format!("Invalid retention {value:?}, expected a number of backups or a duration.")
+        })
+    }
+}
+
+/// Name of the subdirectory holding the backup set created at `timestamp`,
+/// e.g. `2024-06-01T030000Z`.
+pub fn backup_set_name(timestamp: u64) -> String {
+    DateTime::from_timestamp(timestamp as i64, 0)
+        .unwrap_or_default()
+        .format(BACKUP_SET_FORMAT)
+        .to_string()
+}
+
+fn is_backup_set(name: &str) -> bool {
+    NaiveDateTime::parse_from_str(name, BACKUP_SET_FORMAT).is_ok()
+}
+
+/// Deletes the backup sets in `dir` that fall outside of the retention policy.
+///
+/// Only complete sets are counted towards the retention and deleted: a set is
+/// complete when it has a manifest and all of its files pass verification,
+/// including their integrity trailers. Incomplete or damaged sets are left in
+/// place for inspection. The newest complete set is never deleted.
+pub async fn prune_backup_sets(
+    dir: &Path,
+    retention: Retention,
+    now: u64,
+) -> Result<PruneReport, String> {
+    let mut sets = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .map_err(|err| format!("Failed to list backup directory {}: {err}", dir.display()))?
+    {
+        let path = entry
+            .map_err(|err| format!("Failed to list backup directory {}: {err}", dir.display()))?
+            .path();
+        if path.is_dir()
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(is_backup_set)
+        {
+            sets.push(path);
+        }
+    }
+
+    // Names sort chronologically, newest first
+    sets.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut report = PruneReport::default();
+    let mut kept = 0;
+    for path in sets {
+        let created = match verify_set(&path).await {
+            Ok(created) => created,
+            Err(reason) => {
+                report.skipped.push(SkippedSet { path, reason });
+                continue;
+            }
+        };
+        let keep = kept == 0
+            || match retention {
+                Retention::Count(count) => kept < count,
+                Retention::Age(age) => created.saturating_add(age) >= now,
+            };
+
+        if keep {
+            kept += 1;
+        } else {
+            std::fs::remove_dir_all(&path)
+                .map_err(|err| format!("Failed to remove backup set {}: {err}", path.display()))?;
+            report.removed.push(path);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Verifies a backup set, returning its creation time.
+pub(super) async fn verify_set(path: &Path) -> Result<u64, String> {
+    let src = BackupLocation::Path(path.to_path_buf());
+    let manifest = BackupManifest::read(&src)
+        .await?
+        .ok_or_else(|| "No manifest found".to_string())?;
+    for report in verify_backup(&src).await.map_err(|err| err.to_string())? {
+        if let Some(err) = report.errors.into_iter().next() {
+            return Err(err);
+        }
+    }
+
+    Ok(manifest.created)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backup_set_name, is_backup_set, Retention};
+
+    #[test]
+    fn parse_retention() {
+        assert_eq!(Retention::parse("7"), Some(Retention::Count(7)));
+        assert_eq!(Retention::parse("30d"), Some(Retention::Age(30 * 86400)));
+        assert_eq!(Retention::parse("12h"), Some(Retention::Age(12 * 3600)));
+        assert_eq!(Retention::parse("0"), None);
+        assert_eq!(Retention::parse("7w"), None);
+
+        let name = backup_set_name(1717210800);
+        assert_eq!(name, "2024-06-01T030000Z");
+        assert!(is_backup_set(&name));
+        assert!(!is_backup_set("2024-06-01"));
+        assert!(!is_backup_set("lost+found"));
+    }
+}
@@ -0,0 +1,185 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{path::PathBuf, time::Instant};
+
+use store::write::now;
+use utils::config::{cron::SimpleCron, Config};
+
+use crate::SharedCore;
+
+use super::{
+    backup::BackupOptions,
+    retention::{backup_set_name, prune_backup_sets, verify_set, Retention},
+};
+
+/// Backups taken by the running server, each one into a new timestamped
+/// set under `backup.path`.
+#[derive(Debug, Clone)]
+pub struct BackupSchedule {
+    pub cron: SimpleCron,
+    pub path: PathBuf,
+    pub retention: Option<Retention>,
+}
+
+impl BackupSchedule {
+    pub fn parse(config: &mut Config) -> Option<Self> {
+        config.value("backup.schedule")?;
+
+        Some(BackupSchedule {
+            cron: config.property_require::<SimpleCron>("backup.schedule")?,
+            path: config.value_require("backup.path")?.into(),
+            retention: config.property::<Retention>("backup.retain"),
+        })
+    }
+
+    /// Runs the backups in the background. A run is skipped if the previous
+    /// one is still in progress.
+    pub fn spawn(self, core: SharedCore) {
+        tracing::debug!(
+            context = "backup",
+            event = "start",
+            path = %self.path.display(),
+            "Backup task started."
+        );
+
+        tokio::spawn(async move {
+            let mut run: Option<tokio::task::JoinHandle<()>> = None;
+
+            loop {
+                tokio::time::sleep(self.cron.time_to_next()).await;
+
+                if run.as_ref().is_some_and(|run| !run.is_finished()) {
+                    tracing::warn!(
+                        context = "backup",
+                        event = "skip",
+                        path = %self.path.display(),
+                        "Skipping scheduled backup, the previous one is still in progress."
+                    );
+                    continue;
+                }
+
+                let schedule = self.clone();
+                let core = core.load_full();
+                run = Some(tokio::spawn(async move {
+                    let set = schedule.path.join(backup_set_name(now()));
+                    let started = Instant::now();
+                    if let Err(err) = core.try_backup(set.clone(), BackupOptions::default()).await {
+                        tracing::error!(
+                            context = "backup",
+                            event = "error",
+                            path = %set.display(),
+                            reason = %err,
+                            "Scheduled backup failed."
+                        );
+                        return;
+                    }
+
+                    if let Err(reason) = verify_set(&set).await {
+                        tracing::error!(
+                            context = "backup",
+                            event = "error",
+                            path = %set.display(),
+                            reason = %reason,
+                            "Scheduled backup failed verification, no backups were pruned."
+                        );
+                        return;
+                    }
+                    tracing::info!(
+                        context = "backup",
+                        event = "success",
+                        path = %set.display(),
+                        elapsed = ?started.elapsed(),
+                        "Scheduled backup completed."
+                    );
+
+                    if let Some(retention) = schedule.retention {
+                        schedule.prune(retention).await;
+                    }
+                }));
+            }
+        });
+    }
+
+    async fn prune(&self, retention: Retention) {
+        match prune_backup_sets(&self.path, retention, now()).await {
+            Ok(report) => {
+                for skipped in report.skipped {
+                    tracing::warn!(
+                        context = "backup",
+                        event = "prune",
+                        path = %skipped.path.display(),
+                        reason = %skipped.reason,
+                        "Keeping incomplete backup set."
+                    );
+                }
+                for path in report.removed {
+                    tracing::info!(
+                        context = "backup",
+                        event = "prune",
+                        path = %path.display(),
+                        "Removed backup set."
+                    );
+                }
+            }
+            Err(reason) => {
+                tracing::error!(
+                    context = "backup",
+                    event = "error",
+                    reason = %reason,
+                    "Failed to prune backups."
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::config::{cron::SimpleCron, Config};
+
+    use crate::manager::retention::Retention;
+
+    use super::BackupSchedule;
+
+    #[test]
+    fn parse_backup_schedule() {
+        let mut config = Config::new(
+            "[backup]\nschedule = \"0 3 * * *\"\npath = \"/backups\"\nretain = \"7\"\n",
+        )
+        .unwrap();
+        let schedule = BackupSchedule::parse(&mut config).unwrap();
+        assert_eq!(schedule.cron, SimpleCron::Day { hour: 3, minute: 0 });
+        assert_eq!(schedule.path.to_str(), Some("/backups"));
+        assert_eq!(schedule.retention, Some(Retention::Count(7)));
+        assert!(config.errors.is_empty());
+
+        let mut config = Config::new("[backup]\nschedule = \"0 3 *\"\nretain = \"7w\"\n").unwrap();
+        assert!(BackupSchedule::parse(&mut config).is_none());
+        assert!(config.errors.contains_key("backup.path"));
+
+        let mut config = Config::new("[backup]\npath = \"/backups\"\n").unwrap();
+        assert!(BackupSchedule::parse(&mut config).is_none());
+        assert!(config.errors.is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+//! Upgrades of the values found in backup files written by older versions.
+//!
+//! Values are restored as they were exported, so any change to the encoding
+//! of a family has to bump `FILE_VERSION` and register an [`Upgrade`] that
+//! rewrites the values of the files written before it. Upgrades run in the
+//! order they are listed, each on the output of the previous one, so a file
+//! of any version is brought to the current encodings one step at a time.
+//!
+//! | Source version | Families | Change                                          |
+//! |----------------|----------|-------------------------------------------------|
+//! | v1             | none     | Only the integrity trailer was added, values of |
+//! |                |          | all families are restored as they are.          |
+//! | v2             | none     | Only the snapshot id was added to the header,   |
+//! |                |          | values of all families are restored as they are |
+
+use super::backup::Family;
+
+/// Rewrites a key and value of a family, given the collection they belong to.
+pub(super) type UpgradeFn = fn(u8, Vec<u8>, Vec<u8>) -> Result<(Vec<u8>, Vec<u8>), String>;
+
+pub(super) struct Upgrade {
+    /// Last file version written with the old encoding.
+    pub until: u8,
+    pub family: Family,
+    pub description: &'static str,
+    pub apply: UpgradeFn,
+}
+
+/// Upgrades from every file version to the next, in version order.
+pub(super) const UPGRADES: &[Upgrade] = &[];
+
+/// Brings a key and value read from a file of `version` to the current
+/// encoding of its family.
+pub(super) fn upgrade_key_value(
+    version: u8,
+    family: Family,
+    collection: u8,
+    key: Vec<u8>,
+    value: Vec<u8>,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    apply_upgrades(UPGRADES, version, family, collection, key, value)
+}
+
+/// Returns the upgrades that will run on the values of a file of `version`.
+pub(super) fn pending_upgrades(version: u8) -> impl Iterator<Item = &'static Upgrade> {
+    UPGRADES
+        .iter()
+        .filter(move |upgrade| version <= upgrade.until)
+}
+
+fn apply_upgrades(
+    upgrades: &[Upgrade],
+    version: u8,
+    family: Family,
+    collection: u8,
+    mut key: Vec<u8>,
+    mut value: Vec<u8>,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    for upgrade in upgrades {
+        if version <= upgrade.until && upgrade.family == family {
+            (key, value) = (upgrade.apply)(collection, key, value)
+                .map_err(|err| format!("Failed to upgrade from v{}: {err}", upgrade.until))?;
+        }
+    }
+    Ok((key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manager::backup::{Family, FILE_VERSION};
+
+    use super::{apply_upgrades, pending_upgrades, upgrade_key_value, Upgrade, UPGRADES};
+
+    const FAMILIES: [Family; 12] = [
+        Family::Property,
+        Family::TermIndex,
+        Family::Acl,
+        Family::Blob,
+        Family::Config,
+        Family::LookupValue,
+        Family::LookupCounter,
+        Family::Directory,
+        Family::Queue,
+        Family::Index,
+        Family::Bitmap,
+        Family::Log,
+    ];
+
+    #[test]
+    fn upgrades_are_ordered() {
+        for upgrade in UPGRADES {
+            assert!(upgrade.until < FILE_VERSION, "{}", upgrade.description);
+        }
+        for pair in UPGRADES.windows(2) {
+            assert!(pair[0].until <= pair[1].until, "{}", pair[1].description);
+        }
+    }
+
+    #[test]
+    fn upgrade_v1() {
+        // Version 2 only added the integrity trailer
+        assert_eq!(pending_upgrades(1).count(), 0);
+        for family in FAMILIES {
+            assert_eq!(
+                upgrade_key_value(1, family, 0, vec![1, 2], vec![3, 4]),
+                Ok((vec![1, 2], vec![3, 4])),
+                "{family:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn upgrade_v2() {
+        // Version 3 only added the snapshot id to the header
+        assert_eq!(pending_upgrades(2).count(), 0);
+        for family in FAMILIES {
+            assert_eq!(
+                upgrade_key_value(2, family, 0, vec![1, 2], vec![3, 4]),
+                Ok((vec![1, 2], vec![3, 4])),
+                "{family:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn upgrade_current() {
+        assert_eq!(pending_upgrades(FILE_VERSION).count(), 0);
+        for family in FAMILIES {
+            assert_eq!(
+                upgrade_key_value(FILE_VERSION, family, 0, vec![1, 2], vec![3, 4]),
+                Ok((vec![1, 2], vec![3, 4])),
+                "{family:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn upgrades_are_chained() {
+        let upgrades = [
+            Upgrade {
+                until: 1,
+                family: Family::Property,
+                description: "append 1",
+                apply: |_, key, mut value| {
+                    value.push(1);
+                    Ok((key, value))
+                },
+            },
+            Upgrade {
+                until: 2,
+                family: Family::Property,
+                description: "append 2",
+                apply: |_, key, mut value| {
+                    value.push(2);
+                    Ok((key, value))
+                },
+            },
+            Upgrade {
+                until: 2,
+                family: Family::Acl,
+                description: "fail",
+                apply: |_, _, _| Err("unexpected value".to_string()),
+            },
+        ];
+
+        for (version, expected) in [(1, vec![0, 1, 2]), (2, vec![0, 2]), (3, vec![0])] {
+            assert_eq!(
+                apply_upgrades(&upgrades, version, Family::Property, 0, vec![], vec![0]),
+                Ok((vec![], expected)),
+                "v{version}"
+            );
+        }
+        assert_eq!(
+            apply_upgrades(&upgrades, 3, Family::Acl, 0, vec![], vec![0]),
+            Ok((vec![], vec![0]))
+        );
+        assert_eq!(
+            apply_upgrades(&upgrades, 2, Family::Acl, 0, vec![], vec![0]),
+            Err("Failed to upgrade from v2: unexpected value".to_string())
+        );
+    }
+}
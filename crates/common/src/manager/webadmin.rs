@@ -32,7 +32,10 @@ use store::BlobStore;
 
 use crate::Core;
 
-use super::WEBADMIN_KEY;
+use super::{config::ConfigManager, sha256_hex, WEBADMIN_KEY};
+
+/// SHA-256 of the installed webadmin bundle, re-downloads must match it.
+pub const WEBADMIN_VERSION_KEY: &str = "version.webadmin";
 
 pub struct WebAdminManager {
     bundle_path: TempDir,
@@ -131,19 +134,73 @@ impl WebAdminManager {
     }
 
     pub async fn update_and_unpack(&self, core: &Core) -> store::Result<()> {
-        let bytes = core
-            .storage
+        core.storage
             .config
-            .fetch_resource("webadmin")
+            .install_webadmin(&core.storage.blob, true)
             .await
-            .map_err(|err| {
-                store::Error::InternalError(format!("Failed to download webadmin: {err}"))
-            })?;
-        core.storage.blob.put_blob(WEBADMIN_KEY, &bytes).await?;
+            .map_err(store::Error::InternalError)?;
         self.unpack(&core.storage.blob).await
     }
 }
 
+impl ConfigManager {
+    /// Downloads the webadmin bundle and stores it once its hash matches the one
+    /// recorded in 'version.webadmin'. The hash is recorded on first install and,
+    /// when upgrading, replaced with the one of the new bundle.
+    pub async fn install_webadmin(
+        &self,
+        blob_store: &BlobStore,
+        upgrade: bool,
+    ) -> Result<String, String> {
+        let bytes = self
+            .fetch_resource("webadmin")
+            .await
+            .map_err(|err| format!("Failed to download webadmin: {err}"))?;
+        let hash = sha256_hex(&bytes);
+        let expected = self
+            .webadmin_hash()
+            .await
+            .map_err(|err| format!("Failed to obtain webadmin hash: {err}"))?;
+
+        if let Some(expected) = &expected {
+            if !upgrade && !expected.eq_ignore_ascii_case(&hash) {
+                return Err(format!(
+                    "Webadmin bundle hash mismatch: expected {expected}, found {hash}"
+                ));
+            }
+        }
+
+        // Blob stores may skip writes to existing keys of the same size
+        blob_store
+            .delete_blob(WEBADMIN_KEY)
+            .await
+            .map_err(|err| format!("Failed to replace webadmin blob: {err}"))?;
+        blob_store
+            .put_blob(WEBADMIN_KEY, &bytes)
+            .await
+            .map_err(|err| format!("Failed to store webadmin blob: {err}"))?;
+
+        if !matches!(&expected, Some(expected) if expected.eq_ignore_ascii_case(&hash)) {
+            self.set([(WEBADMIN_VERSION_KEY, hash.as_str())])
+                .await
+                .map_err(|err| format!("Failed to record webadmin hash: {err}"))?;
+        }
+
+        Ok(hash)
+    }
+
+    pub async fn webadmin_hash(&self) -> store::Result<Option<String>> {
+        self.get(WEBADMIN_VERSION_KEY)
+            .await
+            .map(|hash| hash.filter(|hash| !hash.is_empty()))
+    }
+}
+
+/// Returns whether a stored webadmin bundle matches the recorded hash.
+pub fn webadmin_matches(bytes: &[u8], expected: Option<&str>) -> bool {
+    !matches!(expected, Some(expected) if !expected.eq_ignore_ascii_case(&sha256_hex(bytes)))
+}
+
 impl Resource<Vec<u8>> {
     pub fn is_empty(&self) -> bool {
         self.content_type.is_empty() && self.contents.is_empty()
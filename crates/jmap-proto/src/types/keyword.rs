@@ -300,7 +300,7 @@ impl From<Keyword> for TagValue {
             Keyword::Deleted => TagValue::Static(DELETED as u8),
             Keyword::Forwarded => TagValue::Static(FORWARDED as u8),
             Keyword::MdnSent => TagValue::Static(MDN_SENT as u8),
-            Keyword::Other(string) => TagValue::Text(string.into_bytes()),
+            Keyword::Other(string) => TagValue::Text(string.into_bytes().into()),
         }
     }
 }
@@ -320,7 +320,7 @@ impl From<&Keyword> for TagValue {
             Keyword::Deleted => TagValue::Static(DELETED as u8),
             Keyword::Forwarded => TagValue::Static(FORWARDED as u8),
             Keyword::MdnSent => TagValue::Static(MDN_SENT as u8),
-            Keyword::Other(string) => TagValue::Text(string.as_bytes().to_vec()),
+            Keyword::Other(string) => TagValue::Text(string.as_bytes().into()),
         }
     }
 }
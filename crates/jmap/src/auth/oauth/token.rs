@@ -355,13 +355,22 @@ impl JMAP {
             .copied()
             .collect::<Vec<_>>();
 
-        // Decrypt
-        SymmetricEncrypt::new(key.as_bytes(), &context)
-            .decrypt(
-                &token[..RANDOM_CODE_LEN + SymmetricEncrypt::ENCRYPT_TAG_LEN],
-                &nonce,
-            )
-            .map_err(|_| "Failed to decrypt token.")?;
+        // Decrypt, falling back to the previous key during its grace period
+        let decrypt = |key: &str| {
+            SymmetricEncrypt::new(key.as_bytes(), &context)
+                .decrypt(
+                    &token[..RANDOM_CODE_LEN + SymmetricEncrypt::ENCRYPT_TAG_LEN],
+                    &nonce,
+                )
+                .is_ok()
+        };
+        if !decrypt(&key) {
+            match &self.core.jmap.oauth_previous_key {
+                Some((previous_key, expires))
+                    if now + 946684800 < *expires && decrypt(previous_key) => {}
+                _ => return Err("Failed to decrypt token."),
+            }
+        }
 
         // Success
         Ok((account_id, client_id, expiry - now))
@@ -0,0 +1,121 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::path::Path;
+
+use common::manager::maildir::{MaildirStats, INBOX_NAME};
+use jmap_proto::types::keyword::Keyword;
+use mail_parser::{
+    mailbox::maildir::{Flag, FolderIterator},
+    MessageParser,
+};
+
+use crate::{mailbox::INBOX_ID, JMAP};
+
+use super::ingest::IngestEmail;
+
+impl JMAP {
+    /// Ingests the messages of a Maildir++ tree into the mailboxes of `account`,
+    /// creating any missing mailboxes. Quotas are not enforced so that a migration
+    /// is never left half done.
+    pub async fn import_maildir(&self, account: &str, path: &Path) -> Result<MaildirStats, String> {
+        let account_id = self.core.maildir_account_id(account).await?;
+        self.mailbox_get_or_create(account_id)
+            .await
+            .map_err(|err| format!("Failed to create mailboxes: {err:?}"))?;
+
+        let mut stats = MaildirStats::default();
+        for folder in FolderIterator::new(path, Some("."))
+            .map_err(|err| format!("Failed to read {}: {err}", path.display()))?
+        {
+            let folder = folder.map_err(|err| format!("Failed to read folder: {err}"))?;
+            let (name, mailbox_id) = match folder.name() {
+                Some(name) => {
+                    // Subfolders of the inbox are stored as '.INBOX.Child'
+                    let mailbox_path = name
+                        .split('.')
+                        .enumerate()
+                        .map(|(pos, part)| {
+                            if pos == 0 && part.eq_ignore_ascii_case(INBOX_NAME) {
+                                "Inbox"
+                            } else {
+                                part
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    let mailbox_id = self
+                        .mailbox_create_path(account_id, &mailbox_path)
+                        .await
+                        .map_err(|err| format!("Failed to create mailbox {name:?}: {err:?}"))?
+                        .ok_or_else(|| format!("Invalid mailbox name {name:?}."))?
+                        .0;
+                    (name.to_string(), mailbox_id)
+                }
+                None => (INBOX_NAME.to_string(), INBOX_ID),
+            };
+
+            let mut count = 0;
+            for message in folder {
+                let message = message.map_err(|err| format!("Failed to read message: {err}"))?;
+                let keywords = message
+                    .flags()
+                    .iter()
+                    .map(|flag| match flag {
+                        Flag::Passed => Keyword::Forwarded,
+                        Flag::Replied => Keyword::Answered,
+                        Flag::Seen => Keyword::Seen,
+                        Flag::Trashed => Keyword::Deleted,
+                        Flag::Draft => Keyword::Draft,
+                        Flag::Flagged => Keyword::Flagged,
+                    })
+                    .collect();
+
+                match self
+                    .email_ingest(IngestEmail {
+                        raw_message: message.contents(),
+                        message: MessageParser::new().parse(message.contents()),
+                        account_id,
+                        account_quota: 0,
+                        mailbox_ids: vec![mailbox_id],
+                        keywords,
+                        received_at: message.internal_date().into(),
+                        skip_duplicates: false,
+                        encrypt: self.core.jmap.encrypt && self.core.jmap.encrypt_append,
+                    })
+                    .await
+                {
+                    Ok(_) => count += 1,
+                    Err(err) => {
+                        eprintln!("⚠️ Failed to import {}: {err:?}", message.path().display());
+                        stats.failed += 1;
+                    }
+                }
+            }
+
+            stats.folders.push((name, count));
+        }
+
+        Ok(stats)
+    }
+}
@@ -30,6 +30,7 @@ pub mod headers;
 pub mod import;
 pub mod index;
 pub mod ingest;
+pub mod maildir;
 pub mod metadata;
 pub mod parse;
 pub mod query;
@@ -21,15 +21,19 @@
  * for more details.
 */
 
-use std::time::Duration;
-
-use common::{config::server::ServerProtocol, manager::boot::BootManager};
+use common::{
+    config::server::ServerProtocol,
+    manager::{
+        boot::{report_config_errors, BootManager},
+        maildir::print_maildir_report,
+    },
+};
 use imap::core::{ImapSessionManager, IMAP};
 use jmap::{api::JmapSessionManager, services::IPC_CHANNEL_BUFFER, JMAP};
 use managesieve::core::ManageSieveSessionManager;
 use smtp::core::{SmtpSessionManager, SMTP};
 use tokio::sync::mpsc;
-use utils::wait_for_shutdown;
+use utils::{wait_for_shutdown, ExitCode, UnwrapFailure};
 
 #[cfg(not(target_env = "msvc"))]
 use jemallocator::Jemalloc;
@@ -53,10 +57,25 @@ async fn main() -> std::io::Result<()> {
     let jmap = JMAP::init(&mut config, delivery_rx, core.clone(), smtp.inner.clone()).await;
     let imap = IMAP::init(&mut config, jmap.clone()).await;
 
-    // Log configuration errors
-    config.log_errors(init.guards.is_none());
+    // Report configuration errors
+    report_config_errors(&config, init.lenient, init.guards.is_none());
     config.log_warnings(init.guards.is_none());
 
+    // Import a Maildir and exit
+    if let Some((account, path)) = init.import_maildir {
+        let stats = JMAP::from(jmap.clone())
+            .import_maildir(&account, &path)
+            .await
+            .failed_with(ExitCode::Store, "Failed to import Maildir");
+        print_maildir_report(&stats);
+        eprintln!("✅ Imported {} into {account}.", path.display());
+        std::process::exit(0);
+    }
+
+    // Reload configuration on SIGHUP
+    #[cfg(not(target_env = "msvc"))]
+    common::manager::reload::spawn_hangup_handler(core.clone());
+
     // Spawn servers
     let shutdown_tx = init.servers.spawn(|server, acceptor, shutdown_rx| {
         match &server.protocol {
@@ -97,8 +116,17 @@ async fn main() -> std::io::Result<()> {
     // Stop services
     let _ = shutdown_tx.send(true);
 
-    // Wait for services to finish
-    tokio::time::sleep(Duration::from_secs(1)).await;
+    // Wait for active connections and queue deliveries to finish
+    if tokio::time::timeout(init.shutdown_timeout, shutdown_tx.closed())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Shutdown timed out after {:?} with connections or deliveries still active.",
+            init.shutdown_timeout
+        );
+        std::process::exit(1);
+    }
 
     Ok(())
 }
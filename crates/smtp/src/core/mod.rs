@@ -100,6 +100,8 @@ pub struct Inner {
     pub queue_throttle: DashMap<ThrottleKey, ConcurrencyLimiter, ThrottleKeyHasherBuilder>,
     pub queue_tx: mpsc::Sender<queue::Event>,
     pub report_tx: mpsc::Sender<reporting::Event>,
+    /// Queue manager and delivery attempts still running, waited for on shutdown.
+    pub queue_tasks: ConcurrencyLimiter,
     pub snowflake_id: SnowflakeIdGenerator,
     pub connectors: TlsConnectors,
     #[cfg(feature = "local_delivery")]
@@ -439,6 +441,7 @@ impl Default for Inner {
             queue_throttle: Default::default(),
             queue_tx: mpsc::channel(1).0,
             report_tx: mpsc::channel(1).0,
+            queue_tasks: ConcurrencyLimiter::new(u64::MAX),
             snowflake_id: Default::default(),
             connectors: TlsConnectors {
                 pki_verify: mail_send::smtp::tls::build_tls_connector(false),
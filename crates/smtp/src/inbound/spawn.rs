@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use common::listener::{self, SessionManager, SessionStream};
 use tokio_rustls::server::TlsStream;
@@ -79,6 +79,12 @@ impl SessionManager for SmtpSessionManager {
                 .report_tx
                 .send(reporting::Event::Stop)
                 .await;
+
+            // Local deliveries are handed to the delivery manager, which is only
+            // stopped once the queue manager and its deliveries have finished
+            while self.inner.inner.queue_tasks.is_active() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
             #[cfg(feature = "local_delivery")]
             let _ = self
                 .inner
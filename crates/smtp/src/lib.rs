@@ -24,7 +24,7 @@
 use crate::core::{throttle::ThrottleKeyHasherBuilder, TlsConnectors};
 use core::{Inner, SmtpInstance, SMTP};
 
-use common::SharedCore;
+use common::{listener::limiter::ConcurrencyLimiter, SharedCore};
 use dashmap::DashMap;
 use mail_send::smtp::tls::build_tls_connector;
 use queue::manager::SpawnQueue;
@@ -79,6 +79,7 @@ impl SMTP {
             ),
             queue_tx,
             report_tx,
+            queue_tasks: ConcurrencyLimiter::new(u64::MAX),
             snowflake_id: config
                 .property::<u64>("cluster.node-id")
                 .map(SnowflakeIdGenerator::with_node_id)
@@ -57,7 +57,9 @@ use crate::queue::{
 
 impl DeliveryAttempt {
     pub async fn try_deliver(mut self, core: SMTP) {
+        let in_flight = core.inner.queue_tasks.is_allowed();
         tokio::spawn(async move {
+            let _in_flight = in_flight;
             // Lock message
             self.event = if let Some(event) = core.try_lock_event(self.event).await {
                 event
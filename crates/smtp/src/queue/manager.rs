@@ -41,7 +41,9 @@ pub struct Queue {
 
 impl SpawnQueue for mpsc::Receiver<Event> {
     fn spawn(mut self, core: SmtpInstance) {
+        let in_flight = core.inner.queue_tasks.is_allowed();
         tokio::spawn(async move {
+            let _in_flight = in_flight;
             let mut queue = Queue::new(core);
 
             loop {
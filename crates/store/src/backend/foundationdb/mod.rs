@@ -31,6 +31,8 @@ pub mod read;
 pub mod write;
 
 const MAX_VALUE_SIZE: usize = 100000;
+/// Transactions writing more than this many bytes are rejected by FoundationDB.
+pub(crate) const MAX_TRANSACTION_SIZE: usize = 10_000_000;
 
 #[allow(dead_code)]
 pub struct FdbStore {
@@ -112,6 +112,14 @@ impl FsStore {
         Ok(())
     }
 
+    pub(crate) async fn blob_size(&self, key: &[u8]) -> crate::Result<Option<usize>> {
+        match fs::metadata(self.build_path(key)).await {
+            Ok(m) => Ok(Some(m.len() as usize)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     pub(crate) async fn delete_blob(&self, key: &[u8]) -> crate::Result<bool> {
         let blob_path = self.build_path(key);
         if fs::metadata(&blob_path).await.is_ok() {
@@ -35,7 +35,7 @@ use rocksdb::{
 };
 
 use super::{
-    bitmap::{clear_bit, set_bit},
+    bitmap::{clear_bit, set_bit, set_bits},
     RocksDbStore, CF_BITMAPS, CF_COUNTERS, CF_INDEXES, CF_LOGS, CF_VALUES,
 };
 use crate::{
@@ -120,6 +120,23 @@ impl RocksDbStore {
         .await
     }
 
+    pub(crate) async fn merge_bitmap(
+        &self,
+        key: BitmapKey<BitmapClass>,
+        document_ids: &RoaringBitmap,
+    ) -> crate::Result<()> {
+        let db = self.db.clone();
+        self.spawn_worker(move || {
+            db.merge_cf(
+                &db.cf_handle(CF_BITMAPS).unwrap(),
+                key.serialize(WITHOUT_BLOCK_NUM),
+                set_bits(document_ids.iter()),
+            )
+            .map_err(Into::into)
+        })
+        .await
+    }
+
     pub(crate) async fn purge_store(&self) -> crate::Result<()> {
         let db = self.db.clone();
         self.spawn_worker(move || {
@@ -128,6 +128,19 @@ impl S3Store {
         }
     }
 
+    pub(crate) async fn blob_size(&self, key: &[u8]) -> crate::Result<Option<usize>> {
+        match self.bucket.head_object(self.build_key(key)).await {
+            Ok((head, status_code)) if (200..300).contains(&status_code) => {
+                Ok(head.content_length.map(|len| len as usize))
+            }
+            Ok((_, 404)) => Ok(None),
+            Ok((_, status_code)) => Err(crate::Error::InternalError(format!(
+                "S3 error code {status_code}"
+            ))),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     pub(crate) async fn delete_blob(&self, key: &[u8]) -> crate::Result<bool> {
         self.bucket
             .delete_object(self.build_key(key))
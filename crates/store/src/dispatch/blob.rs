@@ -23,9 +23,11 @@
 
 use std::{borrow::Cow, ops::Range};
 
-use utils::config::utils::ParseValue;
+use utils::{config::utils::ParseValue, BlobHash};
 
-use crate::{BlobBackend, BlobStore, CompressionAlgo, Store};
+use crate::{write::now, BlobBackend, BlobStore, CompressionAlgo, Store};
+
+use super::store::check_key;
 
 impl BlobStore {
     pub async fn get_blob(
@@ -145,6 +147,49 @@ impl BlobStore {
         }
     }
 
+    /// Size of a blob as stored, after compression. Blob stores keeping it as
+    /// metadata report it without reading the blob, blobs kept in the data
+    /// store are read to measure them.
+    pub async fn blob_size(&self, key: &[u8]) -> crate::Result<Option<usize>> {
+        match &self.backend {
+            BlobBackend::Store(store) => match store {
+                #[cfg(feature = "sqlite")]
+                Store::SQLite(store) => store.get_blob(key, 0..usize::MAX).await,
+                #[cfg(feature = "foundation")]
+                Store::FoundationDb(store) => store.get_blob(key, 0..usize::MAX).await,
+                #[cfg(feature = "postgres")]
+                Store::PostgreSQL(store) => store.get_blob(key, 0..usize::MAX).await,
+                #[cfg(feature = "mysql")]
+                Store::MySQL(store) => store.get_blob(key, 0..usize::MAX).await,
+                #[cfg(feature = "rocks")]
+                Store::RocksDb(store) => store.get_blob(key, 0..usize::MAX).await,
+                Store::None => Err(crate::Error::InternalError("No store configured".into())),
+            }
+            .map(|blob| blob.map(|blob| blob.len())),
+            BlobBackend::Fs(store) => store.blob_size(key).await,
+            #[cfg(feature = "s3")]
+            BlobBackend::S3(store) => store.blob_size(key).await,
+        }
+    }
+
+    /// Writes, reads back and deletes a temporary blob to verify that the store is reachable.
+    pub async fn check_connectivity(&self) -> crate::Result<()> {
+        let key = BlobHash::from(check_key().as_slice());
+        let value = now().to_string().into_bytes();
+
+        self.put_blob(key.as_slice(), &value).await?;
+        let result = self.get_blob(key.as_slice(), 0..usize::MAX).await?;
+        self.delete_blob(key.as_slice()).await?;
+
+        if result.as_ref() == Some(&value) {
+            Ok(())
+        } else {
+            Err(crate::Error::InternalError(
+                "Read after write returned a different blob".into(),
+            ))
+        }
+    }
+
     pub fn with_compression(self, compression: CompressionAlgo) -> Self {
         Self {
             backend: self.backend,
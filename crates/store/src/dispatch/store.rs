@@ -27,10 +27,16 @@ use roaring::RoaringBitmap;
 
 use crate::{
     write::{key::KeySerializer, now, AnyKey, Batch, BitmapClass, ReportClass, ValueClass},
-    BitmapKey, Deserialize, IterateParams, Key, Store, ValueKey, SUBSPACE_BITMAPS,
+    BitmapKey, Deserialize, IterateParams, Key, LookupStore, Store, ValueKey, SUBSPACE_BITMAPS,
     SUBSPACE_INDEXES, SUBSPACE_LOGS, U32_LEN,
 };
 
+/// Key of the temporary value or blob written by a connectivity check, unique
+/// to each check so that servers sharing a store don't race on it.
+pub(crate) fn check_key() -> Vec<u8> {
+    format!("_stalwart_store_check_{:016x}", rand::random::<u64>()).into_bytes()
+}
+
 #[cfg(feature = "test_mode")]
 lazy_static::lazy_static! {
 pub static ref BITMAPS: std::sync::Arc<parking_lot::Mutex<std::collections::HashMap<Vec<u8>, std::collections::HashSet<u32>>>> =
@@ -293,6 +299,54 @@ impl Store {
         }
     }
 
+    /// Whether whole bitmaps can be merged atomically with [`Store::merge_bitmap`],
+    /// otherwise bitmaps have to be written one document at a time.
+    pub fn supports_bitmap_merge(&self) -> bool {
+        match self {
+            #[cfg(feature = "rocks")]
+            Self::RocksDb(_) => true,
+            #[cfg(feature = "sqlite")]
+            Self::SQLite(_) => false,
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(_) => false,
+            #[cfg(feature = "postgres")]
+            Self::PostgreSQL(_) => false,
+            #[cfg(feature = "mysql")]
+            Self::MySQL(_) => false,
+            Self::None => false,
+        }
+    }
+
+    /// Largest number of bytes a single [`Store::write`] can add, for backends
+    /// that reject larger transactions.
+    pub fn max_write_bytes(&self) -> Option<usize> {
+        match self {
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(_) => Some(crate::backend::foundationdb::MAX_TRANSACTION_SIZE),
+            _ => None,
+        }
+    }
+
+    pub async fn merge_bitmap(
+        &self,
+        key: BitmapKey<BitmapClass>,
+        document_ids: &RoaringBitmap,
+    ) -> crate::Result<()> {
+        if document_ids.is_empty() {
+            return Ok(());
+        }
+
+        #[cfg(feature = "rocks")]
+        if let Self::RocksDb(store) = self {
+            return store.merge_bitmap(key, document_ids).await;
+        }
+
+        Err(crate::Error::InternalError(format!(
+            "Bitmap merge not supported by store, key: {:?}",
+            key.class
+        )))
+    }
+
     pub async fn purge_account(&self, account_id: u32) -> crate::Result<()> {
         for subspace in [SUBSPACE_BITMAPS, SUBSPACE_LOGS, SUBSPACE_INDEXES] {
             self.delete_range(
@@ -386,6 +440,27 @@ impl Store {
         }
     }
 
+    /// Writes, reads back and deletes a temporary key to verify that the store is reachable.
+    pub async fn check_connectivity(&self) -> crate::Result<()> {
+        let store = LookupStore::Store(self.clone());
+        let key = check_key();
+        let value = now().to_string();
+
+        store
+            .key_set(key.clone(), value.clone().into_bytes(), Some(60))
+            .await?;
+        let result = store.key_get::<String>(key.clone()).await?;
+        store.key_delete(key).await?;
+
+        if result.as_ref() == Some(&value) {
+            Ok(())
+        } else {
+            Err(crate::Error::InternalError(format!(
+                "Read after write returned {result:?}, expected {value:?}"
+            )))
+        }
+    }
+
     #[cfg(feature = "test_mode")]
     pub async fn destroy(&self) {
         use crate::{SUBSPACE_BLOBS, SUBSPACE_COUNTERS, SUBSPACE_VALUES};
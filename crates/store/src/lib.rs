@@ -119,6 +119,23 @@ pub struct LogKey {
     pub change_id: u64,
 }
 
+/// Storage features compiled into this build.
+pub fn compiled_features() -> Vec<&'static str> {
+    [
+        ("rocks", cfg!(feature = "rocks")),
+        ("foundation", cfg!(feature = "foundation")),
+        ("sqlite", cfg!(feature = "sqlite")),
+        ("postgres", cfg!(feature = "postgres")),
+        ("mysql", cfg!(feature = "mysql")),
+        ("elastic", cfg!(feature = "elastic")),
+        ("s3", cfg!(feature = "s3")),
+        ("redis", cfg!(feature = "redis")),
+    ]
+    .into_iter()
+    .filter_map(|(feature, enabled)| enabled.then_some(feature))
+    .collect()
+}
+
 pub const U64_LEN: usize = std::mem::size_of::<u64>();
 pub const U32_LEN: usize = std::mem::size_of::<u32>();
 
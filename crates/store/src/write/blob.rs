@@ -31,6 +31,12 @@ use crate::{
 
 use super::{key::DeserializeBigEndian, now, BlobOp, Operation, ValueClass, ValueOp};
 
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct OrphanedBlobs {
+    pub count: usize,
+    pub bytes: usize,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct BlobQuota {
     pub bytes: usize,
@@ -133,6 +139,13 @@ impl Store {
     }
 
     pub async fn purge_blobs(&self, blob_store: BlobStore) -> crate::Result<()> {
+        let delete_keys = self.unreferenced_blob_keys().await?;
+        self.delete_blob_keys(&blob_store, delete_keys).await
+    }
+
+    /// Keys of the expired blob reservations, along with the commits of the
+    /// blobs that are neither linked to a document nor reserved.
+    async fn unreferenced_blob_keys(&self) -> crate::Result<Vec<ValueKey<ValueClass>>> {
         // Remove expired temporary blobs
         let from_key = ValueKey {
             account_id: 0,
@@ -233,6 +246,14 @@ impl Store {
         )
         .await?;
 
+        Ok(delete_keys)
+    }
+
+    async fn delete_blob_keys(
+        &self,
+        blob_store: &BlobStore,
+        delete_keys: Vec<ValueKey<ValueClass>>,
+    ) -> crate::Result<()> {
         // Delete expired or unlinked blobs
         for key in &delete_keys {
             if let ValueClass::Blob(BlobOp::Commit { hash }) = &key.class {
@@ -267,6 +288,34 @@ impl Store {
         Ok(())
     }
 
+    /// Finds committed blobs that are neither linked to a document nor reserved,
+    /// deleting them from the store and the blob store if `delete` is set, the
+    /// same way `purge_blobs` does. Sizes are those of the blobs as stored.
+    pub async fn collect_orphaned_blobs(
+        &self,
+        blob_store: BlobStore,
+        delete: bool,
+    ) -> crate::Result<OrphanedBlobs> {
+        let delete_keys = self.unreferenced_blob_keys().await?;
+
+        let mut orphaned = OrphanedBlobs::default();
+        for key in &delete_keys {
+            if let ValueClass::Blob(BlobOp::Commit { hash }) = &key.class {
+                orphaned.bytes += blob_store
+                    .blob_size(hash.as_ref())
+                    .await?
+                    .unwrap_or_default();
+                orphaned.count += 1;
+            }
+        }
+
+        if delete {
+            self.delete_blob_keys(&blob_store, delete_keys).await?;
+        }
+
+        Ok(orphaned)
+    }
+
     pub async fn blob_hash_unlink_account(&self, account_id: u32) -> crate::Result<()> {
         // Validate linked blobs
         let from_key = ValueKey {
@@ -436,7 +436,7 @@ impl<T: AsRef<BitmapClass> + Sync + Send> Key for BitmapKey<T> {
                 .write(self.collection)
                 .write(BM_TAG | TAG_TEXT)
                 .write(*field)
-                .write(text.as_slice()),
+                .write(text.as_ref()),
                 TagValue::Static(id) => if (flags & WITH_SUBSPACE) != 0 {
                     KeySerializer::new(U32_LEN + 5).write(SUBSPACE_BITMAPS)
                 } else {
@@ -25,6 +25,7 @@ use std::{
     collections::HashSet,
     hash::Hash,
     slice::Iter,
+    sync::Arc,
     time::{Duration, SystemTime},
 };
 
@@ -73,7 +74,7 @@ pub struct BatchBuilder {
     pub ops: Vec<Operation>,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Operation {
     AccountId {
         account_id: u32,
@@ -124,7 +125,7 @@ pub struct BitmapHash {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TagValue {
     Id(u32),
-    Text(Vec<u8>),
+    Text(Arc<[u8]>),
     Static(u8),
 }
 
@@ -194,7 +195,7 @@ pub struct ReportEvent {
     pub domain: String,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub enum ValueOp {
     Set(Vec<u8>),
     AtomicAdd(i64),
@@ -224,13 +225,13 @@ impl From<u32> for TagValue {
 
 impl From<Vec<u8>> for TagValue {
     fn from(value: Vec<u8>) -> Self {
-        TagValue::Text(value)
+        TagValue::Text(value.into())
     }
 }
 
 impl From<String> for TagValue {
     fn from(value: String) -> Self {
-        TagValue::Text(value.into_bytes())
+        TagValue::Text(value.into_bytes().into())
     }
 }
 
@@ -242,7 +243,7 @@ impl From<u8> for TagValue {
 
 impl From<()> for TagValue {
     fn from(_: ()) -> Self {
-        TagValue::Text(vec![])
+        TagValue::Text(Arc::from([]))
     }
 }
 
@@ -28,7 +28,7 @@ pub mod utils;
 
 use std::{collections::BTreeMap, time::Duration};
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 use serde::Serialize;
 
 #[derive(Debug, Default, Serialize)]
@@ -37,6 +37,10 @@ pub struct Config {
     pub keys: BTreeMap<String, String>,
     pub warnings: AHashMap<String, ConfigWarning>,
     pub errors: AHashMap<String, ConfigError>,
+    /// Keys whose values were read from files or environment variables
+    /// by macros, these are never displayed.
+    #[serde(skip)]
+    pub secret_keys: AHashSet<String>,
     #[cfg(debug_assertions)]
     #[serde(skip)]
     pub keys_read: parking_lot::Mutex<ahash::AHashSet<String>>,
@@ -72,19 +76,68 @@ pub struct Rate {
 
 pub type Result<T> = std::result::Result<T, String>;
 
+const ENV_PREFIX: &str = "STALWART__";
+const MACRO_CLASSES: [&str; 3] = ["env", "file", "cfg"];
+const MAX_MACRO_DEPTH: usize = 10;
+
 impl Config {
     pub async fn resolve_macros(&mut self) {
-        for macro_class in ["env", "file", "cfg"] {
-            self.resolve_macro_type(macro_class).await;
+        // Expansions may contain further macros, resolve until nothing is left
+        for _ in 0..MAX_MACRO_DEPTH {
+            let pending = self.unresolved_macros();
+            if pending.is_empty() {
+                return;
+            }
+
+            for macro_class in MACRO_CLASSES {
+                self.resolve_macro_type(macro_class).await;
+            }
+
+            // A value that expands to itself can never be resolved
+            for (key, value) in pending {
+                if self.keys.get(&key) == Some(&value) && !self.errors.contains_key(&key) {
+                    self.errors.insert(
+                        key,
+                        ConfigError::Macro {
+                            error: "Cyclic macro reference".to_string(),
+                        },
+                    );
+                }
+            }
         }
+
+        for (key, _) in self.unresolved_macros() {
+            self.errors.insert(
+                key,
+                ConfigError::Macro {
+                    error: format!("Macro expansion exceeds {MAX_MACRO_DEPTH} levels"),
+                },
+            );
+        }
+    }
+
+    fn unresolved_macros(&self) -> Vec<(String, String)> {
+        self.keys
+            .iter()
+            .filter(|(key, value)| {
+                !self.errors.contains_key(*key)
+                    && value.contains("}%")
+                    && MACRO_CLASSES
+                        .iter()
+                        .any(|class| value.contains(&format!("%{{{class}:")))
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
     }
 
     async fn resolve_macro_type(&mut self, class: &str) {
         let macro_start = format!("%{{{class}:");
         let mut replacements = AHashMap::new();
+        let mut secret_keys = Vec::new();
         'outer: for (key, value) in &self.keys {
             if value.contains(&macro_start) && value.contains("}%") {
                 let mut result = String::with_capacity(value.len());
+                let mut is_secret = class != "cfg";
                 let mut snippet: &str = value.as_str();
 
                 loop {
@@ -100,6 +153,7 @@ impl Config {
                                         .or_else(|| self.keys.get(location))
                                     {
                                         result.push_str(value);
+                                        is_secret |= self.secret_keys.contains(location);
                                     } else {
                                         self.errors.insert(
                                             key.clone(),
@@ -127,7 +181,9 @@ impl Config {
                                     match tokio::fs::read(file_name).await {
                                         Ok(value) => match String::from_utf8(value) {
                                             Ok(value) => {
-                                                result.push_str(&value);
+                                                // Secret files usually end with a newline
+                                                result
+                                                    .push_str(value.trim_end_matches(['\r', '\n']));
                                             }
                                             Err(err) => {
                                                 self.errors.insert(
@@ -167,9 +223,13 @@ impl Config {
                     }
                 }
 
+                if is_secret {
+                    secret_keys.push(key.clone());
+                }
                 replacements.insert(key.clone(), result);
             }
         }
+        self.secret_keys.extend(secret_keys);
 
         if !replacements.is_empty() {
             for (key, value) in replacements {
@@ -178,6 +238,60 @@ impl Config {
         }
     }
 
+    /// Overrides settings with the environment variables starting with
+    /// `STALWART__`, taking precedence over both local and stored settings.
+    ///
+    /// The rest of the variable name is the key in uppercase, where `__`
+    /// separates key components, `_` stands for `-` and `___` for a literal
+    /// `_`. For example, `STALWART__SERVER__LISTENER__SMTP__BIND` sets
+    /// `server.listener.smtp.bind` and `STALWART__SERVER__MAX_CONNECTIONS`
+    /// sets `server.max-connections`. An override replaces any array or table
+    /// stored under the same key.
+    pub fn apply_env_overrides(&mut self) {
+        self.apply_overrides(std::env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }));
+    }
+
+    fn apply_overrides(&mut self, vars: impl IntoIterator<Item = (String, String)>) {
+        for (name, value) in vars {
+            let Some(env_key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+
+            let mut key = String::with_capacity(env_key.len());
+            let mut underscores = 0;
+            for ch in env_key.chars().chain([char::from(0)]) {
+                if ch == '_' {
+                    underscores += 1;
+                    continue;
+                }
+                match underscores {
+                    0 => (),
+                    1 => key.push('-'),
+                    2 => key.push('.'),
+                    3 => key.push('_'),
+                    _ => {
+                        key.clear();
+                        break;
+                    }
+                }
+                underscores = 0;
+                if ch != char::from(0) {
+                    key.push(ch.to_ascii_lowercase());
+                }
+            }
+
+            if !key.is_empty() && !key.starts_with('.') && !key.ends_with('.') {
+                let prefix = format!("{key}.");
+                self.keys.retain(|key, _| !key.starts_with(&prefix));
+                self.keys.insert(key, value);
+            } else {
+                self.new_parse_error(name, "Invalid configuration override variable name");
+            }
+        }
+    }
+
     pub fn update(&mut self, settings: Vec<(String, String)>) {
         self.keys.extend(settings);
     }
@@ -234,6 +348,7 @@ impl Clone for Config {
             keys: self.keys.clone(),
             warnings: self.warnings.clone(),
             errors: self.errors.clone(),
+            secret_keys: self.secret_keys.clone(),
             #[cfg(debug_assertions)]
             keys_read: Default::default(),
         }
@@ -24,6 +24,7 @@
 use std::{
     collections::{btree_map::Entry, BTreeMap},
     iter::Peekable,
+    path::{Path, PathBuf},
     str::Chars,
 };
 
@@ -31,6 +32,7 @@ use super::{Config, Result};
 use std::fmt::Write;
 
 const MAX_NEST_LEVEL: usize = 10;
+const INCLUDE_KEY: &str = "include.files";
 
 // Simple TOML parser for Stalwart Mail Server configuration files.
 impl Config {
@@ -134,6 +136,94 @@ impl Config {
     }
 }
 
+impl Config {
+    /// Parses the contents of the configuration file at `path` along with the
+    /// files listed in its `include.files` setting, which are resolved relative
+    /// to the including file. Included files are merged in order and override
+    /// the settings of the files before them.
+    pub fn parse_with_includes(&mut self, toml: &str, path: impl AsRef<Path>) -> Result<()> {
+        self.parse_include(toml, path.as_ref(), &mut Vec::new())
+    }
+
+    /// Parses every `*.toml` file in `dir` in lexical order, with later files
+    /// overriding the settings of the files before them. Returns the keys that
+    /// were already set along with the file whose value was kept.
+    pub fn parse_dir(&mut self, dir: impl AsRef<Path>) -> Result<Vec<(String, PathBuf)>> {
+        let dir = dir.as_ref();
+        let mut files = std::fs::read_dir(dir)
+            .map_err(|err| format!("Could not read directory {}: {err}", dir.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.is_file() && path.extension().and_then(|ext| ext.to_str()) == Some("toml")
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+
+        let mut overrides = Vec::new();
+        for file in files {
+            let toml = std::fs::read_to_string(&file)
+                .map_err(|err| format!("Could not read file {}: {err}", file.display()))?;
+            let mut config = Config::default();
+            config.parse_with_includes(&toml, &file)?;
+            for (key, value) in config.keys {
+                if self.keys.insert(key.clone(), value).is_some() {
+                    overrides.push((key, file.clone()));
+                }
+            }
+        }
+
+        Ok(overrides)
+    }
+
+    fn parse_include(&mut self, toml: &str, path: &Path, stack: &mut Vec<PathBuf>) -> Result<()> {
+        let mut config = Config::default();
+        config
+            .parse(toml)
+            .map_err(|err| format!("Failed to parse {}: {err}", path.display()))?;
+
+        // Included files are not part of the configuration
+        let include_prefix = format!("{INCLUDE_KEY}.");
+        let mut includes = Vec::new();
+        config.keys.retain(|key, value| {
+            if key == INCLUDE_KEY || key.starts_with(&include_prefix) {
+                includes.push(std::mem::take(value));
+                false
+            } else {
+                true
+            }
+        });
+        self.keys.extend(config.keys);
+
+        if !includes.is_empty() {
+            stack.push(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+            for include in includes {
+                let include = path.parent().unwrap_or(Path::new("")).join(include);
+                let canonical = include.canonicalize().map_err(|err| {
+                    format!(
+                        "Could not read file {} included from {}: {err}",
+                        include.display(),
+                        path.display()
+                    )
+                })?;
+                if stack.contains(&canonical) {
+                    return Err(format!(
+                        "Include cycle detected: {} includes {} which is already being included.",
+                        path.display(),
+                        include.display()
+                    ));
+                }
+                let toml = std::fs::read_to_string(&include).map_err(|err| {
+                    format!("Could not read included file {}: {err}", include.display())
+                })?;
+                self.parse_include(&toml, &include, stack)?;
+            }
+            stack.pop();
+        }
+
+        Ok(())
+    }
+}
+
 struct TomlParser<'x, 'y> {
     keys: &'y mut BTreeMap<String, String>,
     iter: Peekable<Chars<'x>>,
@@ -462,6 +552,78 @@ mod tests {
 
     use crate::config::Config;
 
+    #[test]
+    fn toml_include() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .to_path_buf()
+            .join("tests")
+            .join("resources")
+            .join("smtp")
+            .join("config")
+            .join("include");
+
+        let file = dir.join("main.toml");
+        let mut config = Config::default();
+        config
+            .parse_with_includes(&fs::read_to_string(&file).unwrap(), &file)
+            .unwrap();
+        assert_eq!(
+            config.keys,
+            BTreeMap::from_iter(
+                [
+                    ("server.hostname", "override.example.org"),
+                    ("server.listener.smtp.bind", "[::]:25"),
+                    ("server.listener.smtp.protocol", "smtp"),
+                    ("spam.enable", "true"),
+                ]
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+            )
+        );
+
+        let file = dir.join("cycle-a.toml");
+        let err = Config::default()
+            .parse_with_includes(&fs::read_to_string(&file).unwrap(), &file)
+            .unwrap_err();
+        assert!(err.contains("Include cycle detected"), "{err}");
+        assert!(err.contains("cycle-a.toml"), "{err}");
+    }
+
+    #[test]
+    fn toml_dir() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .to_path_buf()
+            .join("tests")
+            .join("resources")
+            .join("smtp")
+            .join("config")
+            .join("conf.d");
+
+        let mut config = Config::default();
+        let overrides = config.parse_dir(&dir).unwrap();
+        assert_eq!(
+            config.keys,
+            BTreeMap::from_iter(
+                [
+                    ("server.hostname", "mail.example.org"),
+                    ("server.max-connections", "8192"),
+                ]
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+            )
+        );
+        assert_eq!(
+            overrides,
+            vec![("server.hostname".to_string(), dir.join("20-local.toml"))]
+        );
+    }
+
     #[test]
     fn toml_parse() {
         let file = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
@@ -551,6 +551,29 @@ impl ParseValue for Duration {
     }
 }
 
+/// Parses a size such as '512', '100MB' or '2GiB' into bytes.
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(
+        value
+            .find(|ch: char| !ch.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
 impl ParseValue for Rate {
     fn parse_value(value: &str) -> super::Result<Self> {
         if let Some((requests, period)) = value.split_once('/') {
@@ -666,7 +689,141 @@ impl AsKey for (&str, &str, &str, &str) {
 mod tests {
     use std::net::IpAddr;
 
-    use crate::config::Config;
+    use crate::config::{utils::parse_size, Config, ConfigError};
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("100MB"), Some(100_000_000));
+        assert_eq!(parse_size("2GiB"), Some(2 << 30));
+        assert_eq!(parse_size("1 kib"), Some(1024));
+        assert_eq!(parse_size("2XB"), None);
+        assert_eq!(parse_size("GiB"), None);
+    }
+
+    #[test]
+    fn env_overrides() {
+        let mut config = Config::default();
+        config
+            .parse(
+                r#"
+[server]
+hostname = "mx.example.org"
+max-connections = 8192
+
+[server.listener.smtp]
+bind = ["[::]:25", "[::]:2525"]
+"#,
+            )
+            .unwrap();
+        config.apply_overrides(
+            [
+                ("STALWART__SERVER__HOSTNAME", "mail.example.org"),
+                ("STALWART__SERVER__MAX_CONNECTIONS", "1024"),
+                ("STALWART__SERVER__LISTENER__SMTP__BIND", "127.0.0.1:25"),
+                ("STALWART__LOOKUP__DEFAULT___DOMAIN", "example.org"),
+                ("STALWART__SERVER____HOSTNAME", "invalid"),
+                ("HOSTNAME", "ignored"),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        assert_eq!(
+            config
+                .keys
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("lookup.default_domain", "example.org"),
+                ("server.hostname", "mail.example.org"),
+                ("server.listener.smtp.bind", "127.0.0.1:25"),
+                ("server.max-connections", "1024"),
+            ]
+        );
+        assert!(config.errors.contains_key("STALWART__SERVER____HOSTNAME"));
+    }
+
+    #[tokio::test]
+    async fn secret_macros() {
+        let secret_file = std::env::temp_dir().join("stalwart_secret_macro_test");
+        std::fs::write(&secret_file, "s3cr3t\n").unwrap();
+        std::env::set_var("STALWART_SECRET_MACRO_TEST", "from-env");
+
+        let mut config = Config::new(format!(
+            r#"
+[server]
+hostname = "mx.example.org"
+
+[auth]
+secret = "%{{file:{}}}%"
+token = "%{{env:STALWART_SECRET_MACRO_TEST}}%"
+copy = "%{{cfg:auth.secret}}%"
+host = "%{{cfg:server.hostname}}%"
+"#,
+            secret_file.display()
+        ))
+        .unwrap();
+        config.resolve_macros().await;
+        std::fs::remove_file(&secret_file).unwrap();
+
+        assert!(config.errors.is_empty(), "{:?}", config.errors);
+        assert_eq!(config.value("auth.secret"), Some("s3cr3t"));
+        assert_eq!(config.value("auth.token"), Some("from-env"));
+        assert_eq!(config.value("auth.copy"), Some("s3cr3t"));
+        assert_eq!(config.value("auth.host"), Some("mx.example.org"));
+
+        let mut secret_keys = config.secret_keys.iter().cloned().collect::<Vec<_>>();
+        secret_keys.sort();
+        assert_eq!(secret_keys, ["auth.copy", "auth.secret", "auth.token"]);
+    }
+
+    #[tokio::test]
+    async fn nested_macros() {
+        std::env::set_var("STALWART_NESTED_MACRO_TEST", "%{cfg:server.domain}%");
+
+        let mut config = Config::new(
+            r#"
+[server]
+domain = "example.org"
+hostname = "mx.%{env:STALWART_NESTED_MACRO_TEST}%"
+greeting = "Welcome to %{cfg:server.hostname}%"
+
+[cycle]
+a = "%{cfg:cycle.b}%"
+b = "%{cfg:cycle.a}%"
+runaway = "x%{cfg:cycle.runaway}%"
+"#,
+        )
+        .unwrap();
+        config.resolve_macros().await;
+
+        // Two-level references are fully expanded
+        assert_eq!(config.value("server.hostname"), Some("mx.example.org"));
+        assert_eq!(
+            config.value("server.greeting"),
+            Some("Welcome to mx.example.org")
+        );
+
+        // Cyclic and runaway references are reported
+        let mut errors = config
+            .errors
+            .iter()
+            .map(|(key, err)| match err {
+                ConfigError::Macro { error } => (key.as_str(), error.as_str()),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        errors.sort_unstable();
+        assert_eq!(
+            errors,
+            [
+                ("cycle.a", "Cyclic macro reference"),
+                ("cycle.b", "Cyclic macro reference"),
+                ("cycle.runaway", "Macro expansion exceeds 10 levels"),
+            ]
+        );
+    }
 
     #[test]
     fn toml_utils() {
@@ -99,37 +99,64 @@ impl AsMut<[u8]> for BlobHash {
     }
 }
 
+/// Exit status of the command line tools, so that automation can tell apart
+/// failures worth retrying from those that need an operator.
+///
+/// | Code | Meaning                                                        |
+/// |------|----------------------------------------------------------------|
+/// | 0    | Success                                                        |
+/// | 1    | Unclassified failure, or differences found by `--diff-backup`  |
+/// | 64   | Invalid command line or configuration                          |
+/// | 65   | Invalid or corrupted backup or input data                      |
+/// | 66   | Store or I/O failure, which may succeed if retried             |
+/// | 70   | Internal error                                                 |
+/// | 78   | Configuration file not found or unreadable                     |
+/// | 130  | Import cancelled, it can be continued with `--resume`          |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Failure = 1,
+    Config = 64,
+    Data = 65,
+    Store = 66,
+    Internal = 70,
+    ConfigNotFound = 78,
+    Interrupted = 130,
+}
+
 pub trait UnwrapFailure<T> {
-    fn failed(self, action: &str) -> T;
+    fn failed(self, action: &str) -> T
+    where
+        Self: Sized,
+    {
+        self.failed_with(ExitCode::Failure, action)
+    }
+
+    fn failed_with(self, code: ExitCode, action: &str) -> T;
 }
 
 impl<T> UnwrapFailure<T> for Option<T> {
-    fn failed(self, message: &str) -> T {
+    fn failed_with(self, code: ExitCode, message: &str) -> T {
         match self {
             Some(result) => result,
-            None => {
-                tracing::error!("{message}");
-                eprintln!("{message}");
-                std::process::exit(1);
-            }
+            None => failed_with(code, message),
         }
     }
 }
 
 impl<T, E: std::fmt::Display> UnwrapFailure<T> for Result<T, E> {
-    fn failed(self, message: &str) -> T {
+    fn failed_with(self, code: ExitCode, message: &str) -> T {
         match self {
             Ok(result) => result,
             Err(err) => {
                 tracing::error!("{message}: {err}");
 
                 #[cfg(feature = "test_mode")]
-                panic!("{message}: {err}");
+                panic!("{message}: {err} (exit code {})", code as i32);
 
                 #[cfg(not(feature = "test_mode"))]
                 {
                     eprintln!("{message}: {err}");
-                    std::process::exit(1);
+                    std::process::exit(code as i32);
                 }
             }
         }
@@ -137,9 +164,13 @@ impl<T, E: std::fmt::Display> UnwrapFailure<T> for Result<T, E> {
 }
 
 pub fn failed(message: &str) -> ! {
+    failed_with(ExitCode::Failure, message)
+}
+
+pub fn failed_with(code: ExitCode, message: &str) -> ! {
     tracing::error!("{message}");
     eprintln!("{message}");
-    std::process::exit(1);
+    std::process::exit(code as i32);
 }
 
 pub async fn wait_for_shutdown(message: &str) {
@@ -0,0 +1,170 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of Stalwart Mail Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{path::Path, time::UNIX_EPOCH};
+
+use jmap_proto::types::id::Id;
+
+use crate::{
+    jmap::{assert_is_empty, mailbox::destroy_all_mailboxes},
+    store::TempDir,
+};
+
+use super::JMAPTest;
+
+pub async fn test(params: &mut JMAPTest) {
+    println!("Running Maildir import/export tests...");
+    let server = params.server.clone();
+    let temp_dir = TempDir::new("jmap_maildir_tests", true);
+
+    // Build a Maildir++ tree
+    let import_path = temp_dir.path.join("import");
+    for (folder, file_name, contents, received_at) in [
+        (
+            "",
+            "cur/1.host:2,FS",
+            "Subject: one\r\n\r\nfirst\r\n",
+            1700000000,
+        ),
+        (
+            "",
+            "new/2.host",
+            "Subject: two\r\n\r\nsecond\r\n",
+            1700000001,
+        ),
+        (
+            ".Work.Projects",
+            "cur/3.host:2,DR",
+            "Subject: three\r\n\r\nthird\r\n",
+            1700000002,
+        ),
+        (
+            ".INBOX.Archive",
+            "cur/4.host:2,T",
+            "Subject: four\r\n\r\nfourth\r\n",
+            1700000003,
+        ),
+    ] {
+        let folder = import_path.join(folder);
+        for dir in ["cur", "new", "tmp"] {
+            std::fs::create_dir_all(folder.join(dir)).unwrap();
+        }
+        let file = folder.join(file_name);
+        std::fs::write(&file, contents).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + std::time::Duration::from_secs(received_at))
+            .unwrap();
+    }
+
+    // Import the Maildir
+    let mut stats = server.import_maildir("1", &import_path).await.unwrap();
+    stats.folders.sort();
+    assert_eq!(
+        stats.folders,
+        vec![
+            ("INBOX".to_string(), 2),
+            ("INBOX.Archive".to_string(), 1),
+            ("Work.Projects".to_string(), 1)
+        ]
+    );
+    assert_eq!(stats.failed, 0);
+
+    // Exporting the account should produce the same messages, flags and dates
+    let export_path = temp_dir.path.join("export");
+    let stats = server.core.export_maildir("1", &export_path).await.unwrap();
+    assert_eq!(stats.missing_blobs, 0);
+    for (folder, expected) in [
+        (
+            "",
+            vec![
+                ("Subject: one\r\n\r\nfirst\r\n", "FS", 1700000000),
+                ("Subject: two\r\n\r\nsecond\r\n", "", 1700000001),
+            ],
+        ),
+        (
+            ".INBOX.Archive",
+            vec![("Subject: four\r\n\r\nfourth\r\n", "T", 1700000003)],
+        ),
+        (".Work", vec![]),
+        (
+            ".Work.Projects",
+            vec![("Subject: three\r\n\r\nthird\r\n", "DR", 1700000002)],
+        ),
+    ] {
+        assert_eq!(
+            read_folder(&export_path.join(folder)),
+            expected
+                .into_iter()
+                .map(|(contents, flags, received_at)| (
+                    contents.to_string(),
+                    flags.to_string(),
+                    received_at
+                ))
+                .collect::<Vec<_>>(),
+            "{folder}"
+        );
+    }
+
+    // Unknown accounts are rejected
+    assert!(server
+        .import_maildir("unknown", &import_path)
+        .await
+        .unwrap_err()
+        .contains("not found"));
+
+    params.client.set_default_account_id(Id::new(1).to_string());
+    destroy_all_mailboxes(params).await;
+    assert_is_empty(server).await;
+    temp_dir.delete();
+}
+
+fn read_folder(path: &Path) -> Vec<(String, String, u64)> {
+    let mut messages = std::fs::read_dir(path.join("cur"))
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let flags = path
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .rsplit_once(":2,")
+                .unwrap()
+                .1
+                .to_string();
+            let received_at = std::fs::metadata(&path)
+                .unwrap()
+                .modified()
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            (std::fs::read_to_string(&path).unwrap(), flags, received_at)
+        })
+        .collect::<Vec<_>>();
+    messages.sort_by_key(|(_, _, received_at)| *received_at);
+    messages
+}
@@ -68,6 +68,7 @@ pub mod email_set;
 pub mod email_submission;
 pub mod event_source;
 pub mod mailbox;
+pub mod maildir;
 pub mod push_subscription;
 pub mod quota;
 pub mod sieve_script;
@@ -325,6 +326,7 @@ pub async fn jmap_tests() {
     quota::test(&mut params).await;
     crypto::test(&mut params).await;
     blob::test(&mut params).await;
+    maildir::test(&mut params).await;
 
     if delete {
         params.temp_dir.delete();
@@ -23,7 +23,10 @@
 
 use ahash::AHashMap;
 use store::{
-    write::{blob::BlobQuota, now, BatchBuilder, BlobOp},
+    write::{
+        blob::{BlobQuota, OrphanedBlobs},
+        now, BatchBuilder, BlobOp,
+    },
     BlobClass, BlobStore, Serialize, Stores,
 };
 use utils::{config::Config, BlobHash};
@@ -153,6 +156,43 @@ pub async fn blob_tests() {
             .unwrap()
             .is_none());
 
+        // Commit a blob that is neither reserved nor linked
+        let hash = BlobHash::from(b"orphan".as_slice());
+        blob_store.put_blob(hash.as_ref(), b"orphan").await.unwrap();
+        store
+            .write(
+                BatchBuilder::new()
+                    .set(BlobOp::Commit { hash: hash.clone() }, Vec::new())
+                    .build_batch(),
+            )
+            .await
+            .unwrap();
+
+        // Dry-run should report the blob without deleting it
+        assert_eq!(
+            store
+                .collect_orphaned_blobs(blob_store.clone(), false)
+                .await
+                .unwrap(),
+            OrphanedBlobs { count: 1, bytes: 6 }
+        );
+        assert!(store.blob_exists(&hash).await.unwrap());
+
+        // Orphaned blob should be deleted once confirmed
+        assert_eq!(
+            store
+                .collect_orphaned_blobs(blob_store.clone(), true)
+                .await
+                .unwrap(),
+            OrphanedBlobs { count: 1, bytes: 6 }
+        );
+        assert!(!store.blob_exists(&hash).await.unwrap());
+        assert!(blob_store
+            .get_blob(hash.as_ref(), 0..usize::MAX)
+            .await
+            .unwrap()
+            .is_none());
+
         // Upload one linked blob to accountId 1, two linked blobs to accountId 0, and three unlinked (reserved) blobs to accountId 2
         let expiry_times = AHashMap::from_iter([
             (b"abc", now() - 10),
@@ -421,6 +461,9 @@ pub async fn blob_tests() {
 }
 
 async fn test_store(store: BlobStore) {
+    // Test connectivity check
+    store.check_connectivity().await.unwrap();
+
     // Test small blob
     const DATA: &[u8] = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. Fusce erat nisl, dignissim a porttitor id, varius nec arcu. Sed mauris.";
     let hash = BlobHash::from(DATA);
@@ -448,12 +491,18 @@ async fn test_store(store: BlobStore) {
         .unwrap(),
         std::str::from_utf8(&DATA[11..57]).unwrap()
     );
+    assert!(store
+        .blob_size(hash.as_slice())
+        .await
+        .unwrap()
+        .is_some_and(|size| size > 0));
     assert!(store.delete_blob(hash.as_slice()).await.unwrap());
     assert!(store
         .get_blob(hash.as_slice(), 0..usize::MAX)
         .await
         .unwrap()
         .is_none());
+    assert_eq!(store.blob_size(hash.as_slice()).await.unwrap(), None);
 
     // Test large blob
     let mut data = Vec::with_capacity(50 * 1024 * 1024);
@@ -21,19 +21,44 @@
  * for more details.
 */
 
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
 use ahash::AHashSet;
-use common::Core;
-use jmap_proto::types::{collection::Collection, property::Property};
+use common::{
+    manager::{
+        backup::{
+            BackupFormat, BackupLocation, BackupManifest, BackupOptions, Family, MANIFEST_FILE,
+        },
+        diff::diff_backups,
+        metrics::RESTORE_METRICS,
+        restore::{verify_backup, OnCancel, QueueDue, RestoreOptions, DEFAULT_READ_BUFFER_SIZE},
+        retention::{backup_set_name, prune_backup_sets, Retention},
+    },
+    Core,
+};
+use jmap_proto::{
+    object::Object,
+    types::{collection::Collection, id::Id, keyword::Keyword, property::Property, value::Value},
+};
 use store::{
-    rand,
+    backend::fs::FsStore,
+    blake3, rand,
+    roaring::RoaringBitmap,
     write::{
-        AnyKey, BatchBuilder, BitmapClass, BitmapHash, BlobOp, DirectoryClass, LookupClass,
-        Operation, QueueClass, QueueEvent, TagValue, ValueClass,
+        key::DeserializeBigEndian, now, AnyKey, BatchBuilder, Bincode, BitmapClass, BitmapHash,
+        BlobOp, DirectoryClass, LookupClass, Operation, QueueClass, QueueEvent, TagValue,
+        ValueClass, F_INDEX, F_VALUE,
     },
-    IterateParams, Store, SUBSPACE_BITMAPS, SUBSPACE_BLOBS, SUBSPACE_COUNTERS, SUBSPACE_INDEXES,
-    SUBSPACE_LOGS, SUBSPACE_VALUES,
+    BitmapKey, BlobStore, IterateParams, Serialize, Store, ValueKey, SUBSPACE_BITMAPS,
+    SUBSPACE_BLOBS, SUBSPACE_COUNTERS, SUBSPACE_INDEXES, SUBSPACE_LOGS, SUBSPACE_VALUES, U64_LEN,
 };
-use utils::BlobHash;
+use utils::{config::Config, BlobHash, ExitCode, BLOB_HASH_LEN};
+
+/// Magic marker, version and snapshot id at the start of every backup file.
+const HEADER_LEN: usize = 2 + U64_LEN;
 
 use crate::store::TempDir;
 
@@ -139,7 +164,7 @@ pub async fn test(db: Store) {
                     batch.ops.push(Operation::Bitmap {
                         class: BitmapClass::Tag {
                             field,
-                            value: TagValue::Text(random_bytes(field as usize + 2)),
+                            value: TagValue::Text(random_bytes(field as usize + 2).into()),
                         },
                         set: true,
                     });
@@ -201,6 +226,7 @@ pub async fn test(db: Store) {
         .with_account_id(u32::MAX)
         .with_collection(Collection::Principal);
 
+    // Names, emails and memberships have to point to existing principals
     for account_id in [1, 2, 3, 4, 5] {
         batch
             .create_document(account_id)
@@ -212,13 +238,13 @@ pub async fn test(db: Store) {
                 ValueClass::Directory(DirectoryClass::NameToId(random_bytes(
                     2 + account_id as usize,
                 ))),
-                random_bytes(4),
+                vec![account_id as u8, 0],
             )
             .set(
                 ValueClass::Directory(DirectoryClass::EmailToId(random_bytes(
                     4 + account_id as usize,
                 ))),
-                random_bytes(4),
+                vec![account_id as u8, 0],
             )
             .set(
                 ValueClass::Directory(DirectoryClass::Domain(random_bytes(
@@ -233,14 +259,14 @@ pub async fn test(db: Store) {
             .set(
                 ValueClass::Directory(DirectoryClass::MemberOf {
                     principal_id: account_id,
-                    member_of: rand::random(),
+                    member_of: account_id % 5 + 1,
                 }),
                 random_bytes(15),
             )
             .set(
                 ValueClass::Directory(DirectoryClass::Members {
                     principal_id: account_id,
-                    has_member: rand::random(),
+                    has_member: account_id % 5 + 1,
                 }),
                 random_bytes(15),
             );
@@ -255,7 +281,651 @@ pub async fn test(db: Store) {
     // Export store
     println!("Exporting store...");
     let temp_dir = TempDir::new("art_vandelay_tests", true);
-    core.backup(temp_dir.path.clone()).await;
+    core.backup(temp_dir.path.clone(), Default::default()).await;
+
+    // Verify manifest
+    let manifest = BackupManifest::read(&temp_dir.path.clone().into())
+        .await
+        .unwrap()
+        .expect("Manifest not found");
+    for account_id in 0u32..10u32 {
+        let collections = &manifest.accounts[&account_id];
+        for collection in [0u8, 1, 2, 3] {
+            assert_eq!(
+                collections.get(&Collection::from(collection).to_string()),
+                Some(&5),
+                "{manifest:?}"
+            );
+        }
+    }
+    assert_eq!(manifest.blobs, 5, "{manifest:?}");
+    assert_eq!(
+        manifest.blob_bytes,
+        [16, 128, 1024, 2056, 102400].into_iter().sum::<u64>(),
+        "{manifest:?}"
+    );
+
+    // Exporting the same data again should produce identical files, apart from the snapshot id
+    println!("Validating deterministic export...");
+    let repeat_dir = temp_dir.path.with_extension("repeat");
+    let repeat_manifest = core
+        .try_backup(repeat_dir.clone(), BackupOptions::new())
+        .await
+        .unwrap();
+    assert_eq!(repeat_manifest.ops, manifest.ops);
+    assert_eq!(repeat_manifest.accounts, manifest.accounts);
+    assert_ne!(repeat_manifest.snapshot_id, manifest.snapshot_id);
+    for entry in std::fs::read_dir(&temp_dir.path).unwrap() {
+        let name = entry.unwrap().file_name();
+        if name != MANIFEST_FILE {
+            let file = std::fs::read(temp_dir.path.join(&name)).unwrap();
+            let repeat_file = std::fs::read(repeat_dir.join(&name)).unwrap();
+            assert_eq!(
+                file[..2],
+                repeat_file[..2],
+                "{name:?} differs between exports"
+            );
+            assert!(
+                file[HEADER_LEN..] == repeat_file[HEADER_LEN..],
+                "{name:?} differs between exports"
+            );
+        }
+    }
+
+    // Files of different backups should not be restored together
+    println!("Validating snapshot ids...");
+    std::fs::copy(temp_dir.path.join("blob"), repeat_dir.join("blob")).unwrap();
+    let err = core
+        .try_restore(
+            repeat_dir.clone(),
+            RestoreOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+    assert!(err.cause.contains("different backups"), "{err}");
+    assert_eq!(err.exit_code(), ExitCode::Data, "{err}");
+    std::fs::remove_dir_all(&repeat_dir).unwrap();
+
+    // Filtering by collection should only export the documents and blobs of that collection
+    println!("Exporting a single collection...");
+    let collection_dir = temp_dir.path.with_extension("mailbox");
+    let collection_manifest = core
+        .try_backup(
+            collection_dir.clone(),
+            BackupOptions::new().set_collections([Collection::Mailbox]),
+        )
+        .await
+        .unwrap();
+    for account_id in 0u32..10u32 {
+        assert_eq!(
+            collection_manifest.accounts[&account_id],
+            BTreeMap::from([(Collection::Mailbox.to_string(), 5)]),
+            "{collection_manifest:?}"
+        );
+    }
+    assert_eq!(collection_manifest.blobs, 5, "{collection_manifest:?}");
+    assert!(
+        collection_manifest.ops.keys().all(|family| !matches!(
+            family,
+            Family::Config
+                | Family::LookupValue
+                | Family::LookupCounter
+                | Family::Directory
+                | Family::Queue
+        )),
+        "{collection_manifest:?}"
+    );
+    assert!(
+        collection_manifest.ops[&Family::Property] < manifest.ops[&Family::Property],
+        "{collection_manifest:?}"
+    );
+    for report in verify_backup(&collection_dir.clone().into()).await.unwrap() {
+        assert!(report.errors.is_empty(), "{report:?}");
+    }
+    let err = core
+        .try_backup(
+            collection_dir.clone(),
+            BackupOptions::new()
+                .queue_only()
+                .set_collections([Collection::Mailbox]),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.exit_code(), ExitCode::Config, "{err}");
+    std::fs::remove_dir_all(&collection_dir).unwrap();
+
+    // Backups should fail without exiting when the destination is invalid
+    let err = core
+        .try_backup(temp_dir.path.join(MANIFEST_FILE), BackupOptions::new())
+        .await
+        .unwrap_err();
+    assert_eq!(err.exit_code(), ExitCode::Config, "{err}");
+
+    // A family that can't be written fails its task, the backup returns the
+    // error without exiting and leaves no manifest behind
+    let failed_dir = temp_dir.path.with_extension("failed");
+    std::fs::create_dir_all(failed_dir.join("property")).unwrap();
+    let err = core
+        .try_backup(failed_dir.clone(), BackupOptions::new())
+        .await
+        .unwrap_err();
+    assert!(err.cause.contains("Failed to create backup file"), "{err}");
+    assert_eq!(err.exit_code(), ExitCode::Store, "{err}");
+    assert!(!failed_dir.join(MANIFEST_FILE).exists());
+    std::fs::remove_dir_all(&failed_dir).unwrap();
+
+    // Verify backup files without restoring them
+    println!("Verifying backup...");
+    let reports = verify_backup(&temp_dir.path.clone().into()).await.unwrap();
+    assert!(!reports.is_empty());
+    for report in &reports {
+        assert!(report.errors.is_empty(), "{report:?}");
+    }
+    let verified_ops = reports.iter().fold(BTreeMap::new(), |mut ops, report| {
+        for (family, count) in &report.ops {
+            *ops.entry(*family).or_insert(0) += count;
+        }
+        ops
+    });
+    assert_eq!(verified_ops, manifest.ops);
+
+    // Memory-mapped files and small read buffers should read the same ops as the stream
+    println!("Reading backup with alternate readers...");
+    for (mmap, read_buffer_size) in [(true, DEFAULT_READ_BUFFER_SIZE), (false, 7)] {
+        let stats = core
+            .try_restore(
+                temp_dir.path.clone(),
+                RestoreOptions {
+                    dry_run: true,
+                    mmap,
+                    read_buffer_size,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(stats.errors.is_empty(), "{:?}", stats.errors);
+        assert_eq!(stats.ops, manifest.ops);
+    }
+
+    // Files piped through gzip or zstd should be decompressed as they are read,
+    // also when asked to map them
+    println!("Validating compressed store...");
+    let compressed_dir = temp_dir.path.with_extension("compressed");
+    std::fs::create_dir_all(&compressed_dir).unwrap();
+    let property = std::fs::read(temp_dir.path.join("property")).unwrap();
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    std::io::Write::write_all(&mut gzip, &property).unwrap();
+    for (name, contents) in [
+        ("property.gz", gzip.finish().unwrap()),
+        (
+            "property.zst",
+            zstd::encode_all(property.as_slice(), 0).unwrap(),
+        ),
+    ] {
+        let file = compressed_dir.join(name);
+        std::fs::write(&file, contents).unwrap();
+        for mmap in [false, true] {
+            let stats = core
+                .try_restore(
+                    file.clone(),
+                    RestoreOptions {
+                        dry_run: true,
+                        mmap,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            assert!(stats.errors.is_empty(), "{name}: {:?}", stats.errors);
+            assert_eq!(
+                stats.ops.get(&Family::Property),
+                manifest.ops.get(&Family::Property),
+                "{name}"
+            );
+        }
+    }
+    std::fs::remove_dir_all(&compressed_dir).unwrap();
+
+    // JSON exports should be inspectable
+    println!("Exporting store as JSON...");
+    let json_dir = temp_dir.path.with_extension("json");
+    core.backup(
+        json_dir.clone(),
+        BackupOptions {
+            format: BackupFormat::Json,
+            ..Default::default()
+        },
+    )
+    .await;
+    let bitmaps = std::fs::read_to_string(json_dir.join("bitmap.jsonl")).unwrap();
+    assert!(!bitmaps.is_empty());
+    for line in bitmaps.lines() {
+        let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(entry["family"], "Bitmap", "{line}");
+        assert!(entry["document_ids"].is_array(), "{line}");
+    }
+    std::fs::remove_dir_all(&json_dir).unwrap();
+
+    // Large files should be split into shards that can be read on their own
+    println!("Exporting store in shards...");
+    let sharded_dir = temp_dir.path.with_extension("sharded");
+    core.backup(
+        sharded_dir.clone(),
+        BackupOptions {
+            max_file_size: Some(4096),
+            ..Default::default()
+        },
+    )
+    .await;
+    let sharded_manifest = BackupManifest::read(&sharded_dir.clone().into())
+        .await
+        .unwrap()
+        .expect("Manifest not found");
+    assert_eq!(sharded_manifest.ops, manifest.ops);
+    for name in ["blob", "log"] {
+        assert!(
+            sharded_manifest.shards.contains_key(name),
+            "{name}: {sharded_manifest:?}"
+        );
+    }
+    for (name, shards) in &sharded_manifest.shards {
+        assert_eq!(shards[0], *name);
+        for shard in shards {
+            assert!(sharded_dir.join(shard).is_file(), "{shard}");
+        }
+    }
+    let reports = verify_backup(&sharded_dir.clone().into()).await.unwrap();
+    let mut verified_ops = BTreeMap::new();
+    for report in &reports {
+        assert!(report.errors.is_empty(), "{report:?}");
+        for (family, count) in &report.ops {
+            *verified_ops.entry(*family).or_insert(0) += count;
+        }
+    }
+    assert_eq!(verified_ops, manifest.ops);
+    let diff = diff_backups(&temp_dir.path.clone().into(), &sharded_dir.clone().into())
+        .await
+        .unwrap();
+    assert!(diff.is_empty(), "{:?}", diff.changes);
+
+    // Only complete backup sets outside of the retention should be pruned
+    println!("Validating backup set retention...");
+    let sets_dir = temp_dir.path.with_extension("sets");
+    let created = now();
+    let set_path = |age: u64| sets_dir.join(backup_set_name(created - age * 86400));
+    for age in 0..4 {
+        let set = set_path(age);
+        std::fs::create_dir_all(&set).unwrap();
+        for entry in std::fs::read_dir(&temp_dir.path).unwrap() {
+            let name = entry.unwrap().file_name();
+            if name != MANIFEST_FILE {
+                std::fs::copy(temp_dir.path.join(&name), set.join(&name)).unwrap();
+            }
+        }
+        let manifest = BackupManifest {
+            created: created - age * 86400,
+            ..manifest.clone()
+        };
+        std::fs::write(
+            set.join(MANIFEST_FILE),
+            serde_json::to_vec(&manifest).unwrap(),
+        )
+        .unwrap();
+    }
+    let incomplete_set = set_path(4);
+    std::fs::create_dir_all(&incomplete_set).unwrap();
+    std::fs::copy(
+        temp_dir.path.join("property"),
+        incomplete_set.join("property"),
+    )
+    .unwrap();
+    std::fs::create_dir_all(sets_dir.join("other")).unwrap();
+
+    let report = prune_backup_sets(&sets_dir, Retention::Age(36 * 3600), created)
+        .await
+        .unwrap();
+    assert_eq!(report.removed, vec![set_path(2), set_path(3)]);
+    assert_eq!(report.skipped.len(), 1);
+    assert_eq!(report.skipped[0].path, incomplete_set);
+    let report = prune_backup_sets(&sets_dir, Retention::Count(1), created)
+        .await
+        .unwrap();
+    assert_eq!(report.removed, vec![set_path(1)]);
+    assert!(set_path(0).exists());
+    assert!(incomplete_set.exists());
+    assert!(sets_dir.join("other").exists());
+    std::fs::remove_dir_all(&sets_dir).unwrap();
+
+    // Truncated files should be reported without aborting
+    println!("Validating truncated file...");
+    let property_file = temp_dir.path.join("property");
+    let truncated_file = temp_dir.path.with_extension("truncated");
+    let bytes = std::fs::read(&property_file).unwrap();
+    std::fs::write(&truncated_file, &bytes[..bytes.len() / 2]).unwrap();
+    for mmap in [false, true] {
+        let err = core
+            .try_restore(
+                truncated_file.clone(),
+                RestoreOptions {
+                    dry_run: true,
+                    mmap,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.family, Family::Property, "{err}");
+        assert!(err.offset > 0, "{err}");
+        assert!(err.context.account_id.is_some(), "{err}");
+        assert_eq!(err.exit_code(), ExitCode::Data, "{err}");
+    }
+    let err = core
+        .try_restore(
+            temp_dir.path.with_extension("missing"),
+            RestoreOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.exit_code(), ExitCode::Store, "{err}");
+    let stats = core
+        .try_restore(
+            truncated_file.clone(),
+            RestoreOptions {
+                tolerant: true,
+                merge: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert!(stats.skipped.contains_key(&Family::Property), "{stats:?}");
+    std::fs::remove_file(&truncated_file).unwrap();
+
+    // Corrupted blobs should be detected even with a valid trailer
+    println!("Validating corrupted blob...");
+    let corrupted_file = temp_dir.path.with_extension("corrupted");
+    let mut bytes = std::fs::read(temp_dir.path.join("blob")).unwrap();
+    let trailer_start = bytes.len() - (1 + U64_LEN + 32);
+    bytes[trailer_start - 1] ^= 0xFF;
+    let checksum = blake3::hash(&bytes[HEADER_LEN..trailer_start]);
+    bytes[trailer_start + 1 + U64_LEN..].copy_from_slice(checksum.as_bytes());
+    std::fs::write(&corrupted_file, &bytes).unwrap();
+    let stats = core
+        .try_restore(
+            corrupted_file.clone(),
+            RestoreOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(stats.errors.len(), 1, "{:?}", stats.errors);
+    assert!(
+        stats.errors[0].contains("Blob hash mismatch"),
+        "{:?}",
+        stats.errors
+    );
+    let reports = verify_backup(&corrupted_file.clone().into()).await.unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].errors.len(), 1, "{:?}", reports[0]);
+    assert!(
+        reports[0].errors[0].contains("Blob hash mismatch"),
+        "{:?}",
+        reports[0]
+    );
+    std::fs::remove_file(&corrupted_file).unwrap();
+
+    // Log entries should be restored in change id order
+    println!("Validating log order...");
+    let log_file = temp_dir.path.with_extension("log");
+    for (change_ids, expected_errors) in [([1u64, 2], 0), ([2, 1], 1)] {
+        let mut ops = vec![0, Family::Log as u8, 3, 0, 0, 0, 0, 4, 0];
+        for change_id in change_ids {
+            ops.push(1);
+            ops.extend_from_slice(&(U64_LEN as u32).to_be_bytes());
+            ops.extend_from_slice(&change_id.to_be_bytes());
+            ops.extend_from_slice(&1u32.to_be_bytes());
+            ops.push(b'x');
+        }
+        let mut bytes = vec![123, 2];
+        bytes.extend_from_slice(&ops);
+        bytes.push(u8::MAX);
+        bytes.extend_from_slice(&5u64.to_be_bytes());
+        bytes.extend_from_slice(blake3::hash(&ops).as_bytes());
+        std::fs::write(&log_file, &bytes).unwrap();
+        let stats = core
+            .try_restore(
+                log_file.clone(),
+                RestoreOptions {
+                    dry_run: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(stats.ops.get(&Family::Log), Some(&2), "{stats:?}");
+        assert_eq!(stats.errors.len(), expected_errors, "{:?}", stats.errors);
+        assert!(
+            stats.errors.iter().all(|err| err.contains("out of order")),
+            "{:?}",
+            stats.errors
+        );
+    }
+    std::fs::remove_file(&log_file).unwrap();
+
+    // Values should be rejected when the context of their family is missing
+    println!("Validating op context...");
+    let context_file = temp_dir.path.with_extension("context");
+    let value = [1, 0, 0, 0, 1, 0, 0, 0, 0, 1, b'x'];
+    let mut blob = vec![2, 0, 0, 0, BLOB_HASH_LEN as u8];
+    blob.extend_from_slice(BlobHash::from(b"x".as_slice()).as_slice());
+    for (context, value, num_ops, expected) in [
+        (
+            vec![0, Family::Property as u8, 3, 0, 0, 0, 1, 4, 0],
+            &value[..],
+            4u64,
+            "Property value found before its document id",
+        ),
+        (
+            // Context is not carried over to the next family
+            [
+                &[0, Family::Log as u8, 3, 0, 0, 0, 1, 4, 0][..],
+                &[0, Family::Acl as u8, 5, 0, 0, 0, 1],
+            ]
+            .concat(),
+            &value[..],
+            6,
+            "Acl value found before its account id",
+        ),
+        (
+            vec![0, Family::Blob as u8, 3, 0, 0, 0, 1, 5, 255, 255, 255, 255],
+            &blob[..],
+            4,
+            "neither a link nor a blob",
+        ),
+    ] {
+        let mut ops = context;
+        ops.extend_from_slice(value);
+        let mut bytes = vec![123, 2];
+        bytes.extend_from_slice(&ops);
+        bytes.push(u8::MAX);
+        bytes.extend_from_slice(&num_ops.to_be_bytes());
+        bytes.extend_from_slice(blake3::hash(&ops).as_bytes());
+        std::fs::write(&context_file, &bytes).unwrap();
+        let stats = core
+            .try_restore(
+                context_file.clone(),
+                RestoreOptions {
+                    dry_run: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(stats.errors.len(), 1, "{:?}", stats.errors);
+        assert!(
+            stats.errors[0].contains(expected) && stats.errors[0].contains("at offset"),
+            "{:?}",
+            stats.errors
+        );
+        let reports = verify_backup(&context_file.clone().into()).await.unwrap();
+        assert_eq!(reports[0].errors.len(), 1, "{:?}", reports[0]);
+        assert!(reports[0].errors[0].contains(expected), "{:?}", reports[0]);
+    }
+    std::fs::remove_file(&context_file).unwrap();
+
+    // Families unknown to this version should only be skipped when requested
+    println!("Validating unknown families...");
+    let unknown_file = temp_dir.path.with_extension("unknown");
+    let mut ops = Vec::new();
+    for (family, change_id) in [
+        (Family::Log as u8, 1u64),
+        (42, 2),
+        (42, 3),
+        (Family::Log as u8, 4),
+    ] {
+        ops.extend_from_slice(&[0, family, 3, 0, 0, 0, 0, 4, 0]);
+        ops.push(1);
+        ops.extend_from_slice(&(U64_LEN as u32).to_be_bytes());
+        ops.extend_from_slice(&change_id.to_be_bytes());
+        ops.extend_from_slice(&1u32.to_be_bytes());
+        ops.push(b'x');
+    }
+    let mut bytes = vec![123, 2];
+    bytes.extend_from_slice(&ops);
+    bytes.push(u8::MAX);
+    bytes.extend_from_slice(&16u64.to_be_bytes());
+    bytes.extend_from_slice(blake3::hash(&ops).as_bytes());
+    std::fs::write(&unknown_file, &bytes).unwrap();
+    let err = core
+        .try_restore(
+            unknown_file.clone(),
+            RestoreOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+    assert!(err.unknown, "{err}");
+    assert!(err.cause.contains("Unknown family type 42"), "{err}");
+    let stats = core
+        .try_restore(
+            unknown_file.clone(),
+            RestoreOptions {
+                dry_run: true,
+                skip_unknown: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(stats.ops.get(&Family::Log), Some(&2), "{stats:?}");
+    assert_eq!(
+        stats.skipped[&Family::None].get("Unknown family type 42"),
+        Some(&4),
+        "{stats:?}"
+    );
+    assert!(stats.errors.is_empty(), "{:?}", stats.errors);
+    std::fs::remove_file(&unknown_file).unwrap();
+
+    // Garbage in the middle of a file should only resync to a family marker
+    // followed by an op that decodes, and what follows is never restored
+    println!("Validating garbage in the middle of a file...");
+    let garbage_file = temp_dir.path.with_extension("garbage");
+    let mut ops = Vec::new();
+    for (change_id, garbage) in [
+        (1u64, &[7, 3, 0, 0, 0, 9, 0, Family::Log as u8, 9][..]),
+        (2, &[]),
+    ] {
+        ops.extend_from_slice(&[0, Family::Log as u8, 3, 0, 0, 0, 0, 4, 0]);
+        ops.push(1);
+        ops.extend_from_slice(&(U64_LEN as u32).to_be_bytes());
+        ops.extend_from_slice(&change_id.to_be_bytes());
+        ops.extend_from_slice(&1u32.to_be_bytes());
+        ops.push(b'x');
+        ops.extend_from_slice(garbage);
+    }
+    let mut bytes = vec![123, 2];
+    bytes.extend_from_slice(&ops);
+    bytes.push(u8::MAX);
+    bytes.extend_from_slice(&8u64.to_be_bytes());
+    bytes.extend_from_slice(blake3::hash(&ops).as_bytes());
+    std::fs::write(&garbage_file, &bytes).unwrap();
+    for mmap in [false, true] {
+        let stats = core
+            .try_restore(
+                garbage_file.clone(),
+                RestoreOptions {
+                    dry_run: true,
+                    tolerant: true,
+                    mmap,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(stats.ops.get(&Family::Log), Some(&1), "{stats:?}");
+        let skipped = &stats.skipped[&Family::Log];
+        assert_eq!(skipped.get("Unknown op type 7"), Some(&1), "{stats:?}");
+        assert_eq!(
+            skipped.get("Not restored after resynchronizing past a read error"),
+            Some(&1),
+            "{stats:?}"
+        );
+    }
+    let reports = verify_backup(&garbage_file.clone().into()).await.unwrap();
+    assert_eq!(
+        reports[0].ops.get(&Family::Log),
+        Some(&2),
+        "{:?}",
+        reports[0]
+    );
+    assert!(
+        reports[0].errors[0].contains("Unknown op type 7"),
+        "{:?}",
+        reports[0]
+    );
+    std::fs::remove_file(&garbage_file).unwrap();
+
+    // Stores that already have accounts are only restored into on request
+    println!("Validating non-empty target store...");
+    let err = core
+        .try_restore(temp_dir.path.clone(), Default::default())
+        .await
+        .unwrap_err();
+    assert!(err.cause.contains("already contains 5 accounts"), "{err}");
+    snapshot.assert_is_eq(&Snapshot::new(&db).await);
+    core.try_restore(temp_dir.path.clone(), RestoreOptions::new().merge())
+        .await
+        .unwrap();
+
+    // Bitmaps restored into existing ones keep the documents of both
+    println!("Validating restore into existing bitmaps...");
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0u8)
+        .create_document(50);
+    db.write(batch.build()).await.unwrap();
+    core.try_restore(temp_dir.path.clone(), RestoreOptions::new().merge())
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_bitmap(BitmapKey::document_ids(0, 0u8))
+            .await
+            .unwrap()
+            .unwrap(),
+        RoaringBitmap::from_iter([0, 10, 20, 30, 40, 50])
+    );
 
     // Destroy store
     println!("Destroying store...");
@@ -264,16 +934,1045 @@ pub async fn test(db: Store) {
 
     // Import store
     println!("Importing store...");
-    core.restore(temp_dir.path.clone()).await;
+    let stats = core
+        .restore(temp_dir.path.clone(), Default::default())
+        .await;
+    assert_eq!(stats.manifest.as_ref(), Some(&manifest));
+    assert_eq!(stats.ops, manifest.ops);
+    assert!(stats.ops.keys().eq(stats.families.keys()), "{stats:?}");
+    assert!(stats.families.values().all(|family| family.bytes > 0));
+    assert_eq!(stats.families[&Family::Blob].blobs, 5);
+    assert!(stats.families[&Family::Property].documents > 0);
 
     // Verify hash
     print!("Verifying store hash...");
     snapshot.assert_is_eq(&Snapshot::new(&db).await);
     println!(" GREAT SUCCESS!");
 
+    // Blob commits should match whether uploads run one at a time or have to
+    // be drained before every checkpoint
+    println!("Importing store with serial and overlapping blob uploads...");
+    for (blob_concurrency, batch_size) in [(1, RestoreOptions::default().batch_size), (4, 2)] {
+        db.destroy().await;
+        let stats = core
+            .restore(
+                temp_dir.path.clone(),
+                RestoreOptions {
+                    blob_concurrency,
+                    batch_size,
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert_eq!(stats.ops, manifest.ops);
+        assert_eq!(stats.families[&Family::Blob].blobs, 5);
+        snapshot.assert_is_eq(&Snapshot::new(&db).await);
+    }
+
+    // A memory budget smaller than the largest blob only slows the import down
+    println!("Importing store with a memory budget...");
+    db.destroy().await;
+    let stats = core
+        .restore(
+            temp_dir.path.clone(),
+            RestoreOptions::new().set_memory_budget(4096),
+        )
+        .await;
+    assert_eq!(stats.ops, manifest.ops);
+    snapshot.assert_is_eq(&Snapshot::new(&db).await);
+
+    // Batches are written once their keys, values and blobs reach the byte
+    // limit, regardless of their number of operations
+    println!("Importing store with a batch byte limit...");
+    db.destroy().await;
+    let batches = RESTORE_METRICS.batches();
+    let stats = core
+        .restore(
+            temp_dir.path.clone(),
+            RestoreOptions::new()
+                .set_batch_size(100_000)
+                .set_batch_bytes(1024),
+        )
+        .await;
+    assert_eq!(stats.ops, manifest.ops);
+    // Each of the 102400 byte properties and term indexes fills a batch
+    assert!(RESTORE_METRICS.batches() - batches >= 400);
+    snapshot.assert_is_eq(&Snapshot::new(&db).await);
+    db.destroy().await;
+    let batches = RESTORE_METRICS.batches();
+    core.restore(
+        temp_dir.path.clone(),
+        RestoreOptions::new()
+            .set_families([Family::Blob])
+            .set_batch_size(100_000)
+            .set_batch_bytes(1024),
+    )
+    .await;
+    // The blobs of 1024 bytes or more are written in batches of their own
+    assert!(RESTORE_METRICS.batches() - batches >= 3);
+
+    // A cancelled import keeps its progress files and continues from them,
+    // whether the batch being built is written or dropped
+    println!("Cancelling and resuming import...");
+    let has_progress = |dir: &PathBuf| {
+        std::fs::read_dir(dir).unwrap().any(|entry| {
+            let name = entry.unwrap().file_name();
+            let name = name.to_string_lossy();
+            name.ends_with(".progress") || name.ends_with(".tmp")
+        })
+    };
+    for on_cancel in [OnCancel::Flush, OnCancel::Discard] {
+        db.destroy().await;
+        let options = RestoreOptions {
+            batch_size: 3,
+            on_cancel,
+            ..Default::default()
+        };
+        let cancel = options.cancel.clone();
+        let (stats, _) = tokio::join!(core.restore(temp_dir.path.clone(), options), async {
+            while !has_progress(&temp_dir.path) {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+            cancel.cancel();
+        });
+        assert!(stats.cancelled, "{on_cancel:?}");
+        assert!(has_progress(&temp_dir.path), "{on_cancel:?}");
+        let stats = core
+            .restore(
+                temp_dir.path.clone(),
+                RestoreOptions {
+                    resume: true,
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert!(!stats.cancelled);
+        assert!(!has_progress(&temp_dir.path), "{on_cancel:?}");
+        snapshot.assert_is_eq(&Snapshot::new(&db).await);
+    }
+
+    // Progress files can be kept away from a backup that can't be written to
+    println!("Resuming import with a separate progress directory...");
+    let progress_dir = temp_dir.path.with_extension("progress_dir");
+    db.destroy().await;
+    let options = RestoreOptions {
+        batch_size: 3,
+        ..Default::default()
+    }
+    .set_progress_dir(&progress_dir);
+    let cancel = options.cancel.clone();
+    let (stats, _) = tokio::join!(core.restore(temp_dir.path.clone(), options), async {
+        while !progress_dir.exists() || !has_progress(&progress_dir) {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        cancel.cancel();
+    });
+    assert!(stats.cancelled);
+    assert!(!has_progress(&temp_dir.path));
+    let stats = core
+        .restore(
+            temp_dir.path.clone(),
+            RestoreOptions::new()
+                .resume()
+                .set_progress_dir(&progress_dir),
+        )
+        .await;
+    assert!(!stats.cancelled);
+    assert!(!has_progress(&progress_dir));
+    assert!(!has_progress(&temp_dir.path));
+    snapshot.assert_is_eq(&Snapshot::new(&db).await);
+    std::fs::remove_dir_all(&progress_dir).unwrap();
+
+    // Staged imports only replace the accounts once the whole backup was
+    // staged, and leave them untouched when the import fails
+    println!("Importing store with staged accounts...");
+    db.destroy().await;
+    let stats = core
+        .restore(
+            temp_dir.path.clone(),
+            RestoreOptions::new().stage_accounts(),
+        )
+        .await;
+    assert_eq!(stats.ops, manifest.ops);
+    assert_eq!(
+        stats.swapped_accounts,
+        (0u32..10).collect::<BTreeSet<_>>(),
+        "{stats:?}"
+    );
+    snapshot.assert_is_eq(&Snapshot::new(&db).await);
+    let staged_dir = temp_dir.path.with_extension("staged");
+    std::fs::create_dir_all(&staged_dir).unwrap();
+    for entry in std::fs::read_dir(&temp_dir.path).unwrap() {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), staged_dir.join(entry.file_name())).unwrap();
+    }
+    let bytes = std::fs::read(staged_dir.join("property")).unwrap();
+    std::fs::write(staged_dir.join("property"), &bytes[..bytes.len() / 2]).unwrap();
+    core.try_restore(
+        staged_dir.clone(),
+        RestoreOptions::new()
+            .set_families([
+                Family::Property,
+                Family::TermIndex,
+                Family::Index,
+                Family::Bitmap,
+                Family::Log,
+            ])
+            .stage_accounts(),
+    )
+    .await
+    .unwrap_err();
+    snapshot.assert_is_eq(&Snapshot::new(&db).await);
+    std::fs::remove_dir_all(&staged_dir).unwrap();
+
+    // A swap failing midway leaves the accounts partially replaced, resuming
+    // swaps them again from the staged data
+    println!("Resuming an interrupted swap of staged accounts...");
+    db.destroy().await;
+    let err = core
+        .try_restore(
+            temp_dir.path.clone(),
+            RestoreOptions::new()
+                .stage_accounts()
+                .fail_swap_at(Family::Bitmap),
+        )
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Failed to move staged accounts into place"),
+        "{err}"
+    );
+    assert!(has_progress(&temp_dir.path));
+    let stats = core
+        .restore(
+            temp_dir.path.clone(),
+            RestoreOptions::new().stage_accounts().resume(),
+        )
+        .await;
+    assert_eq!(
+        stats.swapped_accounts,
+        (0u32..10).collect::<BTreeSet<_>>(),
+        "{stats:?}"
+    );
+    assert!(!has_progress(&temp_dir.path));
+    snapshot.assert_is_eq(&Snapshot::new(&db).await);
+
+    // Migrations read a live store and have nothing to resume from
+    let err = core
+        .migrate(&core, RestoreOptions::new().resume())
+        .await
+        .unwrap_err();
+    assert!(err.cause.contains("Migrations can't be resumed"), "{err}");
+
+    // Migrating into a store that already has accounts would merge with them
+    let err = core
+        .migrate(&core, RestoreOptions::new())
+        .await
+        .unwrap_err();
+    assert!(err.cause.contains("already contains"), "{err}");
+    snapshot.assert_is_eq(&Snapshot::new(&db).await);
+
+    // Import sharded backup
+    println!("Importing sharded store...");
+    db.destroy().await;
+    let stats = core.restore(sharded_dir.clone(), Default::default()).await;
+    assert_eq!(stats.ops, manifest.ops);
+    snapshot.assert_is_eq(&Snapshot::new(&db).await);
+
+    // A missing shard should be detected
+    let (name, shards) = sharded_manifest.shards.iter().next().unwrap();
+    std::fs::remove_file(sharded_dir.join(shards.last().unwrap())).unwrap();
+    let err = core
+        .try_restore(sharded_dir.clone(), Default::default())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("missing"), "{name}: {err}");
+    std::fs::remove_dir_all(&sharded_dir).unwrap();
+
+    // Indexed files should be restored one segment at a time
+    println!("Importing indexed store...");
+    let indexed_dir = temp_dir.path.with_extension("indexed");
+    core.backup(
+        indexed_dir.clone(),
+        BackupOptions::new().set_index_interval(512),
+    )
+    .await;
+    let indexed_manifest = BackupManifest::read(&indexed_dir.clone().into())
+        .await
+        .unwrap()
+        .expect("Manifest not found");
+    assert!(
+        indexed_manifest
+            .index
+            .values()
+            .any(|segments| segments.len() > 1),
+        "{indexed_manifest:?}"
+    );
+    assert!(!indexed_manifest.index.contains_key("log"));
+    for segments in indexed_manifest.index.values() {
+        assert_eq!(
+            segments.iter().map(|segment| segment.ops).sum::<u64>(),
+            segments.last().unwrap().ops_before + segments.last().unwrap().ops
+        );
+    }
+    let stats = core
+        .try_restore(
+            indexed_dir.clone(),
+            RestoreOptions {
+                dry_run: true,
+                mmap: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert!(stats.errors.is_empty(), "{:?}", stats.errors);
+    assert_eq!(stats.ops, manifest.ops);
+    db.destroy().await;
+    let stats = core.restore(indexed_dir.clone(), Default::default()).await;
+    assert_eq!(stats.ops, manifest.ops);
+    snapshot.assert_is_eq(&Snapshot::new(&db).await);
+    for entry in std::fs::read_dir(&indexed_dir).unwrap() {
+        let name = entry.unwrap().file_name();
+        assert!(!name.to_string_lossy().ends_with(".progress"), "{name:?}");
+    }
+    std::fs::remove_dir_all(&indexed_dir).unwrap();
+
     // Destroy store
     db.destroy().await;
     temp_dir.delete();
+
+    // Backups created by previous versions should still be restored
+    for version in ["v1", "v2"] {
+        println!("Importing {version} backup...");
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources");
+        path.push("backup");
+        path.push(version);
+        core.restore(path, Default::default()).await;
+
+        for (key, value) in [
+            ("backup.fixture.name", "stalwart"),
+            ("backup.fixture.enable", "true"),
+        ] {
+            assert_eq!(
+                db.get_value::<String>(ValueKey::from(ValueClass::Config(key.as_bytes().to_vec())))
+                    .await
+                    .unwrap()
+                    .as_deref(),
+                Some(value),
+                "{version}: {key}"
+            );
+        }
+
+        db.destroy().await;
+    }
+
+    // Blobs repeated in a backup should only be written once
+    println!("Validating blob deduplication...");
+    let shared_file = std::env::temp_dir().join("art_vandelay_shared_blob");
+    let body = b"shared attachment".to_vec();
+    let hash = BlobHash::from(body.as_slice());
+    let mut ops = vec![0, Family::Blob as u8, 3];
+    ops.extend_from_slice(&u32::MAX.to_be_bytes());
+    ops.push(5);
+    ops.extend_from_slice(&u32::MAX.to_be_bytes());
+    for _ in 0..2 {
+        ops.push(1);
+        ops.extend_from_slice(&(hash.as_slice().len() as u32).to_be_bytes());
+        ops.extend_from_slice(hash.as_slice());
+        ops.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        ops.extend_from_slice(&body);
+    }
+    for account_id in [1u32, 2] {
+        ops.push(3);
+        ops.extend_from_slice(&account_id.to_be_bytes());
+        ops.extend_from_slice(&[4, 0, 5, 0, 0, 0, 0, 2]);
+        ops.extend_from_slice(&(hash.as_slice().len() as u32).to_be_bytes());
+        ops.extend_from_slice(hash.as_slice());
+    }
+    let mut bytes = vec![123, 2];
+    bytes.extend_from_slice(&ops);
+    bytes.push(u8::MAX);
+    bytes.extend_from_slice(&13u64.to_be_bytes());
+    bytes.extend_from_slice(blake3::hash(&ops).as_bytes());
+    std::fs::write(&shared_file, &bytes).unwrap();
+    for (blob_dedup_limit, deduplicated_blobs) in [(1, 1), (0, 0)] {
+        let stats = core
+            .try_restore(
+                shared_file.clone(),
+                RestoreOptions {
+                    blob_dedup_limit,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(stats.deduplicated_blobs, deduplicated_blobs, "{stats:?}");
+        for account_id in [1u32, 2] {
+            assert!(db
+                .get_value::<()>(ValueKey {
+                    account_id,
+                    collection: 0,
+                    document_id: 0,
+                    class: ValueClass::Blob(BlobOp::Link { hash: hash.clone() }),
+                })
+                .await
+                .unwrap()
+                .is_some());
+        }
+        assert_eq!(
+            core.storage
+                .blob
+                .get_blob(hash.as_slice(), 0..usize::MAX)
+                .await
+                .unwrap(),
+            Some(body.clone())
+        );
+        db.destroy().await;
+    }
+    std::fs::remove_file(&shared_file).unwrap();
+
+    // Links to blobs missing from the backup should be detected
+    println!("Validating dangling blob link...");
+    let hash = BlobHash::from(b"dangling".as_slice());
+    let link_key = ValueKey {
+        account_id: 0,
+        collection: 0,
+        document_id: 0,
+        class: ValueClass::Blob(BlobOp::Link { hash: hash.clone() }),
+    };
+    db.write(
+        BatchBuilder::new()
+            .with_account_id(0)
+            .with_collection(0)
+            .update_document(0)
+            .set(BlobOp::Link { hash: hash.clone() }, vec![])
+            .build_batch(),
+    )
+    .await
+    .unwrap();
+    let temp_dir = TempDir::new("art_vandelay_dangling_tests", true);
+    core.backup(temp_dir.path.clone(), Default::default()).await;
+    db.destroy().await;
+    let err = core
+        .try_restore(temp_dir.path.clone(), Default::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.family, Family::Blob, "{err}");
+    assert!(err.cause.contains("Dangling blob link"), "{err}");
+    db.destroy().await;
+    let stats = core
+        .try_restore(
+            temp_dir.path.clone(),
+            RestoreOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert!(
+        stats
+            .errors
+            .iter()
+            .any(|err| err.starts_with("Dangling blob link")),
+        "{stats:?}"
+    );
+    let stats = core
+        .try_restore(
+            temp_dir.path.clone(),
+            RestoreOptions {
+                tolerant: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        stats.skipped[&Family::Blob].get("Dangling blob link"),
+        Some(&1),
+        "{stats:?}"
+    );
+    assert!(db.get_value::<()>(link_key).await.unwrap().is_none());
+    db.destroy().await;
+    temp_dir.delete();
+
+    // Mailboxes shared with principals missing from a remapped restore
+    // should lose those grants only
+    println!("Validating remapped ACL grants...");
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(u32::MAX)
+        .with_collection(Collection::Principal);
+    for principal_id in [1u32, 2, 4] {
+        batch.update_document(principal_id).set(
+            ValueClass::Directory(DirectoryClass::Principal(principal_id)),
+            vec![1, principal_id as u8],
+        );
+    }
+    batch
+        .with_account_id(1)
+        .with_collection(Collection::Mailbox)
+        .update_document(0);
+    for grantee in [2, 3, 4] {
+        batch.set(ValueClass::Acl(grantee), vec![grantee as u8]);
+    }
+    db.write(batch.build()).await.unwrap();
+    let temp_dir = TempDir::new("art_vandelay_acl_tests", true);
+    core.backup(temp_dir.path.clone(), Default::default()).await;
+    db.destroy().await;
+    let stats = core
+        .try_restore(
+            temp_dir.path.clone(),
+            RestoreOptions {
+                account_remap: [(1, 11), (2, 12)].into_iter().collect(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        stats.skipped[&Family::Acl].get("Dangling ACL grant"),
+        Some(&1),
+        "{stats:?}"
+    );
+    for (grantee, expected) in [(12, true), (4, true), (3, false), (2, false)] {
+        let acl_key = ValueKey {
+            account_id: 11,
+            collection: Collection::Mailbox.into(),
+            document_id: 0,
+            class: ValueClass::Acl(grantee),
+        };
+        assert_eq!(
+            db.get_value::<()>(acl_key).await.unwrap().is_some(),
+            expected,
+            "grantee {grantee}"
+        );
+    }
+    db.destroy().await;
+    temp_dir.delete();
+
+    // Memberships in groups missing from the backup should be detected
+    println!("Validating dangling directory references...");
+    let member_of_key = ValueKey::from(ValueClass::Directory(DirectoryClass::MemberOf {
+        principal_id: 1,
+        member_of: 9,
+    }));
+    let name_key = ValueKey::from(ValueClass::Directory(DirectoryClass::NameToId(
+        b"john".to_vec(),
+    )));
+    db.write(
+        BatchBuilder::new()
+            .with_account_id(u32::MAX)
+            .with_collection(Collection::Principal)
+            .update_document(1)
+            .set(DirectoryClass::Principal(1), vec![1, 1])
+            .set(DirectoryClass::NameToId(b"john".to_vec()), vec![1, 0])
+            .set(
+                DirectoryClass::MemberOf {
+                    principal_id: 1,
+                    member_of: 9,
+                },
+                vec![],
+            )
+            .build_batch(),
+    )
+    .await
+    .unwrap();
+    let temp_dir = TempDir::new("art_vandelay_directory_tests", true);
+    core.backup(temp_dir.path.clone(), Default::default()).await;
+    db.destroy().await;
+    let err = core
+        .try_restore(temp_dir.path.clone(), Default::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.family, Family::Directory, "{err}");
+    assert!(err.cause.contains("Dangling directory reference"), "{err}");
+    db.destroy().await;
+    let stats = core
+        .try_restore(
+            temp_dir.path.clone(),
+            RestoreOptions {
+                tolerant: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        stats.skipped[&Family::Directory].get("Dangling directory reference"),
+        Some(&1),
+        "{stats:?}"
+    );
+    assert!(db.get_value::<()>(member_of_key).await.unwrap().is_none());
+    assert!(db.get_value::<()>(name_key).await.unwrap().is_some());
+    db.destroy().await;
+    temp_dir.delete();
+
+    // Blobs of other blob stores should be restored into the store they were
+    // exported from, or the one it is remapped to
+    println!("Validating blobs of other blob stores...");
+    let temp_dir = TempDir::new("art_vandelay_blob_store_tests", true);
+    let tier = fs_blob_store(temp_dir.path.join("tier")).await;
+    let archive = fs_blob_store(temp_dir.path.join("archive")).await;
+    let body = b"archived attachment".to_vec();
+    let hash = BlobHash::from(body.as_slice());
+    tier.put_blob(hash.as_slice(), &body).await.unwrap();
+    db.write(
+        BatchBuilder::new()
+            .set(BlobOp::Commit { hash: hash.clone() }, vec![])
+            .with_account_id(0)
+            .with_collection(0)
+            .update_document(0)
+            .set(BlobOp::Link { hash: hash.clone() }, vec![])
+            .build_batch(),
+    )
+    .await
+    .unwrap();
+    let mut tiered = core.clone();
+    tiered.storage.blobs = [("tier".to_string(), tier.clone())].into_iter().collect();
+    let backup_dir = temp_dir.path.join("backup");
+    tiered.backup(backup_dir.clone(), Default::default()).await;
+    let manifest = BackupManifest::read(&backup_dir.clone().into())
+        .await
+        .unwrap()
+        .expect("Manifest not found");
+    assert_eq!(
+        manifest.blob_stores,
+        BTreeMap::from([(0, "tier".to_string())]),
+        "{manifest:?}"
+    );
+    db.destroy().await;
+    let err = core
+        .try_restore(backup_dir.clone(), Default::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.family, Family::Blob, "{err}");
+    assert!(err.cause.contains("Blob store \"tier\" not found"), "{err}");
+    db.destroy().await;
+    tiered.storage.blobs = [
+        ("tier".to_string(), tier.clone()),
+        ("archive".to_string(), archive.clone()),
+    ]
+    .into_iter()
+    .collect();
+    tiered
+        .try_restore(
+            backup_dir.clone(),
+            RestoreOptions {
+                blob_store_remap: [("tier".to_string(), "archive".to_string())]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        archive
+            .get_blob(hash.as_slice(), 0..usize::MAX)
+            .await
+            .unwrap(),
+        Some(body.clone())
+    );
+    assert!(core
+        .storage
+        .blob
+        .get_blob(hash.as_slice(), 0..usize::MAX)
+        .await
+        .unwrap()
+        .is_none());
+    db.destroy().await;
+
+    // Files exported to a blob store are uploaded in parts, and families
+    // without a file are skipped as they are in a directory
+    println!("Validating backups in a blob store...");
+    let large = random_bytes(20 * 1024 * 1024);
+    let large_hash = BlobHash::from(large.as_slice());
+    core.storage
+        .blob
+        .put_blob(large_hash.as_slice(), &large)
+        .await
+        .unwrap();
+    db.write(
+        BatchBuilder::new()
+            .set(
+                BlobOp::Commit {
+                    hash: large_hash.clone(),
+                },
+                vec![],
+            )
+            .with_account_id(0)
+            .with_collection(0)
+            .update_document(0)
+            .set(
+                BlobOp::Link {
+                    hash: large_hash.clone(),
+                },
+                vec![],
+            )
+            .build_batch(),
+    )
+    .await
+    .unwrap();
+    let location = BackupLocation::BlobStore {
+        id: "archive".to_string(),
+        store: archive.clone(),
+        prefix: "backups/latest".to_string(),
+    };
+    core.backup(location.clone(), Default::default()).await;
+    for (key, exists) in [
+        ("backups/latest/blob", true),
+        ("backups/latest/blob.part2", true),
+        ("backups/latest/blob.part3", false),
+    ] {
+        assert_eq!(
+            archive
+                .get_blob(key.as_bytes(), 0..1)
+                .await
+                .unwrap()
+                .is_some(),
+            exists,
+            "{key}"
+        );
+    }
+    assert!(archive.delete_blob(b"backups/latest/config").await.unwrap());
+    db.destroy().await;
+    core.try_restore(location.clone(), Default::default())
+        .await
+        .unwrap();
+    assert!(db.blob_exists(&large_hash).await.unwrap());
+    db.destroy().await;
+    temp_dir.delete();
+
+    // Blobs without any link should be left out when skipping orphans
+    println!("Validating orphaned blobs...");
+    let linked = BlobHash::from(b"linked".as_slice());
+    let orphan = BlobHash::from(b"orphan".as_slice());
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0);
+    for (hash, body) in [(&linked, b"linked"), (&orphan, b"orphan")] {
+        core.storage
+            .blob
+            .put_blob(hash.as_slice(), body)
+            .await
+            .unwrap();
+        batch.set(BlobOp::Commit { hash: hash.clone() }, vec![]);
+        if hash == &linked {
+            batch.set(BlobOp::Link { hash: hash.clone() }, vec![]);
+        }
+    }
+    db.write(batch.build()).await.unwrap();
+    let temp_dir = TempDir::new("art_vandelay_orphan_tests", true);
+    core.backup(
+        temp_dir.path.clone(),
+        BackupOptions {
+            skip_orphan_blobs: true,
+            ..Default::default()
+        },
+    )
+    .await;
+    let manifest = BackupManifest::read(&temp_dir.path.clone().into())
+        .await
+        .unwrap()
+        .expect("Manifest not found");
+    assert_eq!(manifest.blobs, 1, "{manifest:?}");
+    db.destroy().await;
+    core.restore(temp_dir.path.clone(), Default::default())
+        .await;
+    assert!(db.blob_exists(&linked).await.unwrap());
+    assert!(!db.blob_exists(&orphan).await.unwrap());
+    db.destroy().await;
+    temp_dir.delete();
+
+    // Queue events can be rescheduled on restore
+    println!("Validating queue due times...");
+    let event_key = |due| {
+        ValueKey::from(ValueClass::Queue(QueueClass::MessageEvent(QueueEvent {
+            due,
+            queue_id: 1,
+        })))
+    };
+    db.write(
+        BatchBuilder::new()
+            .set(
+                ValueClass::Queue(QueueClass::MessageEvent(QueueEvent {
+                    due: 1000,
+                    queue_id: 1,
+                })),
+                vec![],
+            )
+            .build_batch(),
+    )
+    .await
+    .unwrap();
+    let temp_dir = TempDir::new("art_vandelay_queue_tests", true);
+    core.backup(temp_dir.path.clone(), Default::default()).await;
+    for (queue_due, due) in [
+        (QueueDue::Keep, 1000),
+        (QueueDue::Offset(60), 1060),
+        (QueueDue::parse("+1h").unwrap(), 4600),
+    ] {
+        db.destroy().await;
+        core.restore(
+            temp_dir.path.clone(),
+            RestoreOptions {
+                queue_due,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(
+            db.get_value::<()>(event_key(due)).await.unwrap().is_some(),
+            "{queue_due:?}"
+        );
+    }
+    db.destroy().await;
+    let before = now();
+    core.restore(
+        temp_dir.path.clone(),
+        RestoreOptions {
+            queue_due: QueueDue::Now,
+            ..Default::default()
+        },
+    )
+    .await;
+    let mut dues = Vec::new();
+    db.iterate(
+        IterateParams::new(event_key(0), event_key(u64::MAX)).no_values(),
+        |key, _| {
+            dues.push(key.deserialize_be_u64(1)?);
+            Ok(true)
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(dues.len(), 1, "{dues:?}");
+    assert!((before..=now()).contains(&dues[0]), "{dues:?}");
+    db.destroy().await;
+    temp_dir.delete();
+
+    // The queue can be exported and imported on its own
+    println!("Validating queue export...");
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct QueuedMessage {
+        id: u64,
+        created: u64,
+        blob_hash: BlobHash,
+        return_path: String,
+    }
+    let queued_message = |id: u64, contents: &[u8]| {
+        Bincode::new(QueuedMessage {
+            id,
+            created: now(),
+            blob_hash: BlobHash::from(contents),
+            return_path: "sender@example.org".to_string(),
+        })
+        .serialize()
+    };
+    for contents in [b"queued message".as_slice(), b"mailbox message"] {
+        core.storage
+            .blob
+            .put_blob(BlobHash::from(contents).as_slice(), contents)
+            .await
+            .unwrap();
+    }
+    db.write(
+        BatchBuilder::new()
+            .set(
+                BlobOp::Commit {
+                    hash: BlobHash::from(b"queued message".as_slice()),
+                },
+                vec![],
+            )
+            .set(
+                ValueClass::Queue(QueueClass::Message(7)),
+                queued_message(7, b"queued message"),
+            )
+            .with_account_id(0)
+            .with_collection(0)
+            .update_document(0)
+            .set(
+                BlobOp::Link {
+                    hash: BlobHash::from(b"mailbox message".as_slice()),
+                },
+                vec![],
+            )
+            .build_batch(),
+    )
+    .await
+    .unwrap();
+    let temp_dir = TempDir::new("art_vandelay_queue_only_tests", true);
+    core.backup(
+        temp_dir.path.clone(),
+        BackupOptions {
+            queue_only: true,
+            ..Default::default()
+        },
+    )
+    .await;
+    let manifest = BackupManifest::read(&temp_dir.path.clone().into())
+        .await
+        .unwrap()
+        .expect("Manifest not found");
+    assert_eq!(
+        manifest.ops,
+        BTreeMap::from([(Family::Blob, 1), (Family::Queue, 1)]),
+        "{manifest:?}"
+    );
+    assert_eq!(manifest.blobs, 1, "{manifest:?}");
+    db.destroy().await;
+    let queue_options = RestoreOptions {
+        queue_only: true,
+        ..Default::default()
+    };
+    core.restore(temp_dir.path.clone(), queue_options.clone())
+        .await;
+    assert!(db
+        .get_value::<()>(ValueKey::from(ValueClass::Queue(QueueClass::Message(7))))
+        .await
+        .unwrap()
+        .is_some());
+    assert!(db
+        .blob_exists(&BlobHash::from(b"queued message".as_slice()))
+        .await
+        .unwrap());
+    db.destroy().await;
+    temp_dir.delete();
+
+    // Queued messages without their blob should be detected
+    db.write(
+        BatchBuilder::new()
+            .set(
+                ValueClass::Queue(QueueClass::Message(8)),
+                queued_message(8, b"missing message"),
+            )
+            .build_batch(),
+    )
+    .await
+    .unwrap();
+    let temp_dir = TempDir::new("art_vandelay_queue_missing_tests", true);
+    core.backup(
+        temp_dir.path.clone(),
+        BackupOptions {
+            queue_only: true,
+            ..Default::default()
+        },
+    )
+    .await;
+    db.destroy().await;
+    let err = core
+        .try_restore(temp_dir.path.clone(), queue_options)
+        .await
+        .unwrap_err();
+    assert_eq!(err.family, Family::Queue, "{err}");
+    assert!(err.cause.contains("Missing queued message blob"), "{err}");
+    db.destroy().await;
+    temp_dir.delete();
+
+    // Export the mailboxes of an account as a Maildir
+    println!("Testing Maildir export...");
+    let account_id = 3;
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(account_id)
+        .with_collection(Collection::Mailbox);
+    for (mailbox_id, mailbox) in [
+        Object::with_capacity(2)
+            .with_property(Property::Name, "Inbox")
+            .with_property(Property::Role, "inbox"),
+        Object::with_capacity(1).with_property(Property::Name, "Work"),
+        Object::with_capacity(2)
+            .with_property(Property::Name, "Projects.2024")
+            .with_property(Property::ParentId, Value::Id(Id::from(2u64))),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        batch.create_document(mailbox_id as u32).set(
+            ValueClass::Property(Property::Value.into()),
+            mailbox.serialize(),
+        );
+    }
+    batch.with_collection(Collection::Email);
+    let mut messages = Vec::new();
+    for (document_id, mailbox_ids, keywords) in [
+        (0u32, vec![0u32], vec![Keyword::Seen, Keyword::Flagged]),
+        (1, vec![2], vec![Keyword::Draft]),
+        (2, vec![0, 1], vec![]),
+    ] {
+        let contents = format!("Subject: message {document_id}\r\n\r\nbody\r\n").into_bytes();
+        let hash = BlobHash::from(contents.as_slice());
+        core.storage
+            .blob
+            .put_blob(hash.as_ref(), &contents)
+            .await
+            .unwrap();
+        batch
+            .create_document(document_id)
+            .set(ValueClass::Blob(BlobOp::Link { hash }), vec![])
+            .value(Property::Keywords, keywords, F_VALUE)
+            .value(Property::ReceivedAt, 1000 + document_id as u64, F_INDEX);
+        for mailbox_id in mailbox_ids {
+            batch.tag(Property::MailboxIds, mailbox_id, 0);
+        }
+        messages.push(contents);
+    }
+    db.write(batch.build()).await.unwrap();
+
+    let temp_dir = TempDir::new("art_vandelay_maildir_tests", true);
+    let stats = core
+        .export_maildir(&account_id.to_string(), &temp_dir.path)
+        .await
+        .unwrap();
+    assert_eq!(
+        stats.folders,
+        vec![
+            ("INBOX".to_string(), 2),
+            ("Work".to_string(), 1),
+            ("Work.Projects_2024".to_string(), 1)
+        ]
+    );
+    assert_eq!(stats.missing_blobs, 0);
+    for (folder, file_name, document_id) in [
+        ("", "1000.3_0.stalwart,S=28:2,FS", 0),
+        ("", "1002.3_2.stalwart,S=28:2,", 2),
+        (".Work", "1002.3_2.stalwart,S=28:2,", 2),
+        (".Work.Projects_2024", "1001.3_1.stalwart,S=28:2,D", 1),
+    ] {
+        let folder = temp_dir.path.join(folder);
+        for dir in ["new", "tmp"] {
+            assert!(folder.join(dir).is_dir(), "{folder:?}");
+        }
+        let file = folder.join("cur").join(file_name);
+        assert_eq!(
+            std::fs::read(&file).unwrap(),
+            messages[document_id],
+            "{file:?}"
+        );
+        assert_eq!(
+            std::fs::metadata(&file)
+                .unwrap()
+                .modified()
+                .unwrap()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            1000 + document_id as u64
+        );
+    }
+    assert!(core
+        .export_maildir("unknown", &temp_dir.path)
+        .await
+        .unwrap_err()
+        .contains("not found"));
+    db.destroy().await;
+    temp_dir.delete();
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -373,3 +2072,14 @@ impl Snapshot {
 fn random_bytes(len: usize) -> Vec<u8> {
     (0..len).map(|_| rand::random::<u8>()).collect()
 }
+
+async fn fs_blob_store(path: PathBuf) -> BlobStore {
+    BlobStore::from(
+        FsStore::open(
+            &mut Config::new(format!("store.fs.path = {path:?}\n")).unwrap(),
+            "store.fs",
+        )
+        .await
+        .unwrap(),
+    )
+}
@@ -107,6 +107,7 @@ pub async fn store_tests() {
     if insert {
         store.destroy().await;
     }
+    store.check_connectivity().await.unwrap();
 
     import_export::test(store.clone()).await;
     ops::test(store.clone()).await;